@@ -2,16 +2,19 @@
 extern crate byteorder;
 extern crate cannyls;
 extern crate futures;
+extern crate tempdir;
 extern crate test;
 #[macro_use]
 extern crate trackable;
 
-use cannyls::device::DeviceBuilder;
+use cannyls::device::{DeviceBuilder, SchedulingPolicy};
 use cannyls::lump::{LumpData, LumpId};
-use cannyls::nvm::MemoryNvm;
+use cannyls::nvm::{FileNvm, MemoryNvm};
 use cannyls::storage::StorageBuilder;
 use cannyls::{Error, Result};
 use futures::Future;
+use std::time::Duration;
+use tempdir::TempDir;
 use test::Bencher;
 
 fn id(id: usize) -> LumpId {
@@ -57,3 +60,59 @@ fn memory_put_and_delete_small(b: &mut Bencher) {
         i += 1;
     });
 }
+
+const RANDOM_GET_LUMPS: usize = 1024;
+const RANDOM_GET_BATCH: usize = 64;
+
+/// データ領域内に散らばったlump群の取得を、スケジューリング方針ごとに計測する.
+///
+/// シークの削減効果を計測するために、(direct I/Oを使う)`FileNvm`を使用する.
+/// 一時ディレクトリがSSDやメモリ上にある場合には、シークのコストがほぼ無いので、
+/// スケジューラ自体のオーバヘッドのみが計測されることになる.
+fn random_get(b: &mut Bencher, policy: SchedulingPolicy) {
+    let dir = track_try_unwrap!(TempDir::new("cannyls_bench").map_err(Error::from));
+    let nvm = track_try_unwrap!(FileNvm::create(
+        dir.path().join("bench.lusf"),
+        64 * 1024 * 1024
+    ));
+    let storage = track_try_unwrap!(StorageBuilder::new().journal_region_ratio(0.1).create(nvm));
+    let device = DeviceBuilder::new()
+        .scheduling_policy(policy)
+        .spawn(|| Ok(storage));
+    let d = device.handle();
+    let _ = wait(d.request().wait_for_running().list()); // デバイスの起動を待機
+
+    let data = LumpData::new(vec![0; 8 * 1024]).unwrap();
+    for i in 0..RANDOM_GET_LUMPS {
+        track_try_unwrap!(wait(d.request().put(id(i), data.clone())));
+    }
+
+    // データ領域内でランダムな位置にあるlump群を、まとめて取得する
+    let mut i = 0;
+    b.iter(|| {
+        let futures = (0..RANDOM_GET_BATCH)
+            .map(|_| {
+                i += 1;
+                d.request().get(id((i * 7919) % RANDOM_GET_LUMPS))
+            })
+            .collect::<Vec<_>>();
+        for f in futures {
+            track_try_unwrap!(wait(f));
+        }
+    });
+}
+
+#[bench]
+fn file_random_get_deadline(b: &mut Bencher) {
+    random_get(b, SchedulingPolicy::Deadline);
+}
+
+#[bench]
+fn file_random_get_elevator(b: &mut Bencher) {
+    random_get(
+        b,
+        SchedulingPolicy::Elevator {
+            urgency: Duration::from_millis(10),
+        },
+    );
+}
@@ -8,7 +8,7 @@ extern crate trackable;
 
 use cannyls::lump::{LumpData, LumpId};
 use cannyls::nvm::{FileNvm, MemoryNvm};
-use cannyls::storage::StorageBuilder;
+use cannyls::storage::{LumpIndexBackend, StorageBuilder};
 use tempdir::TempDir;
 use test::Bencher;
 
@@ -103,3 +103,25 @@ fn memory_put_and_get_and_delete_small(b: &mut Bencher) {
         i += 1;
     });
 }
+
+#[bench]
+fn memory_put_and_delete_with_million_lumps_compact_index(b: &mut Bencher) {
+    let nvm = MemoryNvm::new(vec![0; 256 * 1024 * 1024]);
+    let mut storage = track_try_unwrap!(StorageBuilder::new()
+        .journal_region_ratio(0.99)
+        .lump_index_backend(LumpIndexBackend::Compact)
+        .create(nvm));
+    let data = LumpData::new_embedded("foo".into()).unwrap();
+    for i in 0..1_000_000 {
+        track_try_unwrap!(storage.put(&id(i * 2), &data));
+    }
+
+    // 既存のエントリの間に挿入・削除を行う
+    let mut i = 0;
+    b.iter(|| {
+        let id = id((i * 7_919 % 1_000_000) * 2 + 1);
+        track_try_unwrap!(storage.put(&id, &data));
+        track_try_unwrap!(storage.delete(&id));
+        i += 1;
+    });
+}
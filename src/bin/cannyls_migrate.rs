@@ -0,0 +1,64 @@
+//! ストレージをオフラインで移行するためのコマンド.
+//!
+//! ```text
+//! Usage: cannyls_migrate [--dry-run] [--target <MAJOR.MINOR>] <FILE>
+//! ```
+//!
+//! `--target`が省略された場合には、v1の最新バージョンへの移行が行われる.
+//! 中断された移行が残っているファイルが指定された場合には、その続きから再開される.
+use cannyls::nvm::FileNvmBuilder;
+use cannyls::storage::{Migrator, StorageVersion};
+use cannyls::{ErrorKind, Result};
+use std::env;
+use std::process;
+use trackable::{track, track_assert, track_assert_some, track_panic};
+
+const USAGE: &str = "Usage: cannyls_migrate [--dry-run] [--target <MAJOR.MINOR>] <FILE>";
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    let mut migrator = Migrator::new();
+    let mut dry_run = false;
+    let mut path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--target" => {
+                let version = track_assert_some!(args.next(), ErrorKind::InvalidInput; USAGE);
+                migrator.target(track!(version.parse::<StorageVersion>())?);
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ => {
+                track_assert!(path.is_none(), ErrorKind::InvalidInput; USAGE);
+                path = Some(arg);
+            }
+        }
+    }
+    let path = track_assert_some!(path, ErrorKind::InvalidInput; USAGE);
+
+    let nvm = track!(FileNvmBuilder::new().read_only(dry_run).open(&path))?;
+    let report = track!(migrator.dry_run(dry_run).migrate(nvm))?;
+    if report.resumed {
+        println!("Resumed an interrupted migration");
+    }
+    if report.steps.is_empty() {
+        println!("Already at v{}", report.to);
+    }
+    for (source, target, description) in &report.steps {
+        println!("v{} -> v{}: {}", source, target, description);
+    }
+    if report.dry_run {
+        println!("(dry run: nothing was written)");
+    }
+    Ok(())
+}
@@ -18,7 +18,7 @@ impl AlignedBytes {
     pub fn new(size: usize, block_size: BlockSize) -> Self {
         // バッファの前後をブロック境界に合わせて十分なだけの領域を確保しておく
         let capacity =
-            block_size.ceil_align(size as u64) as usize + block_size.as_u16() as usize - 1;
+            block_size.ceil_align(size as u64) as usize + block_size.as_u32() as usize - 1;
 
         // ゼロ埋めのコストを省くためにunsafeを使用
         let mut buf = Vec::with_capacity(capacity);
@@ -80,7 +80,7 @@ impl AlignedBytes {
     pub fn resize(&mut self, new_len: usize) {
         let new_capacity = self.block_size.ceil_align(new_len as u64) as usize;
         if new_capacity > self.buf.len() - self.offset {
-            let mut new_buf = vec![0; new_capacity + self.block_size.as_u16() as usize - 1];
+            let mut new_buf = vec![0; new_capacity + self.block_size.as_u32() as usize - 1];
             let new_offset = alignment_offset(&new_buf, self.block_size);
             (&mut new_buf[new_offset..][..self.len]).copy_from_slice(self.as_ref());
 
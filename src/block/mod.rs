@@ -15,7 +15,7 @@ mod aligned_bytes;
 /// [`Storage`]: ../storage/struct.Storage.html
 /// [`NonVolatileMemory`]: ../nvm/trait.NonVolatileMemory.html
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct BlockSize(u16);
+pub struct BlockSize(u32);
 impl BlockSize {
     /// 許容されるブロックサイズの最小値.
     ///
@@ -24,6 +24,11 @@ impl BlockSize {
     /// また`BlockSize::default()`で使われる値でもある.
     pub const MIN: u16 = 512;
 
+    /// 許容されるブロックサイズの最大値.
+    ///
+    /// `u16`の範囲を超えるブロックサイズは、ワイドフォーマット(v2)のストレージでのみ使用可能.
+    pub const MAX: u32 = 1024 * 1024;
+
     /// 許容可能な最小のブロックサイズを持つ`BlockSize`インスタンスを返す.
     ///
     /// # Examples
@@ -34,7 +39,7 @@ impl BlockSize {
     /// assert_eq!(BlockSize::min().as_u16(), BlockSize::MIN);
     /// ```
     pub fn min() -> Self {
-        BlockSize(Self::MIN)
+        BlockSize(u32::from(Self::MIN))
     }
 
     /// 指定された値のブロックサイズを表現する`BlockSize`インスタンスを生成する.
@@ -62,6 +67,33 @@ impl BlockSize {
     pub fn new(block_size: u16) -> Result<Self> {
         track_assert!(block_size >= Self::MIN, ErrorKind::InvalidInput);
         track_assert_eq!(block_size % Self::MIN, 0, ErrorKind::InvalidInput);
+        Ok(BlockSize(u32::from(block_size)))
+    }
+
+    /// `u32`で指定された値のブロックサイズを表現する`BlockSize`インスタンスを生成する.
+    ///
+    /// `BlockSize::new`に加えて、`block_size`が`BlockSize::MAX`を超えている場合にもエラーとなる.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cannyls::ErrorKind;
+    /// use cannyls::block::BlockSize;
+    ///
+    /// assert_eq!(BlockSize::from_u32(65536).ok().map(|a| a.as_u32()), Some(65536));
+    ///
+    /// assert_eq!(BlockSize::from_u32(513).err().map(|e| *e.kind()), Some(ErrorKind::InvalidInput));
+    /// assert_eq!(BlockSize::from_u32(BlockSize::MAX * 2).err().map(|e| *e.kind()),
+    ///            Some(ErrorKind::InvalidInput));
+    /// ```
+    pub fn from_u32(block_size: u32) -> Result<Self> {
+        track_assert!(block_size >= u32::from(Self::MIN), ErrorKind::InvalidInput);
+        track_assert!(block_size <= Self::MAX, ErrorKind::InvalidInput);
+        track_assert_eq!(
+            block_size % u32::from(Self::MIN),
+            0,
+            ErrorKind::InvalidInput
+        );
         Ok(BlockSize(block_size))
     }
 
@@ -100,7 +132,22 @@ impl BlockSize {
     }
 
     /// ブロックサイズ値を`u16`に変換して返す.
+    ///
+    /// # Panics
+    ///
+    /// ブロックサイズが`u16`の範囲に収まらない場合には、現在のスレッドがパニックする.
+    /// そのようなブロックサイズを扱う可能性がある場合には`as_u32`を使用すること.
     pub fn as_u16(self) -> u16 {
+        assert!(
+            self.0 <= u32::from(u16::MAX),
+            "Too large block size: {}",
+            self.0
+        );
+        self.0 as u16
+    }
+
+    /// ブロックサイズ値を`u32`として返す.
+    pub fn as_u32(self) -> u32 {
         self.0
     }
 
@@ -0,0 +1,306 @@
+use std::ops::Range;
+use std::time::Duration;
+
+use super::{CancelToken, DeviceHandle, DeviceRequest, QosClass};
+use crate::deadline::Deadline;
+use crate::lump::{LumpData, LumpHeader, LumpId};
+use crate::storage::{Durability, StorageUsage};
+use crate::Result;
+
+/// デバイスを同期的に操作するためのハンドル.
+///
+/// `DeviceHandle`のラッパーで、各操作は結果が得られるまで呼び出し元のスレッドをブロックする.
+/// [`fibers`]のエグゼキュータを必要としないので、CLIやスクリプト等の同期的なコードから利用しやすい.
+///
+/// なお、非同期ランタイムのスレッド(e.g., `fibers`のファイバー)内から呼び出してはいけない.
+///
+/// # Errors
+///
+/// 各操作は、`DeviceRequest`の対応するメソッドと同じエラーを返す.
+/// それに加えて、以下の場合にもエラーとなる:
+///
+/// - タイムアウトが経過しても結果が得られなかった: `ErrorKind::Timeout`
+/// - 結果が得られる前にデバイス(の管理スレッド)が停止した: `ErrorKind::DeviceTerminated`
+///
+/// なお、タイムアウトは、デバイスの過負荷等によってリクエストが拒否された場合の`ErrorKind::DeviceBusy`とは区別される.
+///
+/// [`fibers`]: https://github.com/dwango/fibers-rs
+#[derive(Debug, Clone)]
+pub struct BlockingDeviceHandle {
+    handle: DeviceHandle,
+    timeout: Option<Duration>,
+}
+impl BlockingDeviceHandle {
+    /// 新しい`BlockingDeviceHandle`インスタンスを生成する.
+    pub fn new(handle: DeviceHandle) -> Self {
+        BlockingDeviceHandle {
+            handle,
+            timeout: None,
+        }
+    }
+
+    /// 各操作のデフォルトのタイムアウトを設定する.
+    ///
+    /// デフォルトでは、タイムアウトは無し(i.e., 結果が得られるまで無期限に待機する).
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// 内部の`DeviceHandle`を返す.
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// デバイスに発行するリクエストのビルダを返す.
+    ///
+    /// デッドライン等を指定したい場合に使用する.
+    pub fn request(&self) -> BlockingDeviceRequest<'_> {
+        BlockingDeviceRequest {
+            request: self.handle.request(),
+            deadline: None,
+            timeout: self.timeout,
+        }
+    }
+
+    /// Lumpを格納する.
+    ///
+    /// 新規追加の場合には`true`が、上書きの場合は`false`が、結果として返される.
+    pub fn put(&self, lump_id: LumpId, lump_data: LumpData) -> Result<bool> {
+        track!(self.request().put(lump_id, lump_data))
+    }
+
+    /// Lumpを取得する.
+    pub fn get(&self, lump_id: LumpId) -> Result<Option<LumpData>> {
+        track!(self.request().get(lump_id))
+    }
+
+    /// Lumpのヘッダを取得する.
+    pub fn head(&self, lump_id: LumpId) -> Result<Option<LumpHeader>> {
+        track!(self.request().head(lump_id))
+    }
+
+    /// Lumpを削除する.
+    ///
+    /// 指定されたlumpが存在した場合には`true`が、しなかった場合には`false`が、結果として返される.
+    pub fn delete(&self, lump_id: LumpId) -> Result<bool> {
+        track!(self.request().delete(lump_id))
+    }
+
+    /// Lumpを範囲オブジェクトを用いて削除する.
+    pub fn delete_range(&self, range: Range<LumpId>) -> Result<Vec<LumpId>> {
+        track!(self.request().delete_range(range))
+    }
+
+    /// 保存されているlump一覧を取得する.
+    pub fn list(&self) -> Result<Vec<LumpId>> {
+        track!(self.request().list())
+    }
+
+    /// 範囲を指定してlump一覧を取得する.
+    pub fn list_range(&self, range: Range<LumpId>) -> Result<Vec<LumpId>> {
+        track!(self.request().list_range(range))
+    }
+
+    /// 範囲を指定してlumpの使用量を取得する.
+    pub fn usage_range(&self, range: Range<LumpId>) -> Result<StorageUsage> {
+        track!(self.request().usage_range(range))
+    }
+}
+impl From<DeviceHandle> for BlockingDeviceHandle {
+    fn from(f: DeviceHandle) -> Self {
+        BlockingDeviceHandle::new(f)
+    }
+}
+
+/// デバイスに対して同期的にリクエストを発行するためのビルダ.
+///
+/// 各設定の意味は`DeviceRequest`と同様.
+#[derive(Debug)]
+pub struct BlockingDeviceRequest<'a> {
+    request: DeviceRequest<'a>,
+    deadline: Option<Deadline>,
+    timeout: Option<Duration>,
+}
+impl<'a> BlockingDeviceRequest<'a> {
+    /// Lumpを格納する.
+    pub fn put(&mut self, lump_id: LumpId, lump_data: LumpData) -> Result<bool> {
+        let timeout = self.prepare();
+        track!(self.request.put(lump_id, lump_data).wait_timeout(timeout))
+    }
+
+    /// Lumpを取得する.
+    pub fn get(&mut self, lump_id: LumpId) -> Result<Option<LumpData>> {
+        let timeout = self.prepare();
+        track!(self.request.get(lump_id).wait_timeout(timeout))
+    }
+
+    /// Lumpのヘッダを取得する.
+    pub fn head(&mut self, lump_id: LumpId) -> Result<Option<LumpHeader>> {
+        let timeout = self.prepare();
+        track!(self.request.head(lump_id).wait_timeout(timeout))
+    }
+
+    /// Lumpを削除する.
+    pub fn delete(&mut self, lump_id: LumpId) -> Result<bool> {
+        let timeout = self.prepare();
+        track!(self.request.delete(lump_id).wait_timeout(timeout))
+    }
+
+    /// Lumpを範囲オブジェクトを用いて削除する.
+    pub fn delete_range(&mut self, range: Range<LumpId>) -> Result<Vec<LumpId>> {
+        let timeout = self.prepare();
+        track!(self.request.delete_range(range).wait_timeout(timeout))
+    }
+
+    /// 保存されているlump一覧を取得する.
+    pub fn list(&mut self) -> Result<Vec<LumpId>> {
+        let timeout = self.prepare();
+        track!(self.request.list().wait_timeout(timeout))
+    }
+
+    /// 範囲を指定してlump一覧を取得する.
+    pub fn list_range(&mut self, range: Range<LumpId>) -> Result<Vec<LumpId>> {
+        let timeout = self.prepare();
+        track!(self.request.list_range(range).wait_timeout(timeout))
+    }
+
+    /// 範囲を指定してlumpの使用量を取得する.
+    pub fn usage_range(&mut self, range: Range<LumpId>) -> Result<StorageUsage> {
+        let timeout = self.prepare();
+        track!(self.request.usage_range(range).wait_timeout(timeout))
+    }
+
+    /// 結果を待機する最大時間を指定する.
+    ///
+    /// デッドラインが明示的に指定されていない場合には、
+    /// デバイス側のスケジューリングにも反映されるように、`Deadline::Within(timeout)`がデッドラインとして使用される.
+    ///
+    /// デフォルト値は`BlockingDeviceHandle::timeout`で指定された値.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// 要求のデッドラインを設定する.
+    ///
+    /// デフォルト値は`Deadline::Infinity`(ただしタイムアウトが指定されている場合は`Deadline::Within(timeout)`).
+    pub fn deadline(&mut self, deadline: Deadline) -> &mut Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// 書き込み系のリクエストに要求する永続化の度合いを指定する.
+    pub fn durability(&mut self, durability: Durability) -> &mut Self {
+        self.request.durability(durability);
+        self
+    }
+
+    /// デバイスのキューの最大長を指定する.
+    pub fn max_queue_len(&mut self, max: usize) -> &mut Self {
+        self.request.max_queue_len(max);
+        self
+    }
+
+    /// デバイスが起動処理中の場合には、その完了を待つように指示する.
+    pub fn wait_for_running(&mut self) -> &mut Self {
+        self.request.wait_for_running();
+        self
+    }
+
+    /// リクエストを優先的に処理する.
+    pub fn prioritized(&mut self) -> &mut Self {
+        self.request.prioritized();
+        self
+    }
+
+    /// リクエストをキャンセルするためのトークンを指定する.
+    pub fn cancel_token(&mut self, token: CancelToken) -> &mut Self {
+        self.request.cancel_token(token);
+        self
+    }
+
+    /// リクエストのQoSクラスを指定する.
+    pub fn qos_class(&mut self, class: QosClass) -> &mut Self {
+        self.request.qos_class(class);
+        self
+    }
+
+    fn prepare(&mut self) -> Option<Duration> {
+        if let Some(deadline) = self.deadline.or_else(|| self.timeout.map(Deadline::Within)) {
+            self.request.deadline(deadline);
+        }
+        self.timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+    use std::time::Duration;
+    use trackable::result::TestResult;
+
+    use super::*;
+    use crate::device::{Device, DeviceBuilder};
+    use crate::nvm::MemoryNvm;
+    use crate::storage::StorageBuilder;
+    use crate::ErrorKind;
+
+    fn id(id: usize) -> LumpId {
+        LumpId::new(id as u128)
+    }
+
+    fn data(data: &[u8]) -> LumpData {
+        LumpData::new(Vec::from(data)).unwrap()
+    }
+
+    #[test]
+    fn blocking_handle_works() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+        let storage = track!(StorageBuilder::new().journal_region_ratio(0.99).create(nvm))?;
+        let mut device = DeviceBuilder::new().spawn(|| Ok(storage));
+        let mut d = BlockingDeviceHandle::new(device.handle());
+        d.timeout(Duration::from_secs(10));
+
+        assert!(track!(d
+            .request()
+            .wait_for_running()
+            .put(id(0), data(b"foo")))?);
+        assert!(track!(d.put(id(1), data(b"bar")))?);
+        assert!(!track!(d.put(id(1), data(b"baz")))?);
+        assert_eq!(
+            track!(d.get(id(1)))?.map(|v| v.as_bytes().to_owned()),
+            Some(b"baz".to_vec())
+        );
+        assert!(track!(d.head(id(2)))?.is_none());
+        assert_eq!(track!(d.list_range(id(0)..id(10)))?, vec![id(0), id(1)]);
+        assert!(track!(d.delete(id(0)))?);
+        assert_eq!(track!(d.list())?, vec![id(1)]);
+
+        // デバイスの停止後は`DeviceTerminated`となる
+        device.stop(Deadline::Immediate);
+        while !track!(device.poll())?.is_ready() {}
+        assert_eq!(
+            d.get(id(1)).err().map(|e| *e.kind()),
+            Some(ErrorKind::DeviceTerminated)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn blocking_handle_timeout_works() -> TestResult {
+        // 起動が終わらないデバイス
+        let device = Device::spawn(|| -> Result<crate::storage::Storage<MemoryNvm>> {
+            std::thread::sleep(Duration::from_millis(500));
+            track_panic!(ErrorKind::Other)
+        });
+        let d = BlockingDeviceHandle::new(device.handle());
+        let result = d
+            .request()
+            .wait_for_running()
+            .timeout(Duration::from_millis(10))
+            .get(id(0));
+        assert_eq!(result.err().map(|e| *e.kind()), Some(ErrorKind::Timeout));
+        Ok(())
+    }
+}
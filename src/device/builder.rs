@@ -2,6 +2,8 @@ use prometrics::metrics::MetricBuilder;
 use std::time::Duration;
 
 use super::long_queue_policy::LongQueuePolicy;
+use super::qos::QosClass;
+use super::scheduling_policy::SchedulingPolicy;
 use super::thread::DeviceThread;
 use super::{Device, DeviceHandle};
 use crate::nvm::NonVolatileMemory;
@@ -19,6 +21,12 @@ pub struct DeviceBuilder {
     pub(crate) busy_threshold: usize,
     pub(crate) logger: Logger,
     pub(crate) long_queue_policy: LongQueuePolicy,
+    pub(crate) max_group_commit_size: usize,
+    pub(crate) max_group_commit_delay: Duration,
+    pub(crate) reader_threads: usize,
+    pub(crate) expiry_grace_period: Option<Duration>,
+    pub(crate) qos_classes: Vec<(QosClass, u32)>,
+    pub(crate) scheduling_policy: SchedulingPolicy,
 }
 impl DeviceBuilder {
     /// デフォルト設定で`DeviceBuilder`インスタンスを生成する.
@@ -31,6 +39,12 @@ impl DeviceBuilder {
             busy_threshold: 1_000,
             logger: Logger::root(Discard, o!()),
             long_queue_policy: LongQueuePolicy::default(),
+            max_group_commit_size: 1024,
+            max_group_commit_delay: Duration::from_millis(10),
+            reader_threads: 0,
+            expiry_grace_period: None,
+            qos_classes: Vec::new(),
+            scheduling_policy: SchedulingPolicy::default(),
         }
     }
 
@@ -108,6 +122,106 @@ impl DeviceBuilder {
         self
     }
 
+    /// グループコミットでまとめるコマンドの最大数.
+    ///
+    /// 永続化が要求されたコマンド(e.g., `DeviceRequest::journal_sync`)の応答は、
+    /// 同期が完了するまで保留される.
+    /// 保留中のコマンド群は、キューが空になった時点、その数がこの値に達した時点、
+    /// あるいは、最初の保留から`max_group_commit_delay`が経過した時点で、
+    /// 一度の`Storage::sync_with`の呼び出しでまとめて永続化され、その後に応答が返される.
+    ///
+    /// `1`を指定した場合には、コマンド毎に同期が行われるようになる.
+    ///
+    /// デフォルト値は`1024`.
+    pub fn max_group_commit_size(&mut self, n: usize) -> &mut Self {
+        self.max_group_commit_size = std::cmp::max(1, n);
+        self
+    }
+
+    /// グループコミットのために、永続化が要求されたコマンドの応答を保留する最大時間.
+    ///
+    /// 同期を要求しないコマンドが途切れずに届き続け、キューが空にならない場合でも、
+    /// 最初の保留からこの時間が経過した時点で、保留中のコマンド群はコミットされる.
+    ///
+    /// デフォルト値は`Duration::from_millis(10)`.
+    pub fn max_group_commit_delay(&mut self, delay: Duration) -> &mut Self {
+        self.max_group_commit_delay = delay;
+        self
+    }
+
+    /// `GET`コマンドを並列に処理するための、読み込み用スレッドの数.
+    ///
+    /// `0`より大きい値が指定された場合には、デバイスの実行スレッドとは別に、指定された数の読み込み用スレッドが起動され、
+    /// `GET`コマンドはそれらのスレッドで処理されるようになる.
+    /// 各スレッドは、ストレージのインデックスを共有しつつ、それぞれ専用の読み込みハンドルを使ってデータ領域を参照するので、
+    /// 書き込み系のコマンドの後ろに並ぶことなく、並列に処理が行われる.
+    /// 書き込み系のコマンドは、従来通り、デバイスの実行スレッドのみで直列に処理される.
+    ///
+    /// なお、読み込み用スレッドでは、デッドラインや優先度に関係なく、到着順にコマンドが処理される.
+    /// 読み込み用スレッド群のキューの長さの上限は`max_queue_len`で、
+    /// それを超えて届いたコマンドには、即座に`ErrorKind::DeviceBusy`エラーが返される.
+    /// また、ジャーナル領域に埋め込まれているlumpに対するコマンドは、デバイスの実行スレッドに転送されて処理される.
+    ///
+    /// ストレージのNVMが並列読み込み(`NonVolatileMemory::reader`)に対応していない場合には、
+    /// この設定は無視される.
+    ///
+    /// デフォルト値は`0`(i.e., 全てのコマンドをデバイスの実行スレッドで処理する).
+    pub fn reader_threads(&mut self, n: usize) -> &mut Self {
+        self.reader_threads = n;
+        self
+    }
+
+    /// デッドラインを過ぎたコマンドを、実行せずに破棄するようにする.
+    ///
+    /// これが指定された場合には、キューから取り出された時点で、
+    /// デッドライン(`Deadline::Within`から算出された絶対時刻)を`grace_period`以上過ぎているコマンドは、
+    /// 実行されずに`ErrorKind::RequestExpired`エラーで終了する.
+    /// 発行元が既に結果を待つのを諦めているようなコマンドで、デバイスを占有しないようにするためのもの.
+    ///
+    /// なお、`Deadline::Immediate`や`Deadline::Infinity`が指定されたコマンドは対象外.
+    /// また、読み込み用スレッド(`reader_threads`)で処理される`GET`コマンドも対象外となる.
+    ///
+    /// デフォルトでは、デッドラインを過ぎたコマンドも通常通りに実行される.
+    pub fn expiry_grace_period(&mut self, grace_period: Duration) -> &mut Self {
+        self.expiry_grace_period = Some(grace_period);
+        self
+    }
+
+    /// QoSクラスとその重みを登録する.
+    ///
+    /// キューに複数のクラスのコマンドが溜まっている場合には、
+    /// デバイスの実行時間は、各クラスの重みに比例するように配分される(重み付き公平キューイング).
+    /// 同じクラス内のコマンド群は、従来通りデッドラインに基づいて順序付けられる.
+    ///
+    /// クラスは`DeviceRequest::qos_class`で指定する.
+    /// クラスが指定されていない、あるいは、登録されていないクラスが指定されたリクエストは、
+    /// デフォルトクラス(`QosClass::default()`)として扱われる.
+    /// デフォルトクラスの重みは、明示的に登録されない限り`1`となる.
+    ///
+    /// 同じクラスが複数回登録された場合には、後の重みで上書きされる.
+    /// また、重みに`0`が指定された場合には`1`として扱われる.
+    ///
+    /// なお、読み込み用スレッド(`reader_threads`)で処理される`GET`コマンドは、スケジューリングの対象外となる.
+    pub fn qos_class(&mut self, class: QosClass, weight: u32) -> &mut Self {
+        let weight = std::cmp::max(1, weight);
+        if let Some(entry) = self.qos_classes.iter_mut().find(|(c, _)| *c == class) {
+            entry.1 = weight;
+        } else {
+            self.qos_classes.push((class, weight));
+        }
+        self
+    }
+
+    /// キュー内のコマンド群の実行順を決定する方式を指定する.
+    ///
+    /// QoSクラスが登録されている場合には、各クラス内での実行順に適用される.
+    ///
+    /// デフォルト値は`SchedulingPolicy::Deadline`.
+    pub fn scheduling_policy(&mut self, policy: SchedulingPolicy) -> &mut Self {
+        self.scheduling_policy = policy;
+        self
+    }
+
     /// 指定されたストレージを扱う`Device`を起動する.
     ///
     /// 起動したデバイス用に、一つの専用OSスレッドが割り当てられる.
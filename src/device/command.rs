@@ -1,13 +1,24 @@
 //! デバイスに発行されるコマンド群の定義.
 use fibers::sync::oneshot;
 use futures::{Future, Poll};
+use prometrics::metrics::Histogram;
 use std::ops::Range;
+#[cfg(feature = "std-future")]
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "std-future")]
+use std::task::{self, Context};
+use std::task::{Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
 use trackable::error::ErrorKindExt;
 
 use crate::deadline::Deadline;
+use crate::device::qos::QosClass;
 use crate::lump::{LumpData, LumpHeader, LumpId};
-use crate::storage::StorageUsage;
+use crate::storage::{Durability, StorageUsage};
 use crate::{Error, ErrorKind, Result};
 
 pub type CommandSender = Sender<Command>;
@@ -52,6 +63,95 @@ impl Command {
             Command::Stop(ref c) => c.prioritized,
         }
     }
+    /// コマンドが発行元によってキャンセルされているかどうかを判定する.
+    pub fn is_cancelled(&self) -> bool {
+        match *self {
+            // 更新系のコマンドは、明示的なトークンによってのみキャンセルされる
+            Command::Put(ref c) => c.reply.is_cancelled(false),
+            Command::Get(ref c) => c.reply.is_cancelled(true),
+            Command::Head(ref c) => c.reply.is_cancelled(true),
+            Command::Delete(ref c) => c.reply.is_cancelled(false),
+            Command::DeleteRange(ref c) => c.reply.is_cancelled(false),
+            Command::List(ref c) => c.reply.is_cancelled(true),
+            Command::ListRange(ref c) => c.reply.is_cancelled(true),
+            Command::UsageRange(ref c) => c.reply.is_cancelled(true),
+            Command::Stop(_) => false,
+        }
+    }
+    /// コマンドのQoSクラスを返す.
+    ///
+    /// 指定されていない場合には`None`が返される.
+    pub fn qos_class(&self) -> Option<&QosClass> {
+        match *self {
+            Command::Put(ref c) => c.reply.qos_class.as_ref(),
+            Command::Get(ref c) => c.reply.qos_class.as_ref(),
+            Command::Head(ref c) => c.reply.qos_class.as_ref(),
+            Command::Delete(ref c) => c.reply.qos_class.as_ref(),
+            Command::DeleteRange(ref c) => c.reply.qos_class.as_ref(),
+            Command::List(ref c) => c.reply.qos_class.as_ref(),
+            Command::ListRange(ref c) => c.reply.qos_class.as_ref(),
+            Command::UsageRange(ref c) => c.reply.qos_class.as_ref(),
+            Command::Stop(_) => None,
+        }
+    }
+    pub fn set_qos_class(&mut self, class: QosClass) {
+        match *self {
+            Command::Put(ref mut c) => c.reply.qos_class = Some(class),
+            Command::Get(ref mut c) => c.reply.qos_class = Some(class),
+            Command::Head(ref mut c) => c.reply.qos_class = Some(class),
+            Command::Delete(ref mut c) => c.reply.qos_class = Some(class),
+            Command::DeleteRange(ref mut c) => c.reply.qos_class = Some(class),
+            Command::List(ref mut c) => c.reply.qos_class = Some(class),
+            Command::ListRange(ref mut c) => c.reply.qos_class = Some(class),
+            Command::UsageRange(ref mut c) => c.reply.qos_class = Some(class),
+            Command::Stop(_) => {}
+        }
+    }
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        match *self {
+            Command::Put(ref mut c) => c.reply.cancel_token = Some(token),
+            Command::Get(ref mut c) => c.reply.cancel_token = Some(token),
+            Command::Head(ref mut c) => c.reply.cancel_token = Some(token),
+            Command::Delete(ref mut c) => c.reply.cancel_token = Some(token),
+            Command::DeleteRange(ref mut c) => c.reply.cancel_token = Some(token),
+            Command::List(ref mut c) => c.reply.cancel_token = Some(token),
+            Command::ListRange(ref mut c) => c.reply.cancel_token = Some(token),
+            Command::UsageRange(ref mut c) => c.reply.cancel_token = Some(token),
+            Command::Stop(_) => {}
+        }
+    }
+    /// コマンドの発行時刻を返す.
+    ///
+    /// `Stop`コマンドの場合には`None`が返される.
+    pub fn issued_at(&self) -> Option<Instant> {
+        match *self {
+            Command::Put(ref c) => Some(c.reply.issued_at),
+            Command::Get(ref c) => Some(c.reply.issued_at),
+            Command::Head(ref c) => Some(c.reply.issued_at),
+            Command::Delete(ref c) => Some(c.reply.issued_at),
+            Command::DeleteRange(ref c) => Some(c.reply.issued_at),
+            Command::List(ref c) => Some(c.reply.issued_at),
+            Command::ListRange(ref c) => Some(c.reply.issued_at),
+            Command::UsageRange(ref c) => Some(c.reply.issued_at),
+            Command::Stop(_) => None,
+        }
+    }
+    /// 応答の送信時に、発行からの経過時間(秒)を記録するヒストグラム群を設定する.
+    ///
+    /// 既に設定されているヒストグラム群は置き換えられる.
+    pub fn observe_latency_with(&mut self, histograms: Vec<Histogram>) {
+        match *self {
+            Command::Put(ref mut c) => c.reply.latency = histograms,
+            Command::Get(ref mut c) => c.reply.latency = histograms,
+            Command::Head(ref mut c) => c.reply.latency = histograms,
+            Command::Delete(ref mut c) => c.reply.latency = histograms,
+            Command::DeleteRange(ref mut c) => c.reply.latency = histograms,
+            Command::List(ref mut c) => c.reply.latency = histograms,
+            Command::ListRange(ref mut c) => c.reply.latency = histograms,
+            Command::UsageRange(ref mut c) => c.reply.latency = histograms,
+            Command::Stop(_) => {}
+        }
+    }
     pub fn failed(self, error: Error) {
         match self {
             Command::Put(c) => c.reply.send(Err(error)),
@@ -68,13 +168,73 @@ impl Command {
 }
 
 /// `Result`の非同期版.
+///
+/// デバイスに発行したリクエストの結果を受け取るための`Future`.
+///
+/// [`futures`](https://docs.rs/futures/0.1)の`Future`(v0.1)を実装しており、
+/// 効率的にポーリングするためには[`fibers`](https://github.com/dwango/fibers-rs)を使用する必要がある.
+///
+/// `std-future`フィーチャーが有効な場合には、`std::future::Future`も実装されるので、
+/// `fibers`を使わずに、任意の非同期ランタイム上で`.await`することができる.
+///
+/// 読み込み系のリクエスト(`GET`, `HEAD`, `LIST`等)の場合には、
+/// 結果を受け取る前にインスタンスが破棄されると、リクエストはキャンセルされたものとして扱われ、
+/// まだ実行されていなければ、デバイス側でスキップされる.
+/// 更新系のリクエスト(`PUT`, `DELETE`等)は、インスタンスが破棄されても実行される.
 #[derive(Debug)]
-pub struct AsyncResult<T>(oneshot::Monitor<T, Error>);
+pub struct AsyncResult<T> {
+    monitor: oneshot::Monitor<T, Error>,
+    waker: Arc<Mutex<Option<Waker>>>,
+    dropped: CancelToken,
+}
 impl<T> AsyncResult<T> {
     #[allow(clippy::new_ret_no_self)]
     fn new() -> (AsyncReply<T>, Self) {
         let (tx, rx) = oneshot::monitor();
-        (AsyncReply(tx), AsyncResult(rx))
+        let waker = Arc::new(Mutex::new(None));
+        let dropped = CancelToken::new();
+        let reply = AsyncReply {
+            monitored: Some(tx),
+            waker: waker.clone(),
+            receiver_dropped: dropped.clone(),
+            cancel_token: None,
+            issued_at: Instant::now(),
+            latency: Vec::new(),
+            qos_class: None,
+        };
+        let result = AsyncResult {
+            monitor: rx,
+            waker,
+            dropped,
+        };
+        (reply, result)
+    }
+
+    /// 結果が得られるまで、現在のスレッドをブロックする.
+    ///
+    /// `timeout`が指定されている場合には、その時間が経過しても結果が得られなければ
+    /// `ErrorKind::Timeout`エラーが返される.
+    pub(crate) fn wait_timeout(mut self, timeout: Option<Duration>) -> Result<T> {
+        let expiry = timeout.map(|t| Instant::now() + t);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        loop {
+            *self.waker.lock().expect("Never fails") = Some(waker.clone());
+            if let futures::Async::Ready(v) = track!(Future::poll(&mut self))? {
+                return Ok(v);
+            }
+            if let Some(expiry) = expiry {
+                let now = Instant::now();
+                track_assert!(
+                    now < expiry,
+                    ErrorKind::Timeout,
+                    "Timed out: timeout={:?}",
+                    timeout
+                );
+                thread::park_timeout(expiry - now);
+            } else {
+                thread::park();
+            }
+        }
     }
 }
 impl<T> Future for AsyncResult<T> {
@@ -82,19 +242,104 @@ impl<T> Future for AsyncResult<T> {
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         track!(self
-            .0
+            .monitor
             .poll()
             .map_err(|e| e.unwrap_or_else(|| ErrorKind::DeviceTerminated
                 .cause("monitoring channel disconnected")
                 .into())))
     }
 }
+#[cfg(feature = "std-future")]
+impl<T> std::future::Future for AsyncResult<T> {
+    type Output = Result<T>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        // 結果の送信と競合しても通知を取りこぼさないように、ポーリングの前にウェイカーを登録する
+        *this.waker.lock().expect("Never fails") = Some(cx.waker().clone());
+        match Future::poll(this) {
+            Ok(futures::Async::NotReady) => task::Poll::Pending,
+            Ok(futures::Async::Ready(v)) => task::Poll::Ready(Ok(v)),
+            Err(e) => task::Poll::Ready(Err(e)),
+        }
+    }
+}
+
+impl<T> Drop for AsyncResult<T> {
+    fn drop(&mut self) {
+        self.dropped.cancel();
+    }
+}
+
+/// リクエストをキャンセルするためのトークン.
+///
+/// `DeviceRequest::cancel_token`で指定されたトークンに対して`cancel`が呼び出されると、
+/// それを共有する(まだ実行されていない)リクエスト群は、デバイス側でスキップされ、
+/// `ErrorKind::RequestCancelled`エラーで終了する.
+///
+/// 一つのトークンを、複数のリクエストで共有することも可能.
+#[derive(Debug, Default, Clone)]
+pub struct CancelToken(Arc<AtomicBool>);
+impl CancelToken {
+    /// 新しい`CancelToken`インスタンスを生成する.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// このトークンを共有するリクエスト群をキャンセルする.
+    ///
+    /// 既に実行が開始されているリクエストには影響しない.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// キャンセル済みかどうかを返す.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
 
 #[derive(Debug)]
-struct AsyncReply<T>(oneshot::Monitored<T, Error>);
+struct AsyncReply<T> {
+    monitored: Option<oneshot::Monitored<T, Error>>,
+    waker: Arc<Mutex<Option<Waker>>>,
+    receiver_dropped: CancelToken,
+    cancel_token: Option<CancelToken>,
+    issued_at: Instant,
+    latency: Vec<Histogram>,
+    qos_class: Option<QosClass>,
+}
 impl<T> AsyncReply<T> {
-    fn send(self, result: Result<T>) {
-        self.0.exit(result);
+    fn send(mut self, result: Result<T>) {
+        let elapsed = self.issued_at.elapsed().as_secs_f64();
+        for latency in self.latency.drain(..) {
+            latency.observe(elapsed);
+        }
+        if let Some(monitored) = self.monitored.take() {
+            monitored.exit(result);
+        }
+    }
+    fn is_cancelled(&self, on_receiver_dropped: bool) -> bool {
+        (on_receiver_dropped && self.receiver_dropped.is_cancelled())
+            || self.cancel_token.as_ref().is_some_and(|t| t.is_cancelled())
+    }
+}
+impl<T> Drop for AsyncReply<T> {
+    fn drop(&mut self) {
+        // 結果を送らずに破棄された場合には、受信側で切断が検知される
+        self.monitored = None;
+
+        if let Some(waker) = self.waker.lock().expect("Never fails").take() {
+            waker.wake();
+        }
+    }
+}
+
+/// 結果の到着時に、待機中のスレッドを起こすためのウェイカー.
+struct ThreadWaker(Thread);
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
     }
 }
 
@@ -104,7 +349,7 @@ pub struct PutLump {
     lump_data: LumpData,
     deadline: Deadline,
     prioritized: bool,
-    journal_sync: bool,
+    durability: Durability,
     reply: AsyncReply<bool>,
 }
 impl PutLump {
@@ -114,7 +359,7 @@ impl PutLump {
         lump_data: LumpData,
         deadline: Deadline,
         prioritized: bool,
-        journal_sync: bool,
+        durability: Durability,
     ) -> (Self, AsyncResult<bool>) {
         let (reply, result) = AsyncResult::new();
         let command = PutLump {
@@ -122,7 +367,7 @@ impl PutLump {
             lump_data,
             deadline,
             prioritized,
-            journal_sync,
+            durability,
             reply,
         };
         (command, result)
@@ -133,8 +378,8 @@ impl PutLump {
     pub fn lump_data(&self) -> &LumpData {
         &self.lump_data
     }
-    pub fn do_sync_journal(&self) -> bool {
-        self.journal_sync
+    pub fn durability(&self) -> Durability {
+        self.durability
     }
 
     pub fn reply(self, result: Result<bool>) {
@@ -209,7 +454,7 @@ pub struct DeleteLump {
     lump_id: LumpId,
     deadline: Deadline,
     prioritized: bool,
-    journal_sync: bool,
+    durability: Durability,
     reply: AsyncReply<bool>,
 }
 impl DeleteLump {
@@ -218,14 +463,14 @@ impl DeleteLump {
         lump_id: LumpId,
         deadline: Deadline,
         prioritized: bool,
-        journal_sync: bool,
+        durability: Durability,
     ) -> (Self, AsyncResult<bool>) {
         let (reply, result) = AsyncResult::new();
         let command = DeleteLump {
             lump_id,
             deadline,
             prioritized,
-            journal_sync,
+            durability,
             reply,
         };
         (command, result)
@@ -233,8 +478,8 @@ impl DeleteLump {
     pub fn lump_id(&self) -> &LumpId {
         &self.lump_id
     }
-    pub fn do_sync_journal(&self) -> bool {
-        self.journal_sync
+    pub fn durability(&self) -> Durability {
+        self.durability
     }
     pub fn reply(self, result: Result<bool>) {
         self.reply.send(result);
@@ -246,7 +491,7 @@ pub struct DeleteLumpRange {
     range: Range<LumpId>,
     deadline: Deadline,
     prioritized: bool,
-    journal_sync: bool,
+    durability: Durability,
     reply: AsyncReply<Vec<LumpId>>,
 }
 impl DeleteLumpRange {
@@ -255,14 +500,14 @@ impl DeleteLumpRange {
         range: Range<LumpId>,
         deadline: Deadline,
         prioritized: bool,
-        journal_sync: bool,
+        durability: Durability,
     ) -> (Self, AsyncResult<Vec<LumpId>>) {
         let (reply, result) = AsyncResult::new();
         let command = DeleteLumpRange {
             range,
             deadline,
             prioritized,
-            journal_sync,
+            durability,
             reply,
         };
         (command, result)
@@ -270,8 +515,8 @@ impl DeleteLumpRange {
     pub fn lump_range(&self) -> Range<LumpId> {
         self.range.clone()
     }
-    pub fn do_sync_journal(&self) -> bool {
-        self.journal_sync
+    pub fn durability(&self) -> Durability {
+        self.durability
     }
     pub fn reply(self, result: Result<Vec<LumpId>>) {
         self.reply.send(result);
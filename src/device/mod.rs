@@ -8,15 +8,27 @@
 //!
 //! 並行するリクエスト群が存在する場合には、指定された優先順位(デッドライン)に基づいて
 //! スケジューリングが行われる.
+//! `DeviceBuilder::qos_class`でQoSクラスが登録されている場合には、
+//! デバイスの実行時間はクラス毎の重みに応じて配分され、デッドラインはクラス内での順序付けに使用される.
+//! また`DeviceBuilder::scheduling_policy`でエレベータ方式が選択されている場合には、
+//! デッドラインが差し迫っていない`GET`リクエストは、データ領域内での位置順に処理される.
+//!
+//! ただし`DeviceBuilder::reader_threads`で読み込み用のスレッド群が有効にされている場合には、
+//! `GET`リクエストは、それらのスレッドによって書き込み系のリクエストとは並列に処理される.
 //!
 //! [ストレージ]: ../storage/index.html
 //! [Device]: struct.Device.html
 use futures::{Async, Future, Poll};
 use std::sync::Arc;
 
+pub use self::blocking::{BlockingDeviceHandle, BlockingDeviceRequest};
 pub use self::builder::DeviceBuilder;
+pub use self::command::{AsyncResult, CancelToken};
 pub use self::long_queue_policy::LongQueuePolicy;
+pub use self::pool::{DevicePool, DevicePoolHandle, DevicePoolRequest, RoutingStrategy};
+pub use self::qos::QosClass;
 pub use self::request::DeviceRequest;
+pub use self::scheduling_policy::SchedulingPolicy;
 
 pub(crate) use self::command::Command; // `metrics`モジュール用に公開されている
 
@@ -28,12 +40,17 @@ use crate::nvm::NonVolatileMemory;
 use crate::storage::Storage;
 use crate::{Error, Result};
 
+mod blocking;
 mod builder;
 mod command;
 mod long_queue_policy;
+mod pool;
 mod probabilistic;
+pub(crate) mod qos;
 mod queue;
+mod reader;
 mod request;
+mod scheduling_policy;
 mod thread;
 
 /// [Lump]群を格納するためのデバイス.
@@ -125,6 +142,9 @@ impl Device {
     /// 行った場合には、停止中の旧インスタンスと起動した新インスタンスでリソース(e.g., ファイル)が
     /// 衝突し、エラーが発生するかもしれない.
     /// 確実な終了検知が必要なら、アプリケーションが明示的に`Device::stop`を呼び出す必要がある.
+    ///
+    /// 停止リクエストにより正常に終了する場合には、デバイスが保持するストレージに対して
+    /// `Storage::close`が呼び出される.
     pub fn stop(&self, deadline: Deadline) {
         self.handle()
             .request()
@@ -182,6 +202,15 @@ impl DeviceHandle {
         self.0.metrics()
     }
 
+    /// デバイスが管理しているストレージの、ジャーナル領域への埋め込みの閾値(バイト単位)を返す.
+    ///
+    /// `StorageBuilder::embed_threshold`で指定された値で、`allocate_lump_data_auto`の判定に使用される.
+    ///
+    /// デバイスが稼働中ではない場合には`None`が返される.
+    pub fn embed_threshold(&self) -> Option<usize> {
+        self.0.storage_config().map(|c| c.embed_threshold)
+    }
+
     /// ストレージのブロック境界にアライメントされたメモリ領域を保持する`LumpData`インスタンスを返す.
     ///
     /// `LumpData::new`関数に比べて、このメソッドが返した`LumpData`インスタンスは、
@@ -195,12 +224,14 @@ impl DeviceHandle {
     ///
     /// # Errors
     ///
-    /// 指定されたサイズが`MAX_SIZE`を超えている場合は、`ErrorKind::InvalidInput`エラーが返される.
+    /// 指定されたサイズが`MAX_SIZE`(ワイドフォーマットのストレージの場合には`Storage::max_lump_size`)を
+    /// 超えている場合は、`ErrorKind::InvalidInput`エラーが返される.
     pub fn allocate_lump_data(&self, size: usize) -> Result<LumpData> {
         if let Some(storage) = self.metrics().storage() {
             track!(LumpData::aligned_allocate(
                 size,
-                storage.header().block_size
+                storage.header().block_size,
+                storage.header().max_lump_size()
             ))
         } else {
             // 「デバイスが起動中」ないし「デバイスが停止済み」の場合には、
@@ -234,6 +265,29 @@ impl DeviceHandle {
         data.as_bytes_mut().copy_from_slice(bytes);
         Ok(data)
     }
+
+    /// 保存先の選択をストレージに委ねる`LumpData`インスタンスを、データの初期化も含めて生成する.
+    ///
+    /// 実際の保存先(ジャーナル領域ないしデータ領域)は、PUT時にストレージが
+    /// `StorageBuilder::embed_threshold`の値に基づいて選択する.
+    /// データ領域に保存される見込みの場合には、`allocate_lump_data`と同様に
+    /// アライメント済みのメモリ領域が使用されるため、PUT時に余計なメモリコピーが発生しない.
+    ///
+    /// 詳細な挙動に関しては`Storage::allocate_lump_data_auto`のドキュメントを参照のこと.
+    pub fn allocate_lump_data_auto(&self, bytes: &[u8]) -> Result<LumpData> {
+        if let Some(config) = self.0.storage_config() {
+            if bytes.len() > config.embed_threshold {
+                let mut data = track!(LumpData::aligned_allocate_auto(
+                    bytes.len(),
+                    config.block_size,
+                    config.max_lump_size
+                ))?;
+                data.as_bytes_mut().copy_from_slice(bytes);
+                return Ok(data);
+            }
+        }
+        track!(LumpData::new_auto(bytes.to_vec()))
+    }
 }
 
 /// デバイスの稼働状態.
@@ -258,13 +312,17 @@ pub enum DeviceStatus {
 #[cfg(test)]
 mod tests {
     use fibers_global::execute;
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+    use std::mem;
     use std::ops::Range;
+    use std::sync::Mutex;
     use trackable::result::TestResult;
 
     use super::*;
+    use crate::block::BlockSize;
     use crate::lump::{LumpData, LumpId};
     use crate::nvm::{MemoryNvm, SharedMemoryNvm};
-    use crate::storage::StorageBuilder;
+    use crate::storage::{Durability, StorageBuilder};
     use crate::ErrorKind;
     use std::time::Duration;
 
@@ -485,6 +543,60 @@ mod tests {
         Ok(())
     }
 
+    #[cfg(feature = "std-future")]
+    #[test]
+    fn std_future_works() -> TestResult {
+        use std::future::Future as StdFuture;
+        use std::task::{Context, Poll, Wake};
+        use std::thread::{self, Thread};
+
+        // `fibers`を使わない、単純なエグゼキュータ
+        struct ThreadWaker(Thread);
+        impl Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        fn block_on<F: StdFuture>(future: F) -> F::Output {
+            let mut future = Box::pin(future);
+            let waker = Arc::new(ThreadWaker(thread::current())).into();
+            let mut cx = Context::from_waker(&waker);
+            loop {
+                match future.as_mut().poll(&mut cx) {
+                    Poll::Ready(v) => return v,
+                    Poll::Pending => thread::park(),
+                }
+            }
+        }
+
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+        let storage = track!(StorageBuilder::new().journal_region_ratio(0.99).create(nvm))?;
+        let device = DeviceBuilder::new().spawn(|| Ok(storage));
+        let d = device.handle();
+        track!(block_on(async {
+            let created = d
+                .request()
+                .wait_for_running()
+                .put(id(0), data(b"foo"))
+                .await?;
+            assert!(created);
+            let value = d.request().get(id(0)).await?;
+            assert_eq!(
+                value.map(|v| v.as_bytes().to_owned()),
+                Some(b"foo".to_vec())
+            );
+            assert_eq!(d.request().list().await?, vec![id(0)]);
+            Ok::<_, Error>(())
+        }))?;
+
+        // デバイスの停止後のリクエストはエラーとなる(ハングしない)
+        device.stop(Deadline::Immediate);
+        let mut device = device;
+        while !track!(Future::poll(&mut device))?.is_ready() {}
+        assert!(block_on(d.request().get(id(0))).is_err());
+        Ok(())
+    }
+
     fn id(id: usize) -> LumpId {
         LumpId::new(id as u128)
     }
@@ -532,6 +644,141 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn group_commit_works() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let storage = track!(StorageBuilder::new()
+            .journal_region_ratio(0.99)
+            .create(nvm.clone()))?;
+        let v = nvm.to_bytes();
+
+        // デバイスの起動前に全てのリクエストをキューに入れておき、一度のコミットで処理されるようにする
+        let (start_tx, start_rx) = std::sync::mpsc::channel();
+        let device = DeviceBuilder::new().spawn(move || {
+            let _ = start_rx.recv();
+            Ok(storage)
+        });
+        let d = device.handle();
+        let futures = (0..10)
+            .map(|i| {
+                d.request()
+                    .wait_for_running()
+                    .journal_sync()
+                    .put(id(i), embedded_data(b"hoge"))
+            })
+            .collect::<Vec<_>>();
+        let _ = start_tx.send(());
+        for f in futures {
+            assert!(track!(execute(f))?);
+        }
+        assert_ne!(v, nvm.to_bytes()); // 応答の時点で、既に書き込まれている
+        assert_eq!(d.metrics().group_commits(), 1);
+        assert_eq!(d.metrics().group_committed_commands(), 10);
+
+        // 同期が要求されていないコマンドは、コミットの対象外
+        track!(execute(d.request().put(id(10), embedded_data(b"hoge"))))?;
+        assert_eq!(d.metrics().group_committed_commands(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn durability_works() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let storage = track!(StorageBuilder::new()
+            .journal_region_ratio(0.99)
+            .create(nvm.clone()))?;
+        let v = nvm.to_bytes();
+        let device = DeviceBuilder::new().spawn(|| Ok(storage));
+        let d = device.handle();
+        let _ = execute(d.request().wait_for_running().list());
+
+        track!(execute(
+            d.request()
+                .durability(Durability::Buffered)
+                .put(id(0), embedded_data(b"foo"))
+        ))?;
+        assert_eq!(v, nvm.to_bytes());
+
+        track!(execute(
+            d.request()
+                .durability(Durability::JournalFlushed)
+                .put(id(1), embedded_data(b"bar"))
+        ))?;
+        assert_ne!(v, nvm.to_bytes());
+        assert_eq!(d.metrics().group_committed_commands(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn journal_sync_max_delay_works() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let storage = track!(StorageBuilder::new()
+            .journal_region_ratio(0.99)
+            .journal_sync_max_delay(Duration::from_millis(10))
+            .create(nvm.clone()))?;
+        let v = nvm.to_bytes();
+        let device = DeviceBuilder::new()
+            .idle_threshold(Duration::from_secs(60))
+            .spawn(|| Ok(storage));
+        let d = device.handle();
+        let _ = execute(d.request().wait_for_running().list());
+        track!(execute(d.request().put(id(1234), embedded_data(b"hoge"))))?;
+
+        // 最大遅延時間が経過すると、リクエストが無くても書き込まれる
+        std::thread::sleep(Duration::from_millis(200));
+        assert_ne!(v, nvm.to_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_device_works() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut storage = track!(Storage::create(nvm.clone()))?;
+        track!(storage.put(&id(0), &embedded_data(b"foo")))?;
+        track!(storage.put(&id(1), &data(b"bar")))?;
+        std::mem::drop(storage);
+        let before = nvm.to_bytes();
+
+        let device = DeviceBuilder::new()
+            .idle_threshold(Duration::from_millis(1))
+            .spawn({
+                let nvm = nvm.clone();
+                move || track!(StorageBuilder::new().open_read_only(nvm))
+            });
+        let d = device.handle();
+        let _ = execute(d.request().wait_for_running().list()); // デバイスの起動を待機
+
+        assert_eq!(execute(d.request().list())?, vec![id(0), id(1)]);
+        assert_eq!(
+            execute(d.request().get(id(0)))?.map(|d| d.as_bytes().to_vec()),
+            Some(b"foo".to_vec())
+        );
+        assert_eq!(
+            execute(d.request().get(id(1)))?.map(|d| d.as_bytes().to_vec()),
+            Some(b"bar".to_vec())
+        );
+
+        let result = execute(d.request().journal_sync().put(id(2), data(b"baz")));
+        assert_eq!(result.err().map(|e| *e.kind()), Some(ErrorKind::ReadOnly));
+        let result = execute(d.request().delete(id(0)));
+        assert_eq!(result.err().map(|e| *e.kind()), Some(ErrorKind::ReadOnly));
+        let result = execute(d.request().delete_range(Range {
+            start: id(0),
+            end: id(10),
+        }));
+        assert_eq!(result.err().map(|e| *e.kind()), Some(ErrorKind::ReadOnly));
+
+        // 書き込みに失敗してもデバイスは停止せず、読み込みを継続できる
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(execute(d.request().list())?, vec![id(0), id(1)]);
+        assert_eq!(d.metrics().failed_commands.put(), 1);
+
+        device.stop(Deadline::Immediate);
+        track!(execute(device))?;
+        assert!(before == nvm.to_bytes());
+        Ok(())
+    }
+
     #[test]
     fn device_stop_works() -> TestResult {
         let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
@@ -544,6 +791,332 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn reader_threads_works() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let storage = track!(StorageBuilder::new()
+            .journal_region_ratio(0.5)
+            .pack_threshold(100)
+            .create(nvm))?;
+        let mut device = DeviceBuilder::new().reader_threads(2).spawn(|| Ok(storage));
+        let d = device.handle();
+        let _ = execute(d.request().wait_for_running().list()); // デバイスの起動を待機
+
+        track!(execute(d.request().put(id(0), data(&[1; 1000]))))?;
+        track!(execute(d.request().put(id(1), data(b"packed"))))?;
+        track!(execute(d.request().put(id(2), embedded_data(b"embedded"))))?;
+
+        // データ領域のlumpは読み込み用スレッドで、埋め込まれたlumpはデバイスの実行スレッドで処理される
+        for _ in 0..10 {
+            let results = (0..4).map(|i| d.request().get(id(i))).collect::<Vec<_>>();
+            let results = results
+                .into_iter()
+                .map(|f| execute(f).map(|v| v.map(|v| v.as_bytes().to_owned())))
+                .collect::<Result<Vec<_>>>();
+            assert_eq!(
+                track!(results)?,
+                vec![
+                    Some(vec![1; 1000]),
+                    Some(b"packed".to_vec()),
+                    Some(b"embedded".to_vec()),
+                    None
+                ]
+            );
+        }
+
+        // 上書きや削除も即座に反映される
+        track!(execute(d.request().put(id(0), data(b"bar"))))?;
+        assert!(track!(execute(d.request().delete(id(1))))?);
+        assert_eq!(
+            track!(execute(d.request().get(id(0))))?.map(|v| v.as_bytes().to_owned()),
+            Some(b"bar".to_vec())
+        );
+        assert!(track!(execute(d.request().get(id(1))))?.is_none());
+
+        let metrics = d.metrics();
+        assert_eq!(metrics.enqueued_commands().get(), 42);
+        assert_eq!(metrics.dequeued_commands().get(), 42);
+
+        // デバイスの停止後は`DeviceTerminated`となる
+        device.stop(Deadline::Immediate);
+        while !track!(device.poll())?.is_ready() {}
+        let result = execute(d.request().get(id(0)));
+        assert_eq!(
+            result.err().map(|e| *e.kind()),
+            Some(ErrorKind::DeviceTerminated)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn cancel_works() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+        let storage = track!(StorageBuilder::new().journal_region_ratio(0.99).create(nvm))?;
+
+        // デバイスの起動前にリクエストをキューに入れておき、実行前にキャンセルする
+        let device = DeviceBuilder::new().spawn(|| {
+            std::thread::sleep(Duration::from_millis(100));
+            Ok(storage)
+        });
+        let d = device.handle();
+        let token = CancelToken::new();
+        let dropped_get = d.request().wait_for_running().get(id(0));
+        let dropped_put = d
+            .request()
+            .wait_for_running()
+            .put(id(0), embedded_data(b"foo"));
+        let cancelled = d
+            .request()
+            .wait_for_running()
+            .cancel_token(token.clone())
+            .put(id(1), embedded_data(b"bar"));
+        let executed = d
+            .request()
+            .wait_for_running()
+            .put(id(2), embedded_data(b"baz"));
+        std::mem::drop(dropped_get);
+        std::mem::drop(dropped_put);
+        token.cancel();
+
+        assert_eq!(
+            execute(cancelled).err().map(|e| *e.kind()),
+            Some(ErrorKind::RequestCancelled)
+        );
+        assert!(track!(execute(executed))?);
+
+        // 結果が破棄されても、更新系のコマンドは実行される
+        assert_eq!(track!(execute(d.request().list()))?, vec![id(0), id(2)]);
+
+        let metrics = d.metrics();
+        assert_eq!(metrics.cancelled_commands().get(), 1);
+        assert_eq!(metrics.cancelled_commands().put(), 1);
+        assert_eq!(metrics.failed_commands().get(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn expiry_grace_period_works() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+        let storage = track!(StorageBuilder::new().journal_region_ratio(0.99).create(nvm))?;
+        let device = DeviceBuilder::new()
+            .expiry_grace_period(Duration::from_millis(0))
+            .spawn(|| Ok(storage));
+        let d = device.handle();
+        let _ = execute(d.request().wait_for_running().list()); // デバイスの起動を待機
+
+        // キューから取り出された時点で、デッドラインを過ぎているので破棄される
+        let result = execute(
+            d.request()
+                .deadline(Deadline::Within(Duration::from_millis(0)))
+                .put(id(0), embedded_data(b"foo")),
+        );
+        assert_eq!(
+            result.err().map(|e| *e.kind()),
+            Some(ErrorKind::RequestExpired)
+        );
+
+        // デッドラインに余裕があるもの、および、`Within`以外のデッドラインのものは実行される
+        assert!(track!(execute(
+            d.request()
+                .deadline(Deadline::Within(Duration::from_secs(60)))
+                .put(id(1), embedded_data(b"bar"))
+        ))?);
+        assert!(track!(execute(
+            d.request()
+                .deadline(Deadline::Immediate)
+                .put(id(2), embedded_data(b"baz"))
+        ))?);
+        assert_eq!(track!(execute(d.request().list()))?, vec![id(1), id(2)]);
+
+        let metrics = d.metrics();
+        assert_eq!(metrics.expired_commands().put(), 1);
+        assert_eq!(metrics.failed_commands().put(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn command_histograms_work() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+        let storage = track!(StorageBuilder::new().journal_region_ratio(0.99).create(nvm))?;
+        let device = DeviceBuilder::new().spawn(|| Ok(storage));
+        let d = device.handle();
+        let _ = execute(d.request().wait_for_running().list()); // デバイスの起動を待機
+
+        track!(execute(d.request().put(id(0), embedded_data(b"foo"))))?;
+        track!(execute(
+            d.request().journal_sync().put(id(1), embedded_data(b"bar"))
+        ))?;
+        track!(execute(d.request().get(id(0))))?;
+
+        let metrics = d.metrics();
+        assert_eq!(metrics.queue_wait_seconds().put().count(), 2);
+        assert_eq!(metrics.execution_seconds().put().count(), 2);
+        assert_eq!(metrics.latency_seconds().put().count(), 2); // 同期待ちの応答も含む
+        assert_eq!(metrics.latency_seconds().get().count(), 1);
+        assert_eq!(metrics.latency_seconds().list().count(), 1);
+        assert_eq!(metrics.latency_seconds().delete().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn qos_class_works() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+        let storage = track!(StorageBuilder::new().journal_region_ratio(0.99).create(nvm))?;
+        let interactive = QosClass::new("interactive");
+        let batch = QosClass::new("batch");
+        let device = DeviceBuilder::new()
+            .qos_class(interactive.clone(), 4)
+            .qos_class(batch.clone(), 1)
+            .spawn(|| Ok(storage));
+        let d = device.handle();
+        let _ = execute(d.request().wait_for_running().list()); // デバイスの起動を待機
+
+        track!(execute(
+            d.request()
+                .qos_class(interactive.clone())
+                .put(id(0), embedded_data(b"foo"))
+        ))?;
+        track!(execute(
+            d.request()
+                .qos_class(batch.clone())
+                .delete_range(id(0)..id(10))
+        ))?;
+        track!(execute(
+            d.request().qos_class(QosClass::new("unknown")).get(id(0))
+        ))?;
+
+        let metrics = d.metrics();
+        let class = |c: &QosClass| metrics.qos_class(c).expect("registered");
+        assert_eq!(class(&interactive).latency_seconds().count(), 1);
+        assert_eq!(class(&batch).latency_seconds().count(), 1);
+        assert_eq!(class(&QosClass::default()).latency_seconds().count(), 2); // LIST + GET
+        assert_eq!(class(&interactive).queue_len(), 0);
+        assert!(metrics.qos_class(&QosClass::new("unknown")).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn elevator_scheduling_works() -> TestResult {
+        let gate = Arc::new(Mutex::new(()));
+        let reads = Arc::new(Mutex::new(Vec::new()));
+        let nvm = GatedNvm::new(vec![0; 1024 * 1024], gate.clone(), reads.clone());
+        let mut storage = track!(StorageBuilder::new().journal_region_ratio(0.5).create(nvm))?;
+        for i in 0..10 {
+            track!(storage.put(&id(i), &data(&[i as u8; 1000])))?;
+        }
+        let header = storage.header().clone();
+        let data_region_start = header.region_size() + header.journal_region_size;
+        let data_region_end = data_region_start + header.data_region_size;
+
+        let device = DeviceBuilder::new()
+            .scheduling_policy(SchedulingPolicy::Elevator {
+                urgency: Duration::from_secs(10),
+            })
+            .spawn(|| Ok(storage));
+        let d = device.handle();
+        let _ = execute(d.request().wait_for_running().list()); // デバイスの起動を待機
+
+        // デバイススレッドを読み込みの途中で停止させておき、その間に発行されたGETをキューに溜める
+        let guard = gate.lock().unwrap();
+        let futures = (0..10)
+            .rev()
+            .map(|i| d.request().get(id(i)))
+            .collect::<Vec<_>>();
+        mem::drop(guard);
+        for (i, f) in (0..10).rev().zip(futures) {
+            let value = track!(execute(f))?.map(|v| v.as_bytes().to_owned());
+            assert_eq!(value, Some(vec![i as u8; 1000]));
+        }
+
+        // 最初に処理されたもの以外は、発行順(降順)ではなく、データ領域内での位置の昇順に処理されている
+        let reads = reads
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .filter(|&offset| data_region_start <= offset && offset < data_region_end)
+            .collect::<Vec<_>>();
+        assert_eq!(reads.len(), 10);
+        assert!(reads[1..].windows(2).all(|w| w[0] < w[1]), "{:?}", reads);
+        Ok(())
+    }
+
+    #[test]
+    fn allocate_lump_data_auto_works() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+        let device = DeviceBuilder::new().spawn(|| {
+            track!(StorageBuilder::new()
+                .journal_region_ratio(0.5)
+                .embed_threshold(100)
+                .create(nvm))
+        });
+        let d = device.handle();
+        let _ = execute(d.request().wait_for_running().list()); // デバイスの起動を待機
+        assert_eq!(d.embed_threshold(), Some(100));
+
+        let small = track!(d.allocate_lump_data_auto(&[1; 100]))?;
+        let large = track!(d.allocate_lump_data_auto(&[2; 101]))?;
+        track!(execute(d.request().put(id(0), small)))?;
+        track!(execute(d.request().put(id(1), large)))?;
+
+        // 埋め込まれたlumpのサイズは正確だが、データ領域のlumpのサイズはブロック境界に切り上げられる
+        assert_eq!(
+            execute(d.request().head(id(0)))?.map(|h| h.approximate_data_size),
+            Some(100)
+        );
+        assert_eq!(
+            execute(d.request().head(id(1)))?.map(|h| h.approximate_data_size),
+            Some(512)
+        );
+        assert_eq!(
+            execute(d.request().get(id(0)))?.map(|d| d.into_bytes()),
+            Some(vec![1; 100])
+        );
+        assert_eq!(
+            execute(d.request().get(id(1)))?.map(|d| d.into_bytes()),
+            Some(vec![2; 101])
+        );
+
+        // ワイドフォーマットのストレージでは、`LumpData::MAX_SIZE`を超えるサイズも扱える
+        let nvm = MemoryNvm::new(vec![0; 64 * 1024 * 1024]);
+        let device = DeviceBuilder::new().spawn(|| {
+            track!(StorageBuilder::new()
+                .journal_region_ratio(0.01)
+                .wide_format(true)
+                .create(nvm))
+        });
+        let d = device.handle();
+        let _ = execute(d.request().wait_for_running().list()); // デバイスの起動を待機
+        let size = LumpData::MAX_SIZE + 1000;
+        let large = track!(d.allocate_lump_data_auto(&vec![3; size]))?;
+        track!(execute(d.request().put(id(2), large)))?;
+        assert_eq!(
+            execute(d.request().get(id(2)))?.map(|d| d.as_bytes().len()),
+            Some(size)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn device_stop_closes_storage() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let storage = track!(Storage::create(nvm.clone()))?;
+        assert!(!storage.last_shutdown_clean());
+        let device = Device::spawn(|| Ok(storage));
+        let d = device.handle();
+        track!(execute(
+            d.request().wait_for_running().put(id(0), data(b"foo"))
+        ))?;
+
+        device.stop(Deadline::Immediate);
+        track!(execute(device))?;
+
+        let storage = track!(Storage::open(nvm))?;
+        assert!(storage.last_shutdown_clean());
+        assert_eq!(storage.list(), vec![id(0)]);
+        Ok(())
+    }
+
     #[test]
     fn device_long_queue_policy_refuse_request_works() -> TestResult {
         // TODO: better testing
@@ -783,4 +1356,74 @@ mod tests {
 
         Ok(())
     }
+
+    /// 読み込み位置(NVM全体での位置)を記録し、`gate`がロックされている間は読み込みを待機させるNVM.
+    #[derive(Debug)]
+    struct GatedNvm {
+        inner: MemoryNvm,
+        start: u64,
+        gate: Arc<Mutex<()>>,
+        reads: Arc<Mutex<Vec<u64>>>,
+    }
+    impl GatedNvm {
+        fn new(memory: Vec<u8>, gate: Arc<Mutex<()>>, reads: Arc<Mutex<Vec<u64>>>) -> Self {
+            GatedNvm {
+                inner: MemoryNvm::new(memory),
+                start: 0,
+                gate,
+                reads,
+            }
+        }
+    }
+    impl NonVolatileMemory for GatedNvm {
+        fn sync(&mut self) -> Result<()> {
+            track!(self.inner.sync())
+        }
+        fn position(&self) -> u64 {
+            self.inner.position()
+        }
+        fn capacity(&self) -> u64 {
+            self.inner.capacity()
+        }
+        fn block_size(&self) -> BlockSize {
+            self.inner.block_size()
+        }
+        fn split(self, position: u64) -> Result<(Self, Self)> {
+            let (left, right) = track!(self.inner.split(position))?;
+            let left = GatedNvm {
+                inner: left,
+                start: self.start,
+                gate: self.gate.clone(),
+                reads: self.reads.clone(),
+            };
+            let right = GatedNvm {
+                inner: right,
+                start: self.start + position,
+                gate: self.gate,
+                reads: self.reads,
+            };
+            Ok((left, right))
+        }
+    }
+    impl Seek for GatedNvm {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+    impl Read for GatedNvm {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let _guard = self.gate.lock().unwrap();
+            let offset = self.start + self.inner.position();
+            self.reads.lock().unwrap().push(offset);
+            self.inner.read(buf)
+        }
+    }
+    impl Write for GatedNvm {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.inner.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
 }
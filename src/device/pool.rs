@@ -0,0 +1,759 @@
+use futures::future::{self, Either};
+use futures::Future;
+use std::ops::Range;
+use std::sync::{Arc, RwLock};
+
+use super::{CancelToken, Device, DeviceHandle, DeviceRequest, QosClass};
+use crate::deadline::Deadline;
+use crate::lump::{LumpData, LumpHeader, LumpId};
+use crate::metrics::DeviceMetrics;
+use crate::storage::{Durability, StorageUsage};
+use crate::{Error, ErrorKind, Result};
+
+/// `DevicePool`がlumpの担当デバイスを決定する方法.
+///
+/// いずれの方法でも、同じ構成(メンバー数および設定値)からは常に同じ結果が得られる.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum RoutingStrategy {
+    /// LumpIDのハッシュ値をメンバー数で割った余りで決定する.
+    ///
+    /// デフォルト値.
+    #[default]
+    Hash,
+
+    /// LumpIDの範囲で決定する.
+    ///
+    /// `i`番目の要素は、`i`番目のメンバーが担当する範囲の開始位置で、
+    /// 範囲の終端は次の要素(最後のメンバーの場合にはLumpIDの最大値)となる.
+    ///
+    /// 要素数はメンバー数と一致し、先頭の要素は`LumpId::new(0)`で、かつ昇順に並んでいる必要がある.
+    RangeTable(Vec<LumpId>),
+
+    /// コンシステントハッシュ法で決定する.
+    ///
+    /// 各メンバーは、ハッシュリング上に`virtual_nodes`個の仮想ノードを有する.
+    ConsistentHash {
+        /// メンバー毎の仮想ノードの数.
+        virtual_nodes: usize,
+    },
+}
+
+/// 複数のデバイスにlump群を分散して格納するためのプール.
+///
+/// 各lumpの担当デバイスは、プール生成時に指定された`RoutingStrategy`に基づいて決定される.
+///
+/// `list`等の範囲系のリクエストは、その範囲を担当し得る全てのメンバーに発行され、結果がマージされる.
+///
+/// メンバーのデバイスを一時的にオフラインにすることも可能(e.g., 物理デバイスの交換).
+/// オフラインのメンバーが担当するlumpに対するリクエストは`ErrorKind::DeviceTerminated`エラーとなる.
+/// 範囲系のリクエストも、対象範囲を担当し得るメンバーの中にオフラインのものが含まれる場合には、
+/// 一部の結果のみを返すことはせずに`ErrorKind::DeviceTerminated`エラーとなる.
+/// なお、オフラインにしてもメンバーの位置は変わらないので、他のlumpの担当デバイスが変わることはない.
+#[derive(Debug)]
+pub struct DevicePool {
+    devices: Vec<Option<Device>>,
+    handle: DevicePoolHandle,
+}
+impl DevicePool {
+    /// 新しい`DevicePool`インスタンスを生成する.
+    ///
+    /// # Errors
+    ///
+    /// 以下の場合には`ErrorKind::InvalidInput`エラーが返される:
+    ///
+    /// - `devices`が空
+    /// - `strategy`の設定値が不正(e.g., `RangeTable`の要素数がメンバー数と異なる)
+    pub fn new(devices: Vec<Device>, strategy: RoutingStrategy) -> Result<Self> {
+        track_assert!(!devices.is_empty(), ErrorKind::InvalidInput);
+        let router = track!(Router::new(strategy, devices.len()))?;
+        let members = devices.iter().map(|d| Some(d.handle())).collect();
+        let handle = DevicePoolHandle(Arc::new(PoolInner {
+            members: RwLock::new(members),
+            router,
+        }));
+        Ok(DevicePool {
+            devices: devices.into_iter().map(Some).collect(),
+            handle,
+        })
+    }
+
+    /// プールを操作するためのハンドルを返す.
+    pub fn handle(&self) -> DevicePoolHandle {
+        self.handle.clone()
+    }
+
+    /// メンバーの数を返す(オフラインのものも含む).
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// メンバーが存在しないかどうかを判定する.
+    ///
+    /// 生成時に空ではないことが保証されているので、常に`false`が返される.
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// `index`番目のメンバーがオンラインかどうかを判定する.
+    pub fn is_online(&self, index: usize) -> bool {
+        self.devices.get(index).is_some_and(|d| d.is_some())
+    }
+
+    /// `index`番目のメンバーをオフラインにして、そのデバイスを返す.
+    ///
+    /// 返されたデバイスの停止は、呼び出し側の責務.
+    ///
+    /// # Errors
+    ///
+    /// 範囲外の`index`が指定された場合や、既にオフラインの場合には`ErrorKind::InvalidInput`エラーが返される.
+    pub fn take_offline(&mut self, index: usize) -> Result<Device> {
+        track_assert!(index < self.devices.len(), ErrorKind::InvalidInput; index);
+        let device = track_assert_some!(
+            self.devices[index].take(),
+            ErrorKind::InvalidInput,
+            "Already offline: index={}",
+            index
+        );
+        self.handle.0.members.write().expect("Never fails")[index] = None;
+        Ok(device)
+    }
+
+    /// オフラインの`index`番目のメンバーに`device`を割り当てて、オンラインにする.
+    ///
+    /// # Errors
+    ///
+    /// 範囲外の`index`が指定された場合や、既にオンラインの場合には`ErrorKind::InvalidInput`エラーが返される.
+    pub fn bring_online(&mut self, index: usize, device: Device) -> Result<()> {
+        track_assert!(index < self.devices.len(), ErrorKind::InvalidInput; index);
+        track_assert!(
+            self.devices[index].is_none(),
+            ErrorKind::InvalidInput,
+            "Already online: index={}",
+            index
+        );
+        self.handle.0.members.write().expect("Never fails")[index] = Some(device.handle());
+        self.devices[index] = Some(device);
+        Ok(())
+    }
+
+    /// 全てのオンラインのメンバーに停止リクエストを発行する.
+    ///
+    /// 詳細は`Device::stop`を参照のこと.
+    pub fn stop(&self, deadline: Deadline) {
+        for device in self.devices.iter().flatten() {
+            device.stop(deadline);
+        }
+    }
+
+    /// 全てのメンバーを取り出す.
+    ///
+    /// オフラインのメンバーの位置には`None`が格納される.
+    pub fn into_devices(self) -> Vec<Option<Device>> {
+        self.devices
+    }
+}
+
+/// `DevicePool`を操作するためのハンドル.
+#[derive(Debug, Clone)]
+pub struct DevicePoolHandle(Arc<PoolInner>);
+impl DevicePoolHandle {
+    /// プールに対して発行するリクエストのビルダを返す.
+    pub fn request(&self) -> DevicePoolRequest<'_> {
+        DevicePoolRequest {
+            pool: self,
+            deadline: None,
+            max_queue_len: None,
+            wait_for_running: false,
+            durability: Durability::default(),
+            prioritized: false,
+            cancel_token: None,
+            qos_class: None,
+        }
+    }
+
+    /// `lump_id`を担当するメンバーの位置を返す.
+    pub fn route(&self, lump_id: &LumpId) -> usize {
+        self.0.router.route(lump_id)
+    }
+
+    /// `index`番目のメンバーのハンドルを返す.
+    ///
+    /// 範囲外ないしオフラインの場合には`None`が返される.
+    pub fn member(&self, index: usize) -> Option<DeviceHandle> {
+        self.0
+            .members
+            .read()
+            .expect("Never fails")
+            .get(index)
+            .and_then(|m| m.clone())
+    }
+
+    /// `lump_id`を担当するメンバーのハンドルを返す.
+    ///
+    /// # Errors
+    ///
+    /// 担当メンバーがオフラインの場合には`ErrorKind::DeviceTerminated`エラーが返される.
+    pub fn member_for(&self, lump_id: &LumpId) -> Result<DeviceHandle> {
+        let index = self.route(lump_id);
+        let member = track_assert_some!(
+            self.member(index),
+            ErrorKind::DeviceTerminated,
+            "The member device is offline: index={}, lump_id={}",
+            index,
+            lump_id
+        );
+        Ok(member)
+    }
+
+    /// オンラインの全メンバーのメトリクスを集約したものを返す.
+    ///
+    /// 返り値は呼び出し時点の値のスナップショットで、その後の更新は反映されない.
+    ///
+    /// ヒストグラムは集約できないので空となる.
+    /// メンバー毎の値が必要な場合には`member(index).metrics()`を使用すること.
+    pub fn metrics(&self) -> DeviceMetrics {
+        let members = self.0.members.read().expect("Never fails");
+        DeviceMetrics::aggregate(members.iter().flatten().map(|m| &**m.metrics()))
+    }
+
+    /// `range`内のlumpを担当し得る全てのメンバーのハンドルを返す.
+    ///
+    /// # Errors
+    ///
+    /// 該当メンバーの中にオフラインのものが含まれる場合には`ErrorKind::DeviceTerminated`エラーが返される.
+    fn members_for_range(&self, range: &Range<LumpId>) -> Result<Vec<DeviceHandle>> {
+        track!(self.members_at(self.0.router.covering(range)); range)
+    }
+
+    fn members_at(&self, indices: Range<usize>) -> Result<Vec<DeviceHandle>> {
+        let members = self.0.members.read().expect("Never fails");
+        indices
+            .map(|index| {
+                let member = track_assert_some!(
+                    members[index].clone(),
+                    ErrorKind::DeviceTerminated,
+                    "The member device is offline: index={}",
+                    index
+                );
+                Ok(member)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct PoolInner {
+    members: RwLock<Vec<Option<DeviceHandle>>>,
+    router: Router,
+}
+
+/// `DevicePool`に対してリクエストを発行するためのビルダ.
+///
+/// 各種設定は、実際にリクエストが発行されるメンバーの`DeviceRequest`にそのまま引き継がれる.
+#[derive(Debug)]
+pub struct DevicePoolRequest<'a> {
+    pool: &'a DevicePoolHandle,
+    deadline: Option<Deadline>,
+    max_queue_len: Option<usize>,
+    wait_for_running: bool,
+    durability: Durability,
+    prioritized: bool,
+    cancel_token: Option<CancelToken>,
+    qos_class: Option<QosClass>,
+}
+impl<'a> DevicePoolRequest<'a> {
+    /// Lumpを格納する.
+    ///
+    /// 詳細は`DeviceRequest::put`を参照のこと.
+    pub fn put(
+        &self,
+        lump_id: LumpId,
+        lump_data: LumpData,
+    ) -> impl Future<Item = bool, Error = Error> {
+        match track!(self.pool.member_for(&lump_id)) {
+            Err(e) => Either::A(future::err(e)),
+            Ok(member) => Either::B(self.apply(&mut member.request()).put(lump_id, lump_data)),
+        }
+    }
+
+    /// Lumpを取得する.
+    pub fn get(&self, lump_id: LumpId) -> impl Future<Item = Option<LumpData>, Error = Error> {
+        match track!(self.pool.member_for(&lump_id)) {
+            Err(e) => Either::A(future::err(e)),
+            Ok(member) => Either::B(self.apply(&mut member.request()).get(lump_id)),
+        }
+    }
+
+    /// Lumpのヘッダを取得する.
+    pub fn head(&self, lump_id: LumpId) -> impl Future<Item = Option<LumpHeader>, Error = Error> {
+        match track!(self.pool.member_for(&lump_id)) {
+            Err(e) => Either::A(future::err(e)),
+            Ok(member) => Either::B(self.apply(&mut member.request()).head(lump_id)),
+        }
+    }
+
+    /// Lumpを削除する.
+    pub fn delete(&self, lump_id: LumpId) -> impl Future<Item = bool, Error = Error> {
+        match track!(self.pool.member_for(&lump_id)) {
+            Err(e) => Either::A(future::err(e)),
+            Ok(member) => Either::B(self.apply(&mut member.request()).delete(lump_id)),
+        }
+    }
+
+    /// Lumpを範囲オブジェクトを用いて削除する.
+    ///
+    /// 範囲を担当し得る全てのメンバーに発行され、削除されたlumpのIDは昇順にマージされる.
+    ///
+    /// # Errors
+    ///
+    /// 範囲を担当し得るメンバーの中にオフラインのものが含まれる場合には、
+    /// どのメンバーにも発行されずに`ErrorKind::DeviceTerminated`エラーとなる.
+    pub fn delete_range(
+        &self,
+        range: Range<LumpId>,
+    ) -> impl Future<Item = Vec<LumpId>, Error = Error> {
+        let futures = track!(self.pool.members_for_range(&range)).map(|members| {
+            members
+                .into_iter()
+                .map(|m| self.apply(&mut m.request()).delete_range(range.clone()))
+                .collect::<Vec<_>>()
+        });
+        future::result(futures)
+            .and_then(future::join_all)
+            .map(merge_ids)
+    }
+
+    /// 保存されているlump一覧を取得する.
+    ///
+    /// 全てのメンバーに発行され、結果は昇順にマージされる.
+    ///
+    /// # Errors
+    ///
+    /// オフラインのメンバーが存在する場合には`ErrorKind::DeviceTerminated`エラーとなる.
+    pub fn list(&self) -> impl Future<Item = Vec<LumpId>, Error = Error> {
+        let futures = track!(self.pool.members_at(0..self.pool.0.router.members)).map(|members| {
+            members
+                .into_iter()
+                .map(|m| self.apply(&mut m.request()).list())
+                .collect::<Vec<_>>()
+        });
+        future::result(futures)
+            .and_then(future::join_all)
+            .map(merge_ids)
+    }
+
+    /// 範囲を指定してlump一覧を取得する.
+    ///
+    /// 範囲を担当し得る全てのメンバーに発行され、結果は昇順にマージされる.
+    ///
+    /// # Errors
+    ///
+    /// 範囲を担当し得るメンバーの中にオフラインのものが含まれる場合には`ErrorKind::DeviceTerminated`エラーとなる.
+    pub fn list_range(
+        &self,
+        range: Range<LumpId>,
+    ) -> impl Future<Item = Vec<LumpId>, Error = Error> {
+        let futures = track!(self.pool.members_for_range(&range)).map(|members| {
+            members
+                .into_iter()
+                .map(|m| self.apply(&mut m.request()).list_range(range.clone()))
+                .collect::<Vec<_>>()
+        });
+        future::result(futures)
+            .and_then(future::join_all)
+            .map(merge_ids)
+    }
+
+    /// 範囲を指定してlumpの使用量を取得する.
+    ///
+    /// 範囲を担当し得る全てのメンバーに発行され、結果は合算される.
+    /// いずれかのメンバーの使用量が不明な場合には、結果も`StorageUsage::Unknown`となる.
+    ///
+    /// # Errors
+    ///
+    /// 範囲を担当し得るメンバーの中にオフラインのものが含まれる場合には`ErrorKind::DeviceTerminated`エラーとなる.
+    pub fn usage_range(
+        &self,
+        range: Range<LumpId>,
+    ) -> impl Future<Item = StorageUsage, Error = Error> {
+        let futures = track!(self.pool.members_for_range(&range)).map(|members| {
+            members
+                .into_iter()
+                .map(|m| self.apply(&mut m.request()).usage_range(range.clone()))
+                .collect::<Vec<_>>()
+        });
+        future::result(futures)
+            .and_then(future::join_all)
+            .map(|usages| {
+                usages
+                    .iter()
+                    .map(|u| u.bytecount())
+                    .sum::<Option<u64>>()
+                    .map_or_else(StorageUsage::unknown, StorageUsage::approximate)
+            })
+    }
+
+    /// 要求のデッドラインを設定する.
+    ///
+    /// デフォルト値は`Deadline::Infinity`.
+    pub fn deadline(&mut self, deadline: Deadline) -> &mut Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// 書き込み系のリクエストに要求する永続化の度合いを指定する.
+    ///
+    /// デフォルト値は`Durability::Buffered`.
+    pub fn durability(&mut self, durability: Durability) -> &mut Self {
+        self.durability = durability;
+        self
+    }
+
+    /// 各メンバーのデバイスのキューの最大長を指定する.
+    ///
+    /// デフォルトは無制限.
+    pub fn max_queue_len(&mut self, max: usize) -> &mut Self {
+        self.max_queue_len = Some(max);
+        self
+    }
+
+    /// メンバーのデバイスが起動処理中の場合には、その完了を待つように指示する.
+    pub fn wait_for_running(&mut self) -> &mut Self {
+        self.wait_for_running = true;
+        self
+    }
+
+    /// リクエストを優先的に処理する.
+    pub fn prioritized(&mut self) -> &mut Self {
+        self.prioritized = true;
+        self
+    }
+
+    /// リクエストをキャンセルするためのトークンを指定する.
+    ///
+    /// 複数のメンバーに発行されるリクエストの場合には、それら全てがキャンセル対象となる.
+    pub fn cancel_token(&mut self, token: CancelToken) -> &mut Self {
+        self.cancel_token = Some(token);
+        self
+    }
+
+    /// リクエストのQoSクラスを指定する.
+    pub fn qos_class(&mut self, class: QosClass) -> &mut Self {
+        self.qos_class = Some(class);
+        self
+    }
+
+    fn apply<'b, 'c>(&self, request: &'c mut DeviceRequest<'b>) -> &'c mut DeviceRequest<'b> {
+        if let Some(ref token) = self.cancel_token {
+            request.cancel_token(token.clone());
+        }
+        if let Some(ref class) = self.qos_class {
+            request.qos_class(class.clone());
+        }
+        if let Some(deadline) = self.deadline {
+            request.deadline(deadline);
+        }
+        if let Some(max) = self.max_queue_len {
+            request.max_queue_len(max);
+        }
+        if self.wait_for_running {
+            request.wait_for_running();
+        }
+        if self.prioritized {
+            request.prioritized();
+        }
+        request.durability(self.durability)
+    }
+}
+
+fn merge_ids(results: Vec<Vec<LumpId>>) -> Vec<LumpId> {
+    let mut ids = results.into_iter().flatten().collect::<Vec<_>>();
+    ids.sort();
+    ids
+}
+
+#[derive(Debug)]
+struct Router {
+    strategy: RoutingStrategy,
+    members: usize,
+
+    // コンシステントハッシュ用のリング(ハッシュ値の昇順)
+    ring: Vec<(u64, usize)>,
+}
+impl Router {
+    fn new(strategy: RoutingStrategy, members: usize) -> Result<Self> {
+        let mut ring = Vec::new();
+        match strategy {
+            RoutingStrategy::Hash => {}
+            RoutingStrategy::RangeTable(ref starts) => {
+                track_assert_eq!(starts.len(), members, ErrorKind::InvalidInput);
+                track_assert_eq!(starts[0], LumpId::new(0), ErrorKind::InvalidInput);
+                track_assert!(
+                    starts.windows(2).all(|w| w[0] < w[1]),
+                    ErrorKind::InvalidInput,
+                    "The range table must be sorted in ascending order"
+                );
+            }
+            RoutingStrategy::ConsistentHash { virtual_nodes } => {
+                track_assert!(virtual_nodes > 0, ErrorKind::InvalidInput);
+                for member in 0..members {
+                    for node in 0..virtual_nodes {
+                        let key = ((member as u64) << 32) | node as u64;
+                        ring.push((mix64(mix64(key)), member));
+                    }
+                }
+                ring.sort();
+            }
+        }
+        Ok(Router {
+            strategy,
+            members,
+            ring,
+        })
+    }
+
+    /// `range`内のlumpを担当し得るメンバーの位置を昇順に返す.
+    ///
+    /// `RangeTable`以外の方法では、範囲から担当メンバーを絞り込めないので、全てのメンバーが返される.
+    fn covering(&self, range: &Range<LumpId>) -> Range<usize> {
+        match self.strategy {
+            RoutingStrategy::RangeTable(_) => {
+                if range.start >= range.end {
+                    0..0
+                } else {
+                    let last = LumpId::new(range.end.as_u128() - 1);
+                    self.route(&range.start)..self.route(&last) + 1
+                }
+            }
+            _ => 0..self.members,
+        }
+    }
+
+    fn route(&self, lump_id: &LumpId) -> usize {
+        match self.strategy {
+            RoutingStrategy::Hash => (hash_lump_id(lump_id) % self.members as u64) as usize,
+            RoutingStrategy::RangeTable(ref starts) => {
+                match starts.binary_search(lump_id) {
+                    Ok(i) => i,
+                    Err(i) => i - 1, // `starts[0]`は`0`なので、`i`は常に正
+                }
+            }
+            RoutingStrategy::ConsistentHash { .. } => {
+                let hash = hash_lump_id(lump_id);
+                let i = match self.ring.binary_search_by_key(&hash, |&(h, _)| h) {
+                    Ok(i) | Err(i) => i,
+                };
+                self.ring[i % self.ring.len()].1
+            }
+        }
+    }
+}
+
+/// LumpIDのハッシュ値を計算する.
+///
+/// 格納先の決定に使用されるので、Rustのバージョン等によって値が変わらない独自の関数を使用している.
+fn hash_lump_id(lump_id: &LumpId) -> u64 {
+    let id = lump_id.as_u128();
+    mix64((id as u64) ^ mix64((id >> 64) as u64))
+}
+
+/// SplitMix64の最終段と同じ混ぜ合わせ関数.
+fn mix64(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use fibers_global::execute;
+    use prometrics::metrics::MetricBuilder;
+    use trackable::result::TestResult;
+
+    use super::*;
+    use crate::device::{DeviceBuilder, DeviceStatus};
+    use crate::nvm::MemoryNvm;
+    use crate::storage::StorageBuilder;
+
+    fn device() -> Result<Device> {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024]);
+        let storage = track!(StorageBuilder::new().journal_region_ratio(0.99).create(nvm))?;
+        let device = DeviceBuilder::new().spawn(|| Ok(storage));
+        track!(execute(device.wait_for_running()))
+    }
+
+    fn pool(strategy: RoutingStrategy) -> Result<DevicePool> {
+        let devices = track!((0..3).map(|_| device()).collect::<Result<Vec<_>>>())?;
+        track!(DevicePool::new(devices, strategy))
+    }
+
+    fn id(id: usize) -> LumpId {
+        LumpId::new(id as u128)
+    }
+
+    fn data(data: &[u8]) -> LumpData {
+        LumpData::new_embedded(Vec::from(data)).unwrap()
+    }
+
+    #[test]
+    fn routing_works() -> TestResult {
+        let router = track!(Router::new(
+            RoutingStrategy::RangeTable(vec![id(0), id(10), id(20)]),
+            3
+        ))?;
+        assert_eq!(router.route(&id(0)), 0);
+        assert_eq!(router.route(&id(9)), 0);
+        assert_eq!(router.route(&id(10)), 1);
+        assert_eq!(router.route(&id(100)), 2);
+
+        assert!(Router::new(RoutingStrategy::RangeTable(vec![id(0), id(10)]), 3).is_err());
+        assert!(Router::new(RoutingStrategy::RangeTable(vec![id(1)]), 1).is_err());
+
+        for strategy in [
+            RoutingStrategy::Hash,
+            RoutingStrategy::ConsistentHash { virtual_nodes: 16 },
+        ] {
+            let router = track!(Router::new(strategy, 3))?;
+            let mut counts = [0; 3];
+            for i in 0..300 {
+                counts[router.route(&id(i))] += 1;
+            }
+            assert!(counts.iter().all(|&c| c > 50), "{:?}", counts);
+        }
+
+        // コンシステントハッシュでは、メンバーが増えても大半のlumpの担当は変わらない
+        let router3 = track!(Router::new(
+            RoutingStrategy::ConsistentHash { virtual_nodes: 16 },
+            3
+        ))?;
+        let router4 = track!(Router::new(
+            RoutingStrategy::ConsistentHash { virtual_nodes: 16 },
+            4
+        ))?;
+        let moved = (0..300)
+            .filter(|i| router3.route(&id(*i)) != router4.route(&id(*i)))
+            .count();
+        assert!(moved < 150, "moved={}", moved);
+        Ok(())
+    }
+
+    #[test]
+    fn pool_works() -> TestResult {
+        let pool = track!(pool(RoutingStrategy::Hash))?;
+        let handle = pool.handle();
+        for i in 0..10 {
+            track!(execute(handle.request().put(id(i), data(b"foo"))))?;
+        }
+        for i in 0..10 {
+            let member = track!(handle.member_for(&id(i)))?;
+            assert!(track!(execute(member.request().head(id(i))))?.is_some());
+        }
+        assert_eq!(
+            track!(execute(handle.request().list()))?,
+            (0..10).map(id).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            track!(execute(handle.request().list_range(id(3)..id(6))))?,
+            vec![id(3), id(4), id(5)]
+        );
+        let usage = track!(execute(handle.request().usage_range(id(0)..id(10))))?;
+        assert!(usage.bytecount().is_some_and(|n| n > 0));
+
+        assert_eq!(
+            track!(execute(handle.request().delete_range(id(0)..id(5))))?,
+            (0..5).map(id).collect::<Vec<_>>()
+        );
+        assert!(track!(execute(handle.request().get(id(7))))?.is_some());
+        assert!(track!(execute(handle.request().get(id(2))))?.is_none());
+
+        let metrics = handle.metrics();
+        assert_eq!(metrics.status(), DeviceStatus::Running);
+        assert_eq!(metrics.queue_len(), 0);
+        assert_eq!(metrics.enqueued_commands().put(), 10);
+        assert_eq!(metrics.dequeued_commands().delete_range(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn aggregated_metrics_cover_all_qos_classes() {
+        // メンバー毎に登録されているクラスが異なっていても、全てのクラスのキュー長が集約される
+        let builder = MetricBuilder::without_registry();
+        let (a, b) = (QosClass::new("a"), QosClass::new("b"));
+        let m0 = DeviceMetrics::new(&builder, &[(a.clone(), 1)]);
+        let m1 = DeviceMetrics::new(&builder, &[(b.clone(), 1)]);
+        m0.qos_class(&a).unwrap().queue_len.set(2.0);
+        m1.qos_class(&b).unwrap().queue_len.set(3.0);
+        m1.qos_class(&QosClass::default())
+            .unwrap()
+            .queue_len
+            .set(1.0);
+
+        let metrics = DeviceMetrics::aggregate(vec![&m0, &m1]);
+        assert_eq!(metrics.qos_class(&a).map(|m| m.queue_len()), Some(2));
+        assert_eq!(metrics.qos_class(&b).map(|m| m.queue_len()), Some(3));
+        assert_eq!(
+            metrics
+                .qos_class(&QosClass::default())
+                .map(|m| m.queue_len()),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn take_offline_works() -> TestResult {
+        let mut pool = track!(pool(RoutingStrategy::RangeTable(vec![
+            id(0),
+            id(10),
+            id(20)
+        ])))?;
+        let handle = pool.handle();
+        for i in &[1, 11, 21] {
+            track!(execute(handle.request().put(id(*i), data(b"foo"))))?;
+        }
+
+        let device = track!(pool.take_offline(1))?;
+        assert!(!pool.is_online(1));
+        assert!(pool.take_offline(1).is_err());
+
+        assert_eq!(
+            execute(handle.request().get(id(11)))
+                .err()
+                .map(|e| *e.kind()),
+            Some(ErrorKind::DeviceTerminated)
+        );
+        assert!(track!(execute(handle.request().get(id(21))))?.is_some());
+
+        // オフラインのメンバーが担当し得る範囲に対するリクエストは、部分的な結果を返さずに失敗する
+        for result in [
+            execute(handle.request().list()),
+            execute(handle.request().list_range(id(5)..id(15))),
+            execute(handle.request().delete_range(id(19)..id(21))),
+        ] {
+            assert_eq!(
+                result.err().map(|e| *e.kind()),
+                Some(ErrorKind::DeviceTerminated)
+            );
+        }
+        assert!(execute(handle.request().usage_range(id(10)..id(11))).is_err());
+        assert!(track!(execute(handle.request().get(id(21))))?.is_some());
+
+        // オンラインのメンバーのみが担当する範囲は問題なく処理される
+        assert_eq!(
+            track!(execute(handle.request().list_range(id(0)..id(10))))?,
+            vec![id(1)]
+        );
+        assert_eq!(
+            track!(execute(handle.request().list_range(id(20)..id(30))))?,
+            vec![id(21)]
+        );
+        assert_eq!(handle.metrics().enqueued_commands().put(), 2);
+
+        track!(pool.bring_online(1, device))?;
+        assert!(track!(execute(handle.request().get(id(11))))?.is_some());
+        assert_eq!(
+            track!(execute(handle.request().list()))?,
+            vec![id(1), id(11), id(21)]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+//! QoSクラス毎のスケジューリング.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::device::command::Command;
+use crate::device::queue::ElevatorQueue;
+use crate::metrics::{DeviceMetrics, QosClassMetrics};
+
+/// リクエストのQoSクラス.
+///
+/// デバイスの実行時間は、`DeviceBuilder::qos_class`で指定された重みに比例するように、
+/// 各クラスに割り当てられる.
+/// 例えば、重みが`3`のクラスと`1`のクラスのリクエストが、共にキューに溜まっている状況では、
+/// 前者には後者の三倍の実行時間が割り当てられる.
+///
+/// 同じクラス内では、従来通りにデッドラインに基づいて実行順が決定される.
+///
+/// `DeviceBuilder`に登録されていない名前のクラスが指定されたリクエストは、
+/// デフォルトクラス(`QosClass::default()`)として扱われる.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct QosClass(Arc<str>);
+impl QosClass {
+    /// デフォルトクラスの名前.
+    pub const DEFAULT_NAME: &'static str = "default";
+
+    /// 指定された名前を持つ`QosClass`インスタンスを生成する.
+    pub fn new(name: &str) -> Self {
+        QosClass(Arc::from(name))
+    }
+
+    /// クラスの名前を返す.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+impl Default for QosClass {
+    /// デフォルトクラス(名前は`"default"`)を返す.
+    fn default() -> Self {
+        QosClass::new(Self::DEFAULT_NAME)
+    }
+}
+impl fmt::Display for QosClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// QoSクラス毎の重みに基づく、重み付き公平キュー.
+///
+/// クラス毎にキュー(`ElevatorQueue`)を保持し、次に処理するクラスは、
+/// 各クラスが消費した実行時間を重みで割った値(仮想時間)が最小のものが選ばれる.
+/// 実行時間の消費は`complete`メソッド経由で通知される.
+///
+/// なお、長い間空だったクラスが、その間の"貯金"で他のクラスを圧倒しないように、
+/// 空のクラスに新しいコマンドが追加された時点で、その仮想時間は他の稼働中のクラスに揃えられる.
+#[derive(Debug)]
+pub struct FairQueue {
+    classes: Vec<ClassQueue>,
+    indices: HashMap<QosClass, usize>,
+    default_index: usize,
+    virtual_time: f64,
+    in_service: Option<usize>,
+}
+impl FairQueue {
+    /// 新しい`FairQueue`インスタンスを生成する.
+    ///
+    /// `classes`にデフォルトクラスが含まれていない場合には、重み`1`で追加される.
+    ///
+    /// `urgency`は、各クラス内でのエレベータ方式のスケジューリングの設定(`ElevatorQueue::new`を参照).
+    pub fn new(
+        classes: &[(QosClass, u32)],
+        urgency: Option<Duration>,
+        metrics: &DeviceMetrics,
+    ) -> Self {
+        let mut queue = FairQueue {
+            classes: Vec::new(),
+            indices: HashMap::new(),
+            default_index: 0,
+            virtual_time: 0.0,
+            in_service: None,
+        };
+        for (class, weight) in with_default_class(classes) {
+            queue.indices.insert(class.clone(), queue.classes.len());
+            queue.classes.push(ClassQueue {
+                weight: f64::from(weight),
+                queue: ElevatorQueue::new(urgency),
+                virtual_time: 0.0,
+                metrics: metrics.qos_class(&class).cloned(),
+            });
+        }
+        queue.default_index = queue.indices[&QosClass::default()];
+        queue
+    }
+
+    /// 走査対象のコマンドの位置を解決する必要があるかどうか.
+    pub fn is_elevator_enabled(&self) -> bool {
+        self.classes[self.default_index].queue.is_elevator_enabled()
+    }
+
+    /// 新しいコマンドをキューに追加する.
+    ///
+    /// `position`は、コマンドがアクセスするデータ領域内の位置(ブロック単位).
+    pub fn push(&mut self, command: Command, position: Option<u64>) {
+        let i = command
+            .qos_class()
+            .and_then(|c| self.indices.get(c).cloned())
+            .unwrap_or(self.default_index);
+        if self.classes[i].queue.len() == 0 {
+            let start = self.min_active_virtual_time().unwrap_or(self.virtual_time);
+            let class = &mut self.classes[i];
+            class.virtual_time = class.virtual_time.max(start);
+        }
+        let class = &mut self.classes[i];
+        class.queue.push(command, position);
+        class.update_queue_len();
+    }
+
+    /// 次に処理するコマンドを取り出す.
+    pub fn pop(&mut self) -> Option<Command> {
+        let i = self.next_class()?;
+        let class = &mut self.classes[i];
+        let command = class.queue.pop();
+        class.update_queue_len();
+        self.virtual_time = class.virtual_time;
+        self.in_service = Some(i);
+        command
+    }
+
+    /// デッドラインを`grace`以上過ぎているコマンドがあれば、それを取り出す.
+    ///
+    /// 期限切れのコマンドは実行されないので、クラスの選択に重みは考慮されない.
+    pub fn pop_expired(&mut self, grace: Duration) -> Option<Command> {
+        for class in &mut self.classes {
+            if let Some(command) = class.queue.pop_expired(grace) {
+                class.update_queue_len();
+                return Some(command);
+            }
+        }
+        None
+    }
+
+    /// 直前に`pop`で取り出したコマンドの実行に要した時間を通知する.
+    ///
+    /// 実行時間は、そのコマンドが属するクラスの仮想時間に、重みに応じて加算される.
+    pub fn complete(&mut self, elapsed: Duration) {
+        if let Some(i) = self.in_service.take() {
+            let class = &mut self.classes[i];
+            class.virtual_time += elapsed.as_secs_f64() / class.weight;
+        }
+    }
+
+    /// キューに格納されている要素数を返す.
+    pub fn len(&self) -> usize {
+        self.classes.iter().map(|c| c.queue.len()).sum()
+    }
+
+    fn next_class(&self) -> Option<usize> {
+        self.classes
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.queue.len() > 0)
+            .min_by(|(_, a), (_, b)| a.virtual_time.total_cmp(&b.virtual_time))
+            .map(|(i, _)| i)
+    }
+
+    fn min_active_virtual_time(&self) -> Option<f64> {
+        self.next_class().map(|i| self.classes[i].virtual_time)
+    }
+}
+
+/// デフォルトクラスが含まれていない場合には、重み`1`で追加したクラス一覧を返す.
+pub(crate) fn with_default_class(classes: &[(QosClass, u32)]) -> Vec<(QosClass, u32)> {
+    let mut classes = classes.to_vec();
+    if classes.iter().all(|(c, _)| *c != QosClass::default()) {
+        classes.push((QosClass::default(), 1));
+    }
+    classes
+}
+
+#[derive(Debug)]
+struct ClassQueue {
+    weight: f64,
+    queue: ElevatorQueue,
+    virtual_time: f64,
+    metrics: Option<QosClassMetrics>,
+}
+impl ClassQueue {
+    fn update_queue_len(&self) {
+        if let Some(ref metrics) = self.metrics {
+            metrics.queue_len.set(self.queue.len() as f64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometrics::metrics::MetricBuilder;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::deadline::Deadline;
+    use crate::device::command::{Command, GetLump};
+    use crate::lump::LumpId;
+
+    #[test]
+    fn weighted_fair_queue_works() {
+        let classes = [
+            (QosClass::new("interactive"), 4),
+            (QosClass::new("batch"), 1),
+        ];
+        let metrics = DeviceMetrics::new(&MetricBuilder::new(), &classes);
+        let mut queue = FairQueue::new(&classes, None, &metrics);
+        for i in 0..100 {
+            queue.push(command(i, "interactive", Deadline::Infinity), None);
+            queue.push(command(100 + i, "batch", Deadline::Infinity), None);
+        }
+        assert_eq!(queue.len(), 200);
+        assert_eq!(
+            metrics
+                .qos_class(&QosClass::new("batch"))
+                .unwrap()
+                .queue_len(),
+            100
+        );
+
+        // 実行時間が等しい場合には、重みの比率でコマンドが選択される
+        let mut interactive = 0;
+        for _ in 0..50 {
+            if lump_id(queue.pop()).unwrap() < 100 {
+                interactive += 1;
+            }
+            queue.complete(Duration::from_secs(1));
+        }
+        assert_eq!(interactive, 40);
+    }
+
+    #[test]
+    fn deadline_ordering_within_class_works() {
+        let classes = [(QosClass::new("interactive"), 1)];
+        let metrics = DeviceMetrics::new(&MetricBuilder::new(), &classes);
+        let mut queue = FairQueue::new(&classes, None, &metrics);
+        queue.push(command(0, "interactive", Deadline::Infinity), None);
+        queue.push(command(1, "interactive", Deadline::Immediate), None);
+        queue.push(command(2, "unknown", Deadline::Immediate), None); // デフォルトクラス扱い
+
+        assert_eq!(lump_id(queue.pop()), Some(1));
+        queue.complete(Duration::from_millis(1));
+        assert_eq!(lump_id(queue.pop()), Some(2));
+        queue.complete(Duration::from_millis(1));
+        assert_eq!(lump_id(queue.pop()), Some(0));
+        assert_eq!(lump_id(queue.pop()), None);
+    }
+
+    #[test]
+    fn idle_class_does_not_accumulate_credit() {
+        let classes = [(QosClass::new("a"), 1), (QosClass::new("b"), 1)];
+        let metrics = DeviceMetrics::new(&MetricBuilder::new(), &classes);
+        let mut queue = FairQueue::new(&classes, None, &metrics);
+
+        // "a"だけが長時間稼働する
+        for i in 0..10 {
+            queue.push(command(i, "a", Deadline::Infinity), None);
+            assert_eq!(lump_id(queue.pop()), Some(i));
+            queue.complete(Duration::from_secs(1));
+        }
+
+        // その後に"b"が追加されても、"a"が締め出されることはない
+        for i in 0..4 {
+            queue.push(command(10 + i, "a", Deadline::Infinity), None);
+            queue.push(command(20 + i, "b", Deadline::Infinity), None);
+        }
+        let mut a = 0;
+        for _ in 0..4 {
+            if lump_id(queue.pop()).unwrap() < 20 {
+                a += 1;
+            }
+            queue.complete(Duration::from_secs(1));
+        }
+        assert_eq!(a, 2);
+    }
+
+    fn command(lump_id: u128, class: &str, deadline: Deadline) -> Command {
+        let mut command = Command::Get(GetLump::new(LumpId::new(lump_id), deadline, false).0);
+        command.set_qos_class(QosClass::new(class));
+        command
+    }
+
+    fn lump_id(command: Option<Command>) -> Option<u128> {
+        command.map(|c| {
+            if let Command::Get(c) = c {
+                c.lump_id().as_u128()
+            } else {
+                unreachable!()
+            }
+        })
+    }
+}
@@ -1,6 +1,6 @@
 use std::cmp;
-use std::collections::BinaryHeap;
-use std::time::Instant;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
+use std::time::{Duration, Instant};
 
 use crate::deadline::Deadline;
 use crate::device::command::Command;
@@ -15,7 +15,8 @@ use crate::device::command::Command;
 /// そのデッドラインが近い順に実行される.
 ///
 /// なお、これが行うのはあくまでも並び替えのみで、
-/// デッドラインを過ぎたコマンドの破棄は行わない.
+/// デッドラインを過ぎたコマンドの破棄は行わない
+/// (`pop_expired`を用いて、利用者側で行う必要がある).
 #[derive(Debug)]
 pub struct DeadlineQueue {
     seqno: u64,
@@ -33,6 +34,10 @@ impl DeadlineQueue {
     /// 新しいコマンドをキューに追加する.
     pub fn push(&mut self, command: Command) {
         let deadline = AbsoluteDeadline::new(command.deadline());
+        self.push_with_deadline(command, deadline);
+    }
+
+    fn push_with_deadline(&mut self, command: Command, deadline: AbsoluteDeadline) {
         let item = Item {
             seqno: self.seqno,
             command,
@@ -47,10 +52,163 @@ impl DeadlineQueue {
         self.heap.pop().map(|t| t.command)
     }
 
+    /// デッドラインを`grace`以上過ぎているコマンドがあれば、それを取り出す.
+    ///
+    /// 対象となるのは`Deadline::Within`が指定されたコマンドのみ.
+    pub fn pop_expired(&mut self, grace: Duration) -> Option<Command> {
+        let expired = self
+            .heap
+            .peek()
+            .is_some_and(|t| t.deadline.is_expired(grace));
+        if expired {
+            self.pop()
+        } else {
+            None
+        }
+    }
+
     /// キューに格納されている要素数を返す.
     pub fn len(&self) -> usize {
         self.heap.len()
     }
+
+    fn peek_deadline(&self) -> Option<AbsoluteDeadline> {
+        self.heap.peek().map(|t| t.deadline)
+    }
+}
+
+/// ディスク上の位置を考慮するエレベータ方式(C-LOOK)のキュー.
+///
+/// 位置が判明しているコマンド(i.e., データ領域を読み込む`GET`コマンド)の内、
+/// デッドラインが差し迫っていないものは、デッドライン順ではなく、
+/// データ領域内での位置の昇順に、一方向に走査するように処理される(C-LOOK).
+/// これによって、HDDのシーク量を削減することが期待できる.
+///
+/// デッドラインまでの残り時間が`urgency`以下のコマンド(および`Deadline::Immediate`のコマンド)は、
+/// 走査に割り込んで、デッドライン順に優先的に処理される.
+///
+/// 位置が不明なコマンドと走査対象のコマンドは、互いに飢餓状態にならないように、交互に処理される.
+#[derive(Debug)]
+pub struct ElevatorQueue {
+    deadline_queue: DeadlineQueue,
+    urgency: Option<Duration>,
+    seqno: u64,
+    by_position: BTreeMap<(u64, u64), (Command, AbsoluteDeadline)>,
+    by_deadline: BTreeSet<(AbsoluteDeadline, u64, u64)>,
+    head: u64,
+    prefer_elevator: bool,
+}
+impl ElevatorQueue {
+    /// 新しい`ElevatorQueue`インスタンスを生成する.
+    ///
+    /// `urgency`が`None`の場合には、全てのコマンドがデッドライン順に処理される(i.e., `DeadlineQueue`と等価).
+    pub fn new(urgency: Option<Duration>) -> Self {
+        ElevatorQueue {
+            deadline_queue: DeadlineQueue::new(),
+            urgency,
+            seqno: 0,
+            by_position: BTreeMap::new(),
+            by_deadline: BTreeSet::new(),
+            head: 0,
+            prefer_elevator: false,
+        }
+    }
+
+    /// 走査対象のコマンドの位置を解決する必要があるかどうか.
+    pub fn is_elevator_enabled(&self) -> bool {
+        self.urgency.is_some()
+    }
+
+    /// 新しいコマンドをキューに追加する.
+    ///
+    /// `position`には、コマンドがアクセスするデータ領域内の位置(ブロック単位)を指定する.
+    pub fn push(&mut self, command: Command, position: Option<u64>) {
+        let (position, urgency) = match (position, self.urgency) {
+            (Some(position), Some(urgency)) => (position, urgency),
+            _ => return self.deadline_queue.push(command),
+        };
+        let deadline = AbsoluteDeadline::new(command.deadline());
+        if deadline.is_urgent(urgency) {
+            self.deadline_queue.push_with_deadline(command, deadline);
+        } else {
+            self.by_deadline.insert((deadline, self.seqno, position));
+            self.by_position
+                .insert((position, self.seqno), (command, deadline));
+            self.seqno += 1;
+        }
+    }
+
+    /// 次に処理するコマンドを取り出す.
+    pub fn pop(&mut self) -> Option<Command> {
+        if let Some(urgency) = self.urgency {
+            self.promote_urgent_commands(urgency);
+            if self
+                .deadline_queue
+                .peek_deadline()
+                .is_some_and(|d| d.is_urgent(urgency))
+            {
+                return self.deadline_queue.pop();
+            }
+        }
+        let use_elevator = match (self.deadline_queue.len(), self.by_position.len()) {
+            (_, 0) => false,
+            (0, _) => true,
+            _ => self.prefer_elevator,
+        };
+        if use_elevator {
+            self.prefer_elevator = false;
+            self.pop_next_position()
+        } else {
+            self.prefer_elevator = true;
+            self.deadline_queue.pop()
+        }
+    }
+
+    /// デッドラインを`grace`以上過ぎているコマンドがあれば、それを取り出す.
+    pub fn pop_expired(&mut self, grace: Duration) -> Option<Command> {
+        if let Some(command) = self.deadline_queue.pop_expired(grace) {
+            return Some(command);
+        }
+        let &(deadline, seqno, position) = self.by_deadline.iter().next()?;
+        if deadline.is_expired(grace) {
+            self.by_deadline.remove(&(deadline, seqno, position));
+            self.by_position.remove(&(position, seqno)).map(|t| t.0)
+        } else {
+            None
+        }
+    }
+
+    /// キューに格納されている要素数を返す.
+    pub fn len(&self) -> usize {
+        self.deadline_queue.len() + self.by_position.len()
+    }
+
+    /// デッドラインが差し迫ったコマンドを、走査対象から外して、デッドライン順のキューに移す.
+    fn promote_urgent_commands(&mut self, urgency: Duration) {
+        while let Some(&(deadline, seqno, position)) = self.by_deadline.iter().next() {
+            if !deadline.is_urgent(urgency) {
+                break;
+            }
+            self.by_deadline.remove(&(deadline, seqno, position));
+            if let Some((command, deadline)) = self.by_position.remove(&(position, seqno)) {
+                self.deadline_queue.push_with_deadline(command, deadline);
+            }
+        }
+    }
+
+    /// 現在の位置以降で最も近いコマンドを取り出す(末尾に達したら先頭に戻る).
+    fn pop_next_position(&mut self) -> Option<Command> {
+        let key = self
+            .by_position
+            .range((self.head, 0)..)
+            .next()
+            .or_else(|| self.by_position.iter().next())
+            .map(|(k, _)| *k)?;
+        let (command, deadline) = self.by_position.remove(&key)?;
+        self.by_deadline.remove(&(deadline, key.1, key.0));
+        self.head = key.0;
+        Some(command)
+    }
 }
 
 /// ヒープに格納する要素.
@@ -80,7 +238,7 @@ impl Ord for Item {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 enum AbsoluteDeadline {
     Immediate,
     Until(Instant),
@@ -94,6 +252,27 @@ impl AbsoluteDeadline {
             Deadline::Infinity => AbsoluteDeadline::Infinity,
         }
     }
+
+    /// デッドラインを`grace`以上過ぎているかどうか.
+    fn is_expired(&self, grace: Duration) -> bool {
+        match *self {
+            AbsoluteDeadline::Until(deadline) => deadline
+                .checked_add(grace)
+                .is_some_and(|limit| limit < Instant::now()),
+            _ => false,
+        }
+    }
+
+    /// デッドラインまでの残り時間が`urgency`以下かどうか.
+    fn is_urgent(&self, urgency: Duration) -> bool {
+        match *self {
+            AbsoluteDeadline::Immediate => true,
+            AbsoluteDeadline::Until(deadline) => {
+                deadline.saturating_duration_since(Instant::now()) <= urgency
+            }
+            AbsoluteDeadline::Infinity => false,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -127,6 +306,103 @@ mod tests {
         assert_eq!(queue.len(), 0);
     }
 
+    #[test]
+    fn pop_expired_works() {
+        let mut queue = DeadlineQueue::new();
+
+        queue.push(command(0, Deadline::Infinity));
+        queue.push(command(1, Deadline::Within(Duration::from_millis(1))));
+        queue.push(command(2, Deadline::Within(Duration::from_secs(60))));
+        queue.push(command(3, Deadline::Immediate));
+        thread::sleep(Duration::from_millis(5));
+
+        // `Immediate`のコマンドは期限切れにはならない
+        assert_eq!(lump_id(queue.pop_expired(Duration::from_millis(0))), None);
+        assert_eq!(lump_id(queue.pop()), Some(3));
+
+        // 猶予期間内なら期限切れにはならない
+        assert_eq!(lump_id(queue.pop_expired(Duration::from_secs(60))), None);
+        assert_eq!(
+            lump_id(queue.pop_expired(Duration::from_millis(1))),
+            Some(1)
+        );
+        assert_eq!(lump_id(queue.pop_expired(Duration::from_millis(1))), None);
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn elevator_works() {
+        let mut queue = ElevatorQueue::new(Some(Duration::from_millis(10)));
+
+        // 位置順に一方向に走査される
+        queue.push(command(0, Deadline::Infinity), Some(50));
+        queue.push(command(1, Deadline::Infinity), Some(10));
+        queue.push(command(2, Deadline::Infinity), Some(30));
+        assert_eq!(lump_id(queue.pop()), Some(1));
+        assert_eq!(lump_id(queue.pop()), Some(2));
+
+        // 現在位置より前のものは、末尾に達した後に処理される
+        queue.push(command(3, Deadline::Infinity), Some(20));
+        queue.push(command(4, Deadline::Infinity), Some(60));
+        assert_eq!(lump_id(queue.pop()), Some(0));
+        assert_eq!(lump_id(queue.pop()), Some(4));
+        assert_eq!(lump_id(queue.pop()), Some(3));
+        assert_eq!(lump_id(queue.pop()), None);
+    }
+
+    #[test]
+    fn elevator_urgent_commands_preempt() {
+        // 負荷の高い環境でも結果が変わらないように、十分な余裕を持たせた時間を用いる
+        let mut queue = ElevatorQueue::new(Some(Duration::from_millis(500)));
+        queue.push(command(0, Deadline::Infinity), Some(10));
+        queue.push(
+            command(1, Deadline::Within(Duration::from_secs(1))),
+            Some(30),
+        );
+        queue.push(
+            command(2, Deadline::Within(Duration::from_millis(1))),
+            Some(20),
+        );
+        queue.push(command(3, Deadline::Immediate), Some(40));
+        assert_eq!(queue.len(), 4);
+
+        // 追加時点で差し迫っているものが先
+        assert_eq!(lump_id(queue.pop()), Some(3));
+        assert_eq!(lump_id(queue.pop()), Some(2));
+
+        // 待機中に差し迫ったものも割り込む
+        thread::sleep(Duration::from_millis(700));
+        assert_eq!(lump_id(queue.pop()), Some(1));
+        assert_eq!(lump_id(queue.pop()), Some(0));
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn elevator_alternates_with_non_positional_commands() {
+        let mut queue = ElevatorQueue::new(Some(Duration::from_millis(10)));
+        queue.push(command(0, Deadline::Infinity), Some(10));
+        queue.push(command(1, Deadline::Infinity), Some(20));
+        queue.push(command(2, Deadline::Infinity), None);
+        queue.push(command(3, Deadline::Infinity), None);
+
+        assert_eq!(lump_id(queue.pop()), Some(2));
+        assert_eq!(lump_id(queue.pop()), Some(0));
+        assert_eq!(lump_id(queue.pop()), Some(3));
+        assert_eq!(lump_id(queue.pop()), Some(1));
+    }
+
+    #[test]
+    fn elevator_disabled_works() {
+        // `urgency`が未指定なら、位置は無視されてデッドライン順となる
+        let mut queue = ElevatorQueue::new(None);
+        queue.push(command(0, Deadline::Infinity), Some(10));
+        queue.push(command(1, Deadline::Immediate), Some(20));
+        queue.push(command(2, Deadline::Infinity), Some(0));
+        assert_eq!(lump_id(queue.pop()), Some(1));
+        assert_eq!(lump_id(queue.pop()), Some(0));
+        assert_eq!(lump_id(queue.pop()), Some(2));
+    }
+
     fn command(lump_id: u128, deadline: Deadline) -> Command {
         Command::Get(GetLump::new(LumpId::new(lump_id), deadline, false).0)
     }
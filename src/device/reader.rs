@@ -0,0 +1,130 @@
+//! `GET`コマンドを並列に処理するための読み込み用スレッド群.
+use slog::Logger;
+use std::sync::mpsc as std_mpsc;
+use std::sync::mpsc::SendError;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use trackable::error::ErrorKindExt;
+
+use crate::device::command::{Command, CommandReceiver, CommandSender};
+use crate::metrics::DeviceMetrics;
+use crate::nvm::NonVolatileMemory;
+use crate::storage::{ParallelGet, Storage, StorageReader};
+use crate::{ErrorKind, Result};
+
+/// 読み込み用のスレッド群を起動する.
+///
+/// 各スレッドは、それぞれが専用の読み込みハンドル(`StorageReader`)を保持し、
+/// 送られてきた`GET`コマンドを、デバイスの実行スレッドとは独立に処理する.
+/// ジャーナル領域に埋め込まれているlumpに対するコマンドは、`command_tx`経由でデバイスの実行スレッドに転送される.
+///
+/// ストレージが並列読み込みに対応していない場合には`Ok(None)`が返される.
+///
+/// 返り値の送信口が破棄されると、各スレッドは受信済みのコマンドを処理した上で終了する.
+///
+/// 送信口に溜めておけるコマンドの数は、最大で`queue_len`となる.
+pub fn spawn<N>(
+    storage: &mut Storage<N>,
+    threads: usize,
+    queue_len: usize,
+    command_tx: &CommandSender,
+    metrics: &DeviceMetrics,
+    logger: &Logger,
+) -> Result<Option<std_mpsc::SyncSender<Command>>>
+where
+    N: NonVolatileMemory,
+{
+    let mut readers = Vec::with_capacity(threads);
+    for _ in 0..threads {
+        match track!(storage.reader())? {
+            None => return Ok(None),
+            Some(reader) => readers.push(reader),
+        }
+    }
+
+    let (tx, rx) = std_mpsc::sync_channel(queue_len);
+    let rx = Arc::new(Mutex::new(rx));
+    for reader in readers {
+        let thread = ReaderThread {
+            reader,
+            command_rx: rx.clone(),
+            command_tx: command_tx.clone(),
+            metrics: metrics.clone(),
+        };
+        thread::spawn(move || thread.run());
+    }
+    info!(logger, "Reader threads started: {}", threads);
+    Ok(Some(tx))
+}
+
+/// 読み込み用のスレッド.
+#[derive(Debug)]
+struct ReaderThread {
+    reader: StorageReader,
+    command_rx: Arc<Mutex<CommandReceiver>>,
+    command_tx: CommandSender,
+    metrics: DeviceMetrics,
+}
+impl ReaderThread {
+    fn run(self) {
+        loop {
+            let mut command = {
+                let rx = self.command_rx.lock().unwrap_or_else(|e| e.into_inner());
+                match rx.recv() {
+                    Err(_) => break,
+                    Ok(command) => command,
+                }
+            };
+            if command.is_cancelled() {
+                self.metrics.dequeued_commands.increment(&command);
+                self.metrics.cancelled_commands.increment(&command);
+                let e = track!(ErrorKind::RequestCancelled.cause("Cancelled by the requester"));
+                command.failed(e.into());
+                continue;
+            }
+            // 実行スレッドに転送された場合には、そちらで改めて設定される
+            command.observe_latency_with(self.metrics.latency_histograms(&command));
+            let dequeued_at = Instant::now();
+            let waited = command.issued_at().map(|t| dequeued_at.duration_since(t));
+            match command {
+                Command::Get(c) => match track!(self.reader.get(c.lump_id())) {
+                    Ok(ParallelGet::Done(value)) => {
+                        self.on_executed(waited, dequeued_at);
+                        c.reply(Ok(value));
+                    }
+                    Ok(ParallelGet::Delegated) => self.forward(Command::Get(c)),
+                    Err(e) => {
+                        self.on_executed(waited, dequeued_at);
+                        self.metrics.failed_commands.get.increment();
+                        c.reply(Err(e));
+                    }
+                },
+                command => self.forward(command),
+            }
+        }
+    }
+
+    /// このスレッドで処理した`GET`コマンドのメトリクスを更新する.
+    fn on_executed(&self, waited: Option<Duration>, dequeued_at: Instant) {
+        self.metrics.dequeued_commands.get.increment();
+        if let Some(waited) = waited {
+            self.metrics
+                .queue_wait_seconds
+                .get
+                .observe(waited.as_secs_f64());
+        }
+        self.metrics
+            .execution_seconds
+            .get
+            .observe(dequeued_at.elapsed().as_secs_f64());
+    }
+
+    /// デバイスの実行スレッドにコマンドを転送する.
+    fn forward(&self, command: Command) {
+        if let Err(SendError(command)) = self.command_tx.send(command) {
+            self.metrics.dequeued_commands.increment(&command);
+            self.metrics.failed_commands.increment(&command);
+        }
+    }
+}
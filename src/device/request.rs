@@ -1,14 +1,13 @@
-use futures::Future;
 use std::ops::Range;
 use trackable::error::ErrorKindExt;
 
 use super::thread::DeviceThreadHandle;
 use crate::deadline::Deadline;
-use crate::device::command::{self, Command};
-use crate::device::DeviceStatus;
+use crate::device::command::{self, AsyncResult, CancelToken, Command};
+use crate::device::{DeviceStatus, QosClass};
 use crate::lump::{LumpData, LumpHeader, LumpId};
-use crate::storage::StorageUsage;
-use crate::{Error, ErrorKind, Result};
+use crate::storage::{Durability, StorageUsage};
+use crate::{ErrorKind, Result};
 
 /// デバイスに対してリクエストを発行するためのビルダ.
 ///
@@ -17,6 +16,15 @@ use crate::{Error, ErrorKind, Result};
 /// リクエストを発行した結果返される`Future`を効率的にポーリングするためには
 /// [`fibers`]を使用する必要がある。
 ///
+/// ただし`std-future`フィーチャーが有効な場合には、結果の`AsyncResult`は`std::future::Future`も実装するので、
+/// `fibers`以外の非同期ランタイム上で`.await`することもできる。
+///
+/// # キャンセル
+///
+/// 読み込み系のリクエストは、結果の`AsyncResult`が実行前に破棄されると、デバイス側でスキップされる.
+/// 更新系のリクエストは、明示的に`cancel_token`で指定されたトークンによってのみキャンセルされる
+/// (i.e., 結果を待たずに破棄された書き込みも、従来通りに実行される).
+///
 /// [`fibers`]: https://github.com/dwango/fibers-rs
 #[derive(Debug)]
 pub struct DeviceRequest<'a> {
@@ -24,8 +32,10 @@ pub struct DeviceRequest<'a> {
     deadline: Option<Deadline>,
     max_queue_len: Option<usize>,
     wait_for_running: bool,
-    enforce_journal_sync: bool,
+    durability: Durability,
     prioritized: bool,
+    cancel_token: Option<CancelToken>,
+    qos_class: Option<QosClass>,
 }
 impl<'a> DeviceRequest<'a> {
     pub(crate) fn new(device: &'a DeviceThreadHandle) -> Self {
@@ -34,8 +44,10 @@ impl<'a> DeviceRequest<'a> {
             deadline: None,
             max_queue_len: None,
             wait_for_running: false,
-            enforce_journal_sync: false,
+            durability: Durability::default(),
             prioritized: false,
+            cancel_token: None,
+            qos_class: None,
         }
     }
 
@@ -49,26 +61,17 @@ impl<'a> DeviceRequest<'a> {
     /// デバイスが管理しているストレージへの書き込み時に、
     /// データをストレージのブロック境界にアライメントするためのメモリコピーが余分に発生してしまう.
     /// それを避けたい場合には、`DeviceHandle::allocate_lump_data`メソッドを使用して`LumpData`を生成すると良い.
-    pub fn put(
-        &self,
-        lump_id: LumpId,
-        lump_data: LumpData,
-    ) -> impl Future<Item = bool, Error = Error> {
+    pub fn put(&self, lump_id: LumpId, lump_data: LumpData) -> AsyncResult<bool> {
         let deadline = self.deadline.unwrap_or_default();
         let prioritized = self.prioritized;
-        let (command, response) = command::PutLump::new(
-            lump_id,
-            lump_data,
-            deadline,
-            prioritized,
-            self.enforce_journal_sync,
-        );
+        let (command, response) =
+            command::PutLump::new(lump_id, lump_data, deadline, prioritized, self.durability);
         self.send_command(Command::Put(command));
         response
     }
 
     /// Lumpを取得する.
-    pub fn get(&self, lump_id: LumpId) -> impl Future<Item = Option<LumpData>, Error = Error> {
+    pub fn get(&self, lump_id: LumpId) -> AsyncResult<Option<LumpData>> {
         let deadline = self.deadline.unwrap_or_default();
         let prioritized = self.prioritized;
 
@@ -78,7 +81,7 @@ impl<'a> DeviceRequest<'a> {
     }
 
     /// Lumpのヘッダを取得する.
-    pub fn head(&self, lump_id: LumpId) -> impl Future<Item = Option<LumpHeader>, Error = Error> {
+    pub fn head(&self, lump_id: LumpId) -> AsyncResult<Option<LumpHeader>> {
         let deadline = self.deadline.unwrap_or_default();
         let prioritized = self.prioritized;
 
@@ -90,12 +93,12 @@ impl<'a> DeviceRequest<'a> {
     /// Lumpを削除する.
     ///
     /// 指定されたlumpが存在した場合には`true`が、しなかった場合には`false`が、結果として返される.
-    pub fn delete(&self, lump_id: LumpId) -> impl Future<Item = bool, Error = Error> {
+    pub fn delete(&self, lump_id: LumpId) -> AsyncResult<bool> {
         let deadline = self.deadline.unwrap_or_default();
         let prioritized = self.prioritized;
 
         let (command, response) =
-            command::DeleteLump::new(lump_id, deadline, prioritized, self.enforce_journal_sync);
+            command::DeleteLump::new(lump_id, deadline, prioritized, self.durability);
         self.send_command(Command::Delete(command));
         response
     }
@@ -104,15 +107,12 @@ impl<'a> DeviceRequest<'a> {
     ///
     /// 返り値のvectorは、引数rangeに含まれるlump idのうち、
     /// 対応するlump dataが存在して実際に削除されたもの全体を表す。
-    pub fn delete_range(
-        &self,
-        range: Range<LumpId>,
-    ) -> impl Future<Item = Vec<LumpId>, Error = Error> {
+    pub fn delete_range(&self, range: Range<LumpId>) -> AsyncResult<Vec<LumpId>> {
         let deadline = self.deadline.unwrap_or_default();
         let prioritized = self.prioritized;
 
         let (command, response) =
-            command::DeleteLumpRange::new(range, deadline, prioritized, self.enforce_journal_sync);
+            command::DeleteLumpRange::new(range, deadline, prioritized, self.durability);
         self.send_command(Command::DeleteRange(command));
         response
     }
@@ -123,7 +123,7 @@ impl<'a> DeviceRequest<'a> {
     ///
     /// 例えば巨大なHDDを使用している場合には、lumpの数が数百万以上になることもあるため、
     /// このメソッドは呼び出す際には注意が必要.
-    pub fn list(&self) -> impl Future<Item = Vec<LumpId>, Error = Error> {
+    pub fn list(&self) -> AsyncResult<Vec<LumpId>> {
         let deadline = self.deadline.unwrap_or_default();
         let prioritized = self.prioritized;
 
@@ -134,10 +134,7 @@ impl<'a> DeviceRequest<'a> {
 
     /// 範囲を指定してlump一覧を取得する.
     ///
-    pub fn list_range(
-        &self,
-        range: Range<LumpId>,
-    ) -> impl Future<Item = Vec<LumpId>, Error = Error> {
+    pub fn list_range(&self, range: Range<LumpId>) -> AsyncResult<Vec<LumpId>> {
         let deadline = self.deadline.unwrap_or_default();
         let prioritized = self.prioritized;
 
@@ -148,10 +145,7 @@ impl<'a> DeviceRequest<'a> {
 
     /// 範囲を指定してlump数を取得する.
     ///
-    pub fn usage_range(
-        &self,
-        range: Range<LumpId>,
-    ) -> impl Future<Item = StorageUsage, Error = Error> {
+    pub fn usage_range(&self, range: Range<LumpId>) -> AsyncResult<StorageUsage> {
         let deadline = self.deadline.unwrap_or_default();
         let prioritized = self.prioritized;
 
@@ -187,10 +181,26 @@ impl<'a> DeviceRequest<'a> {
     /// このメソッドを呼ぶことで自動書き出しを待たずに
     /// その場での書き出しを強制することができる。
     ///
+    /// このリクエストに対する応答は、書き出しが完了した後に返される。
+    /// なお、同時に発行された複数のリクエストの書き出しは、
+    /// デバイスによって一度の同期にまとめられることがある(グループコミット)。
+    ///
     /// [journal_sync_interval]: ../storage/struct.StorageBuilder.html#method.journal_sync_interval
     /// [ジャーナルバッファ]: https://github.com/frugalos/cannyls/wiki/Journal-Memory-Buffer
     pub fn journal_sync(&mut self) -> &mut Self {
-        self.enforce_journal_sync = true;
+        self.durability(Durability::JournalSynced)
+    }
+
+    /// 書き込み系のリクエスト(i.e., `put`, `delete`, `delete_range`)に要求する永続化の度合いを指定する.
+    ///
+    /// `Durability::Buffered`以外が指定された場合には、
+    /// リクエストに対する応答は、指定された度合いの永続化が完了した後に返される.
+    /// `journal_sync`と同様に、同時に発行された複数のリクエストの同期は、
+    /// デバイスによって一度にまとめられることがある(その場合には、最も強い度合いで同期が行われる).
+    ///
+    /// デフォルト値は`Durability::Buffered`.
+    pub fn durability(&mut self, durability: Durability) -> &mut Self {
+        self.durability = durability;
         self
     }
 
@@ -227,7 +237,37 @@ impl<'a> DeviceRequest<'a> {
         self
     }
 
-    fn send_command(&self, command: Command) {
+    /// リクエストをキャンセルするためのトークンを指定する.
+    ///
+    /// 実行前に`CancelToken::cancel`が呼び出された場合には、リクエストはデバイス側でスキップされ、
+    /// `ErrorKind::RequestCancelled`エラーで終了する.
+    ///
+    /// なお、読み込み系のリクエスト(`GET`, `HEAD`, `LIST`, `LIST_RANGE`, `USAGE_RANGE`)は、
+    /// トークンを指定しない場合でも、結果の`AsyncResult`が実行前に破棄された時点でキャンセルされる.
+    /// 更新系のリクエスト(`PUT`, `DELETE`, `DELETE_RANGE`)は、結果を待たずに破棄しても実行されるので、
+    /// それらをキャンセルしたい場合には、このトークンを使用する必要がある.
+    pub fn cancel_token(&mut self, token: CancelToken) -> &mut Self {
+        self.cancel_token = Some(token);
+        self
+    }
+
+    /// リクエストのQoSクラスを指定する.
+    ///
+    /// デバイスの実行時間は、`DeviceBuilder::qos_class`で登録された各クラスの重みに応じて配分される.
+    ///
+    /// デフォルト値は`QosClass::default()`.
+    pub fn qos_class(&mut self, class: QosClass) -> &mut Self {
+        self.qos_class = Some(class);
+        self
+    }
+
+    fn send_command(&self, mut command: Command) {
+        if let Some(ref token) = self.cancel_token {
+            command.set_cancel_token(token.clone());
+        }
+        if let Some(ref class) = self.qos_class {
+            command.set_qos_class(class.clone());
+        }
         if !self.wait_for_running && self.device.metrics().status() == DeviceStatus::Starting {
             let e = track!(ErrorKind::DeviceBusy.cause("The device is starting up"));
             command.failed(e.into());
@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+/// デバイスのキューに溜まったコマンド群の実行順を決定する方式.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum SchedulingPolicy {
+    /// デッドラインが近い順に実行する.
+    #[default]
+    Deadline,
+
+    /// デッドラインが差し迫っていない`GET`コマンド群を、データ領域内での位置順に実行する.
+    ///
+    /// HDDのように、シークのコストが大きいデバイス向けの方式.
+    /// データ領域を先頭から末尾に向かって一方向に走査するように(C-LOOK)コマンドが実行されるので、
+    /// ランダムな読み込みが多い場合に、シーク量の削減が期待できる.
+    ///
+    /// デッドラインまでの残り時間が`urgency`以下のコマンドは、走査に割り込んでデッドライン順に実行される.
+    /// `Deadline::Immediate`のコマンドは常に、`Deadline::Infinity`のコマンドは決して、割り込みの対象にはならない.
+    Elevator {
+        /// 走査に割り込む対象となる、デッドラインまでの残り時間.
+        urgency: Duration,
+    },
+}
+
+impl SchedulingPolicy {
+    /// エレベータ方式の場合には、割り込みの閾値を返す.
+    pub(crate) fn urgency(&self) -> Option<Duration> {
+        match *self {
+            SchedulingPolicy::Deadline => None,
+            SchedulingPolicy::Elevator { urgency } => Some(urgency),
+        }
+    }
+}
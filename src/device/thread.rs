@@ -1,22 +1,29 @@
 use fibers::sync::oneshot;
 use futures::{Future, Poll};
 use slog::Logger;
+use std::cmp;
 use std::fmt::Debug;
+use std::mem;
 use std::sync::mpsc as std_mpsc;
-use std::sync::mpsc::{RecvTimeoutError, SendError};
-use std::sync::Arc;
+use std::sync::mpsc::{RecvTimeoutError, SendError, SyncSender, TrySendError};
+use std::sync::{Arc, RwLock};
 use std::thread;
 use std::time::{Duration, Instant};
 use trackable::error::ErrorKindExt;
 
-use crate::device::command::{Command, CommandReceiver, CommandSender};
+use crate::block::BlockSize;
+use crate::device::command::{
+    Command, CommandReceiver, CommandSender, DeleteLump, DeleteLumpRange, PutLump,
+};
 use crate::device::long_queue_policy::LongQueuePolicy;
 use crate::device::probabilistic::{Dropper, ProbabilisticDropper};
-use crate::device::queue::DeadlineQueue;
+use crate::device::qos::FairQueue;
+use crate::device::reader;
 use crate::device::{DeviceBuilder, DeviceStatus};
+use crate::lump::LumpId;
 use crate::metrics::DeviceMetrics;
 use crate::nvm::NonVolatileMemory;
-use crate::storage::Storage;
+use crate::storage::{Durability, Storage};
 use crate::{Error, ErrorKind, Result};
 
 /// デバイスの実行スレッド.
@@ -26,7 +33,7 @@ where
     N: NonVolatileMemory + Send + 'static,
 {
     metrics: DeviceMetrics,
-    queue: DeadlineQueue,
+    queue: FairQueue,
     storage: Storage<N>,
     idle_threshold: Duration,
     max_queue_len: usize,
@@ -38,6 +45,8 @@ where
     logger: Logger,
     long_queue_policy: LongQueuePolicy,
     dropper: Box<dyn Dropper>,
+    pending_replies: PendingReplies,
+    expiry_grace_period: Option<Duration>,
 }
 impl<N> DeviceThread<N>
 where
@@ -51,18 +60,44 @@ where
     where
         F: FnOnce() -> Result<Storage<N>> + Send + 'static,
     {
-        let mut metrics = DeviceMetrics::new(&builder.metrics);
+        let mut metrics = DeviceMetrics::new(&builder.metrics, &builder.qos_classes);
         metrics.status.set(f64::from(DeviceStatus::Starting as u8));
 
         let (command_tx, command_rx) = std_mpsc::channel();
         let (monitored, monitor) = oneshot::monitor();
+        let readers = Arc::new(RwLock::new(None));
+        let storage_config = Arc::new(RwLock::new(None));
         let handle = DeviceThreadHandle {
             command_tx: command_tx.clone(),
+            readers: readers.clone(),
+            storage_config: storage_config.clone(),
             metrics: Arc::new(metrics.clone()),
         };
         thread::spawn(move || {
-            let result = track!(init_storage()).and_then(|storage| {
+            let result = track!(init_storage()).and_then(|mut storage| {
                 metrics.storage = Some(storage.metrics().clone());
+                *storage_config.write().unwrap_or_else(|e| e.into_inner()) = Some(StorageConfig {
+                    block_size: storage.header().block_size,
+                    embed_threshold: storage.embed_threshold(),
+                    max_lump_size: storage.max_lump_size(),
+                });
+                if builder.reader_threads > 0 {
+                    let readers_tx = track!(reader::spawn(
+                        &mut storage,
+                        builder.reader_threads,
+                        builder.max_queue_len,
+                        &command_tx,
+                        &metrics,
+                        &builder.logger
+                    ))?;
+                    if readers_tx.is_none() {
+                        warn!(
+                            builder.logger,
+                            "The NVM does not support parallel reads; reader threads are disabled"
+                        );
+                    }
+                    *readers.write().unwrap_or_else(|e| e.into_inner()) = readers_tx;
+                }
                 metrics.status.set(f64::from(DeviceStatus::Running as u8));
                 // LongQueuePolicy が RefuseNewRequests か Drop だったら、この後 run_once で使うため、dropper を作っておく。
                 // Stop の場合も実装を簡単にするためにプレイスホルダーの dropper を作る。
@@ -71,7 +106,11 @@ where
                     as Box<dyn Dropper>;
                 let mut device = DeviceThread {
                     metrics: metrics.clone(),
-                    queue: DeadlineQueue::new(),
+                    queue: FairQueue::new(
+                        &builder.qos_classes,
+                        builder.scheduling_policy.urgency(),
+                        &metrics,
+                    ),
                     storage,
                     idle_threshold: builder.idle_threshold,
                     max_queue_len: builder.max_queue_len,
@@ -83,15 +122,31 @@ where
                     logger: builder.logger,
                     long_queue_policy: builder.long_queue_policy,
                     dropper,
+                    pending_replies: PendingReplies::new(
+                        builder.max_group_commit_size,
+                        builder.max_group_commit_delay,
+                    ),
+                    expiry_grace_period: builder.expiry_grace_period,
                 };
                 loop {
                     match track!(device.run_once()) {
-                        Err(e) => break Err(e),
-                        Ok(false) => break Ok(()),
+                        Err(e) => {
+                            device.abort_pending_replies(&e);
+                            break Err(e);
+                        }
+                        Ok(false) => {
+                            // 停止リクエストによる正常終了なので、ストレージを閉じておく
+                            // (次回のオープン時に、正常に終了したことが分かるようにするため)
+                            break track!(device.flush_pending_replies())
+                                .and_then(|()| track!(device.storage.close()));
+                        }
                         Ok(true) => {}
                     }
                 }
             });
+            // 読み込み用のスレッド群は、受信済みのコマンドを処理した後に終了する
+            *readers.write().unwrap_or_else(|e| e.into_inner()) = None;
+            *storage_config.write().unwrap_or_else(|e| e.into_inner()) = None;
             metrics.status.set(f64::from(DeviceStatus::Stopped as u8));
             metrics.storage = None;
             monitored.exit(result);
@@ -101,11 +156,35 @@ where
     }
 
     fn run_once(&mut self) -> Result<bool> {
+        // 同期待ちのコマンドが、キューが空になるのを待ち続けることがないように、
+        // 最初の保留から一定時間が経過した場合には、その場でコミットする
+        if self.pending_replies.is_overdue(Instant::now()) {
+            track!(self.flush_pending_replies())?;
+        }
+        if self.storage.journal_sync_deadline().is_some() {
+            track!(self.storage.journal_sync_if_overdue())?;
+        }
         if let Ok(command) = self.command_rx.try_recv() {
             return self.push_to_queue(command);
         }
+        if let Some(command) = self
+            .expiry_grace_period
+            .and_then(|grace| self.queue.pop_expired(grace))
+        {
+            let command = self.on_dequeued(command);
+            self.metrics.expired_commands.increment(&command);
+            let e = track!(ErrorKind::RequestExpired.cause("The deadline has already expired"));
+            command.failed(e.into());
+            return Ok(true);
+        }
         if let Some(command) = self.queue.pop() {
-            self.metrics.dequeued_commands.increment(&command);
+            let command = self.on_dequeued(command);
+            if command.is_cancelled() {
+                self.metrics.cancelled_commands.increment(&command);
+                let e = track!(ErrorKind::RequestCancelled.cause("Cancelled by the requester"));
+                command.failed(e.into());
+                return Ok(true);
+            }
             let result = track!(self.check_overload());
             let prioritized = command.prioritized();
             // 過負荷になっていたら、long_queue_policy に応じて挙動を変える
@@ -134,12 +213,32 @@ where
                     }
                 }
             }
-            return track!(self.handle_command(command));
+            let execution = self.metrics.execution_seconds.select(&command).clone();
+            let start = Instant::now();
+            let result = track!(self.handle_command(command));
+            let elapsed = start.elapsed();
+            execution.observe(elapsed.as_secs_f64());
+            self.queue.complete(elapsed);
+            return result;
         }
 
-        match self.command_rx.recv_timeout(self.idle_threshold) {
+        // 処理待ちのコマンドが無くなったので、同期待ちのコマンド群をまとめてコミットする
+        track!(self.flush_pending_replies())?;
+
+        // ジャーナルの同期期限が近い場合には、それに間に合うように待機時間を短くする
+        let timeout = match self.storage.journal_sync_deadline() {
+            Some(deadline) => {
+                let until_deadline = deadline.saturating_duration_since(Instant::now());
+                std::cmp::min(self.idle_threshold, until_deadline)
+            }
+            None => self.idle_threshold,
+        };
+        match self.command_rx.recv_timeout(timeout) {
             Err(RecvTimeoutError::Disconnected) => unreachable!(),
             Err(RecvTimeoutError::Timeout) => {
+                if track!(self.storage.journal_sync_if_overdue())? {
+                    return Ok(true);
+                }
                 self.metrics.side_jobs.increment();
                 track!(self.storage.run_side_job_once())?;
                 Ok(true)
@@ -195,10 +294,31 @@ where
                 LongQueuePolicy::Drop { .. } => {}
             }
         }
-        self.queue.push(command);
+        let position = match command {
+            Command::Get(ref c) if self.queue.is_elevator_enabled() => {
+                self.storage.lump_position(c.lump_id())
+            }
+            _ => None,
+        };
+        self.queue.push(command, position);
         Ok(true)
     }
 
+    /// キューから取り出されたコマンドのメトリクスを更新する.
+    ///
+    /// キューでの待機時間を記録し、応答時に発行からの経過時間が記録されるようにする.
+    fn on_dequeued(&self, mut command: Command) -> Command {
+        self.metrics.dequeued_commands.increment(&command);
+        if let Some(issued_at) = command.issued_at() {
+            self.metrics
+                .queue_wait_seconds
+                .observe(&command, issued_at.elapsed());
+        }
+        let latency = self.metrics.latency_histograms(&command);
+        command.observe_latency_with(latency);
+        command
+    }
+
     fn handle_command(&mut self, command: Command) -> Result<bool> {
         match command {
             Command::Get(c) => {
@@ -226,7 +346,11 @@ where
             }
             Command::Put(c) => {
                 debug!(self.logger, "Put LumpId=(\"{}\")", c.lump_id());
-                let result = track!(self.storage.put(c.lump_id(), c.lump_data()));
+                let result = track!(self.storage.put_and_sync_data(
+                    c.lump_id(),
+                    c.lump_data(),
+                    c.durability()
+                ));
                 if result.is_err() {
                     self.metrics.failed_commands.put.increment();
                 }
@@ -234,13 +358,15 @@ where
                     c.reply(result);
                     Err(e)
                 } else {
-                    let do_sync = c.do_sync_journal();
-                    c.reply(result);
-                    if do_sync {
-                        let sync_result = track!(self.storage.journal_sync());
-                        sync_result.map(|_| true)
-                    } else {
-                        Ok(true)
+                    match result {
+                        Ok(value) if c.durability() != Durability::Buffered => {
+                            let durability = c.durability();
+                            track!(self.defer_reply(PendingReply::Put(c, value), durability))
+                        }
+                        result => {
+                            c.reply(result);
+                            Ok(true)
+                        }
                     }
                 }
             }
@@ -253,13 +379,15 @@ where
                     c.reply(result);
                     Err(e)
                 } else {
-                    let do_sync = c.do_sync_journal();
-                    c.reply(result);
-                    if do_sync {
-                        let sync_result = track!(self.storage.journal_sync());
-                        sync_result.map(|_| true)
-                    } else {
-                        Ok(true)
+                    match result {
+                        Ok(value) if c.durability() != Durability::Buffered => {
+                            let durability = c.durability();
+                            track!(self.defer_reply(PendingReply::Delete(c, value), durability))
+                        }
+                        result => {
+                            c.reply(result);
+                            Ok(true)
+                        }
                     }
                 }
             }
@@ -272,13 +400,15 @@ where
                     c.reply(result);
                     Err(e)
                 } else {
-                    let do_sync = c.do_sync_journal();
-                    c.reply(result);
-                    if do_sync {
-                        let sync_result = track!(self.storage.journal_sync());
-                        sync_result.map(|_| true)
-                    } else {
-                        Ok(true)
+                    match result {
+                        Ok(value) if c.durability() != Durability::Buffered => {
+                            let durability = c.durability();
+                            track!(self.defer_reply(PendingReply::DeleteRange(c, value), durability))
+                        }
+                        result => {
+                            c.reply(result);
+                            Ok(true)
+                        }
                     }
                 }
             }
@@ -312,6 +442,44 @@ where
         true
     }
 
+    /// ジャーナルの同期完了後に返すべき応答を保留する.
+    ///
+    /// 保留中の応答の数が`max_group_commit_size`に達した場合には、その場でコミットを行う.
+    fn defer_reply(&mut self, reply: PendingReply, durability: Durability) -> Result<bool> {
+        if self.pending_replies.push(reply, durability, Instant::now()) {
+            track!(self.flush_pending_replies())?;
+        }
+        Ok(true)
+    }
+
+    /// 保留中の応答群に対して一度だけ同期を行い(グループコミット)、その完了後に応答を返す.
+    ///
+    /// 同期は、保留中のコマンドが要求している永続化の度合いの内で、最も強いものに従って行われる.
+    ///
+    /// 同期に失敗した場合には、保留中の全てのコマンドにそのエラーが返される.
+    fn flush_pending_replies(&mut self) -> Result<()> {
+        let (durability, replies) = match self.pending_replies.take() {
+            None => return Ok(()),
+            Some(pending) => pending,
+        };
+        let result = track!(self.storage.sync_with(durability));
+        self.metrics.group_commits.increment();
+        self.metrics
+            .group_committed_commands
+            .add_u64(replies.len() as u64);
+        for reply in replies {
+            reply.reply(&result);
+        }
+        result
+    }
+
+    /// 保留中の応答群に、指定されたエラーを返す.
+    fn abort_pending_replies(&mut self, error: &Error) {
+        for reply in self.pending_replies.take().into_iter().flat_map(|(_, r)| r) {
+            reply.reply(&Err(error.clone()));
+        }
+    }
+
     fn check_overload(&mut self) -> Result<()> {
         if self.queue.len() < self.busy_threshold {
             if self.start_busy_time.is_some() {
@@ -333,6 +501,70 @@ where
     }
 }
 
+/// ジャーナルの同期完了を待っているコマンドの応答.
+#[derive(Debug)]
+enum PendingReply {
+    Put(PutLump, bool),
+    Delete(DeleteLump, bool),
+    DeleteRange(DeleteLumpRange, Vec<LumpId>),
+}
+impl PendingReply {
+    fn reply(self, sync_result: &Result<()>) {
+        match self {
+            PendingReply::Put(c, v) => c.reply(sync_result.clone().map(|()| v)),
+            PendingReply::Delete(c, v) => c.reply(sync_result.clone().map(|()| v)),
+            PendingReply::DeleteRange(c, v) => c.reply(sync_result.clone().map(|()| v)),
+        }
+    }
+}
+
+/// グループコミット待ちの応答群.
+#[derive(Debug)]
+struct PendingReplies {
+    replies: Vec<PendingReply>,
+    durability: Durability,
+    deferred_since: Option<Instant>, // 最初の応答が保留された時刻
+    max_size: usize,
+    max_delay: Duration,
+}
+impl PendingReplies {
+    fn new(max_size: usize, max_delay: Duration) -> Self {
+        PendingReplies {
+            replies: Vec::new(),
+            durability: Durability::Buffered,
+            deferred_since: None,
+            max_size,
+            max_delay,
+        }
+    }
+
+    /// 応答を保留する.
+    ///
+    /// 保留中の応答の数が上限に達した場合には`true`が返される.
+    fn push(&mut self, reply: PendingReply, durability: Durability, now: Instant) -> bool {
+        self.replies.push(reply);
+        self.durability = cmp::max(self.durability, durability);
+        self.deferred_since.get_or_insert(now);
+        self.replies.len() >= self.max_size
+    }
+
+    /// 最初の応答が保留されてから、`max_delay`以上が経過しているかどうか.
+    fn is_overdue(&self, now: Instant) -> bool {
+        self.deferred_since
+            .is_some_and(|t| now.saturating_duration_since(t) >= self.max_delay)
+    }
+
+    /// 保留中の応答群と、それらが要求している最も強い永続化の度合いを取り出す.
+    fn take(&mut self) -> Option<(Durability, Vec<PendingReply>)> {
+        if self.replies.is_empty() {
+            return None;
+        }
+        self.deferred_since = None;
+        let durability = mem::replace(&mut self.durability, Durability::Buffered);
+        Some((durability, mem::take(&mut self.replies)))
+    }
+}
+
 /// ストレージのデータが壊れている可能性があるエラーかどうかを判定.
 fn maybe_critical_error<T>(result: &Result<T>) -> Option<Error> {
     result.as_ref().err().and_then(|e| match *e.kind() {
@@ -359,15 +591,32 @@ impl Future for DeviceThreadMonitor {
     }
 }
 
+/// デバイスが管理しているストレージの設定の内で、ハンドル側から参照されるもの.
+#[derive(Debug, Clone, Copy)]
+pub struct StorageConfig {
+    pub block_size: BlockSize,
+    pub embed_threshold: usize,
+    pub max_lump_size: usize,
+}
+
 /// デバイススレッドを操作するためのハンドル.
 #[derive(Debug, Clone)]
 pub struct DeviceThreadHandle {
     command_tx: CommandSender,
+    readers: Arc<RwLock<Option<SyncSender<Command>>>>, // 読み込み用のスレッド群が有効な場合にのみ`Some`となる.
+    storage_config: Arc<RwLock<Option<StorageConfig>>>, // デバイスの稼働中にのみ`Some`となる.
     metrics: Arc<DeviceMetrics>, // 必須では無いが`Clone`時の効率を上げるために`Arc`で囲む.
 }
 impl DeviceThreadHandle {
     pub fn send_command(&self, command: Command) {
         self.metrics.enqueued_commands.increment(&command);
+        let command = match command {
+            Command::Get(_) => match self.send_to_readers(command) {
+                None => return,
+                Some(command) => command,
+            },
+            command => command,
+        };
         if let Err(SendError(command)) = self.command_tx.send(command) {
             self.metrics.dequeued_commands.increment(&command);
             self.metrics.failed_commands.increment(&command);
@@ -376,4 +625,148 @@ impl DeviceThreadHandle {
     pub fn metrics(&self) -> &Arc<DeviceMetrics> {
         &self.metrics
     }
+    pub fn storage_config(&self) -> Option<StorageConfig> {
+        *self
+            .storage_config
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 読み込み用のスレッド群にコマンドを送る.
+    ///
+    /// スレッド群が有効ではない場合には、コマンドがそのまま返される.
+    ///
+    /// スレッド群のキューが一杯(i.e., `DeviceBuilder::max_queue_len`個のコマンドが処理待ち)の場合には、
+    /// コマンドには`ErrorKind::DeviceBusy`エラーが返される.
+    fn send_to_readers(&self, command: Command) -> Option<Command> {
+        let readers = self.readers.read().unwrap_or_else(|e| e.into_inner());
+        match *readers {
+            None => Some(command),
+            Some(ref tx) => match tx.try_send(command) {
+                Ok(()) => None,
+                Err(TrySendError::Full(command)) => {
+                    self.metrics.dequeued_commands.increment(&command);
+                    self.metrics.busy_commands.increment(&command);
+                    let e = track!(ErrorKind::DeviceBusy.cause("The queue of the readers is full"));
+                    command.failed(e.into());
+                    None
+                }
+                Err(TrySendError::Disconnected(command)) => Some(command),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use futures::Future;
+    use prometrics::metrics::MetricBuilder;
+
+    use super::*;
+    use crate::deadline::Deadline;
+    use crate::device::command::GetLump;
+    use crate::lump::LumpData;
+
+    #[test]
+    fn pending_replies_are_flushed_by_size() {
+        let mut pending = PendingReplies::new(3, Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(!pending.push(
+            put(0, Durability::JournalFlushed),
+            Durability::JournalFlushed,
+            now
+        ));
+        assert!(!pending.push(
+            put(1, Durability::JournalSynced),
+            Durability::JournalSynced,
+            now
+        ));
+        assert!(pending.push(
+            put(2, Durability::JournalFlushed),
+            Durability::JournalFlushed,
+            now
+        ));
+
+        // 最も強い度合いで同期される
+        let (durability, replies) = pending.take().expect("Some(_)");
+        assert_eq!(durability, Durability::JournalSynced);
+        assert_eq!(replies.len(), 3);
+        assert!(pending.take().is_none());
+    }
+
+    #[test]
+    fn pending_replies_are_flushed_by_delay() {
+        let mut pending = PendingReplies::new(1024, Duration::from_millis(10));
+        let start = Instant::now();
+        assert!(!pending.is_overdue(start + Duration::from_secs(60))); // 空の間は期限切れにならない
+
+        // 後続の保留によって、期限が延長されることはない
+        pending.push(
+            put(0, Durability::JournalSynced),
+            Durability::JournalSynced,
+            start,
+        );
+        for i in 1..10 {
+            let now = start + Duration::from_millis(i);
+            assert!(!pending.is_overdue(now));
+            pending.push(
+                put(i as u128, Durability::JournalSynced),
+                Durability::JournalSynced,
+                now,
+            );
+        }
+        assert!(pending.is_overdue(start + Duration::from_millis(10)));
+
+        // 取り出した後は、次の保留から計測し直される
+        assert_eq!(pending.take().map(|(_, r)| r.len()), Some(10));
+        assert!(!pending.is_overdue(start + Duration::from_millis(20)));
+        let later = start + Duration::from_millis(30);
+        pending.push(
+            put(10, Durability::JournalSynced),
+            Durability::JournalSynced,
+            later,
+        );
+        assert!(!pending.is_overdue(later + Duration::from_millis(9)));
+        assert!(pending.is_overdue(later + Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn full_reader_queue_refuses_commands() {
+        let (command_tx, _command_rx) = std_mpsc::channel();
+        let (readers_tx, _readers_rx) = std_mpsc::sync_channel(1);
+        let handle = DeviceThreadHandle {
+            command_tx,
+            readers: Arc::new(RwLock::new(Some(readers_tx))),
+            storage_config: Arc::new(RwLock::new(None)),
+            metrics: Arc::new(DeviceMetrics::new(&MetricBuilder::new(), &[])),
+        };
+
+        let (first, _first_result) = GetLump::new(LumpId::new(0), Deadline::Infinity, false);
+        handle.send_command(Command::Get(first));
+        let (second, second_result) = GetLump::new(LumpId::new(1), Deadline::Infinity, false);
+        handle.send_command(Command::Get(second));
+
+        // 読み込み用スレッド群のキューが一杯なので、二つ目は即座に拒否される
+        assert_eq!(
+            second_result.wait().err().map(|e| *e.kind()),
+            Some(ErrorKind::DeviceBusy)
+        );
+        let metrics = handle.metrics();
+        assert_eq!(metrics.busy_commands().get(), 1);
+        assert_eq!(metrics.queue_len(), 1);
+    }
+
+    fn put(lump_id: u128, durability: Durability) -> PendingReply {
+        let data = LumpData::new_embedded(Vec::from("foo")).unwrap();
+        let (command, _) = PutLump::new(
+            LumpId::new(lump_id),
+            data,
+            Deadline::Infinity,
+            false,
+            durability,
+        );
+        PendingReply::Put(command, true)
+    }
 }
@@ -79,6 +79,14 @@ pub enum ErrorKind {
     /// - 利用者側のプログラムを修正して入力を正しくする
     InvalidInput,
 
+    /// 読み込み専用で開かれたストレージ(またはNVM)に対して、書き込み系の操作が発行された.
+    ///
+    /// # 典型的な対応策
+    ///
+    /// - 利用者側のプログラムを修正して、読み込み専用のストレージには書き込みを行わないようにする
+    /// - 書き込みが必要な場合には、読み書き可能なモードでストレージを開き直す
+    ReadOnly,
+
     /// 内部状態が不整合に陥っている.
     ///
     /// プログラムにバグがあることを示している.
@@ -102,6 +110,36 @@ pub enum ErrorKind {
     /// - 負荷の高い時間を避けてもう一度試す
     RequestRefused,
 
+    /// デッドラインを過ぎていたため、リクエストは実行されずに破棄された.
+    ///
+    /// `DeviceBuilder::expiry_grace_period`が指定されている場合にのみ発生する.
+    ///
+    /// # 典型的な対応策
+    ///
+    /// - 必要であれば、デッドラインを延ばしてもう一度試す
+    RequestExpired,
+
+    /// リクエストは、発行元によってキャンセルされた.
+    ///
+    /// 結果の受信側が破棄された場合、あるいは`CancelToken`によってキャンセルされた場合に、
+    /// 実行前のリクエストは、このエラーで終了する.
+    ///
+    /// # 典型的な対応策
+    ///
+    /// - 特になし(発行元がキャンセルしたリクエストなので、結果は不要なはず)
+    RequestCancelled,
+
+    /// 指定された時間内に、リクエストの結果が得られなかった.
+    ///
+    /// 呼び出し元が待機を打ち切ったことを示すもので、デバイス側の拒否や過負荷を意味するものではない
+    /// (それらは`DeviceBusy`等で表される).
+    /// リクエスト自体は、デバイス側で実行される可能性がある.
+    ///
+    /// # 典型的な対応策
+    ///
+    /// - タイムアウトを延ばしてもう一度試す
+    Timeout,
+
     /// その他エラー.
     ///
     /// E.g., I/Oエラー
@@ -122,9 +160,13 @@ impl std::fmt::Display for ErrorKind {
             ErrorKind::DeviceBusy => write!(f, "DeviceBusy"),
             ErrorKind::DeviceTerminated => write!(f, "DeviceTerminated"),
             ErrorKind::InvalidInput => write!(f, "InvalidInput"),
+            ErrorKind::ReadOnly => write!(f, "ReadOnly"),
             ErrorKind::InconsistentState => write!(f, "InconsistentState"),
             ErrorKind::RequestDropped => write!(f, "RequestDropped"),
             ErrorKind::RequestRefused => write!(f, "RequestRefused"),
+            ErrorKind::RequestExpired => write!(f, "RequestExpired"),
+            ErrorKind::RequestCancelled => write!(f, "RequestCancelled"),
+            ErrorKind::Timeout => write!(f, "Timeout"),
             ErrorKind::Other => write!(f, "Other"),
         }
     }
@@ -139,8 +181,12 @@ impl FromStr for ErrorKind {
             "DeviceBusy" => ErrorKind::DeviceBusy,
             "DeviceTerminated" => ErrorKind::DeviceTerminated,
             "InvalidInput" => ErrorKind::InvalidInput,
+            "ReadOnly" => ErrorKind::ReadOnly,
             "RequestDropped" => ErrorKind::RequestDropped,
             "RequestRefused" => ErrorKind::RequestRefused,
+            "RequestExpired" => ErrorKind::RequestExpired,
+            "RequestCancelled" => ErrorKind::RequestCancelled,
+            "Timeout" => ErrorKind::Timeout,
             "InconsistentState" => ErrorKind::InconsistentState,
             "Other" => ErrorKind::Other,
             _ => return Err(()),
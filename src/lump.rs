@@ -119,6 +119,12 @@ impl LumpData {
     /// ということは記しておく.
     pub const MAX_SIZE: usize = 0xFFFF * (BlockSize::MIN as usize) - 2;
 
+    /// ワイドフォーマット(v2)のストレージで保存可能なデータの最大長（バイト単位）.
+    ///
+    /// この長さのデータは`Storage::allocate_lump_data`経由でのみ生成可能.
+    /// 最後の`-4`は、ブロックサイズが64KiBを超える場合に内部的に付与されるメタ情報のサイズ分.
+    pub const MAX_WIDE_SIZE: usize = 0x3_FFFF * (BlockSize::MIN as usize) - 4;
+
     /// ジャーナル領域に埋め込み可能なデータの最大長（バイト単位）.
     pub const MAX_EMBEDDED_SIZE: usize = 0xFFFF;
 
@@ -167,6 +173,31 @@ impl LumpData {
         Ok(LumpData(LumpDataInner::JournalRegion(data)))
     }
 
+    /// 保存先(ジャーナル領域ないしデータ領域)の選択をストレージに委ねる`LumpData`インスタンスを生成する.
+    ///
+    /// PUTによる保存時に、データのサイズが`StorageBuilder::embed_threshold`で指定された閾値以下であれば
+    /// ジャーナル領域に埋め込まれ、そうでなければデータ領域に保存される.
+    ///
+    /// データ領域に保存される場合には、`LumpData::new`と同様に、アライメント用のメモリコピーが発生する.
+    /// それを避けたい場合には[`DeviceHandle`]の`allocate_lump_data_auto`メソッドを使用すると良い.
+    ///
+    /// [`DeviceHandle`]: ../device/struct.DeviceHandle.html
+    ///
+    /// # Errors
+    ///
+    /// データのサイズが`MAX_WIDE_SIZE`を超えている場合は、`ErrorKind::InvalidInput`エラーが返される.
+    ///
+    /// 保存先のストレージの上限(`Storage::max_lump_size`)との比較は、PUT時に行われる.
+    pub fn new_auto(data: Vec<u8>) -> Result<Self> {
+        track_assert!(
+            data.len() <= LumpData::MAX_WIDE_SIZE,
+            ErrorKind::InvalidInput,
+            "Too large lump data: {} bytes",
+            data.len()
+        );
+        Ok(LumpData(LumpDataInner::AutoPlacement(data)))
+    }
+
     /// データを表すバイト列への参照を返す.
     pub fn as_bytes(&self) -> &[u8] {
         self.as_ref()
@@ -186,6 +217,8 @@ impl LumpData {
             LumpDataInner::JournalRegion(d) => d,
             LumpDataInner::DataRegion(d) => Vec::from(d.as_bytes()),
             LumpDataInner::DataRegionUnaligned(d) => d,
+            LumpDataInner::AutoPlacement(d) => d,
+            LumpDataInner::AutoPlacementAligned(d) => Vec::from(d.as_bytes()),
         }
     }
 
@@ -196,10 +229,14 @@ impl LumpData {
     ///
     /// # Errors
     ///
-    /// 指定されたサイズが`MAX_SIZE`を超えている場合は、`ErrorKind::InvalidInput`エラーが返される.
-    pub(crate) fn aligned_allocate(data_len: usize, block_size: BlockSize) -> Result<Self> {
+    /// 指定されたサイズが`max_size`を超えている場合は、`ErrorKind::InvalidInput`エラーが返される.
+    pub(crate) fn aligned_allocate(
+        data_len: usize,
+        block_size: BlockSize,
+        max_size: usize,
+    ) -> Result<Self> {
         track_assert!(
-            data_len <= LumpData::MAX_SIZE,
+            data_len <= max_size,
             ErrorKind::InvalidInput,
             "Too large lump data: {} bytes",
             data_len
@@ -209,6 +246,26 @@ impl LumpData {
         )))
     }
 
+    /// 保存先の選択をストレージに委ねる、アライメント済みの`LumpData`インスタンスを生成する.
+    ///
+    /// # Errors
+    ///
+    /// 指定されたサイズが`max_size`を超えている場合は、`ErrorKind::InvalidInput`エラーが返される.
+    pub(crate) fn aligned_allocate_auto(
+        data_len: usize,
+        block_size: BlockSize,
+        max_size: usize,
+    ) -> Result<Self> {
+        track_assert!(
+            data_len <= max_size,
+            ErrorKind::InvalidInput,
+            "Too large lump data: {} bytes",
+            data_len
+        );
+        let data = DataRegionLumpData::new(data_len, block_size);
+        Ok(LumpData(LumpDataInner::AutoPlacementAligned(data)))
+    }
+
     pub(crate) fn as_inner(&self) -> &LumpDataInner {
         &self.0
     }
@@ -219,6 +276,8 @@ impl AsRef<[u8]> for LumpData {
             LumpDataInner::JournalRegion(ref d) => d,
             LumpDataInner::DataRegion(ref d) => d.as_bytes(),
             LumpDataInner::DataRegionUnaligned(ref d) => d,
+            LumpDataInner::AutoPlacement(ref d) => d,
+            LumpDataInner::AutoPlacementAligned(ref d) => d.as_bytes(),
         }
     }
 }
@@ -228,15 +287,18 @@ impl AsMut<[u8]> for LumpData {
             LumpDataInner::JournalRegion(ref mut d) => d,
             LumpDataInner::DataRegion(ref mut d) => d.as_bytes_mut(),
             LumpDataInner::DataRegionUnaligned(ref mut d) => d,
+            LumpDataInner::AutoPlacement(ref mut d) => d,
+            LumpDataInner::AutoPlacementAligned(ref mut d) => d.as_bytes_mut(),
         }
     }
 }
 impl fmt::Debug for LumpData {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let block_size = if let LumpDataInner::DataRegion(ref x) = self.0 {
-            Some(x.block_size())
-        } else {
-            None
+        let block_size = match self.0 {
+            LumpDataInner::DataRegion(ref x) | LumpDataInner::AutoPlacementAligned(ref x) => {
+                Some(x.block_size())
+            }
+            _ => None,
         };
 
         let len = cmp::min(128, self.as_bytes().len());
@@ -270,6 +332,8 @@ pub(crate) enum LumpDataInner {
     JournalRegion(Vec<u8>),
     DataRegion(DataRegionLumpData),
     DataRegionUnaligned(Vec<u8>),
+    AutoPlacement(Vec<u8>),
+    AutoPlacementAligned(DataRegionLumpData),
 }
 
 /// Lumpの概要情報.
@@ -1,11 +1,12 @@
 //! [Prometheus][prometheus]用のメトリクス.
 //!
 //! [prometheus]: https://prometheus.io/
-use prometrics::metrics::{Counter, Gauge, MetricBuilder};
+use prometrics::metrics::{Counter, Gauge, Histogram, MetricBuilder};
+use std::time::{Duration, UNIX_EPOCH};
 
 use crate::block::BlockSize;
-use crate::device::{Command, DeviceStatus};
-use crate::storage::{JournalRecord, StorageHeader};
+use crate::device::{Command, DeviceStatus, QosClass};
+use crate::storage::{Durability, JournalRecord, StorageHeader, StorageMetadata};
 
 /// ジャーナル領域のキュー（リングバッファ）のメトリクス.
 #[derive(Debug, Clone)]
@@ -171,6 +172,8 @@ pub struct JournalRegionMetrics {
     pub(crate) gc_enqueued_records: Counter,
     pub(crate) gc_dequeued_records: Counter,
     pub(crate) syncs: Counter,
+    pub(crate) recovery_truncations: Counter,
+    pub(crate) recovery_discarded_bytes: Counter,
     queue: JournalQueueMetrics,
 }
 impl JournalRegionMetrics {
@@ -207,6 +210,32 @@ impl JournalRegionMetrics {
         self.syncs.value() as u64
     }
 
+    /// ジャーナルの復元時に、不正なレコードを検出してジャーナルを切り詰めた回数.
+    ///
+    /// `JournalRecoveryMode::TruncateTornTail`が指定されている場合にのみ増加し得る.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_journal_region_recovery_truncations_total <COUNTER>
+    /// ```
+    pub fn recovery_truncations(&self) -> u64 {
+        self.recovery_truncations.value() as u64
+    }
+
+    /// ジャーナルの復元時の切り詰めによって、破棄された領域の合計バイト数.
+    ///
+    /// 不正なレコードの先頭から、そのレコードの読み込みを試みた範囲の終端までが計上される.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_journal_region_recovery_discarded_bytes_total <COUNTER>
+    /// ```
+    pub fn recovery_discarded_bytes(&self) -> u64 {
+        self.recovery_discarded_bytes.value() as u64
+    }
+
     /// リングバッファのメトリクスを返す.
     pub fn queue(&self) -> &JournalQueueMetrics {
         &self.queue
@@ -231,6 +260,16 @@ impl JournalRegionMetrics {
                 .help("Number of synchronization instructions issued to the physical device")
                 .finish()
                 .expect("Never fails"),
+            recovery_truncations: builder
+                .counter("recovery_truncations_total")
+                .help("Number of times the journal was truncated at a torn record during recovery")
+                .finish()
+                .expect("Never fails"),
+            recovery_discarded_bytes: builder
+                .counter("recovery_discarded_bytes_total")
+                .help("Number of bytes discarded by journal truncation during recovery")
+                .finish()
+                .expect("Never fails"),
             queue,
         }
     }
@@ -271,7 +310,7 @@ impl JournalRecordCounter {
         match *record {
             JournalRecord::Delete { .. } => self.delete.increment(),
             JournalRecord::EndOfRecords | JournalRecord::GoToFront => {}
-            JournalRecord::Put { .. } => self.put.increment(),
+            JournalRecord::Put { .. } | JournalRecord::PutPacked { .. } => self.put.increment(),
             JournalRecord::Embed { .. } => self.embed.increment(),
             JournalRecord::DeleteRange { .. } => self.delete_range.increment(),
         }
@@ -476,16 +515,16 @@ impl DataAllocatorMetrics {
         inc - dec
     }
 
-    pub(crate) fn count_allocation(&self, size: u16) {
+    pub(crate) fn count_allocation(&self, size: u32) {
         self.allocated_portions_at_running.increment();
         self.allocated_bytes_at_running
-            .add_u64(u64::from(self.block_size.as_u16()) * u64::from(size));
+            .add_u64(u64::from(self.block_size.as_u32()) * u64::from(size));
     }
 
-    pub(crate) fn count_releasion(&self, size: u16) {
+    pub(crate) fn count_releasion(&self, size: u32) {
         self.released_portions.increment();
         self.released_bytes
-            .add_u64(u64::from(self.block_size.as_u16()) * u64::from(size));
+            .add_u64(u64::from(self.block_size.as_u32()) * u64::from(size));
     }
 }
 
@@ -499,7 +538,15 @@ pub struct DeviceMetrics {
     pub(crate) dequeued_commands: DeviceCommandCounter,
     pub(crate) failed_commands: DeviceCommandCounter,
     pub(crate) busy_commands: DeviceCommandCounter,
+    pub(crate) cancelled_commands: DeviceCommandCounter,
+    pub(crate) expired_commands: DeviceCommandCounter,
+    pub(crate) queue_wait_seconds: DeviceCommandHistogram,
+    pub(crate) execution_seconds: DeviceCommandHistogram,
+    pub(crate) latency_seconds: DeviceCommandHistogram,
+    pub(crate) qos_classes: Vec<QosClassMetrics>,
     pub(crate) side_jobs: Counter,
+    pub(crate) group_commits: Counter,
+    pub(crate) group_committed_commands: Counter,
     pub(crate) storage: Option<StorageMetrics>,
 }
 impl DeviceMetrics {
@@ -586,6 +633,87 @@ impl DeviceMetrics {
         &self.busy_commands
     }
 
+    /// 発行元によってキャンセルされたために、実行がスキップされたコマンドの数.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_device_cancelled_commands_total { command="put" } = <COUNTER>
+    /// cannyls_device_cancelled_commands_total { command="get" } = <COUNTER>
+    /// cannyls_device_cancelled_commands_total { command="head" } = <COUNTER>
+    /// cannyls_device_cancelled_commands_total { command="delete" } = <COUNTER>
+    /// cannyls_device_cancelled_commands_total { command="list" } = <COUNTER>
+    /// ```
+    pub fn cancelled_commands(&self) -> &DeviceCommandCounter {
+        &self.cancelled_commands
+    }
+
+    /// デッドラインを過ぎていたために、実行されずに破棄されたコマンドの数.
+    ///
+    /// `DeviceBuilder::expiry_grace_period`が指定されている場合にのみカウントされる.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_device_expired_commands_total { command="put" } = <COUNTER>
+    /// cannyls_device_expired_commands_total { command="get" } = <COUNTER>
+    /// cannyls_device_expired_commands_total { command="head" } = <COUNTER>
+    /// cannyls_device_expired_commands_total { command="delete" } = <COUNTER>
+    /// cannyls_device_expired_commands_total { command="list" } = <COUNTER>
+    /// ```
+    pub fn expired_commands(&self) -> &DeviceCommandCounter {
+        &self.expired_commands
+    }
+
+    /// コマンドが発行されてから、デバイスのキューから取り出されるまでの時間(秒).
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_device_command_queue_wait_seconds_bucket { command="put", le="..." } = <HISTOGRAM>
+    /// cannyls_device_command_queue_wait_seconds_bucket { command="get", le="..." } = <HISTOGRAM>
+    /// cannyls_device_command_queue_wait_seconds_bucket { command="head", le="..." } = <HISTOGRAM>
+    /// cannyls_device_command_queue_wait_seconds_bucket { command="delete", le="..." } = <HISTOGRAM>
+    /// cannyls_device_command_queue_wait_seconds_bucket { command="list", le="..." } = <HISTOGRAM>
+    /// ```
+    pub fn queue_wait_seconds(&self) -> &DeviceCommandHistogram {
+        &self.queue_wait_seconds
+    }
+
+    /// デバイスがコマンドの実行に要した時間(秒).
+    ///
+    /// グループコミットによる同期待ちの時間は含まれない.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_device_command_execution_seconds_bucket { command="put", le="..." } = <HISTOGRAM>
+    /// cannyls_device_command_execution_seconds_bucket { command="get", le="..." } = <HISTOGRAM>
+    /// cannyls_device_command_execution_seconds_bucket { command="head", le="..." } = <HISTOGRAM>
+    /// cannyls_device_command_execution_seconds_bucket { command="delete", le="..." } = <HISTOGRAM>
+    /// cannyls_device_command_execution_seconds_bucket { command="list", le="..." } = <HISTOGRAM>
+    /// ```
+    pub fn execution_seconds(&self) -> &DeviceCommandHistogram {
+        &self.execution_seconds
+    }
+
+    /// コマンドが発行されてから、その応答が返されるまでの時間(秒).
+    ///
+    /// キューでの待機時間や、グループコミットによる同期待ちの時間も含まれる.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_device_command_latency_seconds_bucket { command="put", le="..." } = <HISTOGRAM>
+    /// cannyls_device_command_latency_seconds_bucket { command="get", le="..." } = <HISTOGRAM>
+    /// cannyls_device_command_latency_seconds_bucket { command="head", le="..." } = <HISTOGRAM>
+    /// cannyls_device_command_latency_seconds_bucket { command="delete", le="..." } = <HISTOGRAM>
+    /// cannyls_device_command_latency_seconds_bucket { command="list", le="..." } = <HISTOGRAM>
+    /// ```
+    pub fn latency_seconds(&self) -> &DeviceCommandHistogram {
+        &self.latency_seconds
+    }
+
     /// 補助タスクの実行回数.
     ///
     /// # Prometheus
@@ -597,6 +725,28 @@ impl DeviceMetrics {
         self.side_jobs.value() as u64
     }
 
+    /// グループコミット(i.e., 複数コマンドで共有されたジャーナルの同期)の実行回数.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_device_group_commits_total <COUNTER>
+    /// ```
+    pub fn group_commits(&self) -> u64 {
+        self.group_commits.value() as u64
+    }
+
+    /// グループコミットによって永続化が完了したコマンドの数.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_device_group_committed_commands_total <COUNTER>
+    /// ```
+    pub fn group_committed_commands(&self) -> u64 {
+        self.group_committed_commands.value() as u64
+    }
+
     /// デバイスキューの長さ(i.e., 実行待ちのコマンド数).
     ///
     /// # Prometheus
@@ -611,6 +761,13 @@ impl DeviceMetrics {
         (inc - dec) as usize
     }
 
+    /// 指定されたQoSクラスのメトリクスを返す.
+    ///
+    /// `DeviceBuilder::qos_class`で登録されていないクラスの場合には`None`が返る.
+    pub fn qos_class(&self, class: &QosClass) -> Option<&QosClassMetrics> {
+        self.qos_classes.iter().find(|m| m.class == *class)
+    }
+
     /// ストレージのメトリクスを返す.
     ///
     /// デバイスの状態が`Running`以外の場合には`None`が返る.
@@ -618,7 +775,19 @@ impl DeviceMetrics {
         self.storage.as_ref()
     }
 
-    pub(crate) fn new(builder: &MetricBuilder) -> Self {
+    /// 応答時に、発行からの経過時間を記録するヒストグラム群を返す.
+    pub(crate) fn latency_histograms(&self, command: &Command) -> Vec<Histogram> {
+        let class = command.qos_class().cloned().unwrap_or_default();
+        let class = self
+            .qos_class(&class)
+            .or_else(|| self.qos_class(&QosClass::default()));
+        Some(self.latency_seconds.select(command).clone())
+            .into_iter()
+            .chain(class.map(|m| m.latency_seconds.clone()))
+            .collect()
+    }
+
+    pub(crate) fn new(builder: &MetricBuilder, qos_classes: &[(QosClass, u32)]) -> Self {
         let mut builder = builder.clone();
         builder.namespace("cannyls").subsystem("device");
         DeviceMetrics {
@@ -647,14 +816,103 @@ impl DeviceMetrics {
                 "busy_commands_total",
                 "Number of commands gave up to execute due to the device is busy",
             ),
+            cancelled_commands: DeviceCommandCounter::new(
+                &builder,
+                "cancelled_commands_total",
+                "Number of commands skipped because they were cancelled by the requester",
+            ),
+            expired_commands: DeviceCommandCounter::new(
+                &builder,
+                "expired_commands_total",
+                "Number of commands discarded because their deadlines had expired",
+            ),
+            queue_wait_seconds: DeviceCommandHistogram::new(
+                &builder,
+                "command_queue_wait_seconds",
+                "Time from issuing a command to dequeuing it from the device queue",
+            ),
+            execution_seconds: DeviceCommandHistogram::new(
+                &builder,
+                "command_execution_seconds",
+                "Time spent executing a command",
+            ),
+            latency_seconds: DeviceCommandHistogram::new(
+                &builder,
+                "command_latency_seconds",
+                "Time from issuing a command to replying to it",
+            ),
             side_jobs: builder
                 .counter("side_jobs_total")
                 .help("Number of exeuction of side jobs")
                 .finish()
                 .expect("Never fails"),
+            group_commits: builder
+                .counter("group_commits_total")
+                .help("Number of journal syncs shared by group commits")
+                .finish()
+                .expect("Never fails"),
+            group_committed_commands: builder
+                .counter("group_committed_commands_total")
+                .help("Number of commands made durable by group commits")
+                .finish()
+                .expect("Never fails"),
+            qos_classes: crate::device::qos::with_default_class(qos_classes)
+                .into_iter()
+                .map(|(class, _)| QosClassMetrics::new(&builder, class))
+                .collect(),
             storage: None,
         }
     }
+
+    /// 複数のデバイスのメトリクスを集約したものを生成する.
+    ///
+    /// カウンタおよびキューの長さは合算され、稼働状態は最も起動が進んでいないメンバーのものとなる
+    /// (メンバーが存在しない場合には`DeviceStatus::Stopped`).
+    ///
+    /// ヒストグラムは合算することができないので、集約結果のものは常に空となる.
+    /// また、ストレージのメトリクスも含まれない.
+    pub(crate) fn aggregate<'a, I>(members: I) -> Self
+    where
+        I: IntoIterator<Item = &'a DeviceMetrics>,
+    {
+        let members = members.into_iter().collect::<Vec<_>>();
+
+        // メンバー毎に登録されているクラスが異なる場合もあるので、全メンバーのクラスの和集合を用いる
+        let mut qos_classes = Vec::new();
+        for c in members.iter().flat_map(|m| &m.qos_classes) {
+            if !qos_classes.iter().any(|(class, _)| *class == c.class) {
+                qos_classes.push((c.class.clone(), 1));
+            }
+        }
+        let aggregated = DeviceMetrics::new(&MetricBuilder::without_registry(), &qos_classes);
+        let status = members
+            .iter()
+            .map(|m| m.status.value())
+            .fold(None, |acc: Option<f64>, v| {
+                Some(acc.map_or(v, |a| a.min(v)))
+            })
+            .unwrap_or(0.0);
+        aggregated.status.set(status);
+        for m in members {
+            aggregated.enqueued_commands.add(&m.enqueued_commands);
+            aggregated.dequeued_commands.add(&m.dequeued_commands);
+            aggregated.failed_commands.add(&m.failed_commands);
+            aggregated.busy_commands.add(&m.busy_commands);
+            aggregated.cancelled_commands.add(&m.cancelled_commands);
+            aggregated.expired_commands.add(&m.expired_commands);
+            aggregated.side_jobs.add_u64(m.side_jobs());
+            aggregated.group_commits.add_u64(m.group_commits());
+            aggregated
+                .group_committed_commands
+                .add_u64(m.group_committed_commands());
+            for c in &m.qos_classes {
+                if let Some(a) = aggregated.qos_classes.iter().find(|a| a.class == c.class) {
+                    a.queue_len.add(c.queue_len.value());
+                }
+            }
+        }
+        aggregated
+    }
 }
 
 /// デバイスのコマンド毎のカウンタ.
@@ -752,6 +1010,18 @@ impl DeviceCommandCounter {
         }
     }
 
+    fn add(&self, other: &Self) {
+        self.put.add_u64(other.put());
+        self.get.add_u64(other.get());
+        self.head.add_u64(other.head());
+        self.delete.add_u64(other.delete());
+        self.delete_range.add_u64(other.delete_range());
+        self.list.add_u64(other.list());
+        self.list_range.add_u64(other.list_range());
+        self.usage_range.add_u64(other.usage_range());
+        self.stop.add_u64(other.stop());
+    }
+
     fn sum(&self) -> u64 {
         // FIXME: list_range() が抜けているのを直す
         self.put()
@@ -764,6 +1034,216 @@ impl DeviceCommandCounter {
     }
 }
 
+/// デバイスのQoSクラス毎のメトリクス.
+#[derive(Debug, Clone)]
+pub struct QosClassMetrics {
+    pub(crate) class: QosClass,
+    pub(crate) queue_len: Gauge,
+    pub(crate) latency_seconds: Histogram,
+}
+impl QosClassMetrics {
+    /// QoSクラスを返す.
+    pub fn class(&self) -> &QosClass {
+        &self.class
+    }
+
+    /// このクラスのコマンドの内、デバイスのキューで実行を待っているものの数.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_device_qos_queue_len { class="default" } <GAUGE>
+    /// ```
+    pub fn queue_len(&self) -> usize {
+        self.queue_len.value() as usize
+    }
+
+    /// このクラスのコマンドが発行されてから、その応答が返されるまでの時間(秒).
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_device_qos_latency_seconds_bucket { class="default", le="..." } = <HISTOGRAM>
+    /// ```
+    pub fn latency_seconds(&self) -> &Histogram {
+        &self.latency_seconds
+    }
+
+    fn new(builder: &MetricBuilder, class: QosClass) -> Self {
+        QosClassMetrics {
+            queue_len: builder
+                .gauge("qos_queue_len")
+                .help("Number of queued commands per QoS class")
+                .label("class", class.as_str())
+                .finish()
+                .expect("Never fails"),
+            latency_seconds: builder
+                .histogram("qos_latency_seconds")
+                .help("Time from issuing a command to replying to it per QoS class")
+                .label("class", class.as_str())
+                .buckets(latency_buckets())
+                .finish()
+                .expect("Never fails"),
+            class,
+        }
+    }
+}
+
+/// レイテンシ用のヒストグラムのバケツ群(0.1ms ~ 52s).
+fn latency_buckets() -> impl Iterator<Item = f64> {
+    (0..20).map(|i| 0.0001 * 2f64.powi(i))
+}
+
+/// デバイスのコマンド毎のヒストグラム.
+///
+/// 値の単位は秒.
+#[derive(Debug, Clone)]
+pub struct DeviceCommandHistogram {
+    pub(crate) put: Histogram,
+    pub(crate) get: Histogram,
+    pub(crate) head: Histogram,
+    pub(crate) delete: Histogram,
+    pub(crate) delete_range: Histogram,
+    pub(crate) list: Histogram,
+    pub(crate) list_range: Histogram,
+    pub(crate) usage_range: Histogram,
+    pub(crate) stop: Histogram,
+}
+impl DeviceCommandHistogram {
+    /// PUTコマンド用のヒストグラムを返す.
+    pub fn put(&self) -> &Histogram {
+        &self.put
+    }
+
+    /// GETコマンド用のヒストグラムを返す.
+    pub fn get(&self) -> &Histogram {
+        &self.get
+    }
+
+    /// HEADコマンド用のヒストグラムを返す.
+    pub fn head(&self) -> &Histogram {
+        &self.head
+    }
+
+    /// DELETEコマンド用のヒストグラムを返す.
+    pub fn delete(&self) -> &Histogram {
+        &self.delete
+    }
+
+    /// DELETE_RANGEコマンド用のヒストグラムを返す.
+    pub fn delete_range(&self) -> &Histogram {
+        &self.delete_range
+    }
+
+    /// LISTコマンド用のヒストグラムを返す.
+    pub fn list(&self) -> &Histogram {
+        &self.list
+    }
+
+    /// LIST_RANGEコマンド用のヒストグラムを返す.
+    pub fn list_range(&self) -> &Histogram {
+        &self.list_range
+    }
+
+    /// USAGE_RANGEコマンド用のヒストグラムを返す.
+    pub fn usage_range(&self) -> &Histogram {
+        &self.usage_range
+    }
+
+    /// STOPコマンド用のヒストグラムを返す.
+    pub fn stop(&self) -> &Histogram {
+        &self.stop
+    }
+
+    pub(crate) fn new(builder: &MetricBuilder, name: &str, help: &str) -> Self {
+        let histogram = |command| {
+            builder
+                .histogram(name)
+                .help(help)
+                .label("command", command)
+                .buckets(latency_buckets())
+                .finish()
+                .expect("Never fails")
+        };
+        DeviceCommandHistogram {
+            put: histogram("put"),
+            get: histogram("get"),
+            head: histogram("head"),
+            delete: histogram("delete"),
+            delete_range: histogram("delete_range"),
+            list: histogram("list"),
+            list_range: histogram("list_range"),
+            usage_range: histogram("usage_range"),
+            stop: histogram("stop"),
+        }
+    }
+
+    pub(crate) fn select(&self, command: &Command) -> &Histogram {
+        match *command {
+            Command::Put { .. } => &self.put,
+            Command::Get { .. } => &self.get,
+            Command::Head { .. } => &self.head,
+            Command::Delete { .. } => &self.delete,
+            Command::DeleteRange { .. } => &self.delete_range,
+            Command::List { .. } => &self.list,
+            Command::ListRange { .. } => &self.list_range,
+            Command::UsageRange { .. } => &self.usage_range,
+            Command::Stop { .. } => &self.stop,
+        }
+    }
+
+    pub(crate) fn observe(&self, command: &Command, elapsed: Duration) {
+        self.select(command).observe(elapsed.as_secs_f64());
+    }
+}
+
+/// 永続化の度合い(`Durability`)毎のカウンタ.
+///
+/// 同期を伴わない`Durability::Buffered`はカウント対象外で、その値は常に`0`となる.
+#[derive(Debug, Clone)]
+pub struct DurabilityCounter {
+    pub(crate) journal_flushed: Counter,
+    pub(crate) journal_synced: Counter,
+    pub(crate) data_and_journal_synced: Counter,
+}
+impl DurabilityCounter {
+    /// 指定された度合い用のカウンタの値を返す.
+    pub fn get(&self, durability: Durability) -> u64 {
+        self.counter(durability).map_or(0, |c| c.value() as u64)
+    }
+
+    pub(crate) fn new(builder: &MetricBuilder, name: &str, help: &str) -> Self {
+        let counter = |durability: Durability| {
+            builder
+                .counter(name)
+                .help(help)
+                .label("level", durability.as_str())
+                .finish()
+                .expect("Never fails")
+        };
+        DurabilityCounter {
+            journal_flushed: counter(Durability::JournalFlushed),
+            journal_synced: counter(Durability::JournalSynced),
+            data_and_journal_synced: counter(Durability::DataAndJournalSynced),
+        }
+    }
+
+    pub(crate) fn increment(&self, durability: Durability) {
+        if let Some(c) = self.counter(durability) {
+            c.increment();
+        }
+    }
+
+    fn counter(&self, durability: Durability) -> Option<&Counter> {
+        match durability {
+            Durability::Buffered => None,
+            Durability::JournalFlushed => Some(&self.journal_flushed),
+            Durability::JournalSynced => Some(&self.journal_synced),
+            Durability::DataAndJournalSynced => Some(&self.data_and_journal_synced),
+        }
+    }
+}
+
 /// [`Storage`]のメトリクス.
 ///
 /// [`Storage`]: ../storage/struct.Storage.html
@@ -782,7 +1262,19 @@ pub struct StorageMetrics {
     pub(crate) delete_lumps: Counter,
     pub(crate) get_journal_lumps: Counter,
     pub(crate) get_data_lumps: Counter,
+    pub(crate) last_shutdown_clean: Gauge,
+    pub(crate) index_memory_bytes: Gauge,
+    pub(crate) open_replay_duration_seconds: Gauge,
+    pub(crate) open_replayed_bytes: Gauge,
+    pub(crate) open_replayed_puts: Counter,
+    pub(crate) open_replayed_embeds: Counter,
+    pub(crate) open_replayed_deletes: Counter,
+    pub(crate) open_replayed_delete_ranges: Counter,
+    pub(crate) auto_placements_journal: Counter,
+    pub(crate) auto_placements_data: Counter,
+    pub(crate) durability_syncs: DurabilityCounter,
     header: Gauge,
+    metadata_created_at: Gauge,
     original_header: StorageHeader, // `header`からも復元できるが効率のためにこちらも保持しておく
     journal_region: JournalRegionMetrics,
     data_region: DataRegionMetrics,
@@ -846,6 +1338,106 @@ impl StorageMetrics {
         (inc - dec) as usize
     }
 
+    /// 前回ストレージが正常に閉じられたかどうか.
+    ///
+    /// `false`の場合には、前回の実行がクラッシュ等により異常終了した可能性がある.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_storage_last_shutdown_clean <GAUGE>
+    /// ```
+    pub fn last_shutdown_clean(&self) -> bool {
+        self.last_shutdown_clean.value() > 0.0
+    }
+
+    /// lumpのインデックスが使用しているメモリ量(バイト単位)の概算値.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_storage_index_memory_bytes <GAUGE>
+    /// ```
+    pub fn index_memory_bytes(&self) -> u64 {
+        self.index_memory_bytes.value() as u64
+    }
+
+    /// オープン時のジャーナルの再生に要した時間(秒単位).
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_storage_open_replay_duration_seconds <GAUGE>
+    /// ```
+    pub fn open_replay_duration_seconds(&self) -> f64 {
+        self.open_replay_duration_seconds.value()
+    }
+
+    /// オープン時に再生したジャーナルのバイト数.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_storage_open_replayed_bytes <GAUGE>
+    /// ```
+    pub fn open_replayed_bytes(&self) -> u64 {
+        self.open_replayed_bytes.value() as u64
+    }
+
+    /// オープン時に再生したジャーナルのレコード数.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_storage_open_replayed_records_total { kind="put|embed|delete|delete_range" } <COUNTER>
+    /// ```
+    pub fn open_replayed_records(&self) -> u64 {
+        [
+            &self.open_replayed_puts,
+            &self.open_replayed_embeds,
+            &self.open_replayed_deletes,
+            &self.open_replayed_delete_ranges,
+        ]
+        .iter()
+        .map(|c| c.value() as u64)
+        .sum()
+    }
+
+    /// 保存先の自動選択によって、ジャーナル領域に埋め込まれたlumpの数.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_storage_auto_placements_total { region="journal" } <COUNTER>
+    /// ```
+    pub fn auto_placements_journal(&self) -> u64 {
+        self.auto_placements_journal.value() as u64
+    }
+
+    /// 保存先の自動選択によって、データ領域に保存されたlumpの数.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_storage_auto_placements_total { region="data" } <COUNTER>
+    /// ```
+    pub fn auto_placements_data(&self) -> u64 {
+        self.auto_placements_data.value() as u64
+    }
+
+    /// `Storage::sync_with`による、永続化の度合い毎の同期の実行回数.
+    ///
+    /// 実際には同期が行われない`Durability::Buffered`は含まれない.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_storage_durability_syncs_total { level="journal_flushed|journal_synced|data_and_journal_synced" } <COUNTER>
+    /// ```
+    pub fn durability_syncs(&self) -> &DurabilityCounter {
+        &self.durability_syncs
+    }
+
     /// ストレージのヘッダ情報.
     ///
     /// # Prometheus
@@ -857,6 +1449,27 @@ impl StorageMetrics {
         &self.original_header
     }
 
+    /// ストレージのヘッダに格納されているメタデータ.
+    ///
+    /// `Storage::update_metadata`による更新は、そのストレージが保持しているメトリクスにのみ反映される
+    /// (更新前に複製されたインスタンスの値は変わらない).
+    ///
+    /// ラベル等の文字列は更新され得るので、Prometheusには作成日時のみが公開される.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_storage_metadata_created_at_seconds <GAUGE>
+    /// ```
+    pub fn metadata(&self) -> &StorageMetadata {
+        &self.original_header.metadata
+    }
+
+    pub(crate) fn set_metadata(&mut self, metadata: StorageMetadata) {
+        self.metadata_created_at.set(created_at_seconds(&metadata));
+        self.original_header.metadata = metadata;
+    }
+
     /// ジャーナル領域のメトリクスを返す.
     pub fn journal_region(&self) -> &JournalRegionMetrics {
         &self.journal_region
@@ -883,7 +1496,7 @@ impl StorageMetrics {
                     "version",
                     &format!("{}.{}", header.major_version, header.minor_version),
                 )
-                .label("block_size", &header.block_size.as_u16().to_string())
+                .label("block_size", &header.block_size.as_u32().to_string())
                 .label("uuid", &header.instance_uuid.to_string())
                 .label(
                     "journal_region_size",
@@ -893,6 +1506,12 @@ impl StorageMetrics {
                 .initial_value(1.0)
                 .finish()
                 .expect("Never fails"),
+            metadata_created_at: builder
+                .gauge("metadata_created_at_seconds")
+                .help("Creation time of the storage recorded in its metadata (UNIX seconds)")
+                .initial_value(created_at_seconds(&header.metadata))
+                .finish()
+                .expect("Never fails"),
             put_lumps_at_starting: builder
                 .counter("put_lumps_total")
                 .help("Number of lumps putted on the storage")
@@ -922,6 +1541,47 @@ impl StorageMetrics {
                 .label("region", "data")
                 .finish()
                 .expect("Never fails"),
+            last_shutdown_clean: builder
+                .gauge("last_shutdown_clean")
+                .help("Whether the storage was closed cleanly last time (1) or not (0)")
+                .finish()
+                .expect("Never fails"),
+            index_memory_bytes: builder
+                .gauge("index_memory_bytes")
+                .help("Approximate memory usage of the lump index in bytes")
+                .finish()
+                .expect("Never fails"),
+            open_replay_duration_seconds: builder
+                .gauge("open_replay_duration_seconds")
+                .help("Time spent replaying the journal when the storage was opened")
+                .finish()
+                .expect("Never fails"),
+            open_replayed_bytes: builder
+                .gauge("open_replayed_bytes")
+                .help("Number of journal bytes replayed when the storage was opened")
+                .finish()
+                .expect("Never fails"),
+            open_replayed_puts: open_replayed_records(&builder, "put"),
+            open_replayed_embeds: open_replayed_records(&builder, "embed"),
+            open_replayed_deletes: open_replayed_records(&builder, "delete"),
+            open_replayed_delete_ranges: open_replayed_records(&builder, "delete_range"),
+            auto_placements_journal: builder
+                .counter("auto_placements_total")
+                .help("Number of lumps whose placement was chosen by the storage")
+                .label("region", "journal")
+                .finish()
+                .expect("Never fails"),
+            auto_placements_data: builder
+                .counter("auto_placements_total")
+                .help("Number of lumps whose placement was chosen by the storage")
+                .label("region", "data")
+                .finish()
+                .expect("Never fails"),
+            durability_syncs: DurabilityCounter::new(
+                &builder,
+                "durability_syncs_total",
+                "Number of syncs performed for each durability level",
+            ),
             original_header: header.clone(),
             journal_region,
             data_region,
@@ -929,10 +1589,29 @@ impl StorageMetrics {
     }
 }
 
+/// 作成日時が記録されていない場合には`0`を返す.
+fn created_at_seconds(metadata: &StorageMetadata) -> f64 {
+    metadata
+        .created_at
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0.0, |d| d.as_secs() as f64)
+}
+
+fn open_replayed_records(builder: &MetricBuilder, kind: &str) -> Counter {
+    builder
+        .counter("open_replayed_records_total")
+        .help("Number of journal records replayed when the storage was opened")
+        .label("kind", kind)
+        .finish()
+        .expect("Never fails")
+}
+
 /// ストレージのデータ領域のメトリクス.
 #[derive(Debug, Clone)]
 pub struct DataRegionMetrics {
     pub(crate) capacity_bytes: Gauge,
+    pub(crate) packs: Gauge,
+    pub(crate) reclaimed_packs: Counter,
     allocator: DataAllocatorMetrics,
 }
 impl DataRegionMetrics {
@@ -961,6 +1640,28 @@ impl DataRegionMetrics {
         inc - dec
     }
 
+    /// 使用中のパック(小さなlumpを詰め込んだブロック)の数を返す.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_data_region_packs <GAUGE>
+    /// ```
+    pub fn packs(&self) -> u64 {
+        self.packs.value() as u64
+    }
+
+    /// 全メンバーが削除されたことにより解放されたパックの数を返す.
+    ///
+    /// # Prometheus
+    ///
+    /// ```prometheus
+    /// cannyls_data_region_reclaimed_packs_total <COUNTER>
+    /// ```
+    pub fn reclaimed_packs(&self) -> u64 {
+        self.reclaimed_packs.value() as u64
+    }
+
     /// アロケータのメトリクスを返す.
     pub fn allocator(&self) -> &DataAllocatorMetrics {
         &self.allocator
@@ -980,6 +1681,16 @@ impl DataRegionMetrics {
                 .initial_value(capacity as f64)
                 .finish()
                 .expect("Never fails"),
+            packs: builder
+                .gauge("packs")
+                .help("Number of packs in use")
+                .finish()
+                .expect("Never fails"),
+            reclaimed_packs: builder
+                .counter("reclaimed_packs_total")
+                .help("Number of packs released because all of their members were deleted")
+                .finish()
+                .expect("Never fails"),
             allocator,
         }
     }
@@ -1,21 +1,24 @@
 use std::cmp;
 use std::fs::{self, File};
 use std::io::{self, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
 use std::path::Path;
 
 use crate::block::BlockSize;
-use crate::nvm::NonVolatileMemory;
+use crate::nvm::{NonVolatileMemory, PositionalRead, SyncLevel};
 use crate::storage::StorageHeader;
 use crate::{ErrorKind, Result};
 
 /// `FileNvm`のビルダ
 ///
-/// `FileNvm`には二つのオプション`direct_io`と`exclusive_lock`が存在する。  
-/// デフォルトでは`direct_io=true`かつ`exclusive_lock=true`の振る舞いをする。  
+/// `FileNvm`には三つのオプション`direct_io`と`exclusive_lock`、`read_only`が存在する。  
+/// デフォルトでは`direct_io=true`かつ`exclusive_lock=true`かつ`read_only=false`の振る舞いをする。  
 /// それぞれのオプション内容については個別のメソッドを参照せよ。
 pub struct FileNvmBuilder {
     direct_io: bool,
     exclusive_lock: bool,
+    read_only: bool,
 }
 
 impl Default for FileNvmBuilder {
@@ -23,6 +26,7 @@ impl Default for FileNvmBuilder {
         FileNvmBuilder {
             direct_io: true,
             exclusive_lock: true,
+            read_only: false,
         }
     }
 }
@@ -33,12 +37,16 @@ impl FileNvmBuilder {
         FileNvmBuilder::default()
     }
 
+    fn base_open_options(&self) -> fs::OpenOptions {
+        let mut options = fs::OpenOptions::new();
+        options.read(true).write(!self.read_only).create(false);
+        options
+    }
+
     #[cfg(target_os = "linux")]
     fn open_options(&self) -> fs::OpenOptions {
         use std::os::unix::fs::OpenOptionsExt;
-        let mut options = fs::OpenOptions::new();
-        options.read(true).write(true).create(false);
-
+        let mut options = self.base_open_options();
         if self.direct_io {
             options.custom_flags(libc::O_DIRECT);
         }
@@ -46,9 +54,7 @@ impl FileNvmBuilder {
     }
     #[cfg(not(target_os = "linux"))]
     fn open_options(&self) -> fs::OpenOptions {
-        let mut options = fs::OpenOptions::new();
-        options.read(true).write(true).create(false);
-        options
+        self.base_open_options()
     }
 
     #[cfg(target_os = "macos")]
@@ -76,7 +82,13 @@ impl FileNvmBuilder {
     fn set_exclusive_file_lock_if_flag_is_on(&self, file: &File) -> Result<()> {
         use std::os::unix::io::AsRawFd;
         if self.exclusive_lock {
-            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            // 読み込み専用モードでは、他の読み込み専用プロセスとの共存を許すために共有ロックを用いる
+            let operation = if self.read_only {
+                libc::LOCK_SH
+            } else {
+                libc::LOCK_EX
+            };
+            if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } != 0 {
                 track_io!(Err(io::Error::last_os_error()))
             } else {
                 Ok(())
@@ -115,6 +127,23 @@ impl FileNvmBuilder {
         self
     }
 
+    /// ファイルを読み込み専用で開くかどうかを設定する。  
+    /// デフォルトでは読み書き可能な状態で開く。
+    /// - `enabled=true`で読み込み専用で開く。
+    /// - `enabled=false`で読み書き可能な状態で開く。
+    ///
+    /// 読み込み専用モードでは、`exclusive_lock`による排他ロックの代わりに
+    /// 共有ロック(`LOCK_SH`)が用いられるため、複数の読み込み専用プロセスが同時にファイルを開くことができる。  
+    /// ただし、書き込み可能な状態で開いているプロセスが存在する場合にはロックの獲得に失敗する。
+    /// そのようなファイルを覗きたい場合には`exclusive_lock(false)`を併用すること。
+    ///
+    /// また、読み込み専用モードでは`create`と`create_if_absent`は常に失敗し、
+    /// 生成された`FileNvm`に対する書き込みは`ErrorKind::ReadOnly`エラーとなる。
+    pub fn read_only(&mut self, enabled: bool) -> &mut Self {
+        self.read_only = enabled;
+        self
+    }
+
     #[cfg(target_os = "linux")]
     fn file_open_with_error_info<P: AsRef<Path>>(
         &self,
@@ -153,8 +182,7 @@ impl FileNvmBuilder {
         }
 
         // Next, we check if the file `filepath` can be opened without `O_DIRECT` option.
-        let mut options = fs::OpenOptions::new();
-        options.read(true).write(true).create(false);
+        let mut options = self.base_open_options();
 
         let file = track_io!(options.open(&filepath));
         if file.is_err() {
@@ -200,6 +228,7 @@ impl FileNvmBuilder {
         filepath: P,
         capacity: u64,
     ) -> Result<(FileNvm, bool)> {
+        track_assert!(!self.read_only, ErrorKind::ReadOnly);
         create_parent_directories(&filepath)?;
         let mut options = self.open_options();
         // OpenOptions::createはファイルが既に存在する場合はそれを開き
@@ -227,6 +256,7 @@ impl FileNvmBuilder {
     /// `filepath`に（非零バイト）ファイルが存在する場合にそれを開きたいならば、
     /// このメソッドの代わりに`create_if_absent`を用いる。
     pub fn create<P: AsRef<Path>>(&mut self, filepath: P, capacity: u64) -> Result<FileNvm> {
+        track_assert!(!self.read_only, ErrorKind::ReadOnly);
         create_parent_directories(&filepath)?;
         let mut options = self.open_options();
         // OpenOptions::create_newはファイルが存在しない場合だけ作成し
@@ -254,7 +284,9 @@ impl FileNvmBuilder {
     fn initialize(&self, file: File, capacity: u64) -> Result<FileNvm> {
         track!(self.set_exclusive_file_lock_if_flag_is_on(&file))?;
         track!(self.set_fnocache_if_flag_is_on(&file))?;
-        Ok(FileNvm::with_range(file, 0, capacity))
+        let mut nvm = FileNvm::with_range(file, 0, capacity);
+        nvm.read_only = self.read_only;
+        Ok(nvm)
     }
 }
 
@@ -276,6 +308,7 @@ pub struct FileNvm {
     cursor_position: u64,
     view_start: u64,
     view_end: u64,
+    read_only: bool,
 }
 impl FileNvm {
     /// デフォルト設定で新しい`FileNvm`インスタンスを生成する.
@@ -318,9 +351,15 @@ impl FileNvm {
             cursor_position: start,
             view_start: start,
             view_end: end,
+            read_only: false,
         }
     }
 
+    /// 読み込み専用モードで開かれているかどうかを返す.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
     fn seek_impl(&mut self, position: u64) -> Result<()> {
         track_assert!(
             self.block_size().is_aligned(position),
@@ -352,6 +391,7 @@ impl FileNvm {
         Ok(len)
     }
     fn write_impl(&mut self, buf: &[u8]) -> Result<usize> {
+        track_assert!(!self.read_only, ErrorKind::ReadOnly);
         track_assert!(
             self.block_size().is_aligned(buf.len() as u64),
             ErrorKind::InvalidInput
@@ -365,6 +405,42 @@ impl FileNvm {
         Ok(len)
     }
 
+    fn check_range(&self, position: u64, len: usize) -> Result<()> {
+        track_assert!(
+            self.block_size().is_aligned(position),
+            ErrorKind::InvalidInput
+        );
+        track_assert!(
+            self.block_size().is_aligned(len as u64),
+            ErrorKind::InvalidInput
+        );
+        track_assert!(
+            position + len as u64 <= self.capacity(),
+            ErrorKind::InvalidInput
+        );
+        Ok(())
+    }
+
+    /// カーソル位置を変更せずに、指定位置から読み込みを行う.
+    #[cfg(unix)]
+    fn pread(&self, buf: &mut [u8], position: u64) -> Result<()> {
+        track!(self.check_range(position, buf.len()))?;
+        let mut offset = 0;
+        while offset < buf.len() {
+            let file_position = self.view_start + position + offset as u64;
+            let read_size = track_io!(self.file.read_at(&mut buf[offset..], file_position))?;
+            if read_size == 0 {
+                // まだ未書き込みの末尾部分から読み込みを行った場合には、ゼロ埋めされているものとして扱う
+                for b in &mut buf[offset..] {
+                    *b = 0;
+                }
+                break;
+            }
+            offset += read_size;
+        }
+        Ok(())
+    }
+
     #[cfg(test)]
     fn inner(&self) -> &File {
         &self.file
@@ -372,7 +448,20 @@ impl FileNvm {
 }
 impl NonVolatileMemory for FileNvm {
     fn sync(&mut self) -> Result<()> {
-        track_io!(self.file.sync_data())?;
+        if !self.read_only {
+            track_io!(self.file.sync_data())?;
+        }
+        Ok(())
+    }
+    fn sync_with(&mut self, level: SyncLevel) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        match level {
+            // 書き込みは常にファイルに直接行われているので、フラッシュするものは無い
+            SyncLevel::Flush => {}
+            SyncLevel::DataSync => track_io!(self.file.sync_data())?,
+        }
         Ok(())
     }
     fn position(&self) -> u64 {
@@ -394,13 +483,39 @@ impl NonVolatileMemory for FileNvm {
         let left_file = track_io!(self.file.try_clone())?;
         let left_start = self.view_start;
         let left_end = left_start + position;
-        let left = Self::with_range(left_file, left_start, left_end);
+        let mut left = Self::with_range(left_file, left_start, left_end);
+        left.read_only = self.read_only;
 
         let right_start = left_end;
         let right_end = self.view_end;
-        let right = Self::with_range(self.file, right_start, right_end);
+        let read_only = self.read_only;
+        let mut right = Self::with_range(self.file, right_start, right_end);
+        right.read_only = read_only;
         Ok((left, right))
     }
+    #[cfg(unix)]
+    fn read_at(&mut self, buf: &mut [u8], position: u64) -> Result<()> {
+        track!(self.pread(buf, position))
+    }
+    #[cfg(unix)]
+    fn write_at(&mut self, buf: &[u8], position: u64) -> Result<()> {
+        track_assert!(!self.read_only, ErrorKind::ReadOnly);
+        track!(self.check_range(position, buf.len()))?;
+        track_io!(self.file.write_all_at(buf, self.view_start + position))
+    }
+    #[cfg(unix)]
+    fn reader(&self) -> Result<Option<Box<dyn PositionalRead>>> {
+        let file = track_io!(self.file.try_clone())?;
+        let mut reader = Self::with_range(file, self.view_start, self.view_end);
+        reader.read_only = true;
+        Ok(Some(Box::new(reader)))
+    }
+}
+#[cfg(unix)]
+impl PositionalRead for FileNvm {
+    fn read_exact_at(&self, buf: &mut [u8], position: u64) -> Result<()> {
+        track!(self.pread(buf, position))
+    }
 }
 impl Seek for FileNvm {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
@@ -443,7 +558,7 @@ mod tests {
 
     use super::*;
     use crate::block::{AlignedBytes, BlockSize};
-    use crate::storage::{StorageHeader, MAJOR_VERSION, MINOR_VERSION};
+    use crate::storage::{StorageHeader, StorageMetadata, MAJOR_VERSION, MINOR_VERSION};
 
     #[test]
     fn create_parent_directories_is_idempotent() -> TestResult {
@@ -510,6 +625,8 @@ mod tests {
 
         data.extend_from_slice(b"bar");
         track_io!(file.write_all(&aligned_bytes(&data[..])))?;
+        track!(file.sync_with(SyncLevel::Flush))?;
+        track!(file.sync_with(SyncLevel::DataSync))?;
 
         // 同じファイルを同時に開くことはできない
         assert!(FileNvm::open(dir.path().join("foo")).is_err());
@@ -726,6 +843,78 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn read_only_works() -> TestResult {
+        let dir = track_io!(TempDir::new("cannyls_test"))?;
+        let path = dir.path().join("foo");
+
+        // 読み込み専用モードではファイルを作成できない
+        assert_eq!(
+            FileNvmBuilder::new()
+                .read_only(true)
+                .create(&path, 10 * 1024)
+                .err()
+                .map(|e| *e.kind()),
+            Some(ErrorKind::ReadOnly)
+        );
+
+        let mut file = track!(FileNvm::create(&path, 10 * 1024))?;
+        let mut data = Vec::new();
+        track!(storage_header().write_to(&mut data))?;
+        data.extend_from_slice(b"bar");
+        track_io!(file.write_all(&aligned_bytes(&data[..])))?;
+        mem::drop(file);
+
+        // 読み込み専用モードであれば、複数同時に開くことができる
+        let mut file0 = track!(FileNvmBuilder::new().read_only(true).open(&path))?;
+        let file1 = track!(FileNvmBuilder::new().read_only(true).open(&path))?;
+        assert!(file0.is_read_only());
+        assert!(file1.is_read_only());
+
+        // 読み書き可能なモードで開くことはできない
+        assert!(FileNvm::open(&path).is_err());
+
+        let mut buf = aligned_bytes_with_size(data.len());
+        track_io!(file0.read_exact(&mut buf[..]))?;
+        assert_eq!(&buf[..data.len()], &data[..]);
+
+        // 書き込みは拒否される
+        track_io!(file0.seek(SeekFrom::Start(0)))?;
+        assert!(file0.write_all(&aligned_bytes(&[1; 512][..])).is_err());
+        track!(file0.sync())?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn positional_read_write_works() -> TestResult {
+        let dir = track_io!(TempDir::new("cannyls_test"))?;
+        let nvm = track!(FileNvm::create(dir.path().join("foo"), 10 * 1024))?;
+        let (_, mut nvm) = track!(nvm.split(1024))?;
+
+        track!(nvm.write_at(&aligned_bytes(&[1; 512][..]), 512))?;
+        assert_eq!(nvm.position(), 0); // カーソル位置は変化しない
+
+        let mut buf = aligned_bytes_with_size(1024);
+        track!(nvm.read_at(&mut buf, 0))?;
+        assert_eq!(&buf[..512], &[0; 512][..]);
+        assert_eq!(&buf[512..], &[1; 512][..]);
+
+        // 分割後の位置を基準に書き込まれている
+        track_io!(nvm.seek(SeekFrom::Start(512)))?;
+        let mut buf = aligned_bytes_with_size(512);
+        track_io!(nvm.read_exact(&mut buf))?;
+        assert_eq!(&buf[..], &[1; 512][..]);
+
+        // 未書き込みの末尾部分はゼロ埋めされる
+        track!(nvm.read_at(&mut buf, 8 * 1024))?;
+        assert_eq!(&buf[..], &[0; 512][..]);
+
+        // 範囲外は指定できない
+        assert!(nvm.read_at(&mut buf, 9 * 1024).is_err());
+        Ok(())
+    }
+
     fn aligned_bytes<T: AsRef<[u8]>>(b: T) -> AlignedBytes {
         let mut buf = AlignedBytes::from_bytes(b.as_ref(), BlockSize::min());
         buf.align();
@@ -744,6 +933,9 @@ mod tests {
             instance_uuid: Uuid::new_v4(),
             journal_region_size: 1024,
             data_region_size: 4096,
+            redundant_headers: false,
+            packed_lumps: false,
+            metadata: StorageMetadata::default(),
         }
     }
 }
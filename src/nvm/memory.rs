@@ -53,6 +53,21 @@ impl MemoryNvm {
         // アライメントを維持するためには`write_all`を使う必要がある
         track_io!(self.memory.write_all(buf))
     }
+    fn check_range(&self, position: u64, len: usize) -> Result<()> {
+        track_assert!(
+            self.block_size().is_aligned(position),
+            ErrorKind::InvalidInput
+        );
+        track_assert!(
+            self.block_size().is_aligned(len as u64),
+            ErrorKind::InvalidInput
+        );
+        track_assert!(
+            position + len as u64 <= self.capacity(),
+            ErrorKind::InvalidInput
+        );
+        Ok(())
+    }
 }
 impl NonVolatileMemory for MemoryNvm {
     fn sync(&mut self) -> Result<()> {
@@ -80,6 +95,18 @@ impl NonVolatileMemory for MemoryNvm {
         self.memory.set_position(0);
         Ok((self, right))
     }
+    fn read_at(&mut self, buf: &mut [u8], position: u64) -> Result<()> {
+        track!(self.check_range(position, buf.len()))?;
+        let start = position as usize;
+        buf.copy_from_slice(&self.memory.get_ref()[start..start + buf.len()]);
+        Ok(())
+    }
+    fn write_at(&mut self, buf: &[u8], position: u64) -> Result<()> {
+        track!(self.check_range(position, buf.len()))?;
+        let start = position as usize;
+        self.memory.get_mut()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
 }
 impl Seek for MemoryNvm {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
@@ -152,4 +179,22 @@ mod tests {
         assert!(right.read_exact(&mut buf).is_err());
         Ok(())
     }
+
+    #[test]
+    fn positional_read_write_works() -> TestResult {
+        let mut nvm = MemoryNvm::new(vec![0; 2048]);
+        track!(nvm.write_at(&[1; 512], 1024))?;
+        assert_eq!(nvm.position(), 0); // カーソル位置は変化しない
+
+        let mut buf = vec![0; 1024];
+        track!(nvm.read_at(&mut buf, 512))?;
+        assert_eq!(&buf[..512], &[0; 512][..]);
+        assert_eq!(&buf[512..], &[1; 512][..]);
+        assert_eq!(nvm.position(), 0);
+
+        // 範囲外やアライメントされていない位置は指定できない
+        assert!(nvm.read_at(&mut buf, 1536).is_err());
+        assert!(nvm.write_at(&[1; 512], 100).is_err());
+        Ok(())
+    }
 }
@@ -2,6 +2,7 @@
 //!
 //! このモジュールは[Storage](../storage/struct.Storage.html)がデータの読み書きに使用する
 //! 永続化領域を提供する.
+use std::fmt;
 use std::io::{Read, Seek, SeekFrom, Write};
 
 pub use self::file::{FileNvm, FileNvmBuilder};
@@ -15,6 +16,22 @@ mod file;
 mod memory;
 mod shared_memory;
 
+/// 不揮発性メモリの同期の強度.
+///
+/// 後のものほど強度が高い(i.e., より確実に永続化されるが、コストも大きい).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SyncLevel {
+    /// 実装内部でバッファされているデータを、下層(e.g., OSのページキャッシュ)に書き出す.
+    ///
+    /// 物理デバイスへの同期は行われないので、OSのクラッシュや電源断の際にはデータが失われる可能性がある.
+    Flush,
+
+    /// データを物理デバイスに同期する(e.g., `fdatasync`).
+    ///
+    /// `NonVolatileMemory::sync`と等価.
+    DataSync,
+}
+
 /// 不揮発性メモリを表すトレイト.
 ///
 /// "不揮発性メモリ"は「永続化可能なバイト列(領域)」を意味し、lump群を保存するために使用される.
@@ -33,6 +50,17 @@ pub trait NonVolatileMemory: Sized + Read + Write + Seek {
     /// このメソッド内で特に何かを行う必要はない。
     fn sync(&mut self) -> Result<()>;
 
+    /// 指定された強度で、メモリの内容を同期する.
+    ///
+    /// デフォルト実装では、`SyncLevel::Flush`の場合には`Write::flush`が、
+    /// それ以外の場合には`sync`が呼び出される.
+    fn sync_with(&mut self, level: SyncLevel) -> Result<()> {
+        match level {
+            SyncLevel::Flush => track_io!(self.flush()),
+            SyncLevel::DataSync => track!(self.sync()),
+        }
+    }
+
     /// 読み書き用カーソルの現在位置を返す.
     fn position(&self) -> u64;
 
@@ -79,6 +107,56 @@ pub trait NonVolatileMemory: Sized + Read + Write + Seek {
         }
     }
 
+    /// 指定位置から、`buf`のサイズ分のデータを読み込む.
+    ///
+    /// `Read`および`Seek`とは異なり、位置を引数で指定して読み込みを行う.
+    /// 位置およびサイズは、ブロック境界にアライメントされている必要がある.
+    ///
+    /// デフォルト実装は`seek`と`read_exact`の組み合わせなので、カーソル位置も変化する.
+    /// 実装が(e.g., `pread`を使って)独自に定義している場合には、カーソル位置は変化しない.
+    ///
+    /// # Errors
+    ///
+    /// 読み込み範囲が容量を超えている場合には、種類が`ErrorKind::InvalidInput`のエラーが返される.
+    fn read_at(&mut self, buf: &mut [u8], position: u64) -> Result<()> {
+        track_assert!(
+            position + buf.len() as u64 <= self.capacity(),
+            ErrorKind::InvalidInput
+        );
+        track_io!(self.seek(SeekFrom::Start(position)))?;
+        track_io!(self.read_exact(buf))
+    }
+
+    /// 指定位置に、`buf`の内容を書き込む.
+    ///
+    /// `Write`および`Seek`とは異なり、位置を引数で指定して書き込みを行う.
+    /// 位置およびサイズは、ブロック境界にアライメントされている必要がある.
+    ///
+    /// デフォルト実装は`seek`と`write_all`の組み合わせなので、カーソル位置も変化する.
+    /// 実装が(e.g., `pwrite`を使って)独自に定義している場合には、カーソル位置は変化しない.
+    ///
+    /// # Errors
+    ///
+    /// 書き込み範囲が容量を超えている場合には、種類が`ErrorKind::InvalidInput`のエラーが返される.
+    fn write_at(&mut self, buf: &[u8], position: u64) -> Result<()> {
+        track_assert!(
+            position + buf.len() as u64 <= self.capacity(),
+            ErrorKind::InvalidInput
+        );
+        track_io!(self.seek(SeekFrom::Start(position)))?;
+        track_io!(self.write_all(buf))
+    }
+
+    /// 並列読み込み用の、独立した読み込みハンドルを生成する.
+    ///
+    /// 返されるハンドルは、このインスタンスと同じ領域を指すが、カーソル位置は共有しない.
+    /// そのため、このインスタンスとは別のスレッドから、書き込みと並行して使用することができる.
+    ///
+    /// 対応していない実装の場合には`Ok(None)`が返される(デフォルト実装).
+    fn reader(&self) -> Result<Option<Box<dyn PositionalRead>>> {
+        Ok(None)
+    }
+
     /// このインスタンスが指定するブロック境界へのアライメントを保証した書き込みを行う.
     ///
     /// `f`の引数(一時バッファ)に対して書き込まれたデータは、その後`AlignedBytes`にコピーされた上で、
@@ -108,3 +186,17 @@ pub trait NonVolatileMemory: Sized + Read + Write + Seek {
         Ok(buf)
     }
 }
+
+/// 位置を指定して読み込みを行うためのハンドル.
+///
+/// `NonVolatileMemory::reader`によって生成される.
+pub trait PositionalRead: fmt::Debug + Send {
+    /// `position`の位置から、`buf`のサイズ分のデータを読み込む.
+    ///
+    /// `NonVolatileMemory`と同様に、位置およびサイズはブロック境界にアライメントされている必要がある.
+    ///
+    /// # Errors
+    ///
+    /// 読み込み範囲が容量を超えている場合には、種類が`ErrorKind::InvalidInput`のエラーが返される.
+    fn read_exact_at(&self, buf: &mut [u8], position: u64) -> Result<()>;
+}
@@ -3,7 +3,7 @@ use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::sync::{Arc, Mutex};
 
 use crate::block::BlockSize;
-use crate::nvm::NonVolatileMemory;
+use crate::nvm::{NonVolatileMemory, PositionalRead};
 use crate::{Error, ErrorKind, Result};
 
 /// インスタンスを共有可能な、メモリベースの`NonVolatileMemory`の実装.
@@ -86,6 +86,34 @@ impl SharedMemoryNvm {
         Ok(size)
     }
 
+    fn check_range(&self, position: u64, len: usize) -> Result<()> {
+        track_assert!(
+            self.block_size().is_aligned(position),
+            ErrorKind::InvalidInput
+        );
+        track_assert!(
+            self.block_size().is_aligned(len as u64),
+            ErrorKind::InvalidInput
+        );
+        track_assert!(
+            position + len as u64 <= self.capacity(),
+            ErrorKind::InvalidInput
+        );
+        Ok(())
+    }
+
+    fn read_at_impl(&self, buf: &mut [u8], position: u64) -> Result<()> {
+        track!(self.check_range(position, buf.len()))?;
+        let start = self.memory_start + position as usize;
+        match self.memory.lock() {
+            Ok(memory) => {
+                buf.copy_from_slice(&memory[start..start + buf.len()]);
+                Ok(())
+            }
+            Err(error) => Err(track!(Error::from(error))),
+        }
+    }
+
     fn write_impl(&mut self, buf: &[u8]) -> Result<()> {
         track_assert!(
             self.block_size().is_aligned(buf.len() as u64),
@@ -132,6 +160,28 @@ impl NonVolatileMemory for SharedMemoryNvm {
 
         Ok((left, right))
     }
+    fn read_at(&mut self, buf: &mut [u8], position: u64) -> Result<()> {
+        track!(self.read_at_impl(buf, position))
+    }
+    fn write_at(&mut self, buf: &[u8], position: u64) -> Result<()> {
+        track!(self.check_range(position, buf.len()))?;
+        let start = self.memory_start + position as usize;
+        match self.memory.lock() {
+            Ok(mut memory) => {
+                memory[start..start + buf.len()].copy_from_slice(buf);
+                Ok(())
+            }
+            Err(error) => Err(track!(Error::from(error))),
+        }
+    }
+    fn reader(&self) -> Result<Option<Box<dyn PositionalRead>>> {
+        Ok(Some(Box::new(self.clone())))
+    }
+}
+impl PositionalRead for SharedMemoryNvm {
+    fn read_exact_at(&self, buf: &mut [u8], position: u64) -> Result<()> {
+        track!(self.read_at_impl(buf, position))
+    }
 }
 impl Seek for SharedMemoryNvm {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
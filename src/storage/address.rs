@@ -1,6 +1,8 @@
 use std::ops::{Add, Sub};
 
-/// ストレージ内のアドレス表現に使われている40bit幅の整数値.
+/// ストレージ内のアドレス表現に使われている44bit幅の整数値.
+///
+/// なお、v1フォーマットのストレージでは、下位40bitの範囲のみが使用される.
 ///
 /// アドレスの単位は、以下のように使用箇所によって異なっている:
 ///
@@ -10,7 +12,10 @@ use std::ops::{Add, Sub};
 pub struct Address(u64);
 impl Address {
     /// 取り得るアドレスの最大値.
-    pub const MAX: u64 = (1 << 40) - 1;
+    pub const MAX: u64 = (1 << 44) - 1;
+
+    /// v1フォーマットのストレージで取り得るアドレスの最大値.
+    pub const MAX_V1: u64 = (1 << 40) - 1;
 
     /// アドレスの値を返す.
     pub fn as_u64(self) -> u64 {
@@ -19,7 +24,7 @@ impl Address {
 
     /// `value`を対応する位置のアドレスに変換する.
     ///
-    /// `value`の値が40bit以内に収まらない場合には`None`が返される.
+    /// `value`の値が44bit以内に収まらない場合には`None`が返される.
     pub fn from_u64(value: u64) -> Option<Self> {
         if value <= Self::MAX {
             Some(Address(value))
@@ -4,8 +4,9 @@ use std::cmp;
 use std::collections::BTreeSet;
 use std::ops::Bound::{Excluded, Included, Unbounded};
 
-use super::free_portion::{EndBasedFreePortion, FreePortion, SizeBasedFreePortion};
-use super::U24;
+use super::free_portion::{
+    EndBasedFreePortion, FreePortion, SizeBasedFreePortion, NARROW_ADDRESS_BITS, WIDE_ADDRESS_BITS,
+};
 use crate::metrics::DataAllocatorMetrics;
 use crate::storage::portion::DataPortion;
 use crate::storage::Address;
@@ -34,10 +35,15 @@ use crate::{ErrorKind, Result};
 ///
 /// 選択された空き領域は、その中から要求サイズ分だけの割当を行い、
 /// もしまだ余剰分がある場合には、再び空き領域リストに戻される.
+///
+/// # 空き領域の表現
+///
+/// 空き領域は`FreePortion`として64bit整数に詰め込まれて管理される.
+/// データ領域のブロック数が40bitに収まる(i.e., v1フォーマットの)場合には、長さに24bit幅が、
+/// そうでない場合には20bit幅が使用され、それを超える空き領域は複数の`FreePortion`に分割される.
 #[derive(Debug)]
 pub struct DataPortionAllocator {
-    size_to_free: BTreeSet<SizeBasedFreePortion>,
-    end_to_free: BTreeSet<EndBasedFreePortion>,
+    free_lists: FreeLists,
     metrics: DataAllocatorMetrics,
 }
 impl DataPortionAllocator {
@@ -50,7 +56,7 @@ impl DataPortionAllocator {
     where
         I: Iterator<Item = DataPortion>,
     {
-        let block_size = u64::from(metrics.block_size.as_u16());
+        let block_size = u64::from(metrics.block_size.as_u32());
         let mut portions = portions.collect::<Vec<_>>();
         metrics
             .allocated_portions_at_starting
@@ -68,13 +74,79 @@ impl DataPortionAllocator {
         // すなわち、ソート後は、先頭であればあるほどend()の値は大きい。
         portions.sort_by_key(|&b| std::cmp::Reverse(b.end()));
 
+        let capacity = metrics.capacity_bytes / block_size;
+        let free_lists = if capacity <= FreePortion::<NARROW_ADDRESS_BITS>::MAX_START {
+            FreeLists::Narrow(track!(FreeList::build(&metrics, capacity, portions))?)
+        } else {
+            FreeLists::Wide(track!(FreeList::build(&metrics, capacity, portions))?)
+        };
+        Ok(DataPortionAllocator {
+            free_lists,
+            metrics,
+        })
+    }
+
+    /// `size`分の部分領域の割当を行う.
+    ///
+    /// 十分な領域が存在しない場合には`None`が返される.
+    pub fn allocate(&mut self, size: u32) -> Option<DataPortion> {
+        let allocated = match self.free_lists {
+            FreeLists::Narrow(ref mut x) => x.allocate(&self.metrics, size),
+            FreeLists::Wide(ref mut x) => x.allocate(&self.metrics, size),
+        };
+        if let Some(allocated) = allocated {
+            self.metrics.count_allocation(allocated.len);
+        } else {
+            self.metrics.nospace_failures.increment();
+        }
+        allocated
+    }
+
+    /// 割当済みの部分領域の解放を行う.
+    ///
+    /// # 事前条件
+    ///
+    /// - `portion`は「以前に割当済み」かつ「未解放」の部分領域である
+    pub fn release(&mut self, portion: DataPortion) {
+        self.metrics.count_releasion(portion.len);
+        match self.free_lists {
+            FreeLists::Narrow(ref mut x) => x.release(&self.metrics, portion),
+            FreeLists::Wide(ref mut x) => x.release(&self.metrics, portion),
+        }
+    }
+
+    /// アロケータ用のメトリクスを返す.
+    pub fn metrics(&self) -> &DataAllocatorMetrics {
+        &self.metrics
+    }
+}
+
+/// アドレスの幅ごとの空き領域リスト.
+#[derive(Debug)]
+enum FreeLists {
+    Narrow(FreeList<NARROW_ADDRESS_BITS>),
+    Wide(FreeList<WIDE_ADDRESS_BITS>),
+}
+
+/// 空き領域のリスト.
+#[derive(Debug)]
+struct FreeList<const ADDRESS_BITS: u32> {
+    size_to_free: BTreeSet<SizeBasedFreePortion<ADDRESS_BITS>>,
+    end_to_free: BTreeSet<EndBasedFreePortion<ADDRESS_BITS>>,
+}
+impl<const ADDRESS_BITS: u32> FreeList<ADDRESS_BITS> {
+    /// `portions`は、終端位置の降順に並んでいる必要がある.
+    fn build(
+        metrics: &DataAllocatorMetrics,
+        capacity: u64,
+        portions: Vec<DataPortion>,
+    ) -> Result<Self> {
         // 変数tailの意味は次の通り:
         // tail位置には値が書き込めない・書き込まれている、すなわち空いてはいない。
-        let mut tail = metrics.capacity_bytes / block_size;
-        let mut allocator = DataPortionAllocator {
+        let mut tail = capacity;
+        let mut list = FreeList {
             size_to_free: BTreeSet::new(),
             end_to_free: BTreeSet::new(),
-            metrics,
         };
         for portion in portions {
             track_assert!(portion.end().as_u64() <= tail, ErrorKind::InvalidInput);
@@ -83,78 +155,70 @@ impl DataPortionAllocator {
             // すなわち、endの手前まではデータが詰まっているが、endにはデータがない
             while portion.end().as_u64() < tail {
                 let delta = tail - portion.end().as_u64(); // いま着目しているportionの後ろ側にある空きブロック数
-                let size = cmp::min(0xFF_FFFF, delta) as U24; // 最大でも24バイト表現なので切り詰めを行う
+                let size = cmp::min(u64::from(FreePortion::<ADDRESS_BITS>::MAX_LEN), delta) as u32; // 表現可能な長さに切り詰めを行う
 
                 // tail-size位置 から size分 の空き容量があることが分かっているので
                 // これを追加する
                 tail -= u64::from(size);
                 let free = FreePortion::new(Address::from_u64(tail).unwrap(), size);
-                allocator.add_free_portion(free);
+                list.add_free_portion(metrics, free);
             }
             tail = portion.start.as_u64();
         }
-        Ok(allocator)
+        Ok(list)
     }
 
-    /// `size`分の部分領域の割当を行う.
-    ///
-    /// 十分な領域が存在しない場合には`None`が返される.
-    pub fn allocate(&mut self, size: u16) -> Option<DataPortion> {
-        let portion = SizeBasedFreePortion(FreePortion::new(Address::from(0), U24::from(size)));
-        if let Some(mut free) = self
+    fn allocate(&mut self, metrics: &DataAllocatorMetrics, size: u32) -> Option<DataPortion> {
+        let portion = SizeBasedFreePortion(FreePortion::new(Address::from(0), size));
+        let mut free = self
             .size_to_free
             // `SizedBasedFreePortion`の全順序を用いて `size` を含むFreePortionを探す
             .range((Included(&portion), Unbounded))
             // 従って、next()では（存在すれば）size以上かつ最小のFreePortionを取得することになる
             .next()
-            .map(|p| p.0)
-        {
-            debug_assert!(U24::from(size) <= free.len());
-            self.delete_free_portion(free);
-            let allocated = free.allocate(size);
-            if free.len() > 0 {
-                // まだfree portionに空きがある場合は再利用する
-                self.add_free_portion(free);
-            }
-            self.metrics.count_allocation(allocated.len);
-            Some(allocated)
-        } else {
-            self.metrics.nospace_failures.increment();
-            None
+            .map(|p| p.0)?;
+        debug_assert!(size <= free.len());
+        self.delete_free_portion(metrics, free);
+        let allocated = free.allocate(size);
+        if free.len() > 0 {
+            // まだfree portionに空きがある場合は再利用する
+            self.add_free_portion(metrics, free);
         }
+        Some(allocated)
     }
 
-    /// 割当済みの部分領域の解放を行う.
-    ///
-    /// # 事前条件
-    ///
-    /// - `portion`は「以前に割当済み」かつ「未解放」の部分領域である
-    pub fn release(&mut self, portion: DataPortion) {
+    fn release(&mut self, metrics: &DataAllocatorMetrics, portion: DataPortion) {
         assert!(self.is_allocated_portion(&portion), "{:?}", portion);
-        self.metrics.count_releasion(portion.len);
-        let portion = self.merge_free_portions_if_possible(FreePortion::from(portion));
-        self.add_free_portion(portion);
+        let portion = self.merge_free_portions_if_possible(metrics, FreePortion::from(portion));
+        self.add_free_portion(metrics, portion);
     }
 
-    /// アロケータ用のメトリクスを返す.
-    pub fn metrics(&self) -> &DataAllocatorMetrics {
-        &self.metrics
-    }
-
-    fn add_free_portion(&mut self, portion: FreePortion) {
+    fn add_free_portion(
+        &mut self,
+        metrics: &DataAllocatorMetrics,
+        portion: FreePortion<ADDRESS_BITS>,
+    ) {
         assert!(self.size_to_free.insert(SizeBasedFreePortion(portion)));
         assert!(self.end_to_free.insert(EndBasedFreePortion(portion)));
-        self.metrics.inserted_free_portions.increment();
+        metrics.inserted_free_portions.increment();
     }
 
-    fn delete_free_portion(&mut self, portion: FreePortion) {
+    fn delete_free_portion(
+        &mut self,
+        metrics: &DataAllocatorMetrics,
+        portion: FreePortion<ADDRESS_BITS>,
+    ) {
         assert!(self.size_to_free.remove(&SizeBasedFreePortion(portion)));
         assert!(self.end_to_free.remove(&EndBasedFreePortion(portion)));
-        self.metrics.removed_free_portions.increment();
+        metrics.removed_free_portions.increment();
     }
 
     // `portion`と隣接する領域がフリーリスト内に存在する場合には、それらをまとめてしまう.
-    fn merge_free_portions_if_possible(&mut self, mut portion: FreePortion) -> FreePortion {
+    fn merge_free_portions_if_possible(
+        &mut self,
+        metrics: &DataAllocatorMetrics,
+        mut portion: FreePortion<ADDRESS_BITS>,
+    ) -> FreePortion<ADDRESS_BITS> {
         // 「`portion`の始端」に一致する終端を持つportion `prev`を探す。
         // もし存在するなら、 prev portion の並びでmerge可能である。
         // 注意: BTreeSetのgetでは、EqではなくOrd traitが用いられる。
@@ -164,7 +228,7 @@ impl DataPortionAllocator {
             if portion.checked_extend(prev.len()) {
                 // trueの場合は副作用が発生するが、次で捨てる
                 portion = FreePortion::new(prev.start(), portion.len());
-                self.delete_free_portion(prev); // prevの情報は不要なので削除
+                self.delete_free_portion(metrics, prev); // prevの情報は不要なので削除
             }
         }
 
@@ -180,7 +244,7 @@ impl DataPortionAllocator {
             // `next`については`portion.end < next.end`を満たす最小のポーションということしか分かっていない。
             // portion.end == next.start かどうかを確認する必要がある。
             if next.start() == portion.end() && portion.checked_extend(next.len()) {
-                self.delete_free_portion(next); // nextの情報は不要なので削除
+                self.delete_free_portion(metrics, next); // nextの情報は不要なので削除
             }
         }
 
@@ -335,11 +399,33 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn large_free_area_is_split_by_24bit_length() -> TestResult {
+        // データ領域のブロック数が40bitに収まる場合には、長さが24bit幅の`FreePortion`が使用される
+        let capacity = Address::from((1 << 25) + 10);
+        let mut allocator = track!(DataPortionAllocator::build(
+            metrics(capacity),
+            iter::empty()
+        ))?;
+        assert_eq!(allocator.metrics().free_list_len(), 3);
+
+        // 末尾から24bit幅の最大長ずつ切り出されるので、先頭に端数の空き領域が残る
+        let max_len = 0xFF_FFFF;
+        assert_eq!(allocator.allocate(12), Some(portion(0, 12)));
+        assert_eq!(allocator.allocate(max_len), Some(portion(12, max_len)));
+        assert_eq!(
+            allocator.allocate(max_len),
+            Some(portion(12 + max_len, max_len))
+        );
+        assert_eq!(allocator.metrics().free_list_len(), 0);
+        Ok(())
+    }
+
     fn lump_id(id: &str) -> LumpId {
         id.parse().unwrap()
     }
 
-    fn portion(offset: u32, length: u16) -> DataPortion {
+    fn portion(offset: u32, length: u32) -> DataPortion {
         DataPortion {
             start: Address::from(offset),
             len: length,
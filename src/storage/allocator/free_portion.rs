@@ -2,32 +2,49 @@
 
 use std::cmp;
 
-use super::U24;
 use crate::storage::portion::DataPortion;
 use crate::storage::Address;
 
+/// v1フォーマットのストレージ用の、開始位置に割り当てるビット幅 (長さは24bit幅となる).
+pub const NARROW_ADDRESS_BITS: u32 = 40;
+
+/// ワイドフォーマットのストレージ用の、開始位置に割り当てるビット幅 (長さは20bit幅となる).
+pub const WIDE_ADDRESS_BITS: u32 = 44;
+
 /// 空き(割当可能)領域を表現するための構造体.  
 /// 空き領域の開始位置と長さを保持しており、
 /// それぞれは `start` メソッドと `len` メソッドで取得できる。
 ///
 /// メモリを節約するために、内部的には64bit整数にエンコードして情報を保持している.
-/// その制約上、一つのインスタンスで表現可能な長さは24bit幅の範囲の制限されている.
+/// 下位`ADDRESS_BITS`bitが開始位置、残りの上位bitが長さとなるので、
+/// 一つのインスタンスで表現可能な長さは`64 - ADDRESS_BITS`bit幅の範囲に制限されている.
+///
+/// アドレスの幅を広げると、その分だけ表現可能な長さが短くなり、大きな空き領域がより多くのインスタンスに分割される.
+/// そのため、v1フォーマットのストレージでは`NARROW_ADDRESS_BITS`(長さは24bit幅)を、
+/// 44bit幅のアドレスを使用するワイドフォーマットのストレージでのみ`WIDE_ADDRESS_BITS`(長さは20bit幅)を使用する.
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
-pub struct FreePortion(u64);
+pub struct FreePortion<const ADDRESS_BITS: u32>(u64);
 
 #[allow(clippy::len_without_is_empty)]
-impl FreePortion {
+impl<const ADDRESS_BITS: u32> FreePortion<ADDRESS_BITS> {
+    /// 表現可能な長さの最大値.
+    pub const MAX_LEN: u32 = ((1u64 << (64 - ADDRESS_BITS)) - 1) as u32;
+
+    /// 表現可能な開始位置の最大値.
+    pub const MAX_START: u64 = (1 << ADDRESS_BITS) - 1;
+
     /// 空き領域の開始位置 `offset` と 長さ `len` からインスタンスを作る。
-    pub fn new(offset: Address, len: U24) -> Self {
-        // Address構造体は事実上40bitであることに注意する。
-        // lenを40bit左shiftしておくことで、`offset`と`len`の両方を
+    pub fn new(offset: Address, len: u32) -> Self {
+        // lenを`ADDRESS_BITS`bit左shiftしておくことで、`offset`と`len`の両方を
         // 64bitに詰め込む
-        FreePortion((u64::from(len) << 40) | offset.as_u64())
+        debug_assert!(offset.as_u64() <= Self::MAX_START);
+        debug_assert!(len <= Self::MAX_LEN);
+        FreePortion((u64::from(len) << ADDRESS_BITS) | offset.as_u64())
     }
 
     /// このPortionの開始位置を表す。
     pub fn start(self) -> Address {
-        Address::from_u64(self.0 & Address::MAX).unwrap()
+        Address::from_u64(self.0 & Self::MAX_START).unwrap()
     }
 
     /// このPortionの終了位置を表す。
@@ -36,17 +53,17 @@ impl FreePortion {
     }
 
     /// このPortionの長さを表す。
-    pub fn len(self) -> U24 {
-        (self.0 >> 40) as U24
+    pub fn len(self) -> u32 {
+        (self.0 >> ADDRESS_BITS) as u32
     }
 
     /// `size`分だけ長さを増やす.
     ///
-    /// ただし、それによって`U24`の範囲を超過してしまう場合には、更新は行わず、関数の結果として`false`を返す.
-    pub fn checked_extend(&mut self, size: U24) -> bool {
+    /// ただし、それによって`MAX_LEN`を超過してしまう場合には、更新は行わず、関数の結果として`false`を返す.
+    pub fn checked_extend(&mut self, size: u32) -> bool {
         let new_len = u64::from(self.len()) + u64::from(size);
-        if new_len <= 0xFF_FFFF {
-            *self = FreePortion::new(self.start(), new_len as U24);
+        if new_len <= u64::from(Self::MAX_LEN) {
+            *self = FreePortion::new(self.start(), new_len as u32);
             true
         } else {
             false
@@ -58,18 +75,15 @@ impl FreePortion {
     /// # Panics
     ///
     /// `size`が`self.len()`を超えている場合には、現在のスレッドがパニックする.
-    pub fn allocate(&mut self, size: u16) -> DataPortion {
-        assert!(U24::from(size) <= self.len());
+    pub fn allocate(&mut self, size: u32) -> DataPortion {
+        assert!(size <= self.len());
         // 自分自身を先頭からsizeでsplitする。
         // 前半をallocatedとし、後者により自分自身を更新する。
         let allocated = DataPortion {
             start: self.start(),
             len: size,
         };
-        *self = Self::new(
-            self.start() + Address::from(u32::from(size)),
-            self.len() - U24::from(size),
-        );
+        *self = Self::new(self.start() + Address::from(size), self.len() - size);
         allocated
     }
 }
@@ -78,9 +92,9 @@ impl FreePortion {
 /// すなわち、DataPortion `d` について次が成立する
 /// * `d.start == from(d).start()`
 /// * `d.len == from(d).len()`
-impl From<DataPortion> for FreePortion {
+impl<const ADDRESS_BITS: u32> From<DataPortion> for FreePortion<ADDRESS_BITS> {
     fn from(f: DataPortion) -> Self {
-        FreePortion::new(f.start, U24::from(f.len))
+        FreePortion::new(f.start, f.len)
     }
 }
 
@@ -90,13 +104,13 @@ impl From<DataPortion> for FreePortion {
 /// - 1. 空き領域のサイズ
 /// - 2. 開始位置
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct SizeBasedFreePortion(pub FreePortion);
-impl PartialOrd for SizeBasedFreePortion {
+pub struct SizeBasedFreePortion<const ADDRESS_BITS: u32>(pub FreePortion<ADDRESS_BITS>);
+impl<const ADDRESS_BITS: u32> PartialOrd for SizeBasedFreePortion<ADDRESS_BITS> {
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
-impl Ord for SizeBasedFreePortion {
+impl<const ADDRESS_BITS: u32> Ord for SizeBasedFreePortion<ADDRESS_BITS> {
     fn cmp(&self, other: &Self) -> cmp::Ordering {
         match self.0.len().cmp(&other.0.len()) {
             cmp::Ordering::Equal => self.0.start().cmp(&other.0.start()),
@@ -107,13 +121,13 @@ impl Ord for SizeBasedFreePortion {
 
 /// 比較が"終端位置が小さい順"で行われる`FreePortion`.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct EndBasedFreePortion(pub FreePortion);
-impl PartialOrd for EndBasedFreePortion {
+pub struct EndBasedFreePortion<const ADDRESS_BITS: u32>(pub FreePortion<ADDRESS_BITS>);
+impl<const ADDRESS_BITS: u32> PartialOrd for EndBasedFreePortion<ADDRESS_BITS> {
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
-impl Ord for EndBasedFreePortion {
+impl<const ADDRESS_BITS: u32> Ord for EndBasedFreePortion<ADDRESS_BITS> {
     fn cmp(&self, other: &Self) -> cmp::Ordering {
         self.0.end().cmp(&other.0.end())
     }
@@ -124,14 +138,17 @@ mod tests {
     use super::*;
     use crate::storage::Address;
 
+    type NarrowFreePortion = FreePortion<NARROW_ADDRESS_BITS>;
+    type WideFreePortion = FreePortion<WIDE_ADDRESS_BITS>;
+
     #[test]
     fn it_works() {
-        let mut p = FreePortion::new(Address::from(100), 50);
+        let mut p = WideFreePortion::new(Address::from(100), 50);
         assert_eq!(p.start(), Address::from(100));
         assert_eq!(p.end(), Address::from(150));
         assert_eq!(p.len(), 50);
 
-        assert!(!p.checked_extend(0xFF_FFFF));
+        assert!(!p.checked_extend(WideFreePortion::MAX_LEN));
         assert!(p.checked_extend(100));
         assert_eq!(p.start(), Address::from(100));
         assert_eq!(p.len(), 150);
@@ -149,10 +166,28 @@ mod tests {
         assert_eq!(p.len(), 00);
     }
 
+    #[test]
+    fn layouts_work() {
+        assert_eq!(NarrowFreePortion::MAX_LEN, 0xFF_FFFF);
+        assert_eq!(NarrowFreePortion::MAX_START, Address::MAX_V1);
+        assert_eq!(WideFreePortion::MAX_LEN, 0xF_FFFF);
+        assert_eq!(WideFreePortion::MAX_START, Address::MAX);
+
+        let start = Address::from_u64(Address::MAX_V1).unwrap();
+        let p = NarrowFreePortion::new(start, NarrowFreePortion::MAX_LEN);
+        assert_eq!(p.start(), start);
+        assert_eq!(p.len(), 0xFF_FFFF);
+
+        let start = Address::from_u64(Address::MAX).unwrap();
+        let p = WideFreePortion::new(start, WideFreePortion::MAX_LEN);
+        assert_eq!(p.start(), start);
+        assert_eq!(p.len(), 0xF_FFFF);
+    }
+
     #[test]
     #[should_panic]
     fn underflow() {
-        let mut p = FreePortion::new(Address::from(100), 50);
+        let mut p = WideFreePortion::new(Address::from(100), 50);
         p.allocate(51);
     }
 }
@@ -8,6 +8,3 @@ pub use self::data_portion_allocator::DataPortionAllocator;
 
 mod data_portion_allocator;
 mod free_portion;
-
-/// 24bit幅の整数.
-type U24 = u32;
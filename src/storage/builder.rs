@@ -1,18 +1,28 @@
 use prometrics::metrics::MetricBuilder;
+use slog::{Discard, Logger};
+use std::collections::BTreeMap;
 use std::io::SeekFrom;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
 use crate::block::BlockSize;
+use crate::lump::LumpData;
 use crate::metrics::{DataAllocatorMetrics, StorageMetrics};
 use crate::nvm::NonVolatileMemory;
 use crate::storage::allocator::DataPortionAllocator;
-use crate::storage::data_region::DataRegion;
-use crate::storage::header::FULL_HEADER_SIZE;
-use crate::storage::index::LumpIndex;
-use crate::storage::journal::{JournalRegion, JournalRegionOptions};
+use crate::storage::data_region::{packed_slot_len, DataRegion};
+use crate::storage::header::HEADER_AND_METADATA_SIZE;
+use crate::storage::index::{LumpIndex, LumpIndexBackend};
+use crate::storage::journal::{JournalRecoveryMode, JournalRegion, JournalRegionOptions};
+use crate::storage::portion::PackedPortion;
+use crate::storage::progress::{OpenProgressCallback, OpenProgressReporter};
+use crate::storage::resize;
 use crate::storage::{
-    Storage, StorageHeader, MAJOR_VERSION, MAX_DATA_REGION_SIZE, MAX_JOURNAL_REGION_SIZE,
-    MINOR_VERSION,
+    Migrator, OpenProgress, Storage, StorageHeader, StorageMetadata, StorageVersion,
+    DEFAULT_EMBED_THRESHOLD, MAJOR_VERSION, MAX_DATA_REGION_SIZE, MAX_JOURNAL_REGION_SIZE,
+    MAX_WIDE_DATA_REGION_SIZE, MIGRATION_MARKER_KEY, MINOR_VERSION, RESIZE_MARKER_KEY,
+    WIDE_MAJOR_VERSION, WIDE_MINOR_VERSION,
 };
 use crate::{ErrorKind, Result};
 
@@ -23,6 +33,15 @@ pub struct StorageBuilder {
     instance_uuid: Option<Uuid>,
     journal: JournalRegionOptions,
     metrics: MetricBuilder,
+    logger: Logger,
+    redundant_headers: bool,
+    index_backend: LumpIndexBackend,
+    open_progress: Option<OpenProgressCallback>,
+    embed_threshold: usize,
+    pack_threshold: usize,
+    wide_format: bool,
+    label: String,
+    metadata_properties: BTreeMap<String, String>,
 }
 impl StorageBuilder {
     /// 新しい`StorageBuilder`インスタンスを生成する.
@@ -32,6 +51,15 @@ impl StorageBuilder {
             instance_uuid: None,
             journal: JournalRegionOptions::default(),
             metrics: MetricBuilder::new(),
+            logger: Logger::root(Discard, o!()),
+            redundant_headers: false,
+            index_backend: LumpIndexBackend::default(),
+            open_progress: None,
+            embed_threshold: DEFAULT_EMBED_THRESHOLD,
+            pack_threshold: 0,
+            wide_format: false,
+            label: String::new(),
+            metadata_properties: BTreeMap::new(),
         }
     }
 
@@ -91,6 +119,139 @@ impl StorageBuilder {
         self
     }
 
+    /// 物理デバイスへのジャーナルの同期の最大遅延時間、を設定する.
+    ///
+    /// ジャーナルに未同期のレコードが追加されてから、この時間が経過すると、
+    /// `journal_sync_interval`で指定された数に達していなくても同期命令が発行される.
+    /// 負荷が低く、レコードの追加が稀な場合でも、未同期のレコードが無期限に残らないようにするためのもの.
+    ///
+    /// 期限の判定はレコードの追加時および`Storage::journal_sync_if_overdue`の呼び出し時に行われる.
+    /// `Device`経由で使用している場合には、デバイススレッドが期限に合わせて後者を呼び出す.
+    ///
+    /// デフォルト値は`None`(i.e., 時間による同期は行わない).
+    pub fn journal_sync_max_delay(&mut self, delay: Duration) -> &mut Self {
+        self.journal.sync_max_delay = Some(delay);
+        self
+    }
+
+    /// ストレージのオープン時に、ジャーナル領域から不正なレコードが見つかった場合の挙動を設定する.
+    ///
+    /// デフォルト値は`JournalRecoveryMode::Strict`.
+    ///
+    /// 各モードの詳細は`JournalRecoveryMode`のドキュメントを参照のこと.
+    pub fn journal_recovery_mode(&mut self, mode: JournalRecoveryMode) -> &mut Self {
+        self.journal.recovery_mode = mode;
+        self
+    }
+
+    /// ヘッダの冗長化を有効にするかどうかを設定する.
+    ///
+    /// 有効にした場合には、ストレージの末尾にヘッダのバックアップが書き込まれ、
+    /// ジャーナル領域のヘッダもA/Bの二つのスロットに交互に書き込まれるようになる.
+    /// オープン時に一方のヘッダが壊れていた場合には、もう一方の有効なヘッダが使用され、
+    /// 壊れていた方は修復される(読み込み専用の場合を除く).
+    ///
+    /// デフォルト値は`false`.
+    ///
+    /// なお、これはストレージの新規作成時にのみ反映される値であり、
+    /// 既存のストレージを開く場合には、作成時に指定された値が使用される.
+    pub fn redundant_headers(&mut self, enabled: bool) -> &mut Self {
+        self.redundant_headers = enabled;
+        self
+    }
+
+    /// lumpのインデックス(メモリ上のデータ構造)の実装を指定する.
+    ///
+    /// デフォルト値は`LumpIndexBackend::BTree`.
+    ///
+    /// 小さなlumpを大量に格納する場合には、`LumpIndexBackend::Compact`を指定することで、
+    /// メモリ使用量を削減することができる.
+    /// インデックスは永続化されないため、この値はオープンの度に自由に変更可能.
+    pub fn lump_index_backend(&mut self, backend: LumpIndexBackend) -> &mut Self {
+        self.index_backend = backend;
+        self
+    }
+
+    /// 保存先の自動選択(`LumpData::new_auto`)において、ジャーナル領域に埋め込むデータサイズの上限を設定する.
+    ///
+    /// この値以下のサイズのデータはジャーナル領域に埋め込まれ、それより大きなデータはデータ領域に保存される.
+    /// `LumpData::MAX_EMBEDDED_SIZE`より大きな値が指定された場合には、オープン時にエラーとなる.
+    ///
+    /// デフォルト値は`DEFAULT_EMBED_THRESHOLD`(2048バイト).
+    pub fn embed_threshold(&mut self, bytes: usize) -> &mut Self {
+        self.embed_threshold = bytes;
+        self
+    }
+
+    /// データ領域に保存されるlumpを、パック(複数の小さなlumpで共有されるブロック)に詰め込むサイズの上限を設定する.
+    ///
+    /// この値以下のサイズのデータは、専用のブロックを割り当てられる代わりに、パック内に格納される.
+    /// ブロックサイズに比べて十分に小さいlumpを大量に保存する場合に、データ領域の無駄を大きく削減できる.
+    ///
+    /// パックは、全てのメンバーが削除された時点、ないし`Storage::compact_packs`による詰め直しによって解放される.
+    ///
+    /// デフォルト値は`0`(i.e., パッキングは無効).
+    /// パック内のスロットが一ブロック(ないし4088バイト)に収まらない値が指定された場合には、オープン時にエラーとなる.
+    ///
+    /// # 注意
+    ///
+    /// パッキングを有効にしてオープン(ないし生成)した時点で、ヘッダにその旨のフラグ(`StorageHeader::packed_lumps`)が記録される.
+    /// パッキングを使用して保存されたlumpを含むストレージは、パッキングに未対応の古いバージョンでは開くことができない.
+    /// なお、この値はオープンの度に変更可能で、既にパック内に格納されているlumpは、値に関わらず引き続き読み込み可能.
+    ///
+    /// 書き込み中のパックはメモリ上に保持され、満杯になった時点ないしジャーナルの同期等の時点で一度だけNVMに書き出される.
+    /// そのため、パック内のlumpのPUT操作は、その時点までジャーナルには記録されない.
+    pub fn pack_threshold(&mut self, bytes: usize) -> &mut Self {
+        self.pack_threshold = bytes;
+        self
+    }
+
+    /// ワイドフォーマット(v2)でストレージを生成するかどうかを設定する.
+    ///
+    /// ワイドフォーマットでは、v1に比べて以下の制限が緩和されている:
+    ///
+    /// - ブロックサイズ: 最大`BlockSize::MAX`(v1では`u16`の範囲内)
+    /// - lumpのサイズ: 最大`LumpData::MAX_WIDE_SIZE`(v1では`LumpData::MAX_SIZE`)
+    /// - データ領域のサイズ: 最大`MAX_WIDE_DATA_REGION_SIZE`(v1では`MAX_DATA_REGION_SIZE`)
+    ///
+    /// ここで指定した値は、ストレージの生成時にのみ使われる.
+    /// オープン時には、ヘッダに格納されているバージョンに従って、v1およびv2のいずれのストレージも扱うことができる.
+    /// 既存のv1のストレージは`migrate_to_wide_format`を使って移行可能.
+    ///
+    /// デフォルト値は`false`.
+    ///
+    /// # 注意
+    ///
+    /// ワイドフォーマットのストレージは、それに未対応の古いバージョンでは開くことができない.
+    pub fn wide_format(&mut self, enabled: bool) -> &mut Self {
+        self.wide_format = enabled;
+        self
+    }
+
+    /// ストレージのヘッダに格納するラベルを設定する.
+    ///
+    /// ここで指定した値は、ストレージの生成時にのみ使われる.
+    /// 既存のストレージのラベルは`Storage::set_label`で変更可能.
+    ///
+    /// デフォルト値は空文字列.
+    pub fn label(&mut self, label: &str) -> &mut Self {
+        self.label = label.to_owned();
+        self
+    }
+
+    /// ストレージのヘッダに格納する、任意のキー・バリューを追加する.
+    ///
+    /// ここで指定した値は、ストレージの生成時にのみ使われる.
+    /// 既存のストレージの値は`Storage::update_metadata`で変更可能.
+    ///
+    /// なお、ラベル等も含めたメタデータ全体のサイズが`StorageMetadata::MAX_SIZE`を超える場合には、
+    /// ストレージの生成時にエラーとなる.
+    pub fn metadata_property(&mut self, key: &str, value: &str) -> &mut Self {
+        self.metadata_properties
+            .insert(key.to_owned(), value.to_owned());
+        self
+    }
+
     /// ストレージのブロックサイズを指定する.
     ///
     /// ここで指定した値は、ストレージの生成時にのみ使われる.
@@ -115,6 +276,28 @@ impl StorageBuilder {
         self
     }
 
+    /// ストレージ用の logger を登録する.
+    ///
+    /// デフォルトでは、ログは一切出力されない.
+    pub fn logger(&mut self, logger: Logger) -> &mut Self {
+        self.logger = logger;
+        self
+    }
+
+    /// ストレージのオープン処理の進捗を受け取るコールバックを登録する.
+    ///
+    /// コールバックは、オープン処理のフェーズが切り替わる度と、
+    /// ジャーナルの再生中に一定間隔(一秒程度)で呼び出される.
+    ///
+    /// なお、コールバックの登録の有無に関わらず、進捗は`logger`にもINFOレベルで出力される.
+    pub fn open_progress_callback<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&OpenProgress) + Send + Sync + 'static,
+    {
+        self.open_progress = Some(OpenProgressCallback(Arc::new(f)));
+        self
+    }
+
     /// 新規にストレージを生成する.
     pub fn create<N>(&self, mut nvm: N) -> Result<Storage<N>>
     where
@@ -136,70 +319,279 @@ impl StorageBuilder {
             track!(header.write_header_region_to(&mut temp_buf))?;
 
             // ジャーナル領域を初期化する
-            track!(JournalRegion::<N>::initialize(temp_buf, storage_block_size))?;
+            track!(JournalRegion::<N>::initialize(
+                temp_buf,
+                storage_block_size,
+                header.redundant_headers,
+                0
+            ))?;
 
             Ok(())
         }))?;
+        track!(header.write_backup_region(&mut nvm))?;
         track!(nvm.sync())?;
 
         track!(self.open(nvm))
     }
 
     /// 既に存在するストレージをオープンする.
-    pub fn open<N>(&self, mut nvm: N) -> Result<Storage<N>>
+    ///
+    /// オフラインでの移行(`Migrator`)が中断されたままのストレージが指定された場合には、
+    /// `ErrorKind::InvalidInput`エラーが返される.
+    pub fn open<N>(&self, nvm: N) -> Result<Storage<N>>
+    where
+        N: NonVolatileMemory,
+    {
+        track!(self.open_impl(nvm, false))
+    }
+
+    /// 既に存在するストレージを読み込み専用でオープンする.
+    ///
+    /// `open`とは異なり、ストレージのマイナーバージョンが古い場合でもヘッダの更新は行われず、
+    /// オープン後にヘッダやジャーナル領域への書き込みが行われることもない.
+    ///
+    /// 返されたストレージに対する更新系操作(e.g., `Storage::put`)は、
+    /// 全て`ErrorKind::ReadOnly`エラーとなる.
+    ///
+    /// 他のプロセスが使用中のファイルを覗きたい場合には、
+    /// `FileNvmBuilder::read_only`と組み合わせて使用すること.
+    pub fn open_read_only<N>(&self, nvm: N) -> Result<Storage<N>>
+    where
+        N: NonVolatileMemory,
+    {
+        track!(self.open_impl(nvm, true))
+    }
+
+    /// 既存のv1のストレージを、ワイドフォーマット(v2)に移行する.
+    ///
+    /// v1とv2では、ジャーナル領域およびデータ領域の形式には互換性があるので、
+    /// 移行時にはヘッダ(およびバックアップヘッダ)の書き換えのみが行われる.
+    /// そのため、ストレージのサイズに関わらず、移行は一瞬で完了する.
+    ///
+    /// `Migrator`に`StorageVersion::latest_wide()`を指定して移行した場合と等価.
+    ///
+    /// 移行はオフラインで行う必要がある(i.e., 他からストレージが使用されていないこと).
+    /// 移行後のストレージは、ワイドフォーマットに未対応の古いバージョンでは開くことができない.
+    ///
+    /// 既にワイドフォーマットのストレージが指定された場合には、何も行わない.
+    ///
+    /// 結果として、移行後のヘッダが返される.
+    pub fn migrate_to_wide_format<N>(&self, mut nvm: N) -> Result<StorageHeader>
+    where
+        N: NonVolatileMemory,
+    {
+        let (_, header) = track!(Migrator::new()
+            .target(StorageVersion::latest_wide())
+            .logger(self.logger.clone())
+            .migrate_nvm(&mut nvm))?;
+        Ok(header)
+    }
+
+    /// 既存のストレージのジャーナル領域のサイズを変更する.
+    ///
+    /// ジャーナル領域とデータ領域の合計サイズは変わらないので、
+    /// ジャーナル領域を拡張した場合にはその分だけデータ領域が縮小し、縮小した場合にはその逆となる.
+    /// `journal_region_size`はブロック境界に切り上げられる.
+    ///
+    /// 具体的には、以下の処理が行われる:
+    ///
+    /// - データ領域内の各lumpの位置を、新しいデータ領域の先頭からの相対位置に付け替える
+    ///   - 拡張後のジャーナル領域と重なるlumpは、データ領域内の空き領域に移動される
+    /// - 生存中のlumpのレコード群のみを含む、新しいジャーナル領域のイメージを作成する
+    ///   - LSNは、変更前のジャーナルの末尾から引き継がれる
+    ///   - イメージは、まず新旧いずれのレイアウトにおいても空き領域となっている位置(ステージング領域)に書き込まれる
+    /// - 新しいヘッダに、ステージング領域の位置を示すマーカー(`RESIZE_MARKER_KEY`)を付与して書き込む
+    /// - イメージをジャーナル領域に複写し、マーカーを取り除いたヘッダ(およびバックアップヘッダ)を書き込む
+    ///
+    /// 以下の場合には、ストレージを一切変更せずに`ErrorKind::InvalidInput`エラーが返される:
+    ///
+    /// - 生存中のレコード群が、新しいリングバッファの半分に収まらない
+    /// - 移動が必要なlump(ないしジャーナル領域のイメージ)を格納するだけの空き領域が、新しいデータ領域に存在しない
+    ///   (この場合は`ErrorKind::StorageFull`となる)
+    ///
+    /// サイズ変更はオフラインで行う必要がある(i.e., 他からストレージが使用されていないこと).
+    ///
+    /// マーカー付きのヘッダが書き込まれる前にクラッシュした場合には、ストレージは変更前の状態のまま残る.
+    /// それ以降にクラッシュした場合には、ストレージはオープン不可となるが、
+    /// 再度このメソッドを呼び出すことで、中断されたサイズ変更を完了させることができる
+    /// (その後に、`journal_region_size`へのサイズ変更が改めて行われる).
+    ///
+    /// 結果として、変更後のヘッダが返される.
+    pub fn resize_journal_region<N>(
+        &self,
+        nvm: N,
+        journal_region_size: u64,
+    ) -> Result<StorageHeader>
+    where
+        N: NonVolatileMemory,
+    {
+        track!(resize::resize_journal_region(
+            self,
+            &self.logger,
+            nvm,
+            journal_region_size
+        ))
+    }
+
+    /// ヘッダを読み込む.
+    ///
+    /// 先頭のヘッダが壊れている場合には、末尾のバックアップヘッダを探す.
+    /// 結果の二番目の要素は、先頭のヘッダが正常かどうか.
+    pub(crate) fn read_header<N>(&self, nvm: &mut N) -> Result<(StorageHeader, bool)>
     where
         N: NonVolatileMemory,
     {
         track_io!(nvm.seek(SeekFrom::Start(0)))?;
 
-        // ヘッダを読み込む(アライメントを保証するためにバッファを経由)
-        let buf = track!(nvm.aligned_read_bytes(FULL_HEADER_SIZE as usize))?;
-        let mut header = track!(StorageHeader::read_from(&buf[..]))?;
+        // アライメントを保証するためにバッファを経由する
+        let result = nvm
+            .aligned_read_bytes(HEADER_AND_METADATA_SIZE as usize)
+            .and_then(|buf| StorageHeader::read_from(&buf[..]));
+        match result {
+            Ok(header) => Ok((header, true)),
+            Err(e) => {
+                let header = match StorageHeader::read_backup_from(nvm) {
+                    Ok(header) => header,
+                    Err(_) => return Err(track!(e)),
+                };
+                warn!(
+                    self.logger,
+                    "The primary storage header is broken; the backup one is used instead";
+                    "error" => %e
+                );
+                Ok((header, false))
+            }
+        }
+    }
 
-        // ストレージのマイナーバージョンが古い場合には、最新に更新する
-        if header.minor_version < MINOR_VERSION {
-            header.minor_version = MINOR_VERSION;
-
-            track_io!(nvm.seek(SeekFrom::Start(0)))?;
-            track!(nvm.aligned_write_all(|temp_buf| {
-                track!(header.write_header_region_to(temp_buf))?;
-                Ok(())
-            }))?;
+    fn open_impl<N>(&self, mut nvm: N, read_only: bool) -> Result<Storage<N>>
+    where
+        N: NonVolatileMemory,
+    {
+        track_assert!(
+            self.embed_threshold <= LumpData::MAX_EMBEDDED_SIZE,
+            ErrorKind::InvalidInput,
+            "Too large embed threshold: {}",
+            self.embed_threshold
+        );
+        let (mut header, mut primary_is_valid) = track!(self.read_header(&mut nvm))?;
+        if let Some(marker) = header.metadata.properties.get(MIGRATION_MARKER_KEY) {
+            track_panic!(
+                ErrorKind::InvalidInput,
+                "An offline migration is in progress (resume it by `Migrator`): {}",
+                marker
+            );
+        }
+        if let Some(marker) = header.metadata.properties.get(RESIZE_MARKER_KEY) {
+            track_panic!(
+                ErrorKind::InvalidInput,
+                "A journal resize is in progress (resume it by `StorageBuilder::resize_journal_region`): {}",
+                marker
+            );
         }
 
         // `nvm`がストレージが採用しているブロックサイズに対応可能かを確認
         //
         // ヘッダに記載のストレージのブロックサイズが、NVMのブロック境界に揃っている場合には、
         // 完全一致ではなくても許容する
+        //
+        // 以降の検証を含めて、ヘッダを書き換える前に行う (検証に失敗した場合には、NVMには何も書き込まない)
         track_assert!(
             header.block_size.contains(nvm.block_size()),
             ErrorKind::InvalidInput
         );
-        let mut journal_options = self.journal.clone();
-        journal_options.block_size = header.block_size;
+        if self.pack_threshold > 0 {
+            let slot_len = packed_slot_len(self.pack_threshold);
+            track_assert!(
+                slot_len <= usize::from(PackedPortion::MAX_LEN)
+                    && slot_len <= header.block_size.as_u32() as usize,
+                ErrorKind::InvalidInput,
+                "Too large pack threshold: {}",
+                self.pack_threshold
+            );
+        }
 
         // UUIDをチェック
         if let Some(expected_uuid) = self.instance_uuid {
             track_assert_eq!(header.instance_uuid, expected_uuid, ErrorKind::InvalidInput);
         }
 
+        // ストレージのマイナーバージョンが古い場合には、最新に更新する
+        // (読み込み専用の場合には、何も書き込まない)
+        let latest_minor_version = if header.is_wide_format() {
+            WIDE_MINOR_VERSION
+        } else {
+            MINOR_VERSION
+        };
+        if header.minor_version < latest_minor_version && !read_only {
+            header.minor_version = latest_minor_version;
+            primary_is_valid = false;
+        }
+
+        // パッキングを初めて有効にする場合には、パック用のレコードを書き込む前に、ヘッダにその旨を記録する
+        // (読み込み専用の場合には、パッキングは使用されない)
+        if self.pack_threshold > 0 && !header.packed_lumps && !read_only {
+            header.packed_lumps = true;
+            primary_is_valid = false;
+        }
+
+        // 古い(あるいは壊れた)ヘッダを修復する
+        //
+        // 別のデバイスを誤って開いた場合等に書き換えてしまわないように、修復は全ての検証を通過した後に行う
+        if !read_only {
+            let mut repaired = false;
+            if !primary_is_valid {
+                track!(header.write_primary_region(&mut nvm))?;
+                repaired = true;
+            }
+            if !header.has_valid_backup(&mut nvm) {
+                warn!(
+                    self.logger,
+                    "The backup storage header is broken; repairing it"
+                );
+                track!(header.write_backup_region(&mut nvm))?;
+                repaired = true;
+            }
+            if repaired {
+                track!(nvm.sync())?;
+            }
+        }
+
+        let mut journal_options = self.journal.clone();
+        journal_options.block_size = header.block_size;
+        journal_options.read_only = read_only;
+        journal_options.redundant_header = header.redundant_headers;
+
         // ジャーナルからインデックスとアロケータの状態を復元する
-        let mut lump_index = LumpIndex::new();
-        let (journal_nvm, data_nvm) = track!(header.split_regions(nvm))?;
+        let mut progress =
+            OpenProgressReporter::new(self.logger.clone(), self.open_progress.clone());
+        let mut lump_index = LumpIndex::with_backend(self.index_backend);
+        let regions = track!(header.split_regions(nvm))?;
         let journal_region = track!(JournalRegion::open(
-            journal_nvm,
+            regions.journal,
             &mut lump_index,
             &self.metrics,
+            &self.logger,
+            &mut progress,
             journal_options
         ))?;
 
+        track_assert!(
+            header.packed_lumps || lump_index.packed_portions().next().is_none(),
+            ErrorKind::StorageCorrupted,
+            "Packed lump records are found in a storage without the packed-lumps flag"
+        );
+
+        progress.start_rebuild_allocator();
         let allocator = track!(DataPortionAllocator::build(
             DataAllocatorMetrics::new(&self.metrics, header.data_region_size, header.block_size),
             lump_index.data_portions(),
         ))?;
 
         // データ領域を準備
-        let data_region = DataRegion::new(&self.metrics, allocator, data_nvm);
+        let mut data_region = DataRegion::new(&self.metrics, allocator, regions.data);
+        data_region.set_max_portion_len(header.max_portion_len());
+        data_region.restore_packs(lump_index.packed_portions().map(|(_, p)| p));
 
         let metrics = StorageMetrics::new(
             &self.metrics,
@@ -208,18 +600,32 @@ impl StorageBuilder {
             data_region.metrics().clone(),
         );
         metrics.put_lumps_at_starting.add_u64(lump_index.len());
-        Ok(Storage::new(
+        lump_index.set_memory_usage_gauge(metrics.index_memory_bytes.clone());
+        progress.finish(&metrics);
+        if journal_region.last_shutdown_clean() {
+            metrics.last_shutdown_clean.set(1.0);
+        }
+        let mut storage = Storage::new(
             header,
+            regions.header,
+            regions.backup,
             journal_region,
             data_region,
             lump_index,
             metrics,
-        ))
+        );
+        storage.read_only = read_only;
+        storage.embed_threshold = self.embed_threshold;
+        if storage.header.packed_lumps {
+            storage.pack_threshold = self.pack_threshold;
+        }
+        Ok(storage)
     }
 
     fn make_header(&self, capacity: u64, block_size: BlockSize) -> Result<StorageHeader> {
+        let header_regions = if self.redundant_headers { 2 } else { 1 };
         let journal_and_data_region_size = track_assert_some!(
-            capacity.checked_sub(StorageHeader::calc_region_size(block_size)),
+            capacity.checked_sub(StorageHeader::calc_region_size(block_size) * header_regions),
             ErrorKind::InvalidInput,
             "Too small capacity: {}",
             capacity
@@ -243,10 +649,27 @@ impl StorageBuilder {
             self.journal_region_ratio
         );
 
+        let (major_version, minor_version, max_data_region_size) = if self.wide_format {
+            (
+                WIDE_MAJOR_VERSION,
+                WIDE_MINOR_VERSION,
+                MAX_WIDE_DATA_REGION_SIZE,
+            )
+        } else {
+            // v1のヘッダには、ブロックサイズを`u16`で格納する
+            track_assert!(
+                block_size.as_u32() <= u32::from(u16::MAX),
+                ErrorKind::InvalidInput,
+                "Too large block size for the v1 format: {} (use the wide format instead)",
+                block_size.as_u32()
+            );
+            (MAJOR_VERSION, MINOR_VERSION, MAX_DATA_REGION_SIZE)
+        };
+
         let data_region_size =
             block_size.floor_align(journal_and_data_region_size - journal_region_size);
         track_assert!(
-            data_region_size <= MAX_DATA_REGION_SIZE,
+            data_region_size <= max_data_region_size,
             ErrorKind::InvalidInput,
             "Too large data region: {} (capacity={}, journal_region_ratio={})",
             data_region_size,
@@ -254,13 +677,35 @@ impl StorageBuilder {
             self.journal_region_ratio
         );
 
+        // ヘッダには秒単位で保存されるので、それに合わせておく
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| UNIX_EPOCH + Duration::from_secs(d.as_secs()))
+            .ok();
+        let metadata = StorageMetadata {
+            label: self.label.clone(),
+            created_at,
+            library_version: env!("CARGO_PKG_VERSION").to_owned(),
+            properties: self.metadata_properties.clone(),
+        };
+        track_assert!(
+            metadata.encoded_size() <= StorageMetadata::MAX_SIZE,
+            ErrorKind::InvalidInput,
+            "Too large storage metadata: {} bytes (max={})",
+            metadata.encoded_size(),
+            StorageMetadata::MAX_SIZE
+        );
+
         Ok(StorageHeader {
-            major_version: MAJOR_VERSION,
-            minor_version: MINOR_VERSION,
+            major_version,
+            minor_version,
             instance_uuid: self.instance_uuid.unwrap_or_else(Uuid::new_v4),
             block_size,
             journal_region_size,
             data_region_size,
+            redundant_headers: self.redundant_headers,
+            packed_lumps: self.pack_threshold > 0,
+            metadata,
         })
     }
 }
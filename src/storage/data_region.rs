@@ -1,17 +1,31 @@
 use byteorder::{BigEndian, ByteOrder};
 use prometrics::metrics::MetricBuilder;
-use std::io::{Read, SeekFrom, Write};
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 use crate::block::{AlignedBytes, BlockSize};
 use crate::metrics::DataRegionMetrics;
-use crate::nvm::NonVolatileMemory;
+use crate::nvm::{NonVolatileMemory, PositionalRead, SyncLevel};
 use crate::storage::allocator::DataPortionAllocator;
-use crate::storage::portion::DataPortion;
+use crate::storage::portion::{DataPortion, PackedPortion};
+use crate::storage::Address;
 use crate::{ErrorKind, Result};
 
 /// 各データの末尾に埋め込まれる情報のサイズ.
 const LUMP_DATA_TRAILER_SIZE: usize = 2;
 
+/// ブロックサイズが64KiBを超える場合に、各データの末尾に埋め込まれる情報のサイズ.
+///
+/// パディングの長さが`u16`の範囲に収まらない可能性があるため、拡張されている.
+const WIDE_LUMP_DATA_TRAILER_SIZE: usize = 4;
+
+/// パックとして使用される、ブロック内の先頭部分のサイズの上限.
+const MAX_PACK_SIZE: usize = 0x10000;
+
+/// パック内の各スロットの末尾に埋め込まれる情報のサイズ.
+const PACKED_SLOT_TRAILER_SIZE: usize = 1;
+
 /// ランプのデータを格納するための領域.
 #[derive(Debug)]
 pub struct DataRegion<N> {
@@ -19,6 +33,11 @@ pub struct DataRegion<N> {
     nvm: N,
     block_size: BlockSize,
     metrics: DataRegionMetrics,
+    packs: HashMap<Address, PackState>,
+    open_pack: Option<OpenPack>,
+    max_portion_len: u32,
+    pins: Option<Arc<PortionPins>>,
+    deferred_releases: Vec<DataPortion>,
 }
 impl<N> DataRegion<N>
 where
@@ -34,7 +53,34 @@ where
             nvm,
             block_size,
             metrics: DataRegionMetrics::new(metric_builder, capacity, allocator_metrics),
+            packs: HashMap::new(),
+            open_pack: None,
+            max_portion_len: DataPortion::MAX_LEN_V1,
+            pins: None,
+            deferred_releases: Vec::new(),
+        }
+    }
+
+    /// 一つのデータが占有可能な部分領域の長さの上限(ブロック単位)を設定する.
+    ///
+    /// デフォルト値は`DataPortion::MAX_LEN_V1`.
+    pub fn set_max_portion_len(&mut self, len: u32) {
+        self.max_portion_len = len;
+    }
+
+    /// パック内に格納されている部分領域群から、各パックの使用状況を復元する.
+    ///
+    /// パック自体が占有する部分領域は、アロケータの構築時に割当済みとして扱われている必要がある.
+    pub fn restore_packs<I>(&mut self, portions: I)
+    where
+        I: Iterator<Item = PackedPortion>,
+    {
+        for portion in portions {
+            let state = self.packs.entry(portion.pack).or_default();
+            state.members += 1;
+            state.live_bytes += u32::from(portion.len);
         }
+        self.metrics.packs.set(self.packs.len() as f64);
     }
 
     /// データ領域のメトリクスを返す.
@@ -49,17 +95,25 @@ where
     ///
     /// 成功した場合には、格納場所が返される.
     pub fn put(&mut self, data: &DataRegionLumpData) -> Result<DataPortion> {
+        self.release_deferred();
         track_assert!(
             data.block_size().contains(self.block_size),
             ErrorKind::InvalidInput
         );
-        let block_size = self.block_count(data.as_external_bytes().len() as u32) as u16;
-        let portion =
-            track_assert_some!(self.allocator.allocate(block_size), ErrorKind::StorageFull);
+        let block_count = self.block_count(data.as_external_bytes().len() as u64);
+        track_assert!(
+            block_count <= u64::from(self.max_portion_len),
+            ErrorKind::InvalidInput,
+            "Too large lump data: {} bytes",
+            data.as_bytes().len()
+        );
+        let portion = track_assert_some!(
+            self.allocator.allocate(block_count as u32),
+            ErrorKind::StorageFull
+        );
 
         let (offset, _size) = self.real_portion(&portion);
-        track_io!(self.nvm.seek(SeekFrom::Start(offset)))?;
-        track!(data.write_to(&mut self.nvm))?;
+        track!(self.nvm.write_at(data.as_external_bytes(), offset))?;
 
         // NOTE:
         // この後にジャーナルへの書き込みが行われ、
@@ -70,16 +124,38 @@ where
         Ok(portion)
     }
 
+    /// データ領域への書き込みを、指定された強度で同期する.
+    pub fn sync_with(&mut self, level: SyncLevel) -> Result<()> {
+        track!(self.nvm.sync_with(level))
+    }
+
+    /// 並列読み込み用のハンドルを生成する.
+    ///
+    /// NVMが並列読み込みに対応していない場合には`Ok(None)`が返される.
+    ///
+    /// 一度でもハンドルを生成した後は、読み込み中の部分領域の解放は、その読み込みが完了するまで延期される.
+    pub fn reader(&mut self) -> Result<Option<DataRegionReader>> {
+        let nvm = match track!(self.nvm.reader())? {
+            None => return Ok(None),
+            Some(nvm) => nvm,
+        };
+        let pins = self.pins.get_or_insert_with(Default::default).clone();
+        pins.set_open_pack(self.open_pack.as_ref().map(|p| p.portion.start));
+        Ok(Some(DataRegionReader {
+            nvm,
+            block_size: self.block_size,
+            pins,
+        }))
+    }
+
     /// 指定された領域に格納されているデータを取得する.
     ///
     /// `portion`で指定された領域が有効かどうかの判定は、このメソッド内では行われない.
     pub fn get(&mut self, portion: DataPortion) -> Result<DataRegionLumpData> {
         let (offset, size) = self.real_portion(&portion);
-        track_io!(self.nvm.seek(SeekFrom::Start(offset)))?;
-
-        let buf = AlignedBytes::new(size, self.block_size);
-        let data = track!(DataRegionLumpData::read_from(&mut self.nvm, buf))?;
-        Ok(data)
+        let mut buf = AlignedBytes::new(size, self.block_size);
+        track!(self.nvm.read_at(&mut buf, offset))?;
+        track!(DataRegionLumpData::from_external_bytes(buf))
     }
 
     /// 指定された領域に格納されているデータを削除する.
@@ -89,22 +165,399 @@ where
     /// `portion`で未割当の領域が指定された場合には、
     /// 現在の実行スレッドがパニックする.
     pub fn delete(&mut self, portion: DataPortion) {
-        self.allocator.release(portion);
+        self.release(portion);
+    }
+
+    /// 小さなデータを、他のデータと共有されるパック内に格納する.
+    ///
+    /// パックは一ブロック分の部分領域であり、新規のデータは常に現在"書き込み中"のパックの末尾に追加される.
+    /// 書き込み中のパックが存在しない場合には、新しいパックが割り当てられる.
+    /// もしそのための空きスペースがない場合には、`ErrorKind::StorageFull`エラーが返される.
+    ///
+    /// 書き込み中のパックはメモリ上にのみ存在し、`seal_pack`が呼ばれた時点で一度だけNVMに書き出される.
+    /// 書き出し後のパックに追記されることはないので、コミット済みのデータが上書きされることもない.
+    /// 呼び出し側は、格納したデータをジャーナルに記録する前に`seal_pack`を呼び出す必要がある.
+    ///
+    /// 書き込み中のパックに`data`を格納する空きがない場合(i.e., `needs_seal`が`true`を返す場合)には、
+    /// `ErrorKind::InconsistentState`エラーが返される.
+    ///
+    /// `data`を格納するスロットがパックに収まらない場合には、`ErrorKind::InvalidInput`エラーが返される.
+    ///
+    /// パックの位置は`Address::MAX_V1`以下である必要があるため、
+    /// それを超える位置にしか空きがない場合には`Ok(None)`が返される.
+    /// その場合、呼び出し側は通常の`put`メソッドを使用する必要がある.
+    pub fn put_packed(&mut self, data: &[u8]) -> Result<Option<PackedPortion>> {
+        self.release_deferred();
+        let slot_len = packed_slot_len(data.len());
+        let pack_size = self.pack_size();
+        track_assert!(
+            slot_len <= cmp::min(usize::from(PackedPortion::MAX_LEN), pack_size),
+            ErrorKind::InvalidInput;
+            data.len()
+        );
+        track_assert!(!self.needs_seal(data.len()), ErrorKind::InconsistentState);
+
+        if self.open_pack.is_none() {
+            let portion = track_assert_some!(self.allocator.allocate(1), ErrorKind::StorageFull);
+            if portion.start.as_u64() > Address::MAX_V1 {
+                self.allocator.release(portion);
+                return Ok(None);
+            }
+            let mut bytes = AlignedBytes::new(self.block_size.as_u32() as usize, self.block_size);
+            bytes.align();
+            self.packs.insert(portion.start, PackState::default());
+            self.metrics.packs.set(self.packs.len() as f64);
+            self.open_pack = Some(OpenPack {
+                portion,
+                bytes,
+                used: 0,
+            });
+            if let Some(ref pins) = self.pins {
+                pins.set_open_pack(Some(portion.start));
+            }
+        }
+
+        let pack_portion = self.open_pack.as_ref().expect("Never fails").portion;
+        let open_pack = self.open_pack.as_mut().expect("Never fails");
+        let offset = open_pack.used;
+        {
+            let slot = &mut open_pack.bytes[offset..offset + slot_len];
+            let padding_len = slot_len - data.len() - PACKED_SLOT_TRAILER_SIZE;
+            slot[..data.len()].copy_from_slice(data);
+            for b in &mut slot[data.len()..] {
+                *b = 0;
+            }
+            slot[slot_len - PACKED_SLOT_TRAILER_SIZE] = padding_len as u8;
+        }
+        open_pack.used += slot_len;
+
+        let portion = PackedPortion {
+            pack: pack_portion.start,
+            offset: offset as u16,
+            len: slot_len as u16,
+        };
+        let state = self.packs.get_mut(&portion.pack).expect("Never fails");
+        state.members += 1;
+        state.live_bytes += slot_len as u32;
+        Ok(Some(portion))
+    }
+
+    /// 書き込み中のパックが存在するかどうかを判定する.
+    pub fn has_open_pack(&self) -> bool {
+        self.open_pack.is_some()
+    }
+
+    /// 書き込み中のパックに`data_size`バイトのデータを格納する空きがないかどうかを判定する.
+    ///
+    /// `true`の場合には、`put_packed`の前に`seal_pack`を呼び出す必要がある.
+    pub fn needs_seal(&self, data_size: usize) -> bool {
+        let slot_len = packed_slot_len(data_size);
+        let pack_size = self.pack_size();
+        self.open_pack
+            .as_ref()
+            .is_some_and(|p| p.used + slot_len > pack_size)
+    }
+
+    /// 書き込み中のパックをNVMに書き出して、以後は追記が行われないようにする.
+    ///
+    /// 書き込み中のパックが存在しない場合には何も行わない.
+    pub fn seal_pack(&mut self) -> Result<()> {
+        let open_pack = match self.open_pack.take() {
+            None => return Ok(()),
+            Some(open_pack) => open_pack,
+        };
+        let (offset, _size) = self.real_portion(&open_pack.portion);
+        track!(self.nvm.write_at(&open_pack.bytes, offset))?;
+
+        // NOTE: `put`と同様に、この時点では`flush`のみに留める
+        track_io!(self.nvm.flush())?;
+
+        if let Some(ref pins) = self.pins {
+            pins.set_open_pack(None);
+        }
+        Ok(())
+    }
+
+    /// パック内に格納されているデータを取得する.
+    ///
+    /// `portion`で指定された領域が有効かどうかの判定は、このメソッド内では行われない.
+    pub fn get_packed(&mut self, portion: PackedPortion) -> Result<Vec<u8>> {
+        let start = usize::from(portion.offset);
+        let end = start + usize::from(portion.len);
+        track_assert!(
+            PACKED_SLOT_TRAILER_SIZE <= usize::from(portion.len) && end <= self.pack_size(),
+            ErrorKind::StorageCorrupted
+        );
+
+        let slot = match self.open_pack {
+            Some(ref p) if p.portion.start == portion.pack => p.bytes[start..end].to_vec(),
+            _ => {
+                let (offset, size) = self.real_portion(&portion.pack_portion());
+                let mut buf = AlignedBytes::new(size, self.block_size);
+                track!(self.nvm.read_at(&mut buf, offset))?;
+                buf[start..end].to_vec()
+            }
+        };
+        track!(decode_packed_slot(slot))
+    }
+
+    /// パック内に格納されているデータを削除する.
+    ///
+    /// パックの全てのメンバーが削除された場合には、パック自体も解放される.
+    ///
+    /// # パニック
+    ///
+    /// `portion`で未使用のパックが指定された場合には、
+    /// 現在の実行スレッドがパニックする.
+    pub fn delete_packed(&mut self, portion: PackedPortion) {
+        let is_empty = {
+            let state = self.packs.get_mut(&portion.pack).expect("Unknown pack");
+            state.members -= 1;
+            state.live_bytes -= u32::from(portion.len);
+            state.members == 0
+        };
+        if is_empty {
+            self.packs.remove(&portion.pack);
+            if self
+                .open_pack
+                .as_ref()
+                .is_some_and(|p| p.portion.start == portion.pack)
+            {
+                self.open_pack = None;
+                if let Some(ref pins) = self.pins {
+                    pins.set_open_pack(None);
+                }
+            }
+            self.release(portion.pack_portion());
+            self.metrics.packs.set(self.packs.len() as f64);
+            self.metrics.reclaimed_packs.increment();
+        }
+    }
+
+    /// 使用率(有効なデータが占めるバイト数の割合)が`max_utilization`以下のパックの一覧を返す.
+    ///
+    /// 書き込み中のパックは結果に含まれない.
+    pub fn sparse_packs(&self, max_utilization: f64) -> Vec<Address> {
+        let pack_size = self.pack_size() as f64;
+        let open_pack = self.open_pack.as_ref().map(|p| p.portion.start);
+        self.packs
+            .iter()
+            .filter(|&(pack, state)| {
+                Some(*pack) != open_pack
+                    && f64::from(state.live_bytes) / pack_size <= max_utilization
+            })
+            .map(|(pack, _)| *pack)
+            .collect()
+    }
+
+    /// 部分領域を解放する.
+    ///
+    /// 並列読み込み中の部分領域の場合には、解放は延期される.
+    fn release(&mut self, portion: DataPortion) {
+        self.release_deferred();
+        if self
+            .pins
+            .as_ref()
+            .is_some_and(|pins| pins.is_pinned(portion.start))
+        {
+            self.deferred_releases.push(portion);
+        } else {
+            self.allocator.release(portion);
+        }
+    }
+
+    /// 解放が延期されていた部分領域の内で、読み込みが完了したものを解放する.
+    ///
+    /// 書き込み・削除時に加えて、ストレージの補助タスクからも呼び出される.
+    pub fn release_deferred(&mut self) {
+        if let Some(ref pins) = self.pins {
+            let allocator = &mut self.allocator;
+            self.deferred_releases.retain(|portion| {
+                if pins.is_pinned(portion.start) {
+                    true
+                } else {
+                    allocator.release(*portion);
+                    false
+                }
+            });
+        }
+    }
+
+    /// 一つのパックが使用可能なバイト数.
+    fn pack_size(&self) -> usize {
+        pack_size(self.block_size)
     }
 
     /// 部分領域の単位をブロックからバイトに変換する.
     fn real_portion(&self, portion: &DataPortion) -> (u64, usize) {
-        let offset = portion.start.as_u64() * u64::from(self.block_size.as_u16());
-        let size = portion.len as usize * self.block_size.as_u16() as usize;
-        (offset, size)
+        real_portion(self.block_size, portion)
     }
 
     /// `size`分のデータをカバーするのに必要なブロック数.
-    fn block_count(&self, size: u32) -> u32 {
-        (size + u32::from(self.block_size.as_u16()) - 1) / u32::from(self.block_size.as_u16())
+    fn block_count(&self, size: u64) -> u64 {
+        size.div_ceil(u64::from(self.block_size.as_u32()))
     }
 }
 
+/// データ領域を並列に読み込むためのハンドル.
+///
+/// `DataRegion`を保持するスレッドとは別のスレッドから使用される.
+#[derive(Debug)]
+pub struct DataRegionReader {
+    nvm: Box<dyn PositionalRead>,
+    block_size: BlockSize,
+    pins: Arc<PortionPins>,
+}
+impl DataRegionReader {
+    /// 読み込み中の部分領域の集合を返す.
+    pub fn pins(&self) -> &PortionPins {
+        &self.pins
+    }
+
+    /// 指定された領域に格納されているデータを取得する.
+    ///
+    /// 呼び出し側は、読み込みが完了するまで、`portion`を`pins`に登録しておく必要がある.
+    pub fn get(&self, portion: DataPortion) -> Result<DataRegionLumpData> {
+        let (offset, size) = real_portion(self.block_size, &portion);
+        let mut buf = AlignedBytes::new(size, self.block_size);
+        track!(self.nvm.read_exact_at(&mut buf, offset))?;
+        track!(DataRegionLumpData::from_external_bytes(buf))
+    }
+
+    /// パック内に格納されているデータを取得する.
+    ///
+    /// 呼び出し側は、読み込みが完了するまで、`portion`を含むパックを`pins`に登録しておく必要がある.
+    pub fn get_packed(&self, portion: PackedPortion) -> Result<Vec<u8>> {
+        let start = usize::from(portion.offset);
+        let end = start + usize::from(portion.len);
+        track_assert!(
+            PACKED_SLOT_TRAILER_SIZE <= usize::from(portion.len)
+                && end <= pack_size(self.block_size),
+            ErrorKind::StorageCorrupted
+        );
+
+        // 書き込み中のパック(i.e., NVMに未だ書き出されていないもの)のメンバーは、
+        // 呼び出し側が`PortionPins::is_open_pack`で判定して除外している
+        let (offset, size) = real_portion(self.block_size, &portion.pack_portion());
+        let mut buf = AlignedBytes::new(size, self.block_size);
+        track!(self.nvm.read_exact_at(&mut buf, offset))?;
+        track!(decode_packed_slot(buf[start..end].to_vec()))
+    }
+}
+
+/// 並列読み込み中の部分領域(の開始位置)の集合.
+///
+/// ここに登録されている部分領域は、削除されても、登録が解除されるまでは解放されない.
+///
+/// また、NVMに未だ書き出されていない、書き込み中のパックの位置も保持している.
+#[derive(Debug, Default)]
+pub struct PortionPins {
+    pins: Mutex<HashMap<Address, usize>>,
+    open_pack: Mutex<Option<Address>>,
+}
+impl PortionPins {
+    /// `pack`が書き込み中(i.e., NVMに未だ書き出されていない)のパックかどうかを判定する.
+    ///
+    /// 書き込み中のパックのメンバーは、NVMからは読み込めないので、`Storage`経由で取得する必要がある.
+    pub fn is_open_pack(&self, pack: Address) -> bool {
+        *self.open_pack.lock().unwrap_or_else(|e| e.into_inner()) == Some(pack)
+    }
+
+    fn set_open_pack(&self, pack: Option<Address>) {
+        *self.open_pack.lock().unwrap_or_else(|e| e.into_inner()) = pack;
+    }
+
+    /// 指定位置から始まる部分領域を登録する.
+    ///
+    /// 登録は、返り値が破棄された時点で解除される.
+    pub fn pin(&self, start: Address) -> PortionPin<'_> {
+        *self.lock().entry(start).or_insert(0) += 1;
+        PortionPin { pins: self, start }
+    }
+
+    fn is_pinned(&self, start: Address) -> bool {
+        self.lock().contains_key(&start)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<Address, usize>> {
+        self.pins.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// `PortionPins::pin`で登録された部分領域.
+#[derive(Debug)]
+pub struct PortionPin<'a> {
+    pins: &'a PortionPins,
+    start: Address,
+}
+impl<'a> Drop for PortionPin<'a> {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock();
+        let count = pins.get_mut(&self.start).expect("Never fails");
+        *count -= 1;
+        if *count == 0 {
+            pins.remove(&self.start);
+        }
+    }
+}
+
+/// パックの使用状況.
+#[derive(Debug, Default, Clone)]
+struct PackState {
+    /// パック内に格納されている有効なデータの数.
+    members: u32,
+
+    /// パック内で有効なデータのスロットが占めるバイト数.
+    live_bytes: u32,
+}
+
+/// 書き込み中のパック.
+#[derive(Debug)]
+struct OpenPack {
+    portion: DataPortion,
+    bytes: AlignedBytes,
+    used: usize,
+}
+
+/// 指定されたブロックサイズのストレージで、各データの末尾に埋め込まれる情報のサイズ.
+fn trailer_size(block_size: BlockSize) -> usize {
+    if block_size.as_u32() as usize <= 0x10000 {
+        LUMP_DATA_TRAILER_SIZE
+    } else {
+        WIDE_LUMP_DATA_TRAILER_SIZE
+    }
+}
+
+/// 指定されたブロックサイズのストレージで、一つのパックが使用可能なバイト数.
+fn pack_size(block_size: BlockSize) -> usize {
+    cmp::min(block_size.as_u32() as usize, MAX_PACK_SIZE)
+}
+
+/// 部分領域の単位をブロックからバイトに変換する.
+fn real_portion(block_size: BlockSize, portion: &DataPortion) -> (u64, usize) {
+    let offset = portion.start.as_u64() * u64::from(block_size.as_u32());
+    let size = portion.len as usize * block_size.as_u32() as usize;
+    (offset, size)
+}
+
+/// パック内のスロットから、パディングとトレイラを取り除いたデータを取り出す.
+fn decode_packed_slot(mut slot: Vec<u8>) -> Result<Vec<u8>> {
+    let padding_len = slot[slot.len() - PACKED_SLOT_TRAILER_SIZE] as usize;
+    let data_len = track_assert_some!(
+        slot.len()
+            .checked_sub(PACKED_SLOT_TRAILER_SIZE + padding_len),
+        ErrorKind::StorageCorrupted
+    );
+    slot.truncate(data_len);
+    Ok(slot)
+}
+
+/// `data_size`バイトのデータを格納するのに必要なスロットのサイズ.
+pub(crate) fn packed_slot_len(data_size: usize) -> usize {
+    let alignment = usize::from(PackedPortion::ALIGNMENT);
+    (data_size + PACKED_SLOT_TRAILER_SIZE).div_ceil(alignment) * alignment
+}
+
 #[derive(Debug, Clone)]
 pub struct DataRegionLumpData {
     bytes: AlignedBytes,
@@ -112,14 +565,19 @@ pub struct DataRegionLumpData {
 }
 impl DataRegionLumpData {
     pub fn new(data_size: usize, block_size: BlockSize) -> Self {
-        let size = data_size + LUMP_DATA_TRAILER_SIZE;
+        let trailer_size = trailer_size(block_size);
+        let size = data_size + trailer_size;
         let mut bytes = AlignedBytes::new(size, block_size);
         bytes.align();
 
-        let trailer_offset = bytes.len() - LUMP_DATA_TRAILER_SIZE;
+        let trailer_offset = bytes.len() - trailer_size;
         let padding_len = bytes.len() - size;
-        debug_assert!(padding_len <= 0xFFFF);
-        BigEndian::write_u16(&mut bytes[trailer_offset..], padding_len as u16);
+        if trailer_size == LUMP_DATA_TRAILER_SIZE {
+            debug_assert!(padding_len <= 0xFFFF);
+            BigEndian::write_u16(&mut bytes[trailer_offset..], padding_len as u16);
+        } else {
+            BigEndian::write_u32(&mut bytes[trailer_offset..], padding_len as u32);
+        }
         DataRegionLumpData { bytes, data_size }
     }
 
@@ -140,18 +598,18 @@ impl DataRegionLumpData {
         self.bytes.as_ref()
     }
 
-    fn write_to<W: Write>(&self, mut writer: W) -> Result<()> {
-        track_io!(writer.write_all(self.as_external_bytes()))
-    }
-
-    fn read_from<R: Read>(mut reader: R, mut buf: AlignedBytes) -> Result<Self> {
-        track_assert!(buf.len() >= LUMP_DATA_TRAILER_SIZE, ErrorKind::InvalidInput);
-        track_io!(reader.read_exact(&mut buf))?;
+    /// 永続化用のバイト列から、データを復元する.
+    fn from_external_bytes(buf: AlignedBytes) -> Result<Self> {
+        let trailer_size = trailer_size(buf.block_size());
+        track_assert!(buf.len() >= trailer_size, ErrorKind::InvalidInput);
 
-        let padding_len = BigEndian::read_u16(&buf[buf.len() - LUMP_DATA_TRAILER_SIZE..]) as usize;
-        let data_size = buf
-            .len()
-            .saturating_sub(LUMP_DATA_TRAILER_SIZE + padding_len);
+        let trailer = &buf[buf.len() - trailer_size..];
+        let padding_len = if trailer_size == LUMP_DATA_TRAILER_SIZE {
+            BigEndian::read_u16(trailer) as usize
+        } else {
+            BigEndian::read_u32(trailer) as usize
+        };
+        let data_size = buf.len().saturating_sub(trailer_size + padding_len);
 
         Ok(DataRegionLumpData {
             bytes: buf,
@@ -170,7 +628,7 @@ mod tests {
     use super::*;
     use crate::block::BlockSize;
     use crate::metrics::DataAllocatorMetrics;
-    use crate::nvm::MemoryNvm;
+    use crate::nvm::{MemoryNvm, SharedMemoryNvm};
 
     #[test]
     fn data_region_works() -> TestResult {
@@ -196,4 +654,41 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn pinned_portion_is_not_released() -> TestResult {
+        let capacity = 10 * 1024;
+        let block_size = BlockSize::min();
+        let metrics = MetricBuilder::new();
+        let allocator = track!(DataPortionAllocator::build(
+            DataAllocatorMetrics::new(&metrics, capacity, block_size),
+            iter::empty(),
+        ))?;
+        let nvm = SharedMemoryNvm::new(vec![0; capacity as usize]);
+        let mut region = DataRegion::new(&metrics, allocator, nvm);
+        let reader = track_assert_some!(track!(region.reader())?, ErrorKind::Other);
+
+        let mut data = DataRegionLumpData::new(3, block_size);
+        data.as_bytes_mut().copy_from_slice(b"foo");
+        let portion = track!(region.put(&data))?;
+        {
+            // 読み込み中の部分領域は、削除されても再利用されない
+            let _pin = reader.pins().pin(portion.start);
+            region.delete(portion);
+            data.as_bytes_mut().copy_from_slice(b"bar");
+            assert_ne!(track!(region.put(&data))?.start, portion.start);
+            assert_eq!(
+                reader.get(portion).ok().map(|d| d.as_bytes().to_owned()),
+                Some(b"foo".to_vec())
+            );
+        }
+
+        // 読み込みの完了後には解放される
+        assert_eq!(track!(region.put(&data))?.start, portion.start);
+        assert_eq!(
+            reader.get(portion).ok().map(|d| d.as_bytes().to_owned()),
+            Some(b"bar".to_vec())
+        );
+        Ok(())
+    }
 }
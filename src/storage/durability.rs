@@ -0,0 +1,50 @@
+//! 書き込みの永続化の度合い.
+use std::fmt;
+
+/// 書き込み操作に要求される永続化の度合い.
+///
+/// 後のものほど強い保証を与える(が、その分コストも大きい).
+///
+/// `Storage::put_with_durability`や`DeviceRequest::durability`で指定可能.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Durability {
+    /// 書き込みはメモリ上のジャーナルバッファに留まる.
+    ///
+    /// バッファの内容は`StorageBuilder::journal_sync_interval`等に従って、後で自動的に書き出される.
+    ///
+    /// これがデフォルトの挙動.
+    #[default]
+    Buffered,
+
+    /// ジャーナルバッファをNVMに書き出すが、物理デバイスへの同期は行わない.
+    ///
+    /// `FileNvm`の場合には、OSのページキャッシュへの書き込みまでが保証されるので、
+    /// プロセスがクラッシュしてもデータは失われないが、OSのクラッシュや電源断の際には失われる可能性がある.
+    JournalFlushed,
+
+    /// ジャーナルを物理デバイスに同期する(e.g., `fdatasync`).
+    ///
+    /// `DeviceRequest::journal_sync`と等価.
+    JournalSynced,
+
+    /// データ領域とジャーナルの両方を物理デバイスに同期する.
+    ///
+    /// データ領域に書き込まれるlumpの場合には、そのデータが同期された後に、
+    /// ジャーナルにレコードが追記されるので、ジャーナル上のレコードが指すデータが未同期である状態は生じない.
+    DataAndJournalSynced,
+}
+impl Durability {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Durability::Buffered => "buffered",
+            Durability::JournalFlushed => "journal_flushed",
+            Durability::JournalSynced => "journal_synced",
+            Durability::DataAndJournalSynced => "data_and_journal_synced",
+        }
+    }
+}
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
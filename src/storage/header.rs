@@ -1,18 +1,26 @@
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use adler32::RollingAdler32;
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use std::cmp;
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use trackable::error::ErrorKindExt;
 use uuid::Uuid;
 
 use crate::block::BlockSize;
+use crate::lump::LumpData;
 use crate::nvm::NonVolatileMemory;
+use crate::storage::portion::DataPortion;
 use crate::storage::{
-    MAGIC_NUMBER, MAJOR_VERSION, MAX_DATA_REGION_SIZE, MAX_JOURNAL_REGION_SIZE, MINOR_VERSION,
+    MAGIC_NUMBER, MAJOR_VERSION, MAX_DATA_REGION_SIZE, MAX_JOURNAL_REGION_SIZE,
+    MAX_WIDE_DATA_REGION_SIZE, MINOR_VERSION, WIDE_MAJOR_VERSION, WIDE_MINOR_VERSION,
 };
 use crate::{ErrorKind, Result};
 
-/// ヘッダを表現するのに必要なバイト数.
-const HEADER_SIZE: u16 =
+/// ヘッダ(v1.1まで)を表現するのに必要なバイト数.
+const HEADER_SIZE_V1_1: u16 =
     2 /* major_version */ +
     2 /* minor_version */ +
     2 /* block_size */ +
@@ -20,8 +28,41 @@ const HEADER_SIZE: u16 =
     8 /* journal_region_size */ +
     8 /* data_region_size */;
 
-/// **マジックナンバー** と **ヘッダサイズ** も含めたサイズ.
-pub(crate) const FULL_HEADER_SIZE: u16 = 4 + 2 + HEADER_SIZE;
+/// ヘッダ(v1.2以降)を表現するのに必要なバイト数.
+const HEADER_SIZE: u16 =
+    HEADER_SIZE_V1_1 +
+    2 /* flags */ +
+    4 /* checksum */;
+
+/// ワイドフォーマット(v2)のヘッダを表現するのに必要なバイト数.
+///
+/// ブロックサイズが`u32`で表現される分だけ、v1.2よりも大きくなっている.
+const HEADER_SIZE_V2: u16 = HEADER_SIZE + 2;
+
+/// **マジックナンバー** と **ヘッダサイズ** も含めたサイズ(の最大値).
+const FULL_HEADER_SIZE: u16 = 4 + 2 + HEADER_SIZE_V2;
+
+/// ヘッダとメタデータを合わせたサイズの最大値.
+///
+/// ブロックサイズに関わらず、ヘッダ領域(およびバックアップヘッダ領域)の先頭の`BlockSize::MIN`バイトに収まるようにしている.
+pub(crate) const HEADER_AND_METADATA_SIZE: u16 = BlockSize::MIN - BACKUP_TRAILER_SIZE;
+
+/// ヘッダのフラグ: ヘッダの冗長化が有効になっているかどうか.
+const FLAG_REDUNDANT_HEADERS: u16 = 0b0000_0001;
+
+/// ヘッダのフラグ: ヘッダの直後にメタデータが格納されているかどうか.
+const FLAG_METADATA: u16 = 0b0000_0010;
+
+/// ヘッダのフラグ: パック内に格納されたlumpのレコード(`JournalRecord::PutPacked`)が使用されているかどうか.
+const FLAG_PACKED_LUMPS: u16 = 0b0000_0100;
+
+/// バックアップ用のヘッダ領域の末尾に置かれる、ヘッダ長のバイト数.
+const BACKUP_TRAILER_SIZE: u16 = 2;
+
+/// バックアップ用のヘッダを探索する際に走査する、NVMの末尾からの範囲(バイト単位).
+///
+/// ブロックサイズの最大値の二倍程度.
+const BACKUP_SEARCH_WINDOW: u64 = 2 * BlockSize::MAX as u64;
 
 /// ストレージのヘッダ情報.
 ///
@@ -36,7 +77,8 @@ pub struct StorageHeader {
     ///
     /// メジャーバージョンが異なるストレージ同士のデータ形式には互換性が無い.
     ///
-    /// 現在の最新バージョンは[`MAJOR_VERSION`](./constant.MAJOR_VERSION.html).
+    /// 現在の最新バージョンは[`MAJOR_VERSION`](./constant.MAJOR_VERSION.html)で、
+    /// ワイドフォーマットの場合には[`WIDE_MAJOR_VERSION`](./constant.WIDE_MAJOR_VERSION.html)となる.
     pub major_version: u16,
 
     /// マイナーバージョン.
@@ -58,13 +100,65 @@ pub struct StorageHeader {
 
     /// データ領域のサイズ(バイト単位).
     pub data_region_size: u64,
+
+    /// ヘッダの冗長化が有効かどうか.
+    ///
+    /// 有効な場合には、ストレージの末尾にバックアップ用のヘッダ領域が確保され、
+    /// ジャーナル領域のヘッダも二重化(A/B)される.
+    ///
+    /// マイナーバージョンが`2`未満のストレージでは、常に`false`となる.
+    pub redundant_headers: bool,
+
+    /// パッキング(`StorageBuilder::pack_threshold`)が使用されているかどうか.
+    ///
+    /// `true`の場合には、ジャーナルに`JournalRecord::PutPacked`レコードが含まれている可能性がある.
+    /// このフラグが立っていないストレージのジャーナルに、同レコードが含まれていた場合には、
+    /// オープン時に`ErrorKind::StorageCorrupted`エラーとなる.
+    ///
+    /// マイナーバージョンが`2`未満のストレージでは、常に`false`となる.
+    pub packed_lumps: bool,
+
+    /// 運用管理用のメタデータ.
+    ///
+    /// マイナーバージョンが`2`未満のストレージでは、常に空となる.
+    pub metadata: StorageMetadata,
 }
 impl StorageHeader {
+    /// ワイドフォーマット(v2)のストレージかどうかを返す.
+    pub fn is_wide_format(&self) -> bool {
+        self.major_version == WIDE_MAJOR_VERSION
+    }
+
+    /// 一つのlumpが占有可能な、データ領域内の部分領域の長さの上限(ブロック単位)を返す.
+    pub(crate) fn max_portion_len(&self) -> u32 {
+        if self.is_wide_format() {
+            DataPortion::MAX_LEN
+        } else {
+            DataPortion::MAX_LEN_V1
+        }
+    }
+
+    /// データ領域に保存可能なlumpのサイズの上限(バイト単位)を返す.
+    pub(crate) fn max_lump_size(&self) -> usize {
+        if self.is_wide_format() {
+            let block_size = self.block_size.as_u32() as usize;
+            let max_size = DataPortion::MAX_LEN as usize * block_size - 4;
+            cmp::min(max_size, LumpData::MAX_WIDE_SIZE)
+        } else {
+            LumpData::MAX_SIZE
+        }
+    }
+
     /// ストレージが使用する領域全体のサイズを返す.
     ///
     /// 内訳としては **ヘッダ領域** と **ジャーナル領域** 、 **データ領域** のサイズの合計となる.
+    ///
+    /// ヘッダの冗長化が有効な場合には、末尾の **バックアップヘッダ領域** のサイズも加算される.
     pub fn storage_size(&self) -> u64 {
-        self.region_size() + self.journal_region_size + self.data_region_size
+        self.region_size()
+            + self.journal_region_size
+            + self.data_region_size
+            + self.backup_region_size()
     }
 
     /// ヘッダ領域のサイズを返す.
@@ -79,11 +173,40 @@ impl StorageHeader {
         Self::calc_region_size(self.block_size)
     }
 
+    /// バックアップヘッダ領域のサイズを返す.
+    ///
+    /// ヘッダの冗長化が無効な場合には`0`となる.
+    pub fn backup_region_size(&self) -> u64 {
+        if self.redundant_headers {
+            self.region_size()
+        } else {
+            0
+        }
+    }
+
     /// 存在するLump Storageから
     /// 保存済みのストレージヘッダを取り出す。
+    ///
+    /// 先頭のヘッダが壊れている場合には、ファイル末尾のバックアップヘッダの読み込みを試みる.
     pub fn read_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let file = track_io!(File::open(path))?;
-        track!(Self::read_from(file))
+        let mut file = track_io!(File::open(path))?;
+        match Self::read_from(&mut file) {
+            Ok(header) => Ok(header),
+            Err(e) => {
+                let file_size = track_io!(file.metadata())?.len();
+                let window = cmp::min(file_size, BACKUP_SEARCH_WINDOW);
+                track_io!(file.seek(SeekFrom::Start(file_size - window)))?;
+
+                let mut buf = Vec::new();
+                track_io!(file.read_to_end(&mut buf))?;
+                let step = u64::from(BlockSize::MIN);
+                if let Some(header) = Self::find_backup(&buf, file_size, step) {
+                    Ok(header)
+                } else {
+                    Err(track!(e))
+                }
+            }
+        }
     }
 
     /// ヘッダ情報を`reader`から読み込む.
@@ -95,28 +218,40 @@ impl StorageHeader {
 
         // header size
         let header_size = track_io!(reader.read_u16::<BigEndian>())?;
-        let mut reader = reader.take(u64::from(header_size));
+        let mut body = vec![0; header_size as usize];
+        track_io!(reader.read_exact(&mut body))?;
+        let mut outer_reader = reader;
+        let mut reader = &body[..];
 
         // versions
         let major_version = track_io!(reader.read_u16::<BigEndian>())?;
         let minor_version = track_io!(reader.read_u16::<BigEndian>())?;
-        track_assert_eq!(
-            major_version,
-            MAJOR_VERSION,
-            ErrorKind::InvalidInput,
-            "Unsupported major version",
-        );
+        let is_wide = major_version == WIDE_MAJOR_VERSION;
+        let supported_minor_version = match major_version {
+            MAJOR_VERSION => MINOR_VERSION,
+            WIDE_MAJOR_VERSION => WIDE_MINOR_VERSION,
+            _ => track_panic!(
+                ErrorKind::InvalidInput,
+                "Unsupported major version: {}",
+                major_version
+            ),
+        };
         track_assert!(
-            minor_version <= MINOR_VERSION,
+            minor_version <= supported_minor_version,
             ErrorKind::InvalidInput,
             "Unsupported minor version: actual={}, supported={}",
             minor_version,
-            MINOR_VERSION
+            supported_minor_version
         );
 
         // block_size
-        let block_size = track_io!(reader.read_u16::<BigEndian>())?;
-        let block_size = track!(BlockSize::new(block_size), "block_size:{}", block_size)?;
+        let block_size = if is_wide {
+            let block_size = track_io!(reader.read_u32::<BigEndian>())?;
+            track!(BlockSize::from_u32(block_size), "block_size:{}", block_size)?
+        } else {
+            let block_size = track_io!(reader.read_u16::<BigEndian>())?;
+            track!(BlockSize::new(block_size), "block_size:{}", block_size)?
+        };
 
         // UUID
         let mut instance_uuid = [0; 16];
@@ -132,14 +267,46 @@ impl StorageHeader {
             "journal_region_size:{}",
             journal_region_size
         );
+        let max_data_region_size = if is_wide {
+            MAX_WIDE_DATA_REGION_SIZE
+        } else {
+            MAX_DATA_REGION_SIZE
+        };
         track_assert!(
-            data_region_size <= MAX_DATA_REGION_SIZE,
+            data_region_size <= max_data_region_size,
             ErrorKind::InvalidInput,
             "data_region_size:{}",
             data_region_size
         );
 
-        track_assert_eq!(reader.limit(), 0, ErrorKind::InvalidInput);
+        // flags and checksum (v1.2以降およびv2)
+        let mut redundant_headers = false;
+        let mut packed_lumps = false;
+        let mut has_metadata = false;
+        if is_wide || minor_version >= 2 {
+            let flags = track_io!(reader.read_u16::<BigEndian>())?;
+            redundant_headers = (flags & FLAG_REDUNDANT_HEADERS) != 0;
+            has_metadata = (flags & FLAG_METADATA) != 0;
+            packed_lumps = (flags & FLAG_PACKED_LUMPS) != 0;
+
+            let checksum = track_io!(reader.read_u32::<BigEndian>())?;
+            let expected = calc_checksum(header_size, &body[..body.len() - 4]);
+            track_assert_eq!(
+                checksum,
+                expected,
+                ErrorKind::StorageCorrupted,
+                "Storage header checksum mismatched"
+            );
+        }
+
+        track_assert_eq!(reader.len(), 0, ErrorKind::InvalidInput);
+
+        // metadata
+        let metadata = if has_metadata {
+            track!(StorageMetadata::read_from(&mut outer_reader))?
+        } else {
+            StorageMetadata::default()
+        };
         Ok(StorageHeader {
             major_version,
             minor_version,
@@ -147,19 +314,75 @@ impl StorageHeader {
             block_size,
             journal_region_size,
             data_region_size,
+            redundant_headers,
+            packed_lumps,
+            metadata,
         })
     }
 
     /// ヘッダ情報を`writer`に書き込む.
+    ///
+    /// v1でマイナーバージョンが`2`未満の場合には、v1.1形式(フラグおよびチェックサム無し)で書き込まれる.
+    /// なお、v1.1形式ではメタデータは保存されない.
+    ///
+    /// メタデータが空ではない場合には、ヘッダの直後にメタデータが書き込まれる.
+    ///
+    /// # Errors
+    ///
+    /// 以下の場合には`ErrorKind::InvalidInput`エラーが返される:
+    ///
+    /// - v1のストレージのブロックサイズが`u16`の範囲に収まらない
+    /// - メタデータのサイズが`StorageMetadata::MAX_SIZE`を超えている
     pub fn write_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        let is_wide = self.is_wide_format();
+        let mut body = Vec::with_capacity(HEADER_SIZE_V2 as usize);
+        track_io!(body.write_u16::<BigEndian>(self.major_version))?;
+        track_io!(body.write_u16::<BigEndian>(self.minor_version))?;
+        if is_wide {
+            track_io!(body.write_u32::<BigEndian>(self.block_size.as_u32()))?;
+        } else {
+            track_assert!(
+                self.block_size.as_u32() <= u32::from(u16::MAX),
+                ErrorKind::InvalidInput,
+                "Too large block size for a v1 storage: {}",
+                self.block_size.as_u32()
+            );
+            track_io!(body.write_u16::<BigEndian>(self.block_size.as_u16()))?;
+        }
+        track_io!(body.write_all(self.instance_uuid.as_bytes()))?;
+        track_io!(body.write_u64::<BigEndian>(self.journal_region_size))?;
+        track_io!(body.write_u64::<BigEndian>(self.data_region_size))?;
+
+        let has_flags = is_wide || self.minor_version >= 2;
+        let has_metadata = has_flags && !self.metadata.is_empty();
+        let header_size = if has_flags {
+            let header_size = if is_wide { HEADER_SIZE_V2 } else { HEADER_SIZE };
+            let mut flags = 0;
+            if self.redundant_headers {
+                flags |= FLAG_REDUNDANT_HEADERS;
+            }
+            if has_metadata {
+                flags |= FLAG_METADATA;
+            }
+            if self.packed_lumps {
+                flags |= FLAG_PACKED_LUMPS;
+            }
+            track_io!(body.write_u16::<BigEndian>(flags))?;
+
+            let checksum = calc_checksum(header_size, &body);
+            track_io!(body.write_u32::<BigEndian>(checksum))?;
+            header_size
+        } else {
+            HEADER_SIZE_V1_1
+        };
+        debug_assert_eq!(body.len(), header_size as usize);
+
         track_io!(writer.write_all(&MAGIC_NUMBER[..]))?;
-        track_io!(writer.write_u16::<BigEndian>(HEADER_SIZE))?;
-        track_io!(writer.write_u16::<BigEndian>(self.major_version))?;
-        track_io!(writer.write_u16::<BigEndian>(self.minor_version))?;
-        track_io!(writer.write_u16::<BigEndian>(self.block_size.as_u16()))?;
-        track_io!(writer.write_all(self.instance_uuid.as_bytes()))?;
-        track_io!(writer.write_u64::<BigEndian>(self.journal_region_size))?;
-        track_io!(writer.write_u64::<BigEndian>(self.data_region_size))?;
+        track_io!(writer.write_u16::<BigEndian>(header_size))?;
+        track_io!(writer.write_all(&body))?;
+        if has_metadata {
+            track!(self.metadata.write_to(&mut writer))?;
+        }
         Ok(())
     }
 
@@ -168,27 +391,343 @@ impl StorageHeader {
     /// ヘッダ領域(サイズは`self.region_size()`)の未使用部分に0-パディングを行う以外は、
     /// `write_to`メソッドと同様.
     pub(crate) fn write_header_region_to<W: Write>(&self, mut writer: W) -> Result<()> {
-        track!(self.write_to(&mut writer))?;
+        let mut buf = Vec::with_capacity(self.region_size() as usize);
+        track!(self.write_to(&mut buf))?;
+        buf.resize(self.region_size() as usize, 0);
+        track_io!(writer.write_all(&buf))?;
+        Ok(())
+    }
 
-        let padding = vec![0; self.region_size() as usize - FULL_HEADER_SIZE as usize];
-        track_io!(writer.write_all(&padding))?;
+    /// バックアップヘッダ領域を`writer`に書き込む.
+    ///
+    /// バックアップヘッダ領域の内容は、先頭から順に以下の通り:
+    ///
+    /// - 0-パディング
+    /// - ヘッダ(`write_to`メソッドで書き込まれる内容と同様)
+    /// - ヘッダ長(マジックナンバー等も含む、2バイト)
+    ///
+    /// 先頭のヘッダ領域が壊れている場合には、ストレージのサイズが分からないため、
+    /// NVMの末尾から後ろ向きに走査して見つけられるように、末尾にヘッダ長を配置している.
+    pub(crate) fn write_backup_region_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        let mut header = Vec::with_capacity(HEADER_AND_METADATA_SIZE as usize);
+        track!(self.write_to(&mut header))?;
+
+        let mut buf = vec![0; self.region_size() as usize];
+        let trailer_start = buf.len() - BACKUP_TRAILER_SIZE as usize;
+        let header_start = trailer_start - header.len();
+        buf[header_start..trailer_start].copy_from_slice(&header);
+        BigEndian::write_u16(&mut buf[trailer_start..], header.len() as u16);
+        track_io!(writer.write_all(&buf))?;
         Ok(())
     }
 
+    /// ヘッダ領域を`nvm`の先頭に書き込む.
+    ///
+    /// 同期(`sync`)は呼び出し側の責務.
+    pub(crate) fn write_primary_region<N: NonVolatileMemory>(&self, nvm: &mut N) -> Result<()> {
+        track_io!(nvm.seek(SeekFrom::Start(0)))?;
+        track!(nvm.aligned_write_all(|temp_buf| {
+            track!(self.write_header_region_to(temp_buf))?;
+            Ok(())
+        }))?;
+        Ok(())
+    }
+
+    /// バックアップヘッダ領域を`nvm`に書き込む.
+    ///
+    /// ヘッダの冗長化が無効な場合には何も行わない.
+    ///
+    /// 同期(`sync`)は呼び出し側の責務.
+    pub(crate) fn write_backup_region<N: NonVolatileMemory>(&self, nvm: &mut N) -> Result<()> {
+        if !self.redundant_headers {
+            return Ok(());
+        }
+        track_io!(nvm.seek(SeekFrom::Start(self.backup_region_offset())))?;
+        track!(nvm.aligned_write_all(|temp_buf| {
+            track!(self.write_backup_region_to(temp_buf))?;
+            Ok(())
+        }))?;
+        Ok(())
+    }
+
+    /// `nvm`上のバックアップヘッダ領域が、`self`と同じ内容を保持しているかどうかを判定する.
+    ///
+    /// ヘッダの冗長化が無効な場合には、常に`true`が返される.
+    pub(crate) fn has_valid_backup<N: NonVolatileMemory>(&self, nvm: &mut N) -> bool {
+        if !self.redundant_headers {
+            return true;
+        }
+        let mut expected = Vec::new();
+        if self.write_backup_region_to(&mut expected).is_err() {
+            return false;
+        }
+        if nvm
+            .seek(SeekFrom::Start(self.backup_region_offset()))
+            .is_err()
+        {
+            return false;
+        }
+        match nvm.aligned_read_bytes(expected.len()) {
+            Ok(actual) => actual[..] == expected[..],
+            Err(_) => false,
+        }
+    }
+
+    /// `nvm`の末尾付近からバックアップヘッダを探して読み込む.
+    ///
+    /// 先頭のヘッダ領域が壊れている場合に使用される.
+    pub(crate) fn read_backup_from<N: NonVolatileMemory>(nvm: &mut N) -> Result<Self> {
+        let step = u64::from(nvm.block_size().as_u32());
+        let end = nvm.block_size().floor_align(nvm.capacity());
+        let start = nvm
+            .block_size()
+            .floor_align(end - cmp::min(end, BACKUP_SEARCH_WINDOW));
+        track_io!(nvm.seek(SeekFrom::Start(start)))?;
+        let buf = track!(nvm.aligned_read_bytes((end - start) as usize))?;
+        let header = track_assert_some!(
+            Self::find_backup(&buf[..], end, step),
+            ErrorKind::StorageCorrupted,
+            "No valid backup header is found"
+        );
+        Ok(header)
+    }
+
     /// 指定されたブロックサイズを有するストレージのために必要な、ヘッダ領域のサイズを計算する.
     pub(crate) fn calc_region_size(block_size: BlockSize) -> u64 {
-        block_size.ceil_align(u64::from(FULL_HEADER_SIZE))
+        block_size.ceil_align(u64::from(HEADER_AND_METADATA_SIZE + BACKUP_TRAILER_SIZE))
+    }
+
+    /// バックアップヘッダ領域の開始位置.
+    pub(crate) fn backup_region_offset(&self) -> u64 {
+        self.storage_size() - self.backup_region_size()
+    }
+
+    /// `buf`(末尾の位置は`buf_end`)から、バックアップヘッダを後ろ向きに探索する.
+    ///
+    /// バックアップヘッダ領域の終端は`step`の境界に揃っている必要がある.
+    fn find_backup(buf: &[u8], buf_end: u64, step: u64) -> Option<Self> {
+        let step = step as usize;
+        let mut end = buf.len().checked_sub((buf_end % step as u64) as usize)?;
+        while end >= BACKUP_TRAILER_SIZE as usize {
+            let trailer_start = end - BACKUP_TRAILER_SIZE as usize;
+            let header_len = BigEndian::read_u16(&buf[trailer_start..end]) as usize;
+            if header_len != 0 && header_len <= trailer_start {
+                let header_bytes = &buf[trailer_start - header_len..trailer_start];
+                if let Ok(header) = Self::read_from(header_bytes) {
+                    let header_end = buf_end - (buf.len() - end) as u64;
+                    if header.redundant_headers && header.storage_size() == header_end {
+                        return Some(header);
+                    }
+                }
+            }
+            end = end.checked_sub(step)?;
+        }
+        None
     }
 
-    /// 不揮発性メモリ全体の領域を分割して、ジャーナル領域およびデータ領域用のメモリを返す.
-    pub(crate) fn split_regions<N: NonVolatileMemory>(&self, nvm: N) -> Result<(N, N)> {
+    /// 不揮発性メモリ全体の領域を分割して、各領域用のメモリを返す.
+    pub(crate) fn split_regions<N: NonVolatileMemory>(&self, nvm: N) -> Result<StorageRegions<N>> {
         let header_tail = self.region_size();
-        let (_, body_nvm) = track!(nvm.split(header_tail))?;
-        let (journal_nvm, data_nvm) = track!(body_nvm.split(self.journal_region_size))?;
-        Ok((journal_nvm, data_nvm))
+        let (header_nvm, body_nvm) = track!(nvm.split(header_tail))?;
+        let (journal_nvm, rest_nvm) = track!(body_nvm.split(self.journal_region_size))?;
+        let (data_nvm, backup_nvm) = track!(rest_nvm.split(self.data_region_size))?;
+        Ok(StorageRegions {
+            header: header_nvm,
+            journal: journal_nvm,
+            data: data_nvm,
+            backup: backup_nvm,
+        })
+    }
+
+    /// `split_regions`で分割されたヘッダ領域(およびバックアップヘッダ領域)に、ヘッダを書き込んで同期する.
+    pub(crate) fn write_to_regions<N: NonVolatileMemory>(
+        &self,
+        header_nvm: &mut N,
+        backup_nvm: &mut N,
+    ) -> Result<()> {
+        track!(self.write_primary_region(header_nvm))?;
+        track!(header_nvm.sync())?;
+        if self.redundant_headers {
+            track_io!(backup_nvm.seek(SeekFrom::Start(0)))?;
+            track!(backup_nvm.aligned_write_all(|temp_buf| {
+                track!(self.write_backup_region_to(temp_buf))?;
+                Ok(())
+            }))?;
+            track!(backup_nvm.sync())?;
+        }
+        Ok(())
+    }
+}
+
+/// ストレージが使用するNVMを、領域毎に分割したもの.
+#[derive(Debug)]
+pub(crate) struct StorageRegions<N> {
+    /// ヘッダ領域.
+    pub header: N,
+
+    /// ジャーナル領域.
+    pub journal: N,
+
+    /// データ領域.
+    pub data: N,
+
+    /// データ領域以降の全て(バックアップヘッダ領域を含む).
+    pub backup: N,
+}
+
+/// ストレージのヘッダ領域に格納される、運用管理用のメタデータ.
+///
+/// `StorageBuilder`で生成時に指定できる他、`Storage::update_metadata`で後から更新することも可能.
+///
+/// エンコード後のサイズは`StorageMetadata::MAX_SIZE`以下である必要がある.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorageMetadata {
+    /// 人間向けのラベル(e.g., ディスクの用途や設置場所).
+    pub label: String,
+
+    /// ストレージの生成時刻(秒単位).
+    ///
+    /// メタデータに未対応のバージョンで生成されたストレージの場合には`None`となる.
+    pub created_at: Option<SystemTime>,
+
+    /// ストレージを生成したライブラリ(cannyls)のバージョン.
+    ///
+    /// メタデータに未対応のバージョンで生成されたストレージの場合には空文字列となる.
+    pub library_version: String,
+
+    /// 任意のキー・バリュー.
+    pub properties: BTreeMap<String, String>,
+}
+impl StorageMetadata {
+    /// エンコード後のメタデータのサイズの最大値(バイト単位).
+    pub const MAX_SIZE: usize = (HEADER_AND_METADATA_SIZE - FULL_HEADER_SIZE) as usize;
+
+    /// メタデータが空かどうかを返す.
+    pub fn is_empty(&self) -> bool {
+        *self == StorageMetadata::default()
+    }
+
+    /// エンコード後のサイズ(バイト単位)を返す.
+    pub fn encoded_size(&self) -> usize {
+        let strings = self
+            .properties
+            .iter()
+            .map(|(k, v)| 4 + k.len() + v.len())
+            .sum::<usize>()
+            + 2
+            + self.label.len()
+            + 2
+            + self.library_version.len();
+        2 /* payload length */ + 8 /* created_at */ + 2 /* properties count */ + strings + 4
+        /* checksum */
+    }
+
+    fn write_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        let size = self.encoded_size();
+        track_assert!(
+            size <= Self::MAX_SIZE,
+            ErrorKind::InvalidInput,
+            "Too large storage metadata: {} bytes (max={})",
+            size,
+            Self::MAX_SIZE
+        );
+
+        let mut payload = Vec::with_capacity(size);
+        let created_at = self
+            .created_at
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        track_io!(payload.write_u64::<BigEndian>(created_at))?;
+        write_string(&mut payload, &self.label)?;
+        write_string(&mut payload, &self.library_version)?;
+        track_io!(payload.write_u16::<BigEndian>(self.properties.len() as u16))?;
+        for (key, value) in &self.properties {
+            write_string(&mut payload, key)?;
+            write_string(&mut payload, value)?;
+        }
+
+        let mut adler32 = RollingAdler32::new();
+        adler32.update_buffer(&payload);
+        track_io!(writer.write_u16::<BigEndian>(payload.len() as u16))?;
+        track_io!(writer.write_all(&payload))?;
+        track_io!(writer.write_u32::<BigEndian>(adler32.hash()))?;
+        Ok(())
+    }
+
+    fn read_from<R: Read>(mut reader: R) -> Result<Self> {
+        let payload_len = track_io!(reader.read_u16::<BigEndian>())?;
+        track_assert!(
+            usize::from(payload_len) <= Self::MAX_SIZE,
+            ErrorKind::StorageCorrupted,
+            "Too large storage metadata: {} bytes",
+            payload_len
+        );
+        let mut payload = vec![0; usize::from(payload_len)];
+        track_io!(reader.read_exact(&mut payload))?;
+        let checksum = track_io!(reader.read_u32::<BigEndian>())?;
+        let mut adler32 = RollingAdler32::new();
+        adler32.update_buffer(&payload);
+        track_assert_eq!(
+            checksum,
+            adler32.hash(),
+            ErrorKind::StorageCorrupted,
+            "Storage metadata checksum mismatched"
+        );
+
+        let mut reader = &payload[..];
+        let created_at = track_io!(reader.read_u64::<BigEndian>())?;
+        let created_at = if created_at == 0 {
+            None
+        } else {
+            Some(UNIX_EPOCH + Duration::from_secs(created_at))
+        };
+        let label = track!(read_string(&mut reader))?;
+        let library_version = track!(read_string(&mut reader))?;
+        let count = track_io!(reader.read_u16::<BigEndian>())?;
+        let mut properties = BTreeMap::new();
+        for _ in 0..count {
+            let key = track!(read_string(&mut reader))?;
+            let value = track!(read_string(&mut reader))?;
+            properties.insert(key, value);
+        }
+        track_assert_eq!(reader.len(), 0, ErrorKind::StorageCorrupted);
+        Ok(StorageMetadata {
+            label,
+            created_at,
+            library_version,
+            properties,
+        })
     }
 }
 
+fn write_string(buf: &mut Vec<u8>, s: &str) -> Result<()> {
+    track_io!(buf.write_u16::<BigEndian>(s.len() as u16))?;
+    buf.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+fn read_string(reader: &mut &[u8]) -> Result<String> {
+    let len = track_io!(reader.read_u16::<BigEndian>())?;
+    let mut bytes = vec![0; usize::from(len)];
+    track_io!(reader.read_exact(&mut bytes))?;
+    let s = track!(String::from_utf8(bytes).map_err(|e| ErrorKind::StorageCorrupted.cause(e)))?;
+    Ok(s)
+}
+
+/// ヘッダのチェックサムを計算する.
+///
+/// 対象は **マジックナンバー** と **ヘッダ長** 、およびチェックサム以外のヘッダの内容.
+fn calc_checksum(header_size: u16, body: &[u8]) -> u32 {
+    let mut size = [0; 2];
+    BigEndian::write_u16(&mut size, header_size);
+
+    let mut adler32 = RollingAdler32::new();
+    adler32.update_buffer(&MAGIC_NUMBER[..]);
+    adler32.update_buffer(&size);
+    adler32.update_buffer(body);
+    adler32.hash()
+}
+
 #[cfg(test)]
 mod tests {
     use trackable::result::TestResult;
@@ -206,6 +745,9 @@ mod tests {
             instance_uuid: Uuid::new_v4(),
             journal_region_size: 1024,
             data_region_size: 4096,
+            redundant_headers: false,
+            packed_lumps: false,
+            metadata: StorageMetadata::default(),
         };
 
         // size
@@ -226,6 +768,16 @@ mod tests {
         assert_eq!(h.instance_uuid, header.instance_uuid);
         assert_eq!(h.journal_region_size, header.journal_region_size);
         assert_eq!(h.data_region_size, header.data_region_size);
+        assert!(!h.packed_lumps);
+
+        // flags
+        let mut header = header;
+        header.packed_lumps = true;
+        let mut buf = Vec::new();
+        track!(header.write_to(&mut buf))?;
+        let h = track!(StorageHeader::read_from(&buf[..]))?;
+        assert!(h.packed_lumps);
+        assert!(!h.redundant_headers);
         Ok(())
     }
 
@@ -256,11 +808,31 @@ mod tests {
 
         assert!(StorageHeader::read_from(&buf[..]).is_err());
 
+        // Wide version: OK
+        let mut h = header(WIDE_MAJOR_VERSION, WIDE_MINOR_VERSION);
+        h.block_size = track!(BlockSize::from_u32(BlockSize::MAX))?;
+        let mut buf = Vec::new();
+        track!(h.write_to(&mut buf))?;
+        assert_eq!(buf.len(), 4 + 2 + HEADER_SIZE_V2 as usize);
+
+        let h = track!(StorageHeader::read_from(&buf[..]))?;
+        assert!(h.is_wide_format());
+        assert_eq!(h.block_size.as_u32(), BlockSize::MAX);
+
+        // Higher minor version of the wide format: NG
+        let h = header(WIDE_MAJOR_VERSION, WIDE_MINOR_VERSION + 1);
+        let mut buf = Vec::new();
+        track!(h.write_to(&mut buf))?;
+
+        assert!(StorageHeader::read_from(&buf[..]).is_err());
+
         // Higher major version: NG
-        let h = header(MAJOR_VERSION + 1, MINOR_VERSION);
+        let h = header(WIDE_MAJOR_VERSION + 1, MINOR_VERSION);
         let mut buf = Vec::new();
         track!(h.write_to(&mut buf))?;
 
+        assert!(StorageHeader::read_from(&buf[..]).is_err());
+
         // Lower major version: NG
         let h = header(MAJOR_VERSION - 1, MINOR_VERSION);
         let mut buf = Vec::new();
@@ -271,6 +843,121 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn redundant_headers_works() -> TestResult {
+        let mut header = header(MAJOR_VERSION, MINOR_VERSION);
+        header.redundant_headers = true;
+        assert_eq!(
+            header.storage_size(),
+            u64::from(BlockSize::MIN) * 2 + 1024 + 4096
+        );
+
+        // read/write
+        let mut buf = Vec::new();
+        track!(header.write_to(&mut buf))?;
+        let h = track!(StorageHeader::read_from(&buf[..]))?;
+        assert!(h.redundant_headers);
+
+        // checksum
+        buf[20] ^= 0xFF;
+        assert_eq!(
+            StorageHeader::read_from(&buf[..]).err().map(|e| *e.kind()),
+            Some(ErrorKind::StorageCorrupted)
+        );
+
+        // v1.1形式では、冗長化フラグは保存されない
+        let mut old = header.clone();
+        old.minor_version = 1;
+        let mut buf = Vec::new();
+        track!(old.write_to(&mut buf))?;
+        assert_eq!(buf.len(), 4 + 2 + HEADER_SIZE_V1_1 as usize);
+        let h = track!(StorageHeader::read_from(&buf[..]))?;
+        assert!(!h.redundant_headers);
+
+        // backup
+        let mut buf = vec![0; (header.storage_size() - header.region_size()) as usize];
+        track!(header.write_backup_region_to(&mut buf))?;
+        let step = u64::from(BlockSize::MIN);
+        let end = buf.len() as u64;
+        let h = StorageHeader::find_backup(&buf, end, step).expect("Not found");
+        assert_eq!(h.instance_uuid, header.instance_uuid);
+
+        // 末尾に余分な領域があっても見つけられる
+        buf.extend_from_slice(&[0; 1024]);
+        let end = buf.len() as u64;
+        let h = StorageHeader::find_backup(&buf, end, step).expect("Not found");
+        assert_eq!(h.instance_uuid, header.instance_uuid);
+
+        // 位置が合わない場合には無視される
+        assert!(StorageHeader::find_backup(&buf[512..], end, step).is_some());
+        assert!(StorageHeader::find_backup(&buf, end + 512, step).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_works() -> TestResult {
+        let mut header = header(MAJOR_VERSION, MINOR_VERSION);
+        header.redundant_headers = true;
+        header.metadata.label = "disk-001".to_owned();
+        header.metadata.created_at = Some(UNIX_EPOCH + Duration::from_secs(1_500_000_000));
+        header.metadata.library_version = "0.10.0".to_owned();
+        header
+            .metadata
+            .properties
+            .insert("rack".to_owned(), "r1".to_owned());
+
+        // read/write
+        let mut buf = Vec::new();
+        track!(header.write_to(&mut buf))?;
+        assert_eq!(
+            buf.len(),
+            4 + 2 + HEADER_SIZE as usize + header.metadata.encoded_size()
+        );
+        let h = track!(StorageHeader::read_from(&buf[..]))?;
+        assert_eq!(h.metadata, header.metadata);
+
+        // checksum
+        let last = buf.len() - 1;
+        buf[last] ^= 0xFF;
+        assert_eq!(
+            StorageHeader::read_from(&buf[..]).err().map(|e| *e.kind()),
+            Some(ErrorKind::StorageCorrupted)
+        );
+
+        // backup
+        let mut buf = vec![0; (header.storage_size() - header.region_size()) as usize];
+        track!(header.write_backup_region_to(&mut buf))?;
+        let end = buf.len() as u64;
+        let h =
+            StorageHeader::find_backup(&buf, end, u64::from(BlockSize::MIN)).expect("Not found");
+        assert_eq!(h.metadata, header.metadata);
+
+        // 空のメタデータは書き込まれない
+        let mut buf = Vec::new();
+        track!(StorageHeader {
+            metadata: StorageMetadata::default(),
+            ..header.clone()
+        }
+        .write_to(&mut buf))?;
+        assert_eq!(buf.len(), 4 + 2 + HEADER_SIZE as usize);
+
+        // v1.1形式では、メタデータは保存されない
+        let mut old = header.clone();
+        old.minor_version = 1;
+        let mut buf = Vec::new();
+        track!(old.write_to(&mut buf))?;
+        let h = track!(StorageHeader::read_from(&buf[..]))?;
+        assert!(h.metadata.is_empty());
+
+        // サイズ超過
+        header.metadata.label = "a".repeat(StorageMetadata::MAX_SIZE);
+        assert_eq!(
+            header.write_to(Vec::new()).err().map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+        Ok(())
+    }
+
     fn header(major_version: u16, minor_version: u16) -> StorageHeader {
         StorageHeader {
             major_version,
@@ -279,6 +966,9 @@ mod tests {
             instance_uuid: Uuid::new_v4(),
             journal_region_size: 1024,
             data_region_size: 4096,
+            redundant_headers: false,
+            packed_lumps: false,
+            metadata: StorageMetadata::default(),
         }
     }
 }
@@ -0,0 +1,61 @@
+use std::collections::BTreeMap;
+use std::ops;
+
+use super::IndexBackend;
+use crate::lump::LumpId;
+use crate::storage::portion::PortionU64;
+
+/// `BTreeMap`における、エントリ一つ当たりのメモリ使用量(バイト単位)の概算値.
+///
+/// キー(16バイト)と値(8バイト)に、ノードの管理用の領域や空きスロットの分を加味した経験的な値.
+const BYTES_PER_ENTRY: u64 = 40;
+
+/// `BTreeMap`を用いたインデックスの実装.
+#[derive(Debug, Clone, Default)]
+pub struct BTreeIndex {
+    // `BTreeMap`の方が`HashMap`よりもメモリ効率が良いので、こちらを採用
+    map: BTreeMap<LumpId, PortionU64>,
+}
+impl BTreeIndex {
+    pub fn new() -> Self {
+        BTreeIndex {
+            map: BTreeMap::new(),
+        }
+    }
+}
+impl IndexBackend for BTreeIndex {
+    fn get(&self, lump_id: &LumpId) -> Option<PortionU64> {
+        self.map.get(lump_id).cloned()
+    }
+
+    fn insert(&mut self, lump_id: LumpId, portion: PortionU64) -> Option<PortionU64> {
+        self.map.insert(lump_id, portion)
+    }
+
+    fn remove(&mut self, lump_id: &LumpId) -> Option<PortionU64> {
+        self.map.remove(lump_id)
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn range<'a>(
+        &'a self,
+        range: ops::Range<LumpId>,
+    ) -> Box<dyn Iterator<Item = (LumpId, PortionU64)> + 'a> {
+        Box::new(self.map.range(range).map(|(k, v)| (*k, *v)))
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (LumpId, PortionU64)> + 'a> {
+        Box::new(self.map.iter().map(|(k, v)| (*k, *v)))
+    }
+
+    fn memory_usage(&self) -> u64 {
+        self.map.len() as u64 * BYTES_PER_ENTRY
+    }
+
+    fn clone_box(&self) -> Box<dyn IndexBackend> {
+        Box::new(self.clone())
+    }
+}
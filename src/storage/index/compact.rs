@@ -0,0 +1,370 @@
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::BTreeMap;
+use std::mem;
+use std::ops;
+
+use super::IndexBackend;
+use crate::lump::LumpId;
+use crate::storage::portion::PortionU64;
+
+/// 一つのブロックに格納されるエントリの最大数.
+const MAX_BLOCK_ENTRIES: usize = 128;
+
+/// メモリ効率を重視したインデックスの実装.
+///
+/// 登録されたエントリ群は、IDの昇順に並べられた上で、
+/// 最大`MAX_BLOCK_ENTRIES`個ずつのブロックに分割されて保持される.
+///
+/// 各ブロック内では、IDは直前のエントリとの差分が可変長整数として符号化されているため、
+/// IDが密に割り当てられている場合には、エントリ一つ当たりのメモリ使用量は10バイト程度となる.
+///
+/// ブロック群は先頭IDをキーとする`BTreeMap`で管理されているので、
+/// 検索・更新時の対象ブロックの特定や、ブロックの分割・併合は、いずれもブロック数に対して対数時間で行われる.
+/// その後、ブロック内を線形に走査する.
+#[derive(Debug, Clone, Default)]
+pub struct CompactIndex {
+    // 各ブロックの先頭IDをキーとするマップ
+    blocks: BTreeMap<u128, Block>,
+    len: usize,
+
+    // 全ブロックの符号化済みバイト列の合計サイズ
+    encoded_bytes: usize,
+}
+impl CompactIndex {
+    pub fn new() -> Self {
+        CompactIndex {
+            blocks: BTreeMap::new(),
+            len: 0,
+            encoded_bytes: 0,
+        }
+    }
+
+    /// `key`を含み得るブロックの先頭IDを返す.
+    ///
+    /// `key`が全てのブロックの先頭IDよりも小さい場合には`None`が返される.
+    fn find_block(&self, key: u128) -> Option<u128> {
+        self.blocks.range(..=key).next_back().map(|(&k, _)| k)
+    }
+
+    /// 先頭IDが`first_key`のブロックの内容を`entries`で置き換える.
+    ///
+    /// `entries`の先頭IDが変わった場合には、ブロックのキーも更新される.
+    /// また、`entries`のサイズに応じて、ブロックの分割・削除も行われる.
+    ///
+    /// 置き換え後のブロックの先頭ID(分割された場合には前半のもの)が返される.
+    fn replace_block(&mut self, first_key: u128, mut entries: Vec<(u128, u64)>) -> Option<u128> {
+        let old = self.blocks.remove(&first_key).expect("Never fails");
+        self.encoded_bytes -= old.bytes.len();
+        if entries.is_empty() {
+            return None;
+        }
+        if entries.len() > MAX_BLOCK_ENTRIES {
+            let tail = entries.split_off(entries.len() / 2);
+            self.insert_block(Block::new(&tail));
+        }
+        Some(self.insert_block(Block::new(&entries)))
+    }
+
+    fn insert_block(&mut self, block: Block) -> u128 {
+        let first_key = block.first_key;
+        self.encoded_bytes += block.bytes.len();
+        self.blocks.insert(first_key, block);
+        first_key
+    }
+
+    /// エントリ数が少なくなったブロックを、後続のブロックと併合する.
+    fn merge_if_needed(&mut self, first_key: u128) {
+        let (block, next) = {
+            let mut iter = self.blocks.range(first_key..);
+            match (iter.next(), iter.next()) {
+                (Some((_, block)), Some((_, next))) => (block, next),
+                _ => return,
+            }
+        };
+        let len = block.len as usize + next.len as usize;
+        if block.len as usize >= MAX_BLOCK_ENTRIES / 4 || len > MAX_BLOCK_ENTRIES {
+            return;
+        }
+        let mut entries = block.decode();
+        entries.extend(next.iter());
+        let next_key = next.first_key;
+        let next = self.blocks.remove(&next_key).expect("Never fails");
+        self.encoded_bytes -= next.bytes.len();
+        self.replace_block(first_key, entries);
+    }
+}
+impl IndexBackend for CompactIndex {
+    fn get(&self, lump_id: &LumpId) -> Option<PortionU64> {
+        let key = lump_id.as_u128();
+        let i = self.find_block(key)?;
+        self.blocks[&i]
+            .iter()
+            .take_while(|&(k, _)| k <= key)
+            .find(|&(k, _)| k == key)
+            .map(|(_, v)| PortionU64::from_u64(v))
+    }
+
+    fn insert(&mut self, lump_id: LumpId, portion: PortionU64) -> Option<PortionU64> {
+        let key = lump_id.as_u128();
+        let value = portion.as_u64();
+        let i = match self
+            .find_block(key)
+            .or_else(|| self.blocks.keys().next().cloned())
+        {
+            None => {
+                self.insert_block(Block::new(&[(key, value)]));
+                self.len += 1;
+                return None;
+            }
+            Some(i) => i,
+        };
+        let mut entries = self.blocks[&i].decode();
+        let old = match entries.binary_search_by_key(&key, |&(k, _)| k) {
+            Ok(j) => Some(mem::replace(&mut entries[j].1, value)),
+            Err(j) => {
+                entries.insert(j, (key, value));
+                self.len += 1;
+                None
+            }
+        };
+        self.replace_block(i, entries);
+        old.map(PortionU64::from_u64)
+    }
+
+    fn remove(&mut self, lump_id: &LumpId) -> Option<PortionU64> {
+        let key = lump_id.as_u128();
+        let i = self.find_block(key)?;
+        let mut entries = self.blocks[&i].decode();
+        let j = entries.binary_search_by_key(&key, |&(k, _)| k).ok()?;
+        let (_, old) = entries.remove(j);
+        self.len -= 1;
+
+        if let Some(i) = self.replace_block(i, entries) {
+            self.merge_if_needed(i);
+        }
+        Some(PortionU64::from_u64(old))
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn range<'a>(
+        &'a self,
+        range: ops::Range<LumpId>,
+    ) -> Box<dyn Iterator<Item = (LumpId, PortionU64)> + 'a> {
+        let start = range.start.as_u128();
+        let end = range.end.as_u128();
+        let i = self.find_block(start).unwrap_or(0);
+        let iter = self
+            .blocks
+            .range(i..)
+            .map(|(_, b)| b)
+            .take_while(move |b| b.first_key < end)
+            .flat_map(Block::iter)
+            .skip_while(move |&(k, _)| k < start)
+            .take_while(move |&(k, _)| k < end)
+            .map(|(k, v)| (LumpId::new(k), PortionU64::from_u64(v)));
+        Box::new(iter)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (LumpId, PortionU64)> + 'a> {
+        let iter = self
+            .blocks
+            .values()
+            .flat_map(Block::iter)
+            .map(|(k, v)| (LumpId::new(k), PortionU64::from_u64(v)));
+        Box::new(iter)
+    }
+
+    fn memory_usage(&self) -> u64 {
+        // `BTreeMap`のノードの管理領域は考慮していない(ブロック数はエントリ数に比べて十分に少ない)
+        let fixed = mem::size_of::<Self>()
+            + self.blocks.len() * (mem::size_of::<u128>() + mem::size_of::<Block>());
+        (fixed + self.encoded_bytes) as u64
+    }
+
+    fn clone_box(&self) -> Box<dyn IndexBackend> {
+        Box::new(self.clone())
+    }
+}
+
+/// エントリ群を符号化して保持するブロック.
+///
+/// 各エントリは、以下の形式で`bytes`に格納される:
+///
+/// - 直前のエントリのIDとの差分(LEB128形式の可変長整数)
+///   - 先頭エントリの場合は`first_key`との差分(i.e., 常に`0`)
+/// - `PortionU64`の内部表現(8バイト、リトルエンディアン)
+#[derive(Debug, Clone)]
+struct Block {
+    first_key: u128,
+    len: u16,
+    bytes: Box<[u8]>,
+}
+impl Block {
+    /// `entries`はIDの昇順にソートされている必要がある.
+    fn new(entries: &[(u128, u64)]) -> Self {
+        debug_assert!(!entries.is_empty());
+        debug_assert!(entries.len() <= MAX_BLOCK_ENTRIES * 2);
+
+        let first_key = entries[0].0;
+        let mut bytes = Vec::with_capacity(entries.len() * 10);
+        let mut prev = first_key;
+        for &(key, value) in entries {
+            write_varint(&mut bytes, key - prev);
+            let mut buf = [0; 8];
+            LittleEndian::write_u64(&mut buf, value);
+            bytes.extend_from_slice(&buf);
+            prev = key;
+        }
+        Block {
+            first_key,
+            len: entries.len() as u16,
+            bytes: bytes.into_boxed_slice(),
+        }
+    }
+
+    fn decode(&self) -> Vec<(u128, u64)> {
+        let mut entries = Vec::with_capacity(self.len as usize + 1);
+        entries.extend(self.iter());
+        entries
+    }
+
+    fn iter(&self) -> BlockIter<'_> {
+        BlockIter {
+            bytes: &self.bytes,
+            prev: self.first_key,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BlockIter<'a> {
+    bytes: &'a [u8],
+    prev: u128,
+}
+impl<'a> Iterator for BlockIter<'a> {
+    type Item = (u128, u64);
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        let key = self.prev + read_varint(&mut self.bytes);
+        let value = LittleEndian::read_u64(&self.bytes[..8]);
+        self.bytes = &self.bytes[8..];
+        self.prev = key;
+        Some((key, value))
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u128) {
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> u128 {
+    let mut n = 0;
+    let mut shift = 0;
+    loop {
+        let b = bytes[0];
+        *bytes = &bytes[1..];
+        n |= u128::from(b & 0x7F) << shift;
+        if b < 0x80 {
+            return n;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_works() {
+        for &n in &[0, 1, 127, 128, 300, u128::from(u64::MAX), u128::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, n);
+            let mut bytes = &buf[..];
+            assert_eq!(read_varint(&mut bytes), n);
+            assert!(bytes.is_empty());
+        }
+    }
+
+    #[test]
+    fn blocks_are_split_and_merged() {
+        let mut index = CompactIndex::new();
+        for i in 0..1000 {
+            index.insert(LumpId::new(i), PortionU64::from_u64(i as u64));
+        }
+        assert_eq!(index.len(), 1000);
+        assert!(index.blocks.len() > 1000 / MAX_BLOCK_ENTRIES);
+        assert!(index
+            .blocks
+            .values()
+            .all(|b| b.len as usize <= MAX_BLOCK_ENTRIES));
+
+        // 連続したIDであれば、エントリ一つ当たり9バイトで済む
+        assert_eq!(index.encoded_bytes, 1000 * 9);
+
+        for i in 0..1000 {
+            if i % 10 != 0 {
+                assert!(index.remove(&LumpId::new(i)).is_some());
+            }
+        }
+        assert_eq!(index.len(), 100);
+        assert!(index.blocks.len() <= 100 / (MAX_BLOCK_ENTRIES / 4) + 1);
+        assert_eq!(
+            index.iter().map(|(k, _)| k.as_u128()).collect::<Vec<_>>(),
+            (0..100).map(|i| i * 10).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn random_order_operations_work() {
+        // 10^6エントリ規模での性能は`benches/storage.rs`で計測している
+        const N: u64 = 50_000;
+
+        // 挿入順序を散らすために、`N`と互いに素な数を掛けた値を使う
+        let key = |i: u64| u128::from(i * 7_919 % N);
+        let mut index = CompactIndex::new();
+        for i in 0..N {
+            let k = key(i);
+            assert!(index
+                .insert(LumpId::new(k), PortionU64::from_u64(k as u64))
+                .is_none());
+        }
+        assert_eq!(index.len(), N as usize);
+        assert!(index
+            .blocks
+            .iter()
+            .all(|(&k, b)| k == b.first_key && b.len as usize <= MAX_BLOCK_ENTRIES));
+        for k in (0..N).step_by(997) {
+            let portion = index.get(&LumpId::new(u128::from(k)));
+            assert_eq!(portion.map(|p| p.as_u64()), Some(k));
+        }
+
+        for i in 0..N {
+            let k = key(i);
+            if k % 2 == 1 {
+                assert!(index.remove(&LumpId::new(k)).is_some());
+            }
+        }
+        assert_eq!(index.len(), N as usize / 2);
+        assert_eq!(
+            index
+                .range(LumpId::new(100)..LumpId::new(110))
+                .map(|(k, _)| k.as_u128())
+                .collect::<Vec<_>>(),
+            vec![100, 102, 104, 106, 108]
+        );
+        assert!(index
+            .iter()
+            .map(|(k, _)| k.as_u128())
+            .eq((0..N).step_by(2).map(u128::from)));
+    }
+}
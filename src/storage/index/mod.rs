@@ -0,0 +1,332 @@
+//! デバイスに格納されているlump群の情報を管理するためのインデックス.
+use prometrics::metrics::Gauge;
+use std::collections::HashSet;
+use std::fmt;
+use std::ops;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use self::btree::BTreeIndex;
+use self::compact::CompactIndex;
+use crate::block::BlockSize;
+use crate::lump::LumpId;
+use crate::storage::portion::{DataPortion, PackedPortion, Portion, PortionU64};
+use crate::storage::Address;
+use crate::storage::StorageUsage;
+
+mod btree;
+mod compact;
+
+/// `LumpIndex`の内部実装(バックエンド)の種類.
+///
+/// どのバックエンドを選択しても、インデックスとしての振る舞いは変わらない.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LumpIndexBackend {
+    /// `BTreeMap`を用いた実装.
+    ///
+    /// 検索・更新は高速だが、lump一つ当たり40バイト程度のメモリを消費する.
+    ///
+    /// これがデフォルト.
+    #[default]
+    BTree,
+
+    /// ソート済みのブロック群に、差分符号化されたキーを格納する実装.
+    ///
+    /// `BTree`に比べて検索・更新は遅くなるが、メモリ使用量は半分程度以下に抑えられる.
+    /// (IDが密に割り当てられている場合には、lump一つ当たり10バイト程度)
+    ///
+    /// 小さなlumpを大量に格納する巨大なストレージ向け.
+    Compact,
+}
+
+/// `LumpIndex`のバックエンドが実装すべきインタフェース.
+pub(crate) trait IndexBackend: fmt::Debug + Send + Sync {
+    /// 指定されたlumpを検索する.
+    fn get(&self, lump_id: &LumpId) -> Option<PortionU64>;
+
+    /// lumpを登録する.
+    ///
+    /// 既に同じIDのlumpが存在する場合には、古い値を置き換えた上で、それを返す.
+    fn insert(&mut self, lump_id: LumpId, portion: PortionU64) -> Option<PortionU64>;
+
+    /// 指定されたlumpを削除する.
+    fn remove(&mut self, lump_id: &LumpId) -> Option<PortionU64>;
+
+    /// 登録されているlumpの数を返す.
+    fn len(&self) -> usize;
+
+    /// 指定範囲に含まれるlump群を、IDの昇順に走査するためのイテレータを返す.
+    fn range<'a>(
+        &'a self,
+        range: ops::Range<LumpId>,
+    ) -> Box<dyn Iterator<Item = (LumpId, PortionU64)> + 'a>;
+
+    /// 登録されている全てのlumpを、IDの昇順に走査するためのイテレータを返す.
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (LumpId, PortionU64)> + 'a>;
+
+    /// インデックスが使用しているメモリ量(バイト単位)の概算値を返す.
+    ///
+    /// 実装は`O(1)`で値を返す必要がある.
+    fn memory_usage(&self) -> u64;
+
+    /// インスタンスを複製する.
+    fn clone_box(&self) -> Box<dyn IndexBackend>;
+}
+
+/// Lump群の位置情報を保持するインデックス.
+///
+/// デバイスに格納されているlumpのID群と、それぞれのデータの格納先の情報、を保持している.
+///
+/// このインデックス自体は永続化されることはないメモリ上のデータ構造であり、
+/// デバイスの起動時に、ジャーナルの情報を用いて毎回再構築される.
+#[derive(Debug)]
+pub struct LumpIndex {
+    backend: Box<dyn IndexBackend>,
+    memory_usage_gauge: Option<Gauge>,
+}
+impl LumpIndex {
+    /// 新しい`LumpIndex`インスタンスを生成する.
+    pub fn new() -> Self {
+        Self::with_backend(LumpIndexBackend::default())
+    }
+
+    /// 指定されたバックエンドを用いる`LumpIndex`インスタンスを生成する.
+    pub fn with_backend(backend: LumpIndexBackend) -> Self {
+        let backend: Box<dyn IndexBackend> = match backend {
+            LumpIndexBackend::BTree => Box::new(BTreeIndex::new()),
+            LumpIndexBackend::Compact => Box::new(CompactIndex::new()),
+        };
+        LumpIndex {
+            backend,
+            memory_usage_gauge: None,
+        }
+    }
+
+    /// インデックスのメモリ使用量を反映するためのゲージを設定する.
+    ///
+    /// 以後、インデックスが更新される度にゲージの値も更新される.
+    pub(crate) fn set_memory_usage_gauge(&mut self, gauge: Gauge) {
+        self.memory_usage_gauge = Some(gauge);
+        self.update_memory_usage_gauge();
+    }
+
+    /// 渡された範囲オブジェクトrangeを用いて、
+    /// 登録されているlumpのうちrangeに含まれるもののストレージ使用量を返す。
+    pub fn usage_range(&self, range: ops::Range<LumpId>, block_size: BlockSize) -> StorageUsage {
+        StorageUsage::approximate(self.backend.range(range).fold(0, |acc, (_, p)| {
+            acc + Portion::from(p).len(block_size) as u64
+        }))
+    }
+
+    /// 指定されたlumpを検索する.
+    pub fn get(&self, lump_id: &LumpId) -> Option<Portion> {
+        self.backend.get(lump_id).map(std::convert::Into::into)
+    }
+
+    /// 新規lumpを登録する.
+    pub fn insert(&mut self, lump_id: LumpId, portion: Portion) {
+        self.backend.insert(lump_id, portion.into());
+        self.update_memory_usage_gauge();
+    }
+
+    /// インデックスのサイズ(i.e., 登録lump数)を返す.
+    ///
+    /// 結果は昇順にソートされている.
+    pub fn remove(&mut self, lump_id: &LumpId) -> Option<Portion> {
+        let removed = self.backend.remove(lump_id).map(std::convert::Into::into);
+        self.update_memory_usage_gauge();
+        removed
+    }
+
+    /// 登録されているlumpのID一覧を返す.
+    pub fn list(&self) -> Vec<LumpId> {
+        self.backend.iter().map(|(k, _)| k).collect()
+    }
+
+    /// インデックスのサイズ(i.e., 登録lump数)を返す.
+    pub fn len(&self) -> u64 {
+        self.backend.len() as u64
+    }
+
+    /// 割当済みのデータ部分領域を操作するためのイテレータを返す.
+    ///
+    /// パック内に格納されているlumpに関しては、パックが占有する部分領域が(重複なしで)返される.
+    pub fn data_portions(&self) -> DataPortions<'_> {
+        DataPortions {
+            inner: self.backend.iter(),
+            packs: HashSet::new(),
+        }
+    }
+
+    /// パック内に格納されているlump群を、IDの昇順に走査するためのイテレータを返す.
+    pub fn packed_portions<'a>(&'a self) -> impl Iterator<Item = (LumpId, PackedPortion)> + 'a {
+        self.backend
+            .iter()
+            .filter_map(|(lump_id, portion)| match portion.into() {
+                Portion::Packed(portion) => Some((lump_id, portion)),
+                _ => None,
+            })
+    }
+
+    /// 渡された範囲オブジェクトrangeを用いて、
+    /// 登録されているlumpのうちrangeに含まれるものの一覧を返す。
+    pub fn list_range(&self, range: ops::Range<LumpId>) -> Vec<LumpId> {
+        self.backend.range(range).map(|(k, _)| k).collect()
+    }
+
+    fn update_memory_usage_gauge(&self) {
+        if let Some(ref gauge) = self.memory_usage_gauge {
+            gauge.set(self.backend.memory_usage() as f64);
+        }
+    }
+}
+impl Default for LumpIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Clone for LumpIndex {
+    fn clone(&self) -> Self {
+        LumpIndex {
+            backend: self.backend.clone_box(),
+            memory_usage_gauge: None,
+        }
+    }
+}
+
+/// 複数のスレッドから共有可能な`LumpIndex`.
+///
+/// 更新は`Storage`を保持するスレッドのみが行い、並列読み込み用のスレッド群は検索のみを行う.
+#[derive(Debug, Clone)]
+pub(crate) struct SharedLumpIndex(Arc<RwLock<LumpIndex>>);
+impl SharedLumpIndex {
+    pub fn new(index: LumpIndex) -> Self {
+        SharedLumpIndex(Arc::new(RwLock::new(index)))
+    }
+
+    /// 検索用にインデックスをロックする.
+    pub fn read(&self) -> RwLockReadGuard<'_, LumpIndex> {
+        // インデックスの操作中にパニックすることはないので、ポイズニングは無視する
+        self.0.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 更新用にインデックスをロックする.
+    pub fn write(&self) -> RwLockWriteGuard<'_, LumpIndex> {
+        self.0.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub struct DataPortions<'a> {
+    inner: Box<dyn Iterator<Item = (LumpId, PortionU64)> + 'a>,
+    packs: HashSet<Address>,
+}
+impl<'a> fmt::Debug for DataPortions<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DataPortions(_)")
+    }
+}
+impl<'a> Iterator for DataPortions<'a> {
+    type Item = DataPortion;
+    fn next(&mut self) -> Option<Self::Item> {
+        for (_, portion) in &mut self.inner {
+            match portion.into() {
+                Portion::Data(portion) => return Some(portion),
+                Portion::Packed(portion) => {
+                    if self.packs.insert(portion.pack) {
+                        return Some(portion.pack_portion());
+                    }
+                }
+                Portion::Journal(_) => {}
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::portion::JournalPortion;
+    use crate::storage::Address;
+
+    #[test]
+    fn backends_behave_identically() {
+        let mut btree = LumpIndex::with_backend(LumpIndexBackend::BTree);
+        let mut compact = LumpIndex::with_backend(LumpIndexBackend::Compact);
+
+        // 疑似乱数で、ある程度まばらなIDの挿入・削除を行う
+        let mut x: u64 = 88_172_645_463_325_252;
+        for i in 0..20_000u64 {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            let id = LumpId::new(u128::from(x % 5000) << (i % 3 * 40));
+            let portion = portion(i);
+            if x % 4 == 0 {
+                assert_eq!(btree.remove(&id), compact.remove(&id));
+            } else {
+                btree.insert(id, portion);
+                compact.insert(id, portion);
+            }
+        }
+        btree.insert(LumpId::new(u128::MAX), portion(1));
+        compact.insert(LumpId::new(u128::MAX), portion(1));
+
+        assert_eq!(btree.len(), compact.len());
+        assert_eq!(btree.list(), compact.list());
+        for id in btree.list() {
+            assert_eq!(btree.get(&id), compact.get(&id));
+        }
+        let range = LumpId::new(100)..LumpId::new(3000 << 40);
+        assert_eq!(
+            btree.list_range(range.clone()),
+            compact.list_range(range.clone())
+        );
+        assert_eq!(
+            btree
+                .usage_range(range.clone(), BlockSize::min())
+                .bytecount(),
+            compact.usage_range(range, BlockSize::min()).bytecount()
+        );
+        assert_eq!(
+            btree.data_portions().collect::<Vec<_>>(),
+            compact.data_portions().collect::<Vec<_>>()
+        );
+        assert!(compact.backend.memory_usage() < btree.backend.memory_usage());
+    }
+
+    #[test]
+    fn memory_usage_gauge_works() {
+        let mut index = LumpIndex::with_backend(LumpIndexBackend::Compact);
+        let gauge = Gauge::new("test_index_memory_usage").unwrap();
+        index.set_memory_usage_gauge(gauge.clone());
+        let empty = gauge.value();
+        assert_eq!(empty, index.backend.memory_usage() as f64);
+
+        for i in 0..1000 {
+            index.insert(LumpId::new(i), portion(i as u64));
+        }
+        let full = gauge.value();
+        assert!(full > empty);
+        assert_eq!(full, index.backend.memory_usage() as f64);
+
+        for i in 0..1000 {
+            index.remove(&LumpId::new(i));
+        }
+        assert!(gauge.value() < full);
+        assert_eq!(gauge.value(), index.backend.memory_usage() as f64);
+    }
+
+    fn portion(i: u64) -> Portion {
+        if i % 2 == 0 {
+            Portion::Data(DataPortion {
+                start: Address::from_u64(i * 10).unwrap(),
+                len: (i % 100) as u32,
+            })
+        } else {
+            Portion::Journal(JournalPortion {
+                start: Address::from_u64(i).unwrap(),
+                len: (i % 100) as u16,
+            })
+        }
+    }
+}
@@ -1,50 +1,173 @@
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use std::io::{Read, SeekFrom, Write};
+use adler32::RollingAdler32;
+use byteorder::{BigEndian, ByteOrder};
+use std::io::{Read, Write};
+use trackable::error::ErrorKindExt;
 
-use crate::Result;
+use crate::{ErrorKind, Result};
 
 use crate::block::{AlignedBytes, BlockSize};
 use crate::nvm::NonVolatileMemory;
 
+/// ジャーナルヘッダの先頭(リングバッファの始端位置の直後)に書き込まれるマジックナンバー.
+///
+/// これを含まないヘッダは、v1.1以前の形式として扱われる.
+const MAGIC_NUMBER: [u8; 4] = *b"jhdr";
+
+/// ヘッダを表現するのに必要なバイト数.
+const HEADER_SIZE: usize =
+    8 /* ring_buffer_head */ +
+    4 /* magic number */ +
+    8 /* seqno */ +
+    1 /* flags */ +
+    4 /* checksum */;
+
+/// LSNセクション(基本部分の直後に置かれる)を表現するのに必要なバイト数.
+///
+/// 基本部分のチェックサムとは独立したチェックサムを持つため、
+/// このセクションを知らない古い実装でも、ヘッダを読み込むことができる.
+const LSN_SECTION_SIZE: usize =
+    8 /* ring_buffer_head_lsn */ +
+    4 /* checksum */;
+
+/// ヘッダのフラグ: ストレージが正常に閉じられたかどうか.
+const FLAG_CLEAN_SHUTDOWN: u8 = 0b0000_0001;
+
+/// ヘッダのフラグ: LSNセクションを含むかどうか.
+const FLAG_HAS_LSN: u8 = 0b0000_0010;
+
 /// ジャーナルのヘッダ.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct JournalHeader {
     /// ジャーナルのリングバッファの始端位置.
     pub ring_buffer_head: u64,
+
+    /// ヘッダのシーケンス番号.
+    ///
+    /// ヘッダが書き込まれる度にインクリメントされる.
+    /// 二重化されたヘッダのどちらが新しいかを判定するために使用される.
+    pub seqno: u64,
+
+    /// ストレージが正常に閉じられた(i.e., `Storage::close`が呼ばれた)かどうか.
+    ///
+    /// ストレージのオープン時には、この値は`false`に更新される.
+    /// v1.1以前の形式のヘッダでは、常に`false`となる.
+    pub clean_shutdown: bool,
+
+    /// リングバッファの始端位置に対応するLSN(ログシーケンス番号).
+    ///
+    /// LSNの詳細は`JournalEntry::lsn`を参照のこと.
+    /// LSNセクションを含まない古い形式のヘッダでは、常に`0`となる.
+    pub ring_buffer_head_lsn: u64,
 }
 impl JournalHeader {
     /// ストレージ初期化時のヘッダを生成する.
     pub fn new() -> Self {
         JournalHeader {
             ring_buffer_head: 0,
+            seqno: 0,
+            clean_shutdown: false,
+            ring_buffer_head_lsn: 0,
         }
     }
 
     /// ヘッダを書き込む.
     pub fn write_to<W: Write>(&self, mut writer: W, block_size: BlockSize) -> Result<()> {
-        let padding = vec![0; JournalHeader::region_size(block_size) - 8];
-        track_io!(writer.write_u64::<BigEndian>(self.ring_buffer_head))?;
-        track_io!(writer.write_all(&padding))?;
+        let mut buf = vec![0; JournalHeader::region_size(block_size)];
+        BigEndian::write_u64(&mut buf[0..8], self.ring_buffer_head);
+        buf[8..12].copy_from_slice(&MAGIC_NUMBER[..]);
+        BigEndian::write_u64(&mut buf[12..20], self.seqno);
+        buf[20] = FLAG_HAS_LSN;
+        if self.clean_shutdown {
+            buf[20] |= FLAG_CLEAN_SHUTDOWN;
+        }
+        let checksum = adler32(&buf[0..21]);
+        BigEndian::write_u32(&mut buf[21..HEADER_SIZE], checksum);
+
+        let lsn_section = &mut buf[HEADER_SIZE..HEADER_SIZE + LSN_SECTION_SIZE];
+        BigEndian::write_u64(&mut lsn_section[0..8], self.ring_buffer_head_lsn);
+        let checksum = adler32(&lsn_section[0..8]);
+        BigEndian::write_u32(&mut lsn_section[8..12], checksum);
+        track_io!(writer.write_all(&buf))?;
         Ok(())
     }
 
     /// ヘッダを読み込む.
-    pub fn read_from<R: Read>(mut reader: R, block_size: BlockSize) -> Result<Self> {
-        let mut padding = vec![0; JournalHeader::region_size(block_size) - 8];
-        let ring_buffer_head = track_io!(reader.read_u64::<BigEndian>())?;
-        track_io!(reader.read_exact(&mut padding))?;
-        Ok(JournalHeader { ring_buffer_head })
+    ///
+    /// マジックナンバーを含まないヘッダは、v1.1以前の形式とみなして`seqno=0`として扱う.
+    pub fn read_from<R: Read>(reader: R, block_size: BlockSize) -> Result<Self> {
+        let header = track!(Self::read_from_impl(reader, block_size))?;
+        Ok(header.unwrap_or_else(|ring_buffer_head| JournalHeader {
+            ring_buffer_head,
+            seqno: 0,
+            clean_shutdown: false,
+            ring_buffer_head_lsn: 0,
+        }))
+    }
+
+    /// ヘッダを読み込む.
+    ///
+    /// `read_from`とは異なり、v1.1以前の形式のヘッダはエラーとして扱う.
+    pub fn read_strictly_from<R: Read>(reader: R, block_size: BlockSize) -> Result<Self> {
+        let header = track!(Self::read_from_impl(reader, block_size))?;
+        let header = track!(header
+            .map_err(|_| ErrorKind::StorageCorrupted.cause("Journal header has no magic number")))?;
+        Ok(header)
     }
 
     /// ヘッダ領域のサイズ（バイト数）.
     pub fn region_size(block_size: BlockSize) -> usize {
-        block_size.as_u16() as usize
+        block_size.as_u32() as usize
+    }
+
+    /// v1.1以前の形式の場合には`Err(ring_buffer_head)`が返される.
+    fn read_from_impl<R: Read>(
+        mut reader: R,
+        block_size: BlockSize,
+    ) -> Result<std::result::Result<Self, u64>> {
+        let mut buf = vec![0; JournalHeader::region_size(block_size)];
+        track_io!(reader.read_exact(&mut buf))?;
+
+        let ring_buffer_head = BigEndian::read_u64(&buf[0..8]);
+        if buf[8..12] != MAGIC_NUMBER[..] {
+            return Ok(Err(ring_buffer_head));
+        }
+        let seqno = BigEndian::read_u64(&buf[12..20]);
+        let flags = buf[20];
+        let checksum = BigEndian::read_u32(&buf[21..HEADER_SIZE]);
+        track_assert_eq!(
+            adler32(&buf[0..21]),
+            checksum,
+            ErrorKind::StorageCorrupted,
+            "Journal header checksum mismatched"
+        );
+
+        let mut ring_buffer_head_lsn = 0;
+        if (flags & FLAG_HAS_LSN) != 0 {
+            let lsn_section = &buf[HEADER_SIZE..HEADER_SIZE + LSN_SECTION_SIZE];
+            track_assert_eq!(
+                adler32(&lsn_section[0..8]),
+                BigEndian::read_u32(&lsn_section[8..12]),
+                ErrorKind::StorageCorrupted,
+                "Journal header LSN checksum mismatched"
+            );
+            ring_buffer_head_lsn = BigEndian::read_u64(&lsn_section[0..8]);
+        }
+        Ok(Ok(JournalHeader {
+            ring_buffer_head,
+            seqno,
+            clean_shutdown: (flags & FLAG_CLEAN_SHUTDOWN) != 0,
+            ring_buffer_head_lsn,
+        }))
     }
 }
 
 /// ジャーナルのヘッダ領域.
 ///
 /// 先頭の一ブロックがヘッダ用に割り当てられる.
+///
+/// ヘッダの冗長化が有効な場合には、先頭の二ブロックが割り当てられ(A/Bスロット)、
+/// ヘッダの書き込みはシーケンス番号に応じて交互に行われる.
+/// 読み込み時には、有効なスロットの中で、最もシーケンス番号が大きいものが採用される.
 #[derive(Debug)]
 pub struct JournalHeaderRegion<N> {
     /// ヘッダ用の領域.
@@ -52,49 +175,168 @@ pub struct JournalHeaderRegion<N> {
 
     /// ストレージが採用しているブロックサイズ.
     block_size: BlockSize,
+
+    /// ヘッダが二重化されているかどうか.
+    redundant: bool,
+
+    /// 最後に読み書きしたヘッダ.
+    last_header: JournalHeader,
+
+    /// 最後の読み込み時に、壊れていることが判明したスロット群.
+    damaged_slots: Vec<u64>,
 }
 impl<N: NonVolatileMemory> JournalHeaderRegion<N> {
     /// ヘッダ領域管理用のインスタンスを生成する.
-    pub fn new(nvm: N, block_size: BlockSize) -> Self {
-        JournalHeaderRegion { nvm, block_size }
+    pub fn new(nvm: N, block_size: BlockSize, redundant: bool) -> Self {
+        JournalHeaderRegion {
+            nvm,
+            block_size,
+            redundant,
+            last_header: JournalHeader::new(),
+            damaged_slots: Vec::new(),
+        }
+    }
+
+    /// ヘッダ領域全体のサイズ（バイト数）.
+    pub fn region_size(block_size: BlockSize, redundant: bool) -> usize {
+        JournalHeader::region_size(block_size) * Self::slot_count(redundant) as usize
+    }
+
+    /// ヘッダ領域の初期化を行う.
+    ///
+    /// `head_lsn`は、リングバッファの先頭に割り当てるLSN(新規作成時には`0`).
+    pub fn initialize<W: Write>(
+        mut writer: W,
+        block_size: BlockSize,
+        redundant: bool,
+        head_lsn: u64,
+    ) -> Result<()> {
+        let header = JournalHeader {
+            ring_buffer_head_lsn: head_lsn,
+            ..JournalHeader::new()
+        };
+        for _ in 0..Self::slot_count(redundant) {
+            track!(header.write_to(&mut writer, block_size))?;
+        }
+        Ok(())
     }
 
     /// ヘッダを書き込む.
+    ///
+    /// `header.seqno`の値は無視され、前回の値をインクリメントしたものが使用される.
     pub fn write_header(&mut self, header: &JournalHeader) -> Result<()> {
-        let mut buf =
-            AlignedBytes::new(JournalHeader::region_size(self.block_size), self.block_size);
-        track!(header.write_to(&mut buf[..], self.block_size))?;
-
-        track_io!(self.nvm.seek(SeekFrom::Start(0)))?;
-        track_io!(self.nvm.write_all(&buf))?;
+        let header = JournalHeader {
+            seqno: self.last_header.seqno + 1,
+            ..*header
+        };
+        let slot = header.seqno % Self::slot_count(self.redundant);
+        track!(self.write_slot(slot, &header))?;
         track!(self.nvm.sync())?;
+        self.last_header = header;
         Ok(())
     }
 
     /// ヘッダを読み込む.
     pub fn read_header(&mut self) -> Result<JournalHeader> {
-        let mut buf =
-            AlignedBytes::new(JournalHeader::region_size(self.block_size), self.block_size);
-        track_io!(self.nvm.seek(SeekFrom::Start(0)))?;
-        track_io!(self.nvm.read_exact(&mut buf))?;
+        self.damaged_slots.clear();
+        if !self.redundant {
+            let buf = track!(self.read_slot(0))?;
+            let header = track!(JournalHeader::read_from(&buf[..], self.block_size))?;
+            self.last_header = header;
+            return Ok(header);
+        }
 
-        let header = track!(JournalHeader::read_from(&buf[..], self.block_size))?;
-        Ok(header)
+        let mut latest: Option<JournalHeader> = None;
+        let mut last_error = None;
+        for slot in 0..Self::slot_count(self.redundant) {
+            let result = self
+                .read_slot(slot)
+                .and_then(|buf| JournalHeader::read_strictly_from(&buf[..], self.block_size));
+            match result {
+                Err(e) => {
+                    self.damaged_slots.push(slot);
+                    last_error = Some(e);
+                }
+                Ok(header) => {
+                    if latest.is_none_or(|h| h.seqno < header.seqno) {
+                        latest = Some(header);
+                    }
+                }
+            }
+        }
+        if let Some(header) = latest {
+            self.last_header = header;
+            Ok(header)
+        } else {
+            Err(track!(last_error.expect("Never fails")))
+        }
+    }
+
+    /// 直前の`read_header`呼び出しで壊れていることが判明したスロットの数を返す.
+    pub fn damaged_slots(&self) -> usize {
+        self.damaged_slots.len()
+    }
+
+    /// 壊れていたスロットを、最新のヘッダの内容で上書きする.
+    pub fn repair(&mut self) -> Result<()> {
+        if self.damaged_slots.is_empty() {
+            return Ok(());
+        }
+        let header = self.last_header;
+        for slot in std::mem::take(&mut self.damaged_slots) {
+            track!(self.write_slot(slot, &header))?;
+        }
+        track!(self.nvm.sync())?;
+        Ok(())
+    }
+
+    fn slot_count(redundant: bool) -> u64 {
+        if redundant {
+            2
+        } else {
+            1
+        }
+    }
+
+    fn write_slot(&mut self, slot: u64, header: &JournalHeader) -> Result<()> {
+        let size = JournalHeader::region_size(self.block_size);
+        let mut buf = AlignedBytes::new(size, self.block_size);
+        track!(header.write_to(&mut buf[..], self.block_size))?;
+
+        track!(self.nvm.write_at(&buf, slot * size as u64))
+    }
+
+    fn read_slot(&mut self, slot: u64) -> Result<AlignedBytes> {
+        let size = JournalHeader::region_size(self.block_size);
+        let mut buf = AlignedBytes::new(size, self.block_size);
+        track!(self.nvm.read_at(&mut buf, slot * size as u64))?;
+        Ok(buf)
     }
 }
 
+/// `bytes`のAdler-32チェックサムを計算する.
+pub(crate) fn adler32(bytes: &[u8]) -> u32 {
+    let mut adler32 = RollingAdler32::new();
+    adler32.update_buffer(bytes);
+    adler32.hash()
+}
+
 #[cfg(test)]
 mod tests {
     use trackable::result::TestResult;
 
     use super::*;
     use crate::block::BlockSize;
+    use crate::nvm::MemoryNvm;
 
     #[test]
     fn it_works() -> TestResult {
         let block_size = BlockSize::min();
         let header = JournalHeader {
             ring_buffer_head: 1234,
+            seqno: 5,
+            clean_shutdown: true,
+            ring_buffer_head_lsn: 0x1_0000_1234,
         };
 
         let mut buf = Vec::new();
@@ -103,6 +345,73 @@ mod tests {
             JournalHeader::read_from(&buf[..], block_size).ok(),
             Some(header)
         );
+
+        // LSNセクションを含まない(古い形式の)ヘッダ
+        buf[20] &= !FLAG_HAS_LSN;
+        let checksum = adler32(&buf[0..21]);
+        BigEndian::write_u32(&mut buf[21..HEADER_SIZE], checksum);
+        assert_eq!(
+            JournalHeader::read_from(&buf[..], block_size).ok(),
+            Some(JournalHeader {
+                ring_buffer_head_lsn: 0,
+                ..header
+            })
+        );
+
+        // LSNセクションが壊れている
+        track!(header.write_to(&mut buf[..], block_size))?;
+        buf[HEADER_SIZE] ^= 0xFF;
+        assert!(JournalHeader::read_from(&buf[..], block_size).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn redundant_header_region_works() -> TestResult {
+        let block_size = BlockSize::min();
+        let region_size = JournalHeaderRegion::<MemoryNvm>::region_size(block_size, true);
+        assert_eq!(region_size, block_size.as_u32() as usize * 2);
+
+        let mut buf = Vec::new();
+        track!(JournalHeaderRegion::<MemoryNvm>::initialize(
+            &mut buf, block_size, true, 0
+        ))?;
+        let mut region = JournalHeaderRegion::new(MemoryNvm::new(buf), block_size, true);
+        assert_eq!(track!(region.read_header())?, JournalHeader::new());
+
+        // A/Bの各スロットに交互に書き込まれる
+        for head in 1..4 {
+            let header = JournalHeader {
+                ring_buffer_head: head,
+                seqno: 0,
+                clean_shutdown: false,
+                ring_buffer_head_lsn: head,
+            };
+            track!(region.write_header(&header))?;
+        }
+        let header = track!(region.read_header())?;
+        assert_eq!(header.ring_buffer_head, 3);
+        assert_eq!(header.seqno, 3);
+        assert_eq!(region.damaged_slots(), 0);
+
+        // 最新のスロット(B)を壊すと、一つ前のヘッダが使われる
+        let mut bytes = region.nvm.as_bytes().to_owned();
+        bytes[block_size.as_u32() as usize + 1] ^= 0xFF;
+        let mut region = JournalHeaderRegion::new(MemoryNvm::new(bytes), block_size, true);
+        let header = track!(region.read_header())?;
+        assert_eq!(header.ring_buffer_head, 2);
+        assert_eq!(region.damaged_slots(), 1);
+
+        // 修復
+        track!(region.repair())?;
+        assert_eq!(track!(region.read_header())?, header);
+        assert_eq!(region.damaged_slots(), 0);
+
+        // 両方壊れている場合にはエラー
+        let mut bytes = region.nvm.as_bytes().to_owned();
+        bytes[1] ^= 0xFF;
+        bytes[block_size.as_u32() as usize + 1] ^= 0xFF;
+        let mut region = JournalHeaderRegion::new(MemoryNvm::new(bytes), block_size, true);
+        assert!(region.read_header().is_err());
         Ok(())
     }
 }
@@ -1,6 +1,7 @@
+pub(crate) use self::header::adler32;
 pub use self::header::{JournalHeader, JournalHeaderRegion};
 pub use self::nvm_buffer::JournalNvmBuffer;
-pub use self::options::JournalRegionOptions;
+pub use self::options::{JournalRecoveryMode, JournalRegionOptions};
 pub use self::record::{JournalEntry, JournalRecord};
 pub use self::region::JournalRegion;
 
@@ -3,7 +3,7 @@ use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::ptr;
 
 use crate::block::{AlignedBytes, BlockSize};
-use crate::nvm::NonVolatileMemory;
+use crate::nvm::{NonVolatileMemory, SyncLevel};
 use crate::{ErrorKind, Result};
 
 /// ジャーナル領域用のバッファ.
@@ -100,9 +100,8 @@ impl<N: NonVolatileMemory> JournalNvmBuffer<N> {
             return Ok(());
         }
 
-        track_io!(self.inner.seek(SeekFrom::Start(self.write_buf_offset)))?;
-        track_io!(self.inner.write(&self.write_buf))?;
-        if self.write_buf.len() > self.block_size().as_u16() as usize {
+        track!(self.inner.write_at(&self.write_buf, self.write_buf_offset))?;
+        if self.write_buf.len() > self.block_size().as_u32() as usize {
             // このif節では、
             // バッファに末端のalignmentバイト分(= new_len)の情報を残す。
             // write_buf_offsetは、write_buf.len() - new_len(= drop_len)分だけ進められる。
@@ -112,7 +111,7 @@ impl<N: NonVolatileMemory> JournalNvmBuffer<N> {
             // ブロック長でしか書き出すことができないため、その場合は次回の書き込み時に
             // NVMに一度アクセスしてブロック全体を取得しなくてはならない。
             // この読み込みを避けるため、現在の実装の形をとっている。
-            let new_len = self.block_size().as_u16() as usize;
+            let new_len = self.block_size().as_u32() as usize;
             let drop_len = self.write_buf.len() - new_len;
             unsafe {
                 // This nonoverlappingness is guranteed by the callers.
@@ -130,6 +129,34 @@ impl<N: NonVolatileMemory> JournalNvmBuffer<N> {
         Ok(())
     }
 
+    /// 指定位置からの読み込みを行う(カーソル位置は変化しない).
+    ///
+    /// 返り値は、実際に読み込まれたバイト数.
+    fn read_impl(&mut self, buf: &mut [u8], position: u64) -> Result<usize> {
+        if self.is_dirty_area(position, buf.len()) {
+            track!(self.flush_write_buf())?;
+        }
+
+        let aligned_start = self.block_size().floor_align(position);
+        let aligned_end = cmp::min(
+            self.block_size().ceil_align(position + buf.len() as u64),
+            self.capacity(),
+        );
+        if aligned_end <= position {
+            return Ok(0);
+        }
+
+        self.read_buf
+            .aligned_resize((aligned_end - aligned_start) as usize);
+        track!(self.inner.read_at(&mut self.read_buf, aligned_start))?;
+
+        let start = (position - aligned_start) as usize;
+        let end = cmp::min(self.read_buf.len(), start + buf.len());
+        let read_size = end - start;
+        buf[..read_size].copy_from_slice(&self.read_buf[start..end]);
+        Ok(read_size)
+    }
+
     fn check_overflow(&self, write_len: usize) -> Result<()> {
         let next_position = self.position() + write_len as u64;
         track_assert!(
@@ -149,6 +176,11 @@ impl<N: NonVolatileMemory> NonVolatileMemory for JournalNvmBuffer<N> {
         self.inner.sync()
     }
 
+    fn sync_with(&mut self, level: SyncLevel) -> Result<()> {
+        track!(self.flush_write_buf())?;
+        track!(self.inner.sync_with(level))
+    }
+
     fn position(&self) -> u64 {
         self.position
     }
@@ -164,6 +196,16 @@ impl<N: NonVolatileMemory> NonVolatileMemory for JournalNvmBuffer<N> {
     fn split(self, _: u64) -> Result<(Self, Self)> {
         unreachable!()
     }
+
+    fn read_at(&mut self, buf: &mut [u8], position: u64) -> Result<()> {
+        track_assert!(
+            position + buf.len() as u64 <= self.capacity(),
+            ErrorKind::InvalidInput
+        );
+        let read_size = track!(self.read_impl(buf, position))?;
+        debug_assert_eq!(read_size, buf.len());
+        Ok(())
+    }
 }
 impl<N: NonVolatileMemory> Drop for JournalNvmBuffer<N> {
     fn drop(&mut self) {
@@ -179,24 +221,7 @@ impl<N: NonVolatileMemory> Seek for JournalNvmBuffer<N> {
 }
 impl<N: NonVolatileMemory> Read for JournalNvmBuffer<N> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.is_dirty_area(self.position, buf.len()) {
-            track!(self.flush_write_buf())?;
-        }
-
-        let aligned_start = self.block_size().floor_align(self.position);
-        let aligned_end = self
-            .block_size()
-            .ceil_align(self.position + buf.len() as u64);
-
-        self.read_buf
-            .aligned_resize((aligned_end - aligned_start) as usize);
-        self.inner.seek(SeekFrom::Start(aligned_start))?;
-        let inner_read_size = self.inner.read(&mut self.read_buf)?;
-
-        let start = (self.position - aligned_start) as usize;
-        let end = cmp::min(inner_read_size, start + buf.len());
-        let read_size = end - start;
-        (&mut buf[..read_size]).copy_from_slice(&self.read_buf[start..end]);
+        let read_size = track!(self.read_impl(buf, self.position))?;
         self.position += read_size as u64;
         Ok(read_size)
     }
@@ -226,11 +251,12 @@ impl<N: NonVolatileMemory> Write for JournalNvmBuffer<N> {
                 self.write_buf.aligned_resize(0);
             } else {
                 // シーク位置より前方の既存データが破棄されてしまわないように、一度読み込みを行う.
-                let size = self.block_size().as_u16();
+                let size = self.block_size().as_u32();
                 self.write_buf_offset = self.block_size().floor_align(self.position);
                 self.write_buf.aligned_resize(size as usize);
-                self.inner.seek(SeekFrom::Start(self.write_buf_offset))?;
-                self.inner.read_exact(&mut self.write_buf)?;
+                track!(self
+                    .inner
+                    .read_at(&mut self.write_buf, self.write_buf_offset))?;
             }
             self.write(buf)
         }
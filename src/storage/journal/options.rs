@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use crate::block::BlockSize;
 
 /// ジャーナル領域の挙動を調整するためのパラメータ群.
@@ -7,14 +9,49 @@ use crate::block::BlockSize;
 pub struct JournalRegionOptions {
     pub gc_queue_size: usize,
     pub sync_interval: usize,
+    pub sync_max_delay: Option<Duration>,
     pub block_size: BlockSize,
+    pub recovery_mode: JournalRecoveryMode,
+    pub read_only: bool,
+    pub redundant_header: bool,
 }
 impl Default for JournalRegionOptions {
     fn default() -> Self {
         JournalRegionOptions {
             gc_queue_size: 0x1000,
             sync_interval: 0x1000,
+            sync_max_delay: None,
             block_size: BlockSize::min(),
+            recovery_mode: JournalRecoveryMode::default(),
+            read_only: false,
+            redundant_header: false,
         }
     }
 }
+
+/// ストレージのオープン時に、ジャーナル領域から不正なレコードが見つかった場合の挙動.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum JournalRecoveryMode {
+    /// 不正なレコードが見つかった場合には、ストレージのオープンを失敗させる.
+    ///
+    /// これがデフォルトの挙動.
+    #[default]
+    Strict,
+
+    /// 永続化されているジャーナルの先頭以降で最初に見つかった不正なレコードの位置で、ジャーナルを切り詰める.
+    ///
+    /// クラッシュ等によりジャーナルの末尾が中途半端に書き込まれた(torn write)場合でも、
+    /// それ以前のレコード群を使ってストレージをオープンできるようにするためのモード.
+    ///
+    /// 不正なレコードの位置には`EndOfRecords`が書き込まれ、それ以降のレコード群は破棄される.
+    /// 破棄が発生したことはログに出力され、`JournalRegionMetrics::recovery_truncations`および
+    /// `JournalRegionMetrics::recovery_discarded_bytes`でも確認可能.
+    ///
+    /// 切り詰めの対象となるのは、チェックサムの不一致やデコードの失敗といった、
+    /// レコードの破損(`ErrorKind::StorageCorrupted`)が検出された場合のみで、
+    /// I/Oエラー等のそれ以外のエラーは、従来通りに呼び出し元に返される.
+    ///
+    /// なお、ストレージが読み込み専用で開かれている場合には`EndOfRecords`の書き込みは行われず、
+    /// メモリ上の状態のみが切り詰められる.
+    TruncateTornTail,
+}
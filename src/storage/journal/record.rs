@@ -4,7 +4,7 @@ use std::io::{Read, Write};
 use std::ops::Range;
 
 use crate::lump::LumpId;
-use crate::storage::portion::DataPortion;
+use crate::storage::portion::{DataPortion, PackedPortion};
 use crate::storage::Address;
 use crate::{ErrorKind, Result};
 
@@ -12,6 +12,8 @@ pub const TAG_SIZE: usize = 1;
 pub const CHECKSUM_SIZE: usize = 4;
 pub const LENGTH_SIZE: usize = 2;
 pub const PORTION_SIZE: usize = 5;
+pub const WIDE_LENGTH_SIZE: usize = 4;
+pub const WIDE_PORTION_SIZE: usize = 6;
 pub const END_OF_RECORDS_SIZE: usize = CHECKSUM_SIZE + TAG_SIZE;
 pub const EMBEDDED_DATA_OFFSET: usize = CHECKSUM_SIZE + TAG_SIZE + LumpId::SIZE + LENGTH_SIZE;
 
@@ -21,6 +23,8 @@ const TAG_PUT: u8 = 3;
 const TAG_EMBED: u8 = 4;
 const TAG_DELETE: u8 = 5;
 const TAG_DELETE_RANGE: u8 = 6;
+const TAG_PUT_PACKED: u8 = 7;
+const TAG_PUT_WIDE: u8 = 8;
 
 /// ジャーナル領域のリングバッファのエントリ.
 #[derive(Debug)]
@@ -28,6 +32,15 @@ pub struct JournalEntry {
     /// ジャーナル内でのレコードの開始位置.
     pub start: Address,
 
+    /// レコードのLSN(ログシーケンス番号).
+    ///
+    /// LSNは、ストレージの生成時からジャーナルに追記されたバイト数の累計(i.e., 仮想的な無限長のログ上でのオフセット)で、
+    /// レコードの追記順に単調増加する.
+    /// リングバッファの終端で先頭に戻る際に読み飛ばされる領域も、LSN上は消費されたものとして扱われる.
+    ///
+    /// なお、GCによって再配置されたレコードには、新しいLSNが割り当てられる.
+    pub lsn: u64,
+
     /// レコード.
     pub record: JournalRecord<Vec<u8>>,
 }
@@ -36,6 +49,11 @@ impl JournalEntry {
     pub fn end(&self) -> Address {
         self.start + Address::from(self.record.external_size() as u32)
     }
+
+    /// 後続のレコードのLSNの下限(i.e., このレコードの終端位置に対応するLSN)を返す.
+    pub fn next_lsn(&self) -> u64 {
+        self.lsn + self.record.external_size() as u64
+    }
 }
 
 /// ジャーナル領域のリングバッファに追記されていくレコード.
@@ -48,16 +66,25 @@ pub enum JournalRecord<T> {
     Embed(LumpId, T),
     Delete(LumpId),
     DeleteRange(Range<LumpId>),
+
+    /// データ領域のパック内に格納されたlumpのPUT.
+    ///
+    /// パッキングが有効なストレージでのみ使用される.
+    PutPacked(LumpId, PackedPortion),
 }
 impl<T: AsRef<[u8]>> JournalRecord<T> {
     /// 読み書き時のサイズ（バイト数）を返す.
     pub(crate) fn external_size(&self) -> usize {
         let record_size = match *self {
             JournalRecord::EndOfRecords | JournalRecord::GoToFront => 0,
+            JournalRecord::Put(_, portion) if is_wide_portion(portion) => {
+                LumpId::SIZE + WIDE_LENGTH_SIZE + WIDE_PORTION_SIZE
+            }
             JournalRecord::Put(..) => LumpId::SIZE + LENGTH_SIZE + PORTION_SIZE,
             JournalRecord::Embed(_, ref data) => LumpId::SIZE + LENGTH_SIZE + data.as_ref().len(),
             JournalRecord::Delete(..) => LumpId::SIZE,
             JournalRecord::DeleteRange(..) => LumpId::SIZE * 2,
+            JournalRecord::PutPacked(..) => LumpId::SIZE + PORTION_SIZE + LENGTH_SIZE * 2,
         };
         CHECKSUM_SIZE + TAG_SIZE + record_size
     }
@@ -72,10 +99,15 @@ impl<T: AsRef<[u8]>> JournalRecord<T> {
             JournalRecord::GoToFront => {
                 track_io!(writer.write_u8(TAG_GO_TO_FRONT))?;
             }
+            JournalRecord::Put(ref lump_id, portion) if is_wide_portion(portion) => {
+                track_io!(writer.write_u8(TAG_PUT_WIDE))?;
+                track_io!(writer.write_u128::<BigEndian>(lump_id.as_u128()))?;
+                track_io!(writer.write_all(&wide_portion_to_bytes(portion)))?;
+            }
             JournalRecord::Put(ref lump_id, portion) => {
                 track_io!(writer.write_u8(TAG_PUT))?;
                 track_io!(writer.write_u128::<BigEndian>(lump_id.as_u128()))?;
-                track_io!(writer.write_u16::<BigEndian>(portion.len))?;
+                track_io!(writer.write_u16::<BigEndian>(portion.len as u16))?;
                 track_io!(writer.write_uint::<BigEndian>(portion.start.as_u64(), PORTION_SIZE))?;
             }
             JournalRecord::Embed(ref lump_id, ref data) => {
@@ -94,6 +126,11 @@ impl<T: AsRef<[u8]>> JournalRecord<T> {
                 track_io!(writer.write_u128::<BigEndian>(range.start.as_u128()))?;
                 track_io!(writer.write_u128::<BigEndian>(range.end.as_u128()))?;
             }
+            JournalRecord::PutPacked(ref lump_id, portion) => {
+                track_io!(writer.write_u8(TAG_PUT_PACKED))?;
+                track_io!(writer.write_u128::<BigEndian>(lump_id.as_u128()))?;
+                track_io!(writer.write_all(&packed_portion_to_bytes(portion)))?;
+            }
         }
         Ok(())
     }
@@ -107,11 +144,16 @@ impl<T: AsRef<[u8]>> JournalRecord<T> {
             JournalRecord::GoToFront => {
                 adler32.update(TAG_GO_TO_FRONT);
             }
+            JournalRecord::Put(ref lump_id, portion) if is_wide_portion(portion) => {
+                adler32.update(TAG_PUT_WIDE);
+                adler32.update_buffer(&lump_id_to_u128(lump_id)[..]);
+                adler32.update_buffer(&wide_portion_to_bytes(portion));
+            }
             JournalRecord::Put(ref lump_id, portion) => {
                 adler32.update(TAG_PUT);
                 adler32.update_buffer(&lump_id_to_u128(lump_id)[..]);
                 let mut buf = [0; 7];
-                BigEndian::write_u16(&mut buf, portion.len);
+                BigEndian::write_u16(&mut buf, portion.len as u16);
                 BigEndian::write_uint(&mut buf[2..], portion.start.as_u64(), PORTION_SIZE);
                 adler32.update_buffer(&buf);
             }
@@ -133,6 +175,11 @@ impl<T: AsRef<[u8]>> JournalRecord<T> {
                 adler32.update_buffer(&lump_id_to_u128(&range.start)[..]);
                 adler32.update_buffer(&lump_id_to_u128(&range.end)[..]);
             }
+            JournalRecord::PutPacked(ref lump_id, portion) => {
+                adler32.update(TAG_PUT_PACKED);
+                adler32.update_buffer(&lump_id_to_u128(lump_id)[..]);
+                adler32.update_buffer(&packed_portion_to_bytes(portion));
+            }
         }
         adler32.hash()
     }
@@ -151,6 +198,18 @@ impl JournalRecord<Vec<u8>> {
                 let data_offset = track_io!(reader.read_uint::<BigEndian>(PORTION_SIZE))?;
                 let portion = DataPortion {
                     start: Address::from_u64(data_offset).unwrap(),
+                    len: u32::from(data_len),
+                };
+                JournalRecord::Put(lump_id, portion)
+            }
+            TAG_PUT_WIDE => {
+                let lump_id = track!(read_lump_id(&mut reader))?;
+                let data_len = track_io!(reader.read_u32::<BigEndian>())?;
+                let data_offset = track_io!(reader.read_uint::<BigEndian>(WIDE_PORTION_SIZE))?;
+                let start =
+                    track_assert_some!(Address::from_u64(data_offset), ErrorKind::StorageCorrupted);
+                let portion = DataPortion {
+                    start,
                     len: data_len,
                 };
                 JournalRecord::Put(lump_id, portion)
@@ -171,6 +230,18 @@ impl JournalRecord<Vec<u8>> {
                 let end = track!(read_lump_id(&mut reader))?;
                 JournalRecord::DeleteRange(Range { start, end })
             }
+            TAG_PUT_PACKED => {
+                let lump_id = track!(read_lump_id(&mut reader))?;
+                let pack = track_io!(reader.read_uint::<BigEndian>(PORTION_SIZE))?;
+                let offset = track_io!(reader.read_u16::<BigEndian>())?;
+                let len = track_io!(reader.read_u16::<BigEndian>())?;
+                let portion = PackedPortion {
+                    pack: Address::from_u64(pack).unwrap(),
+                    offset,
+                    len,
+                };
+                JournalRecord::PutPacked(lump_id, portion)
+            }
             _ => track_panic!(
                 ErrorKind::StorageCorrupted,
                 "Unknown journal record tag: {}",
@@ -187,6 +258,33 @@ fn read_lump_id<R: Read>(reader: &mut R) -> Result<LumpId> {
     Ok(LumpId::new(id))
 }
 
+/// v1形式のPUTレコードでは表現できない部分領域かどうかを判定する.
+///
+/// v1フォーマットのストレージでは、このような部分領域が生成されることはないので、
+/// 常にv1形式のレコードが使われることになる.
+fn is_wide_portion(portion: DataPortion) -> bool {
+    portion.start.as_u64() > Address::MAX_V1 || portion.len > DataPortion::MAX_LEN_V1
+}
+
+fn wide_portion_to_bytes(portion: DataPortion) -> [u8; WIDE_LENGTH_SIZE + WIDE_PORTION_SIZE] {
+    let mut bytes = [0; WIDE_LENGTH_SIZE + WIDE_PORTION_SIZE];
+    BigEndian::write_u32(&mut bytes, portion.len);
+    BigEndian::write_uint(
+        &mut bytes[WIDE_LENGTH_SIZE..],
+        portion.start.as_u64(),
+        WIDE_PORTION_SIZE,
+    );
+    bytes
+}
+
+fn packed_portion_to_bytes(portion: PackedPortion) -> [u8; PORTION_SIZE + LENGTH_SIZE * 2] {
+    let mut bytes = [0; PORTION_SIZE + LENGTH_SIZE * 2];
+    BigEndian::write_uint(&mut bytes, portion.pack.as_u64(), PORTION_SIZE);
+    BigEndian::write_u16(&mut bytes[PORTION_SIZE..], portion.offset);
+    BigEndian::write_u16(&mut bytes[PORTION_SIZE + LENGTH_SIZE..], portion.len);
+    bytes
+}
+
 fn lump_id_to_u128(id: &LumpId) -> [u8; LumpId::SIZE] {
     let mut bytes = [0; LumpId::SIZE];
     BigEndian::write_u128(&mut bytes, id.as_u128());
@@ -199,7 +297,7 @@ mod tests {
 
     use super::*;
     use crate::lump::LumpId;
-    use crate::storage::portion::DataPortion;
+    use crate::storage::portion::{DataPortion, PackedPortion};
     use crate::storage::Address;
 
     #[test]
@@ -221,6 +319,13 @@ mod tests {
                     len: 0xFFFF,
                 },
             ),
+            JournalRecord::Put(
+                lump_id("000"),
+                DataPortion {
+                    start: Address::from_u64(Address::MAX).unwrap(),
+                    len: DataPortion::MAX_LEN,
+                },
+            ),
             JournalRecord::Embed(lump_id("111"), b"222".to_vec()),
             JournalRecord::Embed(lump_id("111"), vec![0; 0xFFFF]),
             JournalRecord::Delete(lump_id("333")),
@@ -228,10 +333,19 @@ mod tests {
                 start: lump_id("123"),
                 end: lump_id("456"),
             }),
+            JournalRecord::PutPacked(
+                lump_id("444"),
+                PackedPortion {
+                    pack: Address::from_u64((1 << 40) - 1).unwrap(),
+                    offset: 4088,
+                    len: 8,
+                },
+            ),
         ];
         for e0 in records {
             let mut buf = Vec::new();
             track!(e0.write_to(&mut buf))?;
+            assert_eq!(buf.len(), e0.external_size());
             let e1 = track!(JournalRecord::read_from(&buf[..]))?;
             assert_eq!(e1, e0);
         }
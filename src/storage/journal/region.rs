@@ -1,18 +1,21 @@
 use prometrics::metrics::MetricBuilder;
+use slog::Logger;
 use std::collections::VecDeque;
 use std::io::Write;
 use std::ops::Range;
+use std::time::{Duration, Instant};
 
-use super::options::JournalRegionOptions;
+use super::options::{JournalRecoveryMode, JournalRegionOptions};
 use super::record::{JournalEntry, JournalRecord, EMBEDDED_DATA_OFFSET};
 use super::ring_buffer::JournalRingBuffer;
 use super::{JournalHeader, JournalHeaderRegion};
 use crate::block::BlockSize;
 use crate::lump::LumpId;
 use crate::metrics::JournalRegionMetrics;
-use crate::nvm::NonVolatileMemory;
+use crate::nvm::{NonVolatileMemory, SyncLevel};
 use crate::storage::index::LumpIndex;
-use crate::storage::portion::{DataPortion, JournalPortion, Portion};
+use crate::storage::portion::{DataPortion, JournalPortion, PackedPortion, Portion};
+use crate::storage::progress::OpenProgressReporter;
 use crate::storage::Address;
 use crate::{ErrorKind, Result};
 
@@ -36,9 +39,15 @@ pub struct JournalRegion<N: NonVolatileMemory> {
     ring_buffer: JournalRingBuffer<N>,
     metrics: JournalRegionMetrics,
     gc_queue: VecDeque<JournalEntry>,
-    sync_countdown: usize, // `0`になったら`sync()`を呼び出す
+    sync_countdown: usize,           // `0`になったら`sync()`を呼び出す
+    unsynced_since: Option<Instant>, // 未同期のレコードが最初に追記された時刻
+    committed_lsn: u64,              // 物理デバイスへの同期が完了しているレコード群の終端のLSN
     options: JournalRegionOptions,
     gc_after_append: bool,
+    last_shutdown_clean: bool,
+
+    // `committed_entries_since`の走査を開始可能な、レコード境界のLSN(前回の呼び出し結果に基づく)
+    scan_hint: Option<u64>,
 }
 impl<N> JournalRegion<N>
 where
@@ -51,8 +60,21 @@ where
     /// ジャーナル領域の初期化を行う.
     ///
     /// 具体的には`nmヘッダと最初のエントリ(EndOfEntries)を書き込む
-    pub fn initialize<W: Write>(mut writer: W, block_size: BlockSize) -> Result<()> {
-        track!(JournalHeader::new().write_to(&mut writer, block_size))?;
+    ///
+    /// `redundant_header`が`true`の場合には、二重化されたヘッダ領域が初期化される.
+    /// `head_lsn`は、リングバッファの先頭に割り当てるLSN.
+    pub fn initialize<W: Write>(
+        mut writer: W,
+        block_size: BlockSize,
+        redundant_header: bool,
+        head_lsn: u64,
+    ) -> Result<()> {
+        track!(JournalHeaderRegion::<N>::initialize(
+            &mut writer,
+            block_size,
+            redundant_header,
+            head_lsn
+        ))?;
         track!(JournalRecord::EndOfRecords::<[_; 0]>.write_to(&mut writer))?;
         Ok(())
     }
@@ -64,6 +86,8 @@ where
         nvm: N,
         index: &mut LumpIndex,
         metric_builder: &MetricBuilder,
+        logger: &Logger,
+        progress: &mut OpenProgressReporter,
         options: JournalRegionOptions,
     ) -> Result<JournalRegion<N>>
     where
@@ -75,13 +99,31 @@ where
         );
         let block_size = options.block_size;
 
-        let (header_nvm, ring_buffer_nvm) =
-            track!(nvm.split(JournalHeader::region_size(block_size) as u64))?;
+        let header_region_size =
+            JournalHeaderRegion::<N>::region_size(block_size, options.redundant_header);
+        let (header_nvm, ring_buffer_nvm) = track!(nvm.split(header_region_size as u64))?;
 
-        let mut header_region = JournalHeaderRegion::new(header_nvm, block_size);
+        let mut header_region =
+            JournalHeaderRegion::new(header_nvm, block_size, options.redundant_header);
         let header = track!(header_region.read_header())?;
-        let ring_buffer =
-            JournalRingBuffer::new(ring_buffer_nvm, header.ring_buffer_head, metric_builder);
+        if header_region.damaged_slots() > 0 {
+            warn!(
+                logger,
+                "Damaged journal header slots are found";
+                "damaged_slots" => header_region.damaged_slots(),
+                "seqno" => header.seqno,
+                "read_only" => options.read_only
+            );
+            if !options.read_only {
+                track!(header_region.repair())?;
+            }
+        }
+        let ring_buffer = JournalRingBuffer::new(
+            ring_buffer_nvm,
+            header.ring_buffer_head,
+            header.ring_buffer_head_lsn,
+            metric_builder,
+        );
 
         let metrics = JournalRegionMetrics::new(metric_builder, ring_buffer.metrics().clone());
         let mut journal = JournalRegion {
@@ -90,10 +132,20 @@ where
             metrics,
             gc_queue: VecDeque::new(),
             sync_countdown: options.sync_interval,
+            unsynced_since: None,
+            committed_lsn: 0,
             options,
             gc_after_append: true,
+            last_shutdown_clean: header.clean_shutdown,
+            scan_hint: None,
         };
-        track!(journal.restore(index))?;
+        track!(journal.restore(index, logger, progress))?;
+        journal.committed_lsn = journal.ring_buffer.lsn_of(journal.ring_buffer.tail());
+
+        // 次回のオープン時にクラッシュを検知できるように、正常終了フラグを落としておく
+        if header.clean_shutdown && !journal.options.read_only {
+            track!(journal.write_journal_header(header.ring_buffer_head, false))?;
+        }
         Ok(journal)
     }
 
@@ -109,6 +161,28 @@ where
         Ok(())
     }
 
+    /// パック内に格納されたlump群のPUT操作をジャーナルに記録する.
+    ///
+    /// 途中でGCが走ってインデックスとジャーナルの整合性が崩れることがないように、
+    /// 全てのレコードを追記した後に、その数だけGCを行う.
+    pub fn records_put_packed(
+        &mut self,
+        index: &mut LumpIndex,
+        members: &[(LumpId, PackedPortion)],
+    ) -> Result<()> {
+        for &(lump_id, portion) in members {
+            let record = JournalRecord::PutPacked::<[_; 0]>(lump_id, portion);
+            track!(self.append_record(index, &record))?;
+        }
+        if self.gc_after_append {
+            for _ in members {
+                track!(self.gc_once(index))?;
+            }
+        }
+        track!(self.try_sync())?;
+        Ok(())
+    }
+
     /// 埋め込みPUT操作をジャーナルに記録する.
     pub fn records_embed(
         &mut self,
@@ -162,6 +236,100 @@ where
         Ok(())
     }
 
+    /// ジャーナル領域を正常に閉じる.
+    ///
+    /// GCキューに残っているエントリを全て処理し、ジャーナルを同期した上で、
+    /// 正常終了フラグを立てたヘッダを書き込む.
+    pub fn close(&mut self, index: &mut LumpIndex) -> Result<()> {
+        track!(self.gc_all_entries_in_queue(index))?;
+        track!(self.sync())?;
+
+        let ring_buffer_head = self.ring_buffer.head();
+        track!(self.write_journal_header(ring_buffer_head, true))?;
+        Ok(())
+    }
+
+    /// 物理デバイスへの同期が完了している(i.e., コミット済みの)レコード群の終端のLSNを返す.
+    pub fn committed_lsn(&self) -> u64 {
+        self.committed_lsn
+    }
+
+    /// ジャーナルから読み出し可能な最古のレコードのLSNを返す.
+    ///
+    /// これよりも前のレコード群は、GCによって既に上書きされている可能性がある.
+    pub fn oldest_lsn(&self) -> u64 {
+        self.ring_buffer.lsn_of(self.ring_buffer.unreleased_head())
+    }
+
+    /// LSNが`since`以上の、コミット済みのレコード群を、追記順に返す.
+    ///
+    /// 削除系のレコードは全て結果に含まれるが、PUT系のレコードは、
+    /// 対象のlumpの現在の位置を指しているもののみが含まれる.
+    /// 上書きないし削除されたlumpに対するPUT系のレコードは、それらの操作を記録した後続のレコードによって
+    /// 打ち消されることになるので、結果から除外しても、全てを適用した後の状態は変わらない.
+    ///
+    /// 結果の要素数は最大で`max_entries`となる.
+    ///
+    /// # Errors
+    ///
+    /// `since`が`oldest_lsn()`よりも小さい場合には、
+    /// 必要なレコードが既に失われている可能性があるので、`ErrorKind::InvalidInput`エラーが返される.
+    pub fn committed_entries_since(
+        &mut self,
+        index: &LumpIndex,
+        since: u64,
+        max_entries: usize,
+    ) -> Result<Vec<JournalEntry>> {
+        let oldest_lsn = self.oldest_lsn();
+        track_assert!(
+            oldest_lsn <= since,
+            ErrorKind::InvalidInput,
+            "Too old LSN: since={}, oldest={}",
+            since,
+            oldest_lsn
+        );
+
+        // 前回の呼び出し位置以降が指定された場合には、ジャーナルの先頭からではなく、そこから走査を開始する
+        let start_lsn = self
+            .scan_hint
+            .filter(|&lsn| oldest_lsn <= lsn && lsn <= since)
+            .unwrap_or(oldest_lsn);
+        let mut hint = start_lsn;
+
+        let committed_lsn = self.committed_lsn;
+        let mut entries = Vec::new();
+        for entry in track!(self.ring_buffer.unreleased_entries_from(start_lsn))? {
+            if entries.len() >= max_entries {
+                break;
+            }
+            let entry = track!(entry)?;
+            if entry.next_lsn() > committed_lsn {
+                break;
+            }
+            if entry.lsn <= since {
+                hint = entry.lsn;
+            }
+            if entry.lsn < since {
+                continue;
+            }
+            let is_put = matches!(
+                entry.record,
+                JournalRecord::Put(..) | JournalRecord::Embed(..) | JournalRecord::PutPacked(..)
+            );
+            if is_put && Self::is_garbage(index, &entry) {
+                continue;
+            }
+            entries.push(entry);
+        }
+        self.scan_hint = Some(entries.last().map_or(hint, |e| e.next_lsn()));
+        Ok(entries)
+    }
+
+    /// 前回ストレージが正常に閉じられたかどうかを返す.
+    pub fn last_shutdown_clean(&self) -> bool {
+        self.last_shutdown_clean
+    }
+
     /// ジャーナル領域用のメトリクスを返す.
     pub fn metrics(&self) -> &JournalRegionMetrics {
         &self.metrics
@@ -176,7 +344,7 @@ where
         }
         while let Some(entry) = self.gc_queue.pop_front() {
             self.metrics.gc_dequeued_records.increment();
-            if !self.is_garbage(index, &entry) {
+            if !Self::is_garbage(index, &entry) {
                 // まだ回収できない場合には、ジャーナル領域の「末尾に」追加する
                 track!(self.append_record(index, &entry.record))?;
                 break;
@@ -217,15 +385,22 @@ where
         // そこで現在の`head`の値をジャーナルエントリ開始位置として永続化し、
         // `unreleased_head`も更新する。
         let ring_buffer_head = self.ring_buffer.head();
-        track!(self.write_journal_header(ring_buffer_head))?;
+        track!(self.write_journal_header(ring_buffer_head, false))?;
 
         Ok(())
     }
 
     /// `ring_buffer_head`をジャーナルエントリ開始位置として永続化し、
     /// `unreleased_head`を`ring_buffer_head`に移動する。
-    fn write_journal_header(&mut self, ring_buffer_head: u64) -> Result<()> {
-        let header = JournalHeader { ring_buffer_head };
+    ///
+    /// `clean_shutdown`はストレージのクローズ時にのみ`true`となる.
+    fn write_journal_header(&mut self, ring_buffer_head: u64, clean_shutdown: bool) -> Result<()> {
+        let header = JournalHeader {
+            ring_buffer_head,
+            seqno: 0, // `JournalHeaderRegion`によって適切な値が設定される
+            clean_shutdown,
+            ring_buffer_head_lsn: self.ring_buffer.lsn_of(ring_buffer_head),
+        };
         track!(self.header_region.write_header(&header))?;
         self.ring_buffer.release_bytes_until(ring_buffer_head);
         Ok(())
@@ -256,13 +431,45 @@ where
         B: AsRef<[u8]>,
     {
         let embedded = track!(self.ring_buffer.enqueue(record))?;
+        if self.unsynced_since.is_none() {
+            self.unsynced_since = Some(Instant::now());
+        }
         if let Some((lump_id, portion)) = embedded {
             index.insert(lump_id, Portion::Journal(portion));
         }
         Ok(())
     }
 
+    /// 未同期のレコードを同期しなければならない期限を返す.
+    ///
+    /// `JournalRegionOptions::sync_max_delay`が未指定の場合や、
+    /// 未同期のレコードが存在しない場合には`None`が返される.
+    pub fn sync_deadline(&self) -> Option<Instant> {
+        let delay = self.options.sync_max_delay?;
+        self.unsynced_since.map(|t| t + delay)
+    }
+
+    /// 未同期のレコードの同期期限までの猶予(`JournalRegionOptions::sync_max_delay`)を返す.
+    pub fn sync_max_delay(&self) -> Option<Duration> {
+        self.options.sync_max_delay
+    }
+
+    /// 未同期のレコードが同期期限を過ぎている場合には、同期命令を発行する.
+    ///
+    /// 同期を行った場合には`true`が返される.
+    pub fn sync_if_overdue(&mut self) -> Result<bool> {
+        if self.sync_deadline().is_some_and(|t| t <= Instant::now()) {
+            track!(self.sync())?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
     fn try_sync(&mut self) -> Result<()> {
+        if track!(self.sync_if_overdue())? {
+            return Ok(());
+        }
         if self.sync_countdown == 0 {
             track!(self.sync())?;
         } else {
@@ -277,14 +484,26 @@ where
     /// FIXME: 最適化として、
     /// 既に同期済みで必要のない場合は、同期命令を発行しないようにする。
     pub fn sync(&mut self) -> Result<()> {
-        track!(self.ring_buffer.sync())?;
-        self.sync_countdown = self.options.sync_interval;
-        self.metrics.syncs.increment();
+        track!(self.sync_with(SyncLevel::DataSync))
+    }
+
+    /// 指定された強度で、ジャーナルバッファを書き出す.
+    ///
+    /// `SyncLevel::Flush`の場合には、物理デバイスへの同期は行われないので、
+    /// 同期間隔(`sync_interval`や`sync_max_delay`)に基づく次回の同期はリセットされない.
+    pub fn sync_with(&mut self, level: SyncLevel) -> Result<()> {
+        track!(self.ring_buffer.sync_with(level))?;
+        if level != SyncLevel::Flush {
+            self.sync_countdown = self.options.sync_interval;
+            self.unsynced_since = None;
+            self.committed_lsn = self.ring_buffer.lsn_of(self.ring_buffer.tail());
+            self.metrics.syncs.increment();
+        }
         Ok(())
     }
 
     /// エントリが回収可能かどうかを判定する.
-    fn is_garbage(&self, index: &LumpIndex, entry: &JournalEntry) -> bool {
+    fn is_garbage(index: &LumpIndex, entry: &JournalEntry) -> bool {
         match entry.record {
             JournalRecord::Put(ref lump_id, ref portion) => {
                 index.get(lump_id) != Some(Portion::Data(*portion))
@@ -296,6 +515,9 @@ where
                 };
                 index.get(lump_id) != Some(Portion::Journal(portion))
             }
+            JournalRecord::PutPacked(ref lump_id, ref portion) => {
+                index.get(lump_id) != Some(Portion::Packed(*portion))
+            }
             _ => true,
         }
     }
@@ -311,7 +533,7 @@ where
         // 現在のhead位置をジャーナルエントリの開始位置として永続化し、
         // `unreleased_head`の位置も更新する。
         let ring_buffer_head = self.ring_buffer.head();
-        track!(self.write_journal_header(ring_buffer_head))?;
+        track!(self.write_journal_header(ring_buffer_head, false))?;
 
         if self.ring_buffer.is_empty() {
             return Ok(());
@@ -329,9 +551,38 @@ where
     }
 
     /// リングバッファおよびインデックスを前回の状態に復元する.
-    fn restore(&mut self, index: &mut LumpIndex) -> Result<()> {
+    fn restore(
+        &mut self,
+        index: &mut LumpIndex,
+        logger: &Logger,
+        progress: &mut OpenProgressReporter,
+    ) -> Result<()> {
+        let head = self.ring_buffer.head();
+        let capacity = self.ring_buffer.capacity();
+        progress.start_replay(capacity);
+
+        let mut torn = None;
         for result in track!(self.ring_buffer.restore_entries())? {
-            let JournalEntry { start, record } = track!(result)?;
+            let entry = match result {
+                Err(e)
+                    if self.options.recovery_mode == JournalRecoveryMode::TruncateTornTail
+                        && *e.kind() == ErrorKind::StorageCorrupted =>
+                {
+                    torn = Some(e);
+                    break;
+                }
+                result => track!(result)?,
+            };
+
+            // 再生済みのバイト数には、このレコード自体のサイズも含める
+            let end = entry.end().as_u64();
+            let replayed = if end >= head {
+                end - head
+            } else {
+                end + capacity - head
+            };
+            progress.record_replayed(&entry.record, replayed);
+            let JournalEntry { start, record, .. } = entry;
             match record {
                 JournalRecord::Put(lump_id, portion) => {
                     index.insert(lump_id, Portion::Data(portion));
@@ -343,6 +594,9 @@ where
                     };
                     index.insert(lump_id, Portion::Journal(portion));
                 }
+                JournalRecord::PutPacked(lump_id, portion) => {
+                    index.insert(lump_id, Portion::Packed(portion));
+                }
                 JournalRecord::Delete(lump_id) => {
                     index.remove(&lump_id);
                 }
@@ -354,6 +608,31 @@ where
                 JournalRecord::EndOfRecords | JournalRecord::GoToFront => unreachable!(),
             }
         }
+        if let Some(e) = torn {
+            // 最後に正常に読み込めたレコードの直後でジャーナルを切り詰める
+            let (discarded_start, discarded_end) = self
+                .ring_buffer
+                .torn_range()
+                .unwrap_or((self.ring_buffer.tail(), self.ring_buffer.tail()));
+            let discarded_bytes = (discarded_end + capacity - discarded_start) % capacity;
+            warn!(
+                logger,
+                "Journal records after the first invalid record are discarded";
+                "head" => self.ring_buffer.head(),
+                "truncated_at" => discarded_start,
+                "discarded_end" => discarded_end,
+                "discarded_bytes" => discarded_bytes,
+                "read_only" => self.options.read_only,
+                "error" => %e
+            );
+            if !self.options.read_only {
+                track!(self.ring_buffer.truncate_tail())?;
+            }
+            self.metrics.recovery_truncations.increment();
+            self.metrics
+                .recovery_discarded_bytes
+                .add_u64(discarded_bytes);
+        }
         Ok(())
     }
 }
@@ -1,11 +1,11 @@
 use prometrics::metrics::MetricBuilder;
-use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::io::{BufReader, Seek, SeekFrom};
 
 use super::record::{EMBEDDED_DATA_OFFSET, END_OF_RECORDS_SIZE};
 use super::{JournalEntry, JournalNvmBuffer, JournalRecord};
 use crate::lump::LumpId;
 use crate::metrics::JournalQueueMetrics;
-use crate::nvm::NonVolatileMemory;
+use crate::nvm::{NonVolatileMemory, SyncLevel};
 use crate::storage::portion::JournalPortion;
 use crate::storage::Address;
 use crate::{ErrorKind, Result};
@@ -23,6 +23,9 @@ pub struct JournalRingBuffer<N: NonVolatileMemory> {
     /// 安全に上書きすることができない.
     unreleased_head: u64,
 
+    /// `unreleased_head`に対応するLSN.
+    unreleased_head_lsn: u64,
+
     /// リングバッファの始端位置.
     head: u64,
 
@@ -33,6 +36,11 @@ pub struct JournalRingBuffer<N: NonVolatileMemory> {
     /// 不変項: `unreleased_head <= head <= tail`
     tail: u64,
 
+    /// `restore_entries`の途中で不正なレコードが見つかった場合の、そのレコードの読み込み終了位置.
+    ///
+    /// `tail`からこの位置までが、切り詰めによって破棄される領域となる.
+    torn_end: Option<u64>,
+
     metrics: JournalQueueMetrics,
 }
 impl<N: NonVolatileMemory> JournalRingBuffer<N> {
@@ -42,23 +50,56 @@ impl<N: NonVolatileMemory> JournalRingBuffer<N> {
     pub fn tail(&self) -> u64 {
         self.tail
     }
+    pub fn unreleased_head(&self) -> u64 {
+        self.unreleased_head
+    }
+
+    /// `restore_entries`の途中で不正なレコードが見つかった場合に、切り詰めによって破棄される領域を返す.
+    ///
+    /// 返り値は`(tail, 不正なレコードの読み込み終了位置)`.
+    pub fn torn_range(&self) -> Option<(u64, u64)> {
+        self.torn_end.map(|end| (self.tail, end))
+    }
 
     pub fn journal_entries(&mut self) -> Result<(u64, u64, u64, Vec<JournalEntry>)> {
         track_io!(self.nvm.seek(SeekFrom::Start(self.head)))?;
+        let head_lsn = self.lsn_of(self.head);
         let result: Result<Vec<JournalEntry>> =
-            ReadEntries::new(&mut self.nvm, self.head).collect();
+            ReadEntries::new(&mut self.nvm, self.head, head_lsn).collect();
         result.map(|r| (self.unreleased_head, self.head, self.tail, r))
     }
 
+    /// LSNが`lsn`のレコード以降の(i.e., まだ上書きされていない)全てのエントリを走査するためのイテレータを返す.
+    ///
+    /// `dequeue_iter`とは異なり、リングバッファの状態は変更されない.
+    ///
+    /// `lsn`は、`unreleased_head`から`tail`の間にあるレコードの境界(のLSN)である必要がある.
+    pub fn unreleased_entries_from(&mut self, lsn: u64) -> Result<ReadEntries<'_, N>> {
+        track_assert!(
+            self.unreleased_head_lsn <= lsn && lsn <= self.lsn_of(self.tail),
+            ErrorKind::InvalidInput,
+            "Out of range LSN: lsn={}, unreleased_head_lsn={}",
+            lsn,
+            self.unreleased_head_lsn
+        );
+        let position = (self.unreleased_head + (lsn - self.unreleased_head_lsn)) % self.capacity();
+        track_io!(self.nvm.seek(SeekFrom::Start(position)))?;
+        Ok(ReadEntries::new(&mut self.nvm, position, lsn))
+    }
+
     /// `JournalRingBuffer`インスタンスを生成する.
-    pub fn new(nvm: N, head: u64, metric_builder: &MetricBuilder) -> Self {
+    ///
+    /// `head_lsn`は`head`の位置に対応するLSN.
+    pub fn new(nvm: N, head: u64, head_lsn: u64, metric_builder: &MetricBuilder) -> Self {
         let metrics = JournalQueueMetrics::new(metric_builder);
         metrics.capacity_bytes.set(nvm.capacity() as f64);
         JournalRingBuffer {
             nvm: JournalNvmBuffer::new(nvm),
             unreleased_head: head,
+            unreleased_head_lsn: head_lsn,
             head,
             tail: head,
+            torn_end: None,
             metrics,
         }
     }
@@ -70,6 +111,17 @@ impl<N: NonVolatileMemory> JournalRingBuffer<N> {
         track!(RestoredEntries::new(self))
     }
 
+    /// 現在の終端位置に`EndOfRecords`を書き込み、それ以降のレコード群を破棄する.
+    ///
+    /// `restore_entries`の途中で不正なレコードが見つかった場合に、
+    /// それ以降を切り詰めるために使用される.
+    pub fn truncate_tail(&mut self) -> Result<()> {
+        track_io!(self.nvm.seek(SeekFrom::Start(self.tail)))?;
+        track!(JournalRecord::EndOfRecords::<[_; 0]>.write_to(&mut self.nvm))?;
+        track!(self.nvm.sync())?;
+        Ok(())
+    }
+
     /// リングバッファ内に要素が存在するかどうかを判定する.
     pub fn is_empty(&self) -> bool {
         self.head == self.tail
@@ -84,6 +136,12 @@ impl<N: NonVolatileMemory> JournalRingBuffer<N> {
         }
     }
 
+    /// リングバッファ内の(`unreleased_head`から`tail`の間の)位置に対応するLSNを返す.
+    pub fn lsn_of(&self, position: u64) -> u64 {
+        let capacity = self.capacity();
+        self.unreleased_head_lsn + (position + capacity - self.unreleased_head) % capacity
+    }
+
     /// リングバッファの容量(バイト単位)を返す.
     pub fn capacity(&self) -> u64 {
         self.nvm.capacity()
@@ -98,14 +156,12 @@ impl<N: NonVolatileMemory> JournalRingBuffer<N> {
     ///
     /// データの妥当性検証は`cannyls`内では行わない.
     pub fn read_embedded_data(&mut self, position: u64, buf: &mut [u8]) -> Result<()> {
-        track_io!(self.nvm.seek(SeekFrom::Start(position)))?;
-        track_io!(self.nvm.read_exact(buf))?;
-        Ok(())
+        track!(self.nvm.read_at(buf, position))
     }
 
-    /// 物理デバイスに同期命令を発行する.
-    pub fn sync(&mut self) -> Result<()> {
-        track!(self.nvm.sync())
+    /// 指定された強度で、物理デバイスに同期命令を発行する.
+    pub fn sync_with(&mut self, level: SyncLevel) -> Result<()> {
+        track!(self.nvm.sync_with(level))
     }
 
     /// レコードをジャーナルの末尾に追記する.
@@ -175,6 +231,7 @@ impl<N: NonVolatileMemory> JournalRingBuffer<N> {
         self.metrics.released_bytes.add_u64(released_bytes);
 
         self.unreleased_head = point;
+        self.unreleased_head_lsn += released_bytes;
     }
 
     /// `record`を書き込んだら、リングバッファ用の領域を超えてしまうかどうかを判定する.
@@ -220,6 +277,7 @@ pub struct RestoredEntries<'a, N: 'a + NonVolatileMemory> {
     entries: ReadEntries<'a, N>,
     head: u64,
     tail: &'a mut u64,
+    torn_end: &'a mut Option<u64>,
     capacity: u64,
     metrics: &'a JournalQueueMetrics,
 }
@@ -236,10 +294,12 @@ impl<'a, N: 'a + NonVolatileMemory> RestoredEntries<'a, N> {
 
         track_io!(ring.nvm.seek(SeekFrom::Start(ring.head)))?;
         let capacity = ring.nvm.capacity();
+        let lsn = ring.unreleased_head_lsn;
         Ok(RestoredEntries {
-            entries: ReadEntries::with_capacity(&mut ring.nvm, ring.head, 1024 * 1024),
+            entries: ReadEntries::with_capacity(&mut ring.nvm, ring.head, lsn, 1024 * 1024),
             head: ring.head,
             tail: &mut ring.tail,
+            torn_end: &mut ring.torn_end,
             capacity,
             metrics: &ring.metrics,
         })
@@ -256,7 +316,10 @@ impl<'a, N: 'a + NonVolatileMemory> Iterator for RestoredEntries<'a, N> {
                     .increment(&entry.record);
                 *self.tail = entry.end().as_u64();
             }
-            None => {
+            None | Some(Err(_)) => {
+                if next.is_some() {
+                    *self.torn_end = self.entries.position().ok();
+                }
                 let size = if self.head <= *self.tail {
                     *self.tail - self.head
                 } else {
@@ -264,7 +327,6 @@ impl<'a, N: 'a + NonVolatileMemory> Iterator for RestoredEntries<'a, N> {
                 };
                 self.metrics.consumed_bytes_at_starting.add_u64(size);
             }
-            _ => {}
         }
         next
     }
@@ -280,8 +342,9 @@ impl<'a, N: 'a + NonVolatileMemory> DequeuedEntries<'a, N> {
     #[allow(clippy::new_ret_no_self)]
     fn new(ring: &'a mut JournalRingBuffer<N>) -> Result<Self> {
         track_io!(ring.nvm.seek(SeekFrom::Start(ring.head)))?;
+        let lsn = ring.lsn_of(ring.head);
         Ok(DequeuedEntries {
-            entries: ReadEntries::new(&mut ring.nvm, ring.head),
+            entries: ReadEntries::new(&mut ring.nvm, ring.head, lsn),
             head: &mut ring.head,
             metrics: &ring.metrics,
         })
@@ -300,32 +363,52 @@ impl<'a, N: 'a + NonVolatileMemory> Iterator for DequeuedEntries<'a, N> {
 }
 
 #[derive(Debug)]
-struct ReadEntries<'a, N: 'a + NonVolatileMemory> {
+pub struct ReadEntries<'a, N: 'a + NonVolatileMemory> {
     reader: BufReader<&'a mut JournalNvmBuffer<N>>,
     current: u64,
+    current_lsn: u64,
+    capacity: u64,
     is_second_lap: bool,
 }
 impl<'a, N: 'a + NonVolatileMemory> ReadEntries<'a, N> {
-    fn new(nvm: &'a mut JournalNvmBuffer<N>, head: u64) -> Self {
+    fn new(nvm: &'a mut JournalNvmBuffer<N>, head: u64, head_lsn: u64) -> Self {
+        let capacity = nvm.capacity();
         ReadEntries {
             reader: BufReader::new(nvm),
             current: head,
+            current_lsn: head_lsn,
+            capacity,
             is_second_lap: false,
         }
     }
-    fn with_capacity(nvm: &'a mut JournalNvmBuffer<N>, head: u64, capacity: usize) -> Self {
+    fn with_capacity(
+        nvm: &'a mut JournalNvmBuffer<N>,
+        head: u64,
+        head_lsn: u64,
+        buf_capacity: usize,
+    ) -> Self {
+        let capacity = nvm.capacity();
         ReadEntries {
-            reader: BufReader::with_capacity(capacity, nvm),
+            reader: BufReader::with_capacity(buf_capacity, nvm),
             current: head,
+            current_lsn: head_lsn,
+            capacity,
             is_second_lap: false,
         }
     }
+    /// 現在の読み込み位置を返す.
+    fn position(&mut self) -> Result<u64> {
+        track_io!(self.reader.stream_position())
+    }
     fn read_record(&mut self) -> Result<Option<JournalRecord<Vec<u8>>>> {
         match track!(JournalRecord::read_from(&mut self.reader))? {
             JournalRecord::EndOfRecords => Ok(None),
             JournalRecord::GoToFront => {
                 track_assert!(!self.is_second_lap, ErrorKind::StorageCorrupted);
                 track_io!(self.reader.seek(SeekFrom::Start(0)))?;
+
+                // 読み飛ばされる終端までの領域も、LSN上は消費されたものとして扱う
+                self.current_lsn += self.capacity - self.current;
                 self.current = 0;
                 self.is_second_lap = true;
                 self.read_record()
@@ -342,8 +425,10 @@ impl<'a, N: 'a + NonVolatileMemory> Iterator for ReadEntries<'a, N> {
             Ok(None) => None,
             Ok(Some(record)) => {
                 let start = Address::from_u64(self.current).expect("Never fails");
+                let lsn = self.current_lsn;
                 self.current += record.external_size() as u64;
-                let entry = JournalEntry { start, record };
+                self.current_lsn += record.external_size() as u64;
+                let entry = JournalEntry { start, lsn, record };
                 Some(Ok(entry))
             }
         }
@@ -364,7 +449,7 @@ mod tests {
     #[test]
     fn append_and_read_records() -> TestResult {
         let nvm = MemoryNvm::new(vec![0; 1024]);
-        let mut ring = JournalRingBuffer::new(nvm, 0, &MetricBuilder::new());
+        let mut ring = JournalRingBuffer::new(nvm, 0, 0, &MetricBuilder::new());
 
         let records = vec![
             record_put("000", 30, 5),
@@ -397,7 +482,7 @@ mod tests {
     #[test]
     fn read_embedded_data() -> TestResult {
         let nvm = MemoryNvm::new(vec![0; 1024]);
-        let mut ring = JournalRingBuffer::new(nvm, 0, &MetricBuilder::new());
+        let mut ring = JournalRingBuffer::new(nvm, 0, 0, &MetricBuilder::new());
 
         track!(ring.enqueue(&record_put("000", 30, 5)))?;
         track!(ring.enqueue(&record_delete("111")))?;
@@ -415,7 +500,7 @@ mod tests {
     #[test]
     fn go_round_ring_buffer() -> TestResult {
         let nvm = MemoryNvm::new(vec![0; 1024]);
-        let mut ring = JournalRingBuffer::new(nvm, 512, &MetricBuilder::new());
+        let mut ring = JournalRingBuffer::new(nvm, 512, 0, &MetricBuilder::new());
         assert_eq!(ring.head, 512);
         assert_eq!(ring.tail, 512);
 
@@ -430,10 +515,42 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn unreleased_entries_from_works() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024]);
+        let mut ring = JournalRingBuffer::new(nvm, 512, 100, &MetricBuilder::new());
+
+        // 終端を跨ぐようにレコードを追記する
+        let record = record_delete("000");
+        let mut lsns = Vec::new();
+        for _ in 0..(512 / record.external_size()) + 3 {
+            track!(ring.enqueue(&record))?;
+        }
+        for entry in track!(ring.unreleased_entries_from(100))? {
+            lsns.push(track!(entry)?.lsn);
+        }
+        assert_eq!(lsns[0], 100);
+
+        // 任意のレコード境界から走査を開始できる
+        for (i, &lsn) in lsns.iter().enumerate() {
+            let entries = track!(ring.unreleased_entries_from(lsn))?
+                .map(|e| e.map(|e| e.lsn))
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(entries[..], lsns[i..]);
+        }
+
+        // 範囲外
+        assert_eq!(
+            ring.unreleased_entries_from(99).err().map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+        Ok(())
+    }
+
     #[test]
     fn full() -> TestResult {
         let nvm = MemoryNvm::new(vec![0; 1024]);
-        let mut ring = JournalRingBuffer::new(nvm, 0, &MetricBuilder::new());
+        let mut ring = JournalRingBuffer::new(nvm, 0, 0, &MetricBuilder::new());
 
         let record = record_put("000", 1, 2);
         while ring.tail <= 1024 - record.external_size() as u64 {
@@ -464,7 +581,7 @@ mod tests {
     #[test]
     fn too_large_record() {
         let nvm = MemoryNvm::new(vec![0; 1024]);
-        let mut ring = JournalRingBuffer::new(nvm, 0, &MetricBuilder::new());
+        let mut ring = JournalRingBuffer::new(nvm, 0, 0, &MetricBuilder::new());
 
         let record = record_embed("000", &[0; 997]);
         assert_eq!(record.external_size(), 1020);
@@ -479,7 +596,7 @@ mod tests {
         assert_eq!(ring.tail, 1019);
     }
 
-    fn record_put(lump_id: &str, start: u32, len: u16) -> JournalRecord<Vec<u8>> {
+    fn record_put(lump_id: &str, start: u32, len: u32) -> JournalRecord<Vec<u8>> {
         JournalRecord::Put(
             lump_id.parse().unwrap(),
             DataPortion {
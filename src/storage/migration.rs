@@ -0,0 +1,799 @@
+//! ストレージフォーマットのオフライン移行(マイグレーション)用の枠組み.
+//!
+//! 移行は「あるバージョンから別のバージョンへの変換」を表す[`MigrationStep`]の列として実行される.
+//! 利用可能なステップ群は[`MigrationRegistry`]に登録され、
+//! [`Migrator`]が現在のバージョンから目標のバージョンまでの経路を組み立てて、順に適用する.
+//!
+//! 各ステップの完了は、バージョンを更新したヘッダの書き込み(およびバックアップヘッダの書き込みと同期)によって確定する.
+//! ステップの実行中は、ヘッダのメタデータに進捗マーカーが記録されるので、
+//! 途中でクラッシュした場合でも、再度`Migrator::migrate`を呼び出せば、中断したステップから再開することができる.
+//! なお進捗マーカーが残っているストレージは、`StorageBuilder::open`では開くことができない.
+//!
+//! [`MigrationStep`]: ./trait.MigrationStep.html
+//! [`MigrationRegistry`]: ./struct.MigrationRegistry.html
+//! [`Migrator`]: ./struct.Migrator.html
+use slog::{Discard, Logger};
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::str::FromStr;
+use trackable::error::ErrorKindExt;
+
+use crate::block::{AlignedBytes, BlockSize};
+use crate::nvm::NonVolatileMemory;
+use crate::storage::{
+    StorageBuilder, StorageHeader, MAJOR_VERSION, MINOR_VERSION, WIDE_MAJOR_VERSION,
+    WIDE_MINOR_VERSION,
+};
+use crate::{Error, ErrorKind, Result};
+
+/// 実行中の移行の進捗マーカーを保存するために使用される、メタデータのプロパティ名.
+///
+/// 値の形式は`{移行元バージョン}->{移行先バージョン}`で、
+/// ステップが進捗を保存している場合には、その後ろに`#{進捗}`が続く.
+pub const MIGRATION_MARKER_KEY: &str = "cannyls.migration";
+
+/// ストレージフォーマットのバージョン.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StorageVersion {
+    /// メジャーバージョン.
+    pub major: u16,
+
+    /// マイナーバージョン.
+    pub minor: u16,
+}
+impl StorageVersion {
+    /// 新しい`StorageVersion`インスタンスを生成する.
+    pub fn new(major: u16, minor: u16) -> Self {
+        StorageVersion { major, minor }
+    }
+
+    /// v1の最新バージョン.
+    pub fn latest() -> Self {
+        StorageVersion::new(MAJOR_VERSION, MINOR_VERSION)
+    }
+
+    /// ワイドフォーマット(v2)の最新バージョン.
+    pub fn latest_wide() -> Self {
+        StorageVersion::new(WIDE_MAJOR_VERSION, WIDE_MINOR_VERSION)
+    }
+
+    /// ヘッダに記録されているバージョンを返す.
+    pub fn of(header: &StorageHeader) -> Self {
+        StorageVersion::new(header.major_version, header.minor_version)
+    }
+
+    /// このバージョンのヘッダが、メタデータ(および進捗マーカー)を保持可能かどうか.
+    fn supports_metadata(self) -> bool {
+        self.major == WIDE_MAJOR_VERSION || (self.major == MAJOR_VERSION && self.minor >= 2)
+    }
+}
+impl fmt::Display for StorageVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+impl FromStr for StorageVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut tokens = s.splitn(2, '.');
+        let major = tokens.next().unwrap_or("");
+        let minor = tokens.next().unwrap_or("0");
+        let major = track!(major.parse().map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+        let minor = track!(minor.parse().map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+        Ok(StorageVersion::new(major, minor))
+    }
+}
+
+/// 移行ステップが、ストレージの内容を読み書きするために使用するインタフェース.
+///
+/// 位置やサイズは、`NonVolatileMemory`と同様に、ブロック境界にアライメントされている必要がある.
+pub trait MigrationIo: Read + Write + Seek {
+    /// 内容を物理デバイスに同期する.
+    fn sync(&mut self) -> Result<()>;
+
+    /// 容量(バイト単位)を返す.
+    fn capacity(&self) -> u64;
+
+    /// ブロックサイズを返す.
+    fn block_size(&self) -> BlockSize;
+}
+
+/// `NonVolatileMemory`を`MigrationIo`として扱うためのアダプタ.
+#[derive(Debug)]
+struct NvmIo<'a, N: 'a>(&'a mut N);
+impl<'a, N: NonVolatileMemory> MigrationIo for NvmIo<'a, N> {
+    fn sync(&mut self) -> Result<()> {
+        track!(self.0.sync())
+    }
+    fn capacity(&self) -> u64 {
+        self.0.capacity()
+    }
+    fn block_size(&self) -> BlockSize {
+        self.0.block_size()
+    }
+}
+impl<'a, N: NonVolatileMemory> Read for NvmIo<'a, N> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+impl<'a, N: NonVolatileMemory> Write for NvmIo<'a, N> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+impl<'a, N: NonVolatileMemory> Seek for NvmIo<'a, N> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+/// 特定のバージョンから別のバージョンへの移行処理.
+///
+/// `migrate`メソッドは、途中で中断された場合に、同じステップが再度実行されても問題がないように実装する必要がある.
+/// 時間の掛かる処理を行う場合には、`MigrationContext::save_progress`で進捗を保存しておけば、
+/// 再開時に`MigrationContext::progress`で取得することができる.
+pub trait MigrationStep: Send + Sync {
+    /// 移行元のバージョン.
+    fn source(&self) -> StorageVersion;
+
+    /// 移行先のバージョン.
+    ///
+    /// 移行元よりも新しいバージョンである必要がある.
+    fn target(&self) -> StorageVersion;
+
+    /// ステップの説明.
+    fn description(&self) -> &str;
+
+    /// ストレージを変更せずに、移行が可能かどうかを検査する.
+    ///
+    /// ドライラン時には、このメソッドのみが呼び出される.
+    ///
+    /// デフォルトでは常に成功する.
+    fn check(&self, header: &StorageHeader) -> Result<()> {
+        let _ = header;
+        Ok(())
+    }
+
+    /// 移行処理を実行する.
+    ///
+    /// ヘッダのバージョン番号は、このメソッドの成功後に呼び出し側で更新される.
+    fn migrate(&self, context: &mut MigrationContext) -> Result<()>;
+}
+
+/// 移行ステップの実行中に参照される情報.
+pub struct MigrationContext<'a> {
+    base_header: StorageHeader,
+    header: StorageHeader,
+    io: &'a mut dyn MigrationIo,
+    marker: String,
+    progress: Option<String>,
+}
+impl<'a> MigrationContext<'a> {
+    /// ステップ開始時点のヘッダを返す.
+    pub fn base_header(&self) -> &StorageHeader {
+        &self.base_header
+    }
+
+    /// ステップ完了時に書き込まれるヘッダを返す.
+    pub fn header(&self) -> &StorageHeader {
+        &self.header
+    }
+
+    /// ステップ完了時に書き込まれるヘッダへの可変参照を返す.
+    ///
+    /// バージョン番号は呼び出し側で上書きされるので、変更しても意味はない.
+    pub fn header_mut(&mut self) -> &mut StorageHeader {
+        &mut self.header
+    }
+
+    /// ストレージの読み書きに使用するI/Oオブジェクトを返す.
+    pub fn io(&mut self) -> &mut dyn MigrationIo {
+        self.io
+    }
+
+    /// 中断されたステップを再開している場合には、最後に保存された進捗を返す.
+    pub fn progress(&self) -> Option<&str> {
+        self.progress.as_deref()
+    }
+
+    /// 進捗を保存する.
+    ///
+    /// 進捗は、ステップ開始時点のヘッダのメタデータ内に記録され、同期まで行われる.
+    ///
+    /// # Errors
+    ///
+    /// 移行元のバージョンがメタデータに対応していない(i.e., v1.2未満)場合には、
+    /// `ErrorKind::InvalidInput`エラーが返される.
+    pub fn save_progress(&mut self, progress: &str) -> Result<()> {
+        track_assert!(
+            StorageVersion::of(&self.base_header).supports_metadata(),
+            ErrorKind::InvalidInput,
+            "Progress markers are not supported by v{}",
+            StorageVersion::of(&self.base_header)
+        );
+        let mut header = self.base_header.clone();
+        header.metadata.properties.insert(
+            MIGRATION_MARKER_KEY.to_owned(),
+            format!("{}#{}", self.marker, progress),
+        );
+        track!(write_header(self.io, &header))?;
+        self.progress = Some(progress.to_owned());
+        Ok(())
+    }
+}
+impl<'a> fmt::Debug for MigrationContext<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MigrationContext")
+            .field("base_header", &self.base_header)
+            .field("header", &self.header)
+            .field("marker", &self.marker)
+            .field("progress", &self.progress)
+            .finish()
+    }
+}
+
+/// ヘッダの書き換えのみを行う移行ステップ.
+///
+/// ジャーナル領域およびデータ領域の形式に互換性があるバージョン間の移行に使用される.
+#[derive(Debug, Clone)]
+pub struct HeaderOnlyStep {
+    source: StorageVersion,
+    target: StorageVersion,
+    description: String,
+}
+impl HeaderOnlyStep {
+    /// 新しい`HeaderOnlyStep`インスタンスを生成する.
+    pub fn new(source: StorageVersion, target: StorageVersion, description: &str) -> Self {
+        HeaderOnlyStep {
+            source,
+            target,
+            description: description.to_owned(),
+        }
+    }
+}
+impl MigrationStep for HeaderOnlyStep {
+    fn source(&self) -> StorageVersion {
+        self.source
+    }
+    fn target(&self) -> StorageVersion {
+        self.target
+    }
+    fn description(&self) -> &str {
+        &self.description
+    }
+    fn check(&self, header: &StorageHeader) -> Result<()> {
+        if self.target.major == MAJOR_VERSION {
+            track_assert!(
+                header.block_size.as_u32() <= u32::from(u16::MAX),
+                ErrorKind::InvalidInput,
+                "Too large block size for a v1 storage: {}",
+                header.block_size.as_u32()
+            );
+        }
+        Ok(())
+    }
+    fn migrate(&self, _context: &mut MigrationContext) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// 移行ステップのレジストリ.
+pub struct MigrationRegistry {
+    steps: Vec<Box<dyn MigrationStep>>,
+}
+impl MigrationRegistry {
+    /// 空のレジストリを生成する.
+    pub fn empty() -> Self {
+        MigrationRegistry { steps: Vec::new() }
+    }
+
+    /// 組み込みの移行ステップ群が登録されたレジストリを生成する.
+    ///
+    /// 登録されているのは以下のステップ:
+    ///
+    /// - v1.0 -> v1.1
+    /// - v1.1 -> v1.2
+    /// - v1.2 -> v2.0 (ワイドフォーマットへの移行)
+    pub fn new() -> Self {
+        let mut this = Self::empty();
+        this.register(HeaderOnlyStep::new(
+            StorageVersion::new(1, 0),
+            StorageVersion::new(1, 1),
+            "Upgrades the header to v1.1",
+        ));
+        this.register(HeaderOnlyStep::new(
+            StorageVersion::new(1, 1),
+            StorageVersion::new(1, 2),
+            "Adds flags and a checksum to the header",
+        ));
+        this.register(HeaderOnlyStep::new(
+            StorageVersion::new(1, 2),
+            StorageVersion::latest_wide(),
+            "Switches to the wide format",
+        ));
+        this
+    }
+
+    /// 移行ステップを登録する.
+    ///
+    /// 同じバージョンを移行元とするステップが既に存在する場合には、後から登録されたものが優先される.
+    ///
+    /// # Panics
+    ///
+    /// ステップの移行先が、移行元よりも新しいバージョンではない場合には、現在のスレッドがパニックする.
+    pub fn register<S>(&mut self, step: S) -> &mut Self
+    where
+        S: MigrationStep + 'static,
+    {
+        assert!(
+            step.source() < step.target(),
+            "Invalid migration step: {} -> {}",
+            step.source(),
+            step.target()
+        );
+        self.steps.push(Box::new(step));
+        self
+    }
+
+    /// `from`から`to`までの移行に使用するステップ列を返す.
+    ///
+    /// 各バージョンでは、`to`を超えない範囲で、最後に登録されたステップが選択される.
+    ///
+    /// # Errors
+    ///
+    /// 経路が存在しない場合には`ErrorKind::InvalidInput`エラーが返される.
+    pub fn plan(
+        &self,
+        from: StorageVersion,
+        to: StorageVersion,
+    ) -> Result<Vec<&dyn MigrationStep>> {
+        let mut plan = Vec::new();
+        let mut current = from;
+        while current != to {
+            let step = track_assert_some!(
+                self.steps
+                    .iter()
+                    .rev()
+                    .find(|s| s.source() == current && s.target() <= to),
+                ErrorKind::InvalidInput,
+                "No migration path: from={}, to={}, stuck_at={}",
+                from,
+                to,
+                current
+            );
+            plan.push(step.as_ref());
+            current = step.target();
+        }
+        Ok(plan)
+    }
+}
+impl Default for MigrationRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl fmt::Debug for MigrationRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let steps = self
+            .steps
+            .iter()
+            .map(|s| format!("{} -> {}", s.source(), s.target()))
+            .collect::<Vec<_>>();
+        f.debug_struct("MigrationRegistry")
+            .field("steps", &steps)
+            .finish()
+    }
+}
+
+/// 移行の結果.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// 移行元のバージョン.
+    pub from: StorageVersion,
+
+    /// 移行先のバージョン.
+    pub to: StorageVersion,
+
+    /// 実行(ドライランの場合には検査)されたステップの一覧.
+    ///
+    /// 各要素は`({移行元}, {移行先}, {説明})`.
+    pub steps: Vec<(StorageVersion, StorageVersion, String)>,
+
+    /// ドライランだったかどうか.
+    pub dry_run: bool,
+
+    /// 中断されていた移行を再開したかどうか.
+    pub resumed: bool,
+}
+
+/// ストレージをオフラインで移行するためのビルダ.
+///
+/// # Examples
+///
+/// ```
+/// use cannyls::nvm::SharedMemoryNvm;
+/// use cannyls::storage::{Migrator, Storage, StorageVersion};
+///
+/// # fn main() -> Result<(), cannyls::Error> {
+/// let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+/// Storage::create(nvm.clone())?;
+///
+/// let report = Migrator::new()
+///     .target(StorageVersion::latest_wide())
+///     .migrate(nvm.clone())?;
+/// assert_eq!(report.to, StorageVersion::latest_wide());
+///
+/// let storage = Storage::open(nvm)?;
+/// assert!(storage.header().is_wide_format());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Migrator {
+    registry: MigrationRegistry,
+    target: StorageVersion,
+    dry_run: bool,
+    logger: Logger,
+}
+impl Migrator {
+    /// 組み込みのステップ群を使用する`Migrator`インスタンスを生成する.
+    pub fn new() -> Self {
+        Self::with_registry(MigrationRegistry::new())
+    }
+
+    /// 指定のレジストリを使用する`Migrator`インスタンスを生成する.
+    pub fn with_registry(registry: MigrationRegistry) -> Self {
+        Migrator {
+            registry,
+            target: StorageVersion::latest(),
+            dry_run: false,
+            logger: Logger::root(Discard, o!()),
+        }
+    }
+
+    /// 移行先のバージョンを指定する.
+    ///
+    /// デフォルト値は`StorageVersion::latest()`.
+    pub fn target(&mut self, version: StorageVersion) -> &mut Self {
+        self.target = version;
+        self
+    }
+
+    /// ドライランを行うかどうかを指定する.
+    ///
+    /// `true`の場合には、移行経路の組み立てと各ステップの`check`のみが行われ、ストレージには何も書き込まれない.
+    ///
+    /// デフォルト値は`false`.
+    pub fn dry_run(&mut self, enabled: bool) -> &mut Self {
+        self.dry_run = enabled;
+        self
+    }
+
+    /// ロガーを指定する.
+    pub fn logger(&mut self, logger: Logger) -> &mut Self {
+        self.logger = logger;
+        self
+    }
+
+    /// レジストリへの可変参照を返す.
+    pub fn registry_mut(&mut self) -> &mut MigrationRegistry {
+        &mut self.registry
+    }
+
+    /// ストレージを移行する.
+    ///
+    /// 既に移行先のバージョンとなっている場合には、何も行わない.
+    ///
+    /// 各ステップの完了時にはヘッダ(およびバックアップヘッダ)が書き換えられ、同期が行われる.
+    /// 途中で失敗した場合には、完了済みのステップの結果は保持され、
+    /// 実行中だったステップの進捗マーカーがヘッダに残る(移行元がv1.2以降の場合).
+    /// その場合は、再度このメソッドを呼び出すことで、移行を再開することができる.
+    ///
+    /// 移行はオフラインで行う必要がある(i.e., 他からストレージが使用されていないこと).
+    ///
+    /// # Errors
+    ///
+    /// 以下の場合には、ストレージを一切変更せずに`ErrorKind::InvalidInput`エラーが返される:
+    ///
+    /// - 移行経路が存在しない
+    /// - 経路上のいずれかのステップの`check`が失敗した
+    /// - 残っている進捗マーカーが示す移行先と、現在の経路の最初のステップの移行先が異なる
+    pub fn migrate<N>(&self, mut nvm: N) -> Result<MigrationReport>
+    where
+        N: NonVolatileMemory,
+    {
+        let (report, _) = track!(self.migrate_nvm(&mut nvm))?;
+        Ok(report)
+    }
+
+    /// `migrate`と同様だが、結果には移行後のヘッダも含まれる.
+    pub(crate) fn migrate_nvm<N>(&self, nvm: &mut N) -> Result<(MigrationReport, StorageHeader)>
+    where
+        N: NonVolatileMemory,
+    {
+        let (mut header, _) = track!(StorageBuilder::new()
+            .logger(self.logger.clone())
+            .read_header(&mut *nvm))?;
+        let from = StorageVersion::of(&header);
+        let marker = track!(parse_marker(&header))?;
+        let resumed = marker.is_some();
+        if let Some((source, _, _)) = marker {
+            track_assert_eq!(
+                source,
+                from,
+                ErrorKind::StorageCorrupted,
+                "Inconsistent migration marker"
+            );
+        }
+
+        let plan = track!(self.registry.plan(from, self.target))?;
+        if let Some((_, target, _)) = marker {
+            let first = plan.first().map(|s| s.target());
+            track_assert_eq!(
+                first,
+                Some(target),
+                ErrorKind::InvalidInput,
+                "The interrupted migration cannot be resumed with the current plan"
+            );
+        }
+        {
+            let mut simulated = header.clone();
+            for step in &plan {
+                track!(step.check(&simulated); step.source(), step.target())?;
+                simulated.major_version = step.target().major;
+                simulated.minor_version = step.target().minor;
+            }
+        }
+        let steps = plan
+            .iter()
+            .map(|s| (s.source(), s.target(), s.description().to_owned()))
+            .collect();
+        let report = MigrationReport {
+            from,
+            to: self.target,
+            steps,
+            dry_run: self.dry_run,
+            resumed,
+        };
+        if self.dry_run {
+            return Ok((report, header));
+        }
+
+        let mut io = NvmIo(nvm);
+        let mut progress = marker.and_then(|(_, _, p)| p);
+        for step in plan {
+            info!(
+                self.logger,
+                "Migrates the storage";
+                "from" => %step.source(),
+                "to" => %step.target(),
+                "description" => step.description(),
+                "resumed" => progress.is_some()
+            );
+            let marker = format!("{}->{}", step.source(), step.target());
+            if step.source().supports_metadata()
+                && !header
+                    .metadata
+                    .properties
+                    .contains_key(MIGRATION_MARKER_KEY)
+            {
+                header
+                    .metadata
+                    .properties
+                    .insert(MIGRATION_MARKER_KEY.to_owned(), marker.clone());
+                track!(write_header(&mut io, &header))?;
+            }
+
+            let mut base_header = header.clone();
+            base_header.metadata.properties.remove(MIGRATION_MARKER_KEY);
+            let mut context = MigrationContext {
+                header: base_header.clone(),
+                base_header,
+                io: &mut io,
+                marker,
+                progress: progress.take(),
+            };
+            track!(step.migrate(&mut context); step.source(), step.target())?;
+
+            // ステップの完了を確定させる
+            header = context.header;
+            header.major_version = step.target().major;
+            header.minor_version = step.target().minor;
+            header.metadata.properties.remove(MIGRATION_MARKER_KEY);
+            track!(write_header(&mut io, &header))?;
+        }
+        Ok((report, header))
+    }
+}
+impl Default for Migrator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// ヘッダから進捗マーカーを読み込む.
+///
+/// 結果は`(移行元, 移行先, 進捗)`.
+#[allow(clippy::type_complexity)]
+fn parse_marker(
+    header: &StorageHeader,
+) -> Result<Option<(StorageVersion, StorageVersion, Option<String>)>> {
+    let value = match header.metadata.properties.get(MIGRATION_MARKER_KEY) {
+        None => return Ok(None),
+        Some(value) => value,
+    };
+    let (versions, progress) = match value.find('#') {
+        None => (&value[..], None),
+        Some(i) => (&value[..i], Some(value[i + 1..].to_owned())),
+    };
+    let mut tokens = versions.splitn(2, "->");
+    let source = track!(tokens.next().unwrap_or("").parse(); value)?;
+    let target = track!(tokens.next().unwrap_or("").parse(); value)?;
+    Ok(Some((source, target, progress)))
+}
+
+/// ヘッダ(およびバックアップヘッダ)を書き込んで、同期する.
+fn write_header(io: &mut dyn MigrationIo, header: &StorageHeader) -> Result<()> {
+    let mut buf = Vec::new();
+    track!(header.write_header_region_to(&mut buf))?;
+    track!(write_aligned(io, 0, &buf))?;
+    if header.redundant_headers {
+        let mut buf = Vec::new();
+        track!(header.write_backup_region_to(&mut buf))?;
+        track!(write_aligned(io, header.backup_region_offset(), &buf))?;
+    }
+    track!(io.sync())?;
+    Ok(())
+}
+
+fn write_aligned(io: &mut dyn MigrationIo, position: u64, bytes: &[u8]) -> Result<()> {
+    let mut aligned_bytes = AlignedBytes::from_bytes(bytes, io.block_size());
+    aligned_bytes.align();
+    track_io!(io.seek(SeekFrom::Start(position)))?;
+    track_io!(io.write_all(&aligned_bytes))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use trackable::result::TestResult;
+
+    use super::*;
+    use crate::lump::{LumpData, LumpId};
+    use crate::nvm::SharedMemoryNvm;
+    use crate::storage::Storage;
+
+    #[test]
+    fn plan_works() -> TestResult {
+        let registry = MigrationRegistry::new();
+        let plan = track!(registry.plan(StorageVersion::new(1, 0), StorageVersion::latest_wide()))?;
+        let versions = plan
+            .iter()
+            .map(|s| s.target().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(versions, ["1.1", "1.2", "2.0"]);
+
+        assert!(registry
+            .plan(StorageVersion::latest_wide(), StorageVersion::latest())
+            .is_err());
+        assert_eq!(
+            track!("1.2".parse::<StorageVersion>())?,
+            StorageVersion::latest()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_works() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        track!(Storage::create(nvm.clone()))?;
+        let before = nvm.to_bytes();
+
+        let report = track!(Migrator::new()
+            .target(StorageVersion::latest_wide())
+            .dry_run(true)
+            .migrate(nvm.clone()))?;
+        assert!(report.dry_run);
+        assert_eq!(report.steps.len(), 1);
+        assert_eq!(nvm.to_bytes(), before);
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_works() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        {
+            let mut storage = track!(Storage::create(nvm.clone()))?;
+            track!(storage.put(&LumpId::new(1), &track!(LumpData::new(b"foo".to_vec()))?))?;
+        }
+
+        let report = track!(Migrator::new()
+            .target(StorageVersion::latest_wide())
+            .migrate(nvm.clone()))?;
+        assert_eq!(report.from, StorageVersion::latest());
+        assert!(!report.resumed);
+
+        let mut storage = track!(Storage::open(nvm.clone()))?;
+        assert!(storage.header().is_wide_format());
+        assert!(storage.header().metadata.properties.is_empty());
+        assert_eq!(
+            track!(storage.get(&LumpId::new(1)))?.map(|d| d.as_bytes().to_owned()),
+            Some(b"foo".to_vec())
+        );
+        Ok(())
+    }
+
+    struct FlakyStep {
+        fail: Arc<AtomicBool>,
+    }
+    impl MigrationStep for FlakyStep {
+        fn source(&self) -> StorageVersion {
+            StorageVersion::latest()
+        }
+        fn target(&self) -> StorageVersion {
+            StorageVersion::latest_wide()
+        }
+        fn description(&self) -> &str {
+            "flaky"
+        }
+        fn migrate(&self, context: &mut MigrationContext) -> Result<()> {
+            if self.fail.load(Ordering::SeqCst) {
+                assert_eq!(context.progress(), None);
+                track!(context.save_progress("half"))?;
+                track_panic!(ErrorKind::Other, "Crashed");
+            }
+            assert_eq!(context.progress(), Some("half"));
+            context
+                .header_mut()
+                .metadata
+                .properties
+                .insert("migrated".to_owned(), "yes".to_owned());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn resume_works() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        track!(Storage::create(nvm.clone()))?;
+
+        let fail = Arc::new(AtomicBool::new(true));
+        let mut migrator = Migrator::new();
+        migrator
+            .target(StorageVersion::latest_wide())
+            .registry_mut()
+            .register(FlakyStep { fail: fail.clone() });
+
+        // 途中で失敗: 進捗マーカーが残るので、通常のオープンはできない
+        assert!(migrator.migrate(nvm.clone()).is_err());
+        assert_eq!(
+            Storage::open(nvm.clone()).err().map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+
+        // 再開
+        fail.store(false, Ordering::SeqCst);
+        let report = track!(migrator.migrate(nvm.clone()))?;
+        assert!(report.resumed);
+
+        let storage = track!(Storage::open(nvm.clone()))?;
+        assert!(storage.header().is_wide_format());
+        assert_eq!(
+            storage.header().metadata.properties.get("migrated"),
+            Some(&"yes".to_owned())
+        );
+        assert!(!storage
+            .header()
+            .metadata
+            .properties
+            .contains_key(MIGRATION_MARKER_KEY));
+        Ok(())
+    }
+}
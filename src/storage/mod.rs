@@ -15,30 +15,49 @@
 //! [gc]: https://github.com/frugalos/cannyls/wiki/Journal-Region-GC
 pub use self::address::Address;
 pub use self::builder::StorageBuilder;
-pub use self::header::StorageHeader;
-pub use self::journal::{JournalEntry, JournalRecord, JournalSnapshot};
+pub use self::durability::Durability;
+pub use self::header::{StorageHeader, StorageMetadata};
+pub use self::index::LumpIndexBackend;
+pub use self::journal::{JournalEntry, JournalRecord, JournalRecoveryMode, JournalSnapshot};
+pub use self::migration::{
+    HeaderOnlyStep, MigrationContext, MigrationIo, MigrationRegistry, MigrationReport,
+    MigrationStep, Migrator, StorageVersion, MIGRATION_MARKER_KEY,
+};
+pub use self::progress::{OpenPhase, OpenProgress};
+pub(crate) use self::reader::{ParallelGet, StorageReader};
+pub use self::replication::{Mutation, MutationKind};
+pub use self::resize::RESIZE_MARKER_KEY;
 
 pub(crate) use self::data_region::DataRegionLumpData; // `lump`モジュール用に公開
 
 use self::data_region::DataRegion;
-use self::index::LumpIndex;
+use self::index::{LumpIndex, SharedLumpIndex};
 use self::journal::JournalRegion;
-use self::portion::Portion;
+use self::portion::{PackedPortion, Portion};
 use crate::block::BlockSize;
 use crate::lump::{LumpData, LumpDataInner, LumpHeader, LumpId};
 use crate::metrics::StorageMetrics;
-use crate::nvm::NonVolatileMemory;
+use crate::nvm::{NonVolatileMemory, SyncLevel};
+use crate::{ErrorKind, Result};
+use std::collections::HashSet;
+use std::mem;
 use std::ops::Range;
-use crate::Result;
+use std::time::Instant;
 
 mod address;
 mod allocator;
 mod builder;
 mod data_region;
+mod durability;
 mod header;
 mod index;
 mod journal;
+mod migration;
 mod portion;
+mod progress;
+mod reader;
+mod replication;
+mod resize;
 
 /// ストレージの先頭に書き込まれるマジックナンバー.
 ///
@@ -53,17 +72,40 @@ pub const MAJOR_VERSION: u16 = 1;
 /// ストレージフォーマットの現在のマイナーバージョン.
 ///
 /// マイナーバージョンには、後方互換性がある.
-pub const MINOR_VERSION: u16 = 1;
+pub const MINOR_VERSION: u16 = 2;
+
+/// ワイドフォーマットのメジャーバージョン.
+///
+/// v1に比べて、ブロックサイズ(最大`BlockSize::MAX`)や一つのlumpが占有可能な部分領域の長さ、
+/// データ領域のアドレス空間が拡張されている.
+///
+/// `StorageBuilder::wide_format`を指定して生成したストレージ、
+/// ないし`StorageBuilder::migrate_to_wide_format`で移行したストレージのみがこのバージョンとなる.
+/// v1のストレージも引き続き読み書き可能.
+pub const WIDE_MAJOR_VERSION: u16 = 2;
+
+/// ワイドフォーマットの現在のマイナーバージョン.
+///
+/// v1.2までの機能(ヘッダの冗長化等)は全て含まれている.
+pub const WIDE_MINOR_VERSION: u16 = 0;
+
+/// `LumpData::new_auto`で生成されたデータを、ジャーナル領域に埋め込むかどうかの閾値のデフォルト値(バイト単位).
+pub const DEFAULT_EMBED_THRESHOLD: usize = 2048;
 
 /// ジャーナル領域の最大サイズ(バイト単位).
 ///
 /// およそ1TB.
-pub const MAX_JOURNAL_REGION_SIZE: u64 = Address::MAX;
+pub const MAX_JOURNAL_REGION_SIZE: u64 = Address::MAX_V1;
 
 /// データ領域の最大サイズ(バイト単位).
 ///
 /// およそ512TB.
-pub const MAX_DATA_REGION_SIZE: u64 = Address::MAX * BlockSize::MIN as u64;
+pub const MAX_DATA_REGION_SIZE: u64 = Address::MAX_V1 * BlockSize::MIN as u64;
+
+/// ワイドフォーマットにおけるデータ領域の最大サイズ(バイト単位).
+///
+/// およそ8PB.
+pub const MAX_WIDE_DATA_REGION_SIZE: u64 = Address::MAX * BlockSize::MIN as u64;
 
 /// Lumpを格納するためのストレージ.
 ///
@@ -79,10 +121,22 @@ where
     N: NonVolatileMemory,
 {
     header: StorageHeader,
+    header_nvm: N,
+    backup_nvm: N,
     journal_region: JournalRegion<N>,
     data_region: DataRegion<N>,
-    lump_index: LumpIndex,
+    lump_index: SharedLumpIndex,
     metrics: StorageMetrics,
+    read_only: bool,
+    embed_threshold: usize,
+    pack_threshold: usize,
+
+    // 書き込み中のパックに格納済みで、まだジャーナルに記録されていないlump群
+    pending_packed: Vec<(LumpId, PackedPortion)>,
+    pending_packed_since: Option<Instant>,
+
+    // 致命的なエラーが発生したかどうか(発生後は、破棄時にNVMへの書き込みを行わない)
+    poisoned: bool,
 }
 impl<N> Storage<N>
 where
@@ -90,6 +144,8 @@ where
 {
     pub(crate) fn new(
         header: StorageHeader,
+        header_nvm: N,
+        backup_nvm: N,
         journal_region: JournalRegion<N>,
         data_region: DataRegion<N>,
         lump_index: LumpIndex,
@@ -97,10 +153,18 @@ where
     ) -> Self {
         Storage {
             header,
+            header_nvm,
+            backup_nvm,
             journal_region,
             data_region,
-            lump_index,
+            lump_index: SharedLumpIndex::new(lump_index),
             metrics,
+            read_only: false,
+            embed_threshold: DEFAULT_EMBED_THRESHOLD,
+            pack_threshold: 0,
+            pending_packed: Vec::new(),
+            pending_packed_since: None,
+            poisoned: false,
         }
     }
 
@@ -114,11 +178,92 @@ where
         track!(StorageBuilder::new().open(nvm))
     }
 
+    /// デフォルト設定で、既に存在するストレージを読み込み専用でオープンする.
+    ///
+    /// 詳細は`StorageBuilder::open_read_only`を参照のこと.
+    pub fn open_read_only(nvm: N) -> Result<Self> {
+        track!(StorageBuilder::new().open_read_only(nvm))
+    }
+
+    /// ストレージが読み込み専用で開かれているかどうかを返す.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// 前回ストレージが正常に閉じられた(i.e., `Storage::close`が呼ばれた)かどうかを返す.
+    ///
+    /// `false`の場合には、前回の実行がクラッシュ等により異常終了した可能性があるので、
+    /// 必要に応じて利用者側で検査(scrub)等を実施すること.
+    pub fn last_shutdown_clean(&self) -> bool {
+        self.journal_region.last_shutdown_clean()
+    }
+
+    /// ストレージを正常に閉じる.
+    ///
+    /// ジャーナルの同期および最後のGCを行った上で、正常終了フラグを永続化する.
+    /// このメソッドを呼ばずにインスタンスが破棄された場合には、
+    /// 次回のオープン時に`last_shutdown_clean()`が`false`を返すことになる.
+    ///
+    /// 書き込み中のパックに格納されたlumpも、このメソッドによって書き出され、ジャーナルに記録される.
+    /// 破棄時にも可能な範囲で書き出しは試みられるが、致命的なエラーの発生後には行われないので、
+    /// それらを確実に永続化したい場合には、このメソッドを呼び出すこと.
+    ///
+    /// ストレージが読み込み専用で開かれている場合には何も行わない.
+    pub fn close(mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        track!(self.seal_pack(false))?;
+        track!(self.journal_region.close(&mut self.lump_index.write()))?;
+        Ok(())
+    }
+
+    /// このストレージのデータ領域に保存可能なlumpのサイズの上限(バイト単位)を返す.
+    ///
+    /// v1フォーマットの場合には`LumpData::MAX_SIZE`となる.
+    /// ワイドフォーマット(v2)の場合には`LumpData::MAX_WIDE_SIZE`を上限に、ブロックサイズに比例して大きくなる.
+    pub fn max_lump_size(&self) -> usize {
+        self.header.max_lump_size()
+    }
+
+    /// 指定されたIDのlumpの、データ領域内での位置(ブロック単位)を返す.
+    ///
+    /// lumpが存在しない、あるいは、ジャーナル領域に埋め込まれている場合には`None`が返される.
+    pub(crate) fn lump_position(&self, lump_id: &LumpId) -> Option<u64> {
+        match self.lump_index.read().get(lump_id)? {
+            Portion::Journal(_) => None,
+            Portion::Data(portion) => Some(portion.start.as_u64()),
+            Portion::Packed(portion) => Some(portion.pack.as_u64()),
+        }
+    }
+
+    /// 並列読み込み用のハンドルを生成する.
+    ///
+    /// NVMが並列読み込み(`NonVolatileMemory::reader`)に対応していない場合には`Ok(None)`が返される.
+    pub(crate) fn reader(&mut self) -> Result<Option<StorageReader>> {
+        let data_region = match track!(self.data_region.reader())? {
+            None => return Ok(None),
+            Some(data_region) => data_region,
+        };
+        Ok(Some(StorageReader::new(
+            self.lump_index.clone(),
+            data_region,
+            self.metrics.clone(),
+        )))
+    }
+
     /// ストレージのヘッダ情報を返す.
     pub fn header(&self) -> &StorageHeader {
         &self.header
     }
 
+    /// 保存先の自動選択で使用される、ジャーナル領域への埋め込みの閾値(バイト単位).
+    ///
+    /// `StorageBuilder::embed_threshold`で指定された値.
+    pub fn embed_threshold(&self) -> usize {
+        self.embed_threshold
+    }
+
     /// ストレージのメトリクスを返す.
     pub fn metrics(&self) -> &StorageMetrics {
         &self.metrics
@@ -126,7 +271,47 @@ where
 
     /// ストレージに保存されている中で、指定された範囲が占有するバイト数を返す.
     pub fn usage_range(&self, range: Range<LumpId>) -> StorageUsage {
-        self.lump_index.usage_range(range, self.header.block_size)
+        self.lump_index
+            .read()
+            .usage_range(range, self.header.block_size)
+    }
+
+    /// ストレージのヘッダに格納されているメタデータを更新する.
+    ///
+    /// 更新後のヘッダは即座にNVMに書き込まれ、同期される
+    /// (ヘッダの冗長化が有効な場合には、バックアップヘッダも同様に更新される).
+    ///
+    /// 更新後のメタデータのサイズが`StorageMetadata::MAX_SIZE`を超える場合には、
+    /// `ErrorKind::InvalidInput`エラーが返され、ストレージの状態は変更されない.
+    ///
+    /// ストレージが読み込み専用で開かれている場合には`ErrorKind::ReadOnly`エラーが返される.
+    pub fn update_metadata<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut StorageMetadata),
+    {
+        track_assert!(!self.read_only, ErrorKind::ReadOnly);
+        let mut header = self.header.clone();
+        f(&mut header.metadata);
+        let size = header.metadata.encoded_size();
+        track_assert!(
+            size <= StorageMetadata::MAX_SIZE,
+            ErrorKind::InvalidInput,
+            "Too large storage metadata: {} bytes (max={})",
+            size,
+            StorageMetadata::MAX_SIZE
+        );
+
+        track!(header.write_to_regions(&mut self.header_nvm, &mut self.backup_nvm))?;
+        self.metrics.set_metadata(header.metadata.clone());
+        self.header = header;
+        Ok(())
+    }
+
+    /// ストレージのラベルを更新する.
+    ///
+    /// `update_metadata`の糖衣構文.
+    pub fn set_label(&mut self, label: &str) -> Result<()> {
+        track!(self.update_metadata(|metadata| metadata.label = label.to_owned()))
     }
 
     /// 指定されたIDのlumpを取得する.
@@ -138,7 +323,8 @@ where
     /// 以後はこのインスタンスの使用を中止するのが望ましい
     /// (更新系操作とは異なり、何度かリトライを試みても問題はない).
     pub fn get(&mut self, lump_id: &LumpId) -> Result<Option<LumpData>> {
-        match self.lump_index.get(lump_id) {
+        let portion = self.lump_index.read().get(lump_id);
+        match portion {
             None => Ok(None),
             Some(portion) => {
                 let data = match portion {
@@ -151,6 +337,11 @@ where
                         self.metrics.get_data_lumps.increment();
                         track!(self.data_region.get(portion).map(LumpData::from))?
                     }
+                    Portion::Packed(portion) => {
+                        self.metrics.get_data_lumps.increment();
+                        let bytes = track!(self.data_region.get_packed(portion))?;
+                        track!(LumpData::new(bytes))?
+                    }
                 };
                 Ok(Some(data))
             }
@@ -159,9 +350,12 @@ where
 
     /// 指定されたIDのlumpのヘッダ情報を取得する.
     pub fn head(&self, lump_id: &LumpId) -> Option<LumpHeader> {
-        self.lump_index.get(lump_id).map(|portion| LumpHeader {
-            approximate_data_size: portion.len(self.header.block_size),
-        })
+        self.lump_index
+            .read()
+            .get(lump_id)
+            .map(|portion| LumpHeader {
+                approximate_data_size: portion.len(self.header.block_size),
+            })
     }
 
     /// 保存されているlumpのID一覧を返す.
@@ -173,12 +367,12 @@ where
     /// 例えば巨大なHDDを使用している場合には、lumpの数が数百万以上になることもあるため、
     /// このメソッドは呼び出す際には注意が必要.
     pub fn list(&self) -> Vec<LumpId> {
-        self.lump_index.list()
+        self.lump_index.read().list()
     }
 
     /// ストレージに保存されている中で、指定された範囲に含まれるLumpIdの一覧を返す.
     pub fn list_range(&mut self, range: Range<LumpId>) -> Vec<LumpId> {
-        self.lump_index.list_range(range)
+        self.lump_index.read().list_range(range)
     }
 
     /// lumpを保存する.
@@ -198,21 +392,141 @@ where
     /// 引数に渡される`LumpData`が、`LumpData::new`関数経由で生成されている場合には、
     /// NVMへの書き込み前に、データをブロック境界にアライメントするためのメモリコピーが余分に発生してしまう.
     /// それを避けたい場合には、`Storage::allocate_lump_data`メソッドを使用して`LumpData`を生成すると良い.
+    ///
+    /// # 保存先の自動選択
+    ///
+    /// `LumpData::new_auto`(ないし`Storage::allocate_lump_data_auto`)で生成された`LumpData`の場合には、
+    /// データのサイズが`StorageBuilder::embed_threshold`で指定された閾値以下であればジャーナル領域に、
+    /// そうでなければデータ領域に保存される.
+    ///
+    /// # パッキング
+    ///
+    /// `StorageBuilder::pack_threshold`でパッキングが有効にされている場合には、
+    /// データ領域に保存されるlumpの内で、サイズが閾値以下のものは、
+    /// 専用のブロックを割り当てる代わりに、他の小さなlumpと共有されるパックに詰め込まれる.
+    ///
+    /// # Errors
+    ///
+    /// ストレージが読み込み専用で開かれている場合には`ErrorKind::ReadOnly`エラーが返される.
+    ///
+    /// 保存先が自動選択される`LumpData`のサイズが`Storage::max_lump_size`を超えている場合には、
+    /// `ErrorKind::InvalidInput`エラーが返される(この場合、既存のlumpは変更されない).
     pub fn put(&mut self, lump_id: &LumpId, data: &LumpData) -> Result<bool> {
+        track!(self.put_impl(lump_id, data, false))
+    }
+
+    /// 永続化の度合いを指定して、lumpを保存する.
+    ///
+    /// 保存自体は`put`メソッドと同様に行われ、その後に`durability`に従った同期が行われる.
+    /// `Durability::DataAndJournalSynced`の場合には、データ領域への書き込みの同期が、
+    /// ジャーナルへのレコードの追記よりも前に行われる.
+    ///
+    /// # Errors
+    ///
+    /// `put`メソッドと同様.
+    pub fn put_with_durability(
+        &mut self,
+        lump_id: &LumpId,
+        data: &LumpData,
+        durability: Durability,
+    ) -> Result<bool> {
+        let is_new = track!(self.put_and_sync_data(lump_id, data, durability))?;
+        track!(self.sync_with(durability))?;
+        Ok(is_new)
+    }
+
+    /// `put_with_durability`の処理の内で、ジャーナルの同期以外の部分を行う.
+    ///
+    /// ジャーナルの同期は、呼び出し側が(他の操作とまとめて)`sync_with`を使って行う必要がある.
+    pub(crate) fn put_and_sync_data(
+        &mut self,
+        lump_id: &LumpId,
+        data: &LumpData,
+        durability: Durability,
+    ) -> Result<bool> {
+        let sync_data = durability == Durability::DataAndJournalSynced;
+        track!(self.put_impl(lump_id, data, sync_data))
+    }
+
+    /// 指定された永続化の度合いを満たすように、ストレージを同期する.
+    ///
+    /// それまでに行われた全ての書き込み操作が対象となる.
+    ///
+    /// ストレージが読み込み専用で開かれている場合には何も行わない.
+    pub fn sync_with(&mut self, durability: Durability) -> Result<()> {
+        if self.read_only || durability == Durability::Buffered {
+            return Ok(());
+        }
+        let result = self.sync_with_impl(durability);
+        track!(self.poison_on_error(result))
+    }
+
+    fn sync_with_impl(&mut self, durability: Durability) -> Result<()> {
+        track!(self.seal_pack(durability == Durability::DataAndJournalSynced))?;
+        match durability {
+            Durability::Buffered => unreachable!(),
+            Durability::JournalFlushed => {
+                track!(self.journal_region.sync_with(SyncLevel::Flush))?;
+            }
+            Durability::JournalSynced => {
+                track!(self.journal_region.sync_with(SyncLevel::DataSync))?;
+            }
+            Durability::DataAndJournalSynced => {
+                track!(self.data_region.sync_with(SyncLevel::DataSync))?;
+                track!(self.journal_region.sync_with(SyncLevel::DataSync))?;
+            }
+        }
+        self.metrics.durability_syncs.increment(durability);
+        Ok(())
+    }
+
+    fn put_impl(&mut self, lump_id: &LumpId, data: &LumpData, sync_data: bool) -> Result<bool> {
+        track_assert!(!self.read_only, ErrorKind::ReadOnly);
+        if let LumpDataInner::AutoPlacement(_) | LumpDataInner::AutoPlacementAligned(_) =
+            data.as_inner()
+        {
+            // `LumpData::new_auto`はワイドフォーマットの上限までを受け付けるので、
+            // 既存のlumpを削除する前に、このストレージの上限を超えていないかを確認しておく
+            track_assert!(
+                data.as_bytes().len() <= self.max_lump_size(),
+                ErrorKind::InvalidInput;
+                data.as_bytes().len(),
+                self.max_lump_size()
+            );
+        }
+        let result = self.put_lump(lump_id, data, sync_data);
+        track!(self.poison_on_error(result))
+    }
+
+    fn put_lump(&mut self, lump_id: &LumpId, data: &LumpData, sync_data: bool) -> Result<bool> {
         let updated = track!(self.delete_if_exists(lump_id, false))?;
         match data.as_inner() {
             LumpDataInner::JournalRegion(data) => {
-                track!(self
-                    .journal_region
-                    .records_embed(&mut self.lump_index, lump_id, data))?;
+                track!(self.put_lump_to_journal_region(lump_id, data))?;
             }
             LumpDataInner::DataRegion(data) => {
-                track!(self.put_lump_to_data_region(lump_id, data))?;
+                track!(self.put_lump_to_data_region(lump_id, data, sync_data))?;
             }
             LumpDataInner::DataRegionUnaligned(data) => {
-                let mut aligned_data = DataRegionLumpData::new(data.len(), self.header.block_size);
-                aligned_data.as_bytes_mut().copy_from_slice(data);
-                track!(self.put_lump_to_data_region(lump_id, &aligned_data))?;
+                track!(self.put_unaligned_lump_to_data_region(lump_id, data, sync_data))?;
+            }
+            LumpDataInner::AutoPlacement(data) => {
+                if data.len() <= self.embed_threshold {
+                    self.metrics.auto_placements_journal.increment();
+                    track!(self.put_lump_to_journal_region(lump_id, data))?;
+                } else {
+                    self.metrics.auto_placements_data.increment();
+                    track!(self.put_unaligned_lump_to_data_region(lump_id, data, sync_data))?;
+                }
+            }
+            LumpDataInner::AutoPlacementAligned(data) => {
+                if data.as_bytes().len() <= self.embed_threshold {
+                    self.metrics.auto_placements_journal.increment();
+                    track!(self.put_lump_to_journal_region(lump_id, data.as_bytes()))?;
+                } else {
+                    self.metrics.auto_placements_data.increment();
+                    track!(self.put_lump_to_data_region(lump_id, data, sync_data))?;
+                }
             }
         }
         self.metrics.put_lumps_at_running.increment();
@@ -228,8 +542,13 @@ where
     /// このメソッドがエラーを返した場合には、
     /// 不整合ないしI/O周りで致命的な問題が発生している可能性があるので、
     /// 以後はこのインスタンスの使用を中止するのが望ましい.
+    ///
+    /// ただし、ストレージが読み込み専用で開かれている場合に返される`ErrorKind::ReadOnly`エラーは例外で、
+    /// この場合にはインスタンスの状態は一切変更されていない.
     pub fn delete(&mut self, lump_id: &LumpId) -> Result<bool> {
-        track!(self.delete_if_exists(lump_id, true))
+        track_assert!(!self.read_only, ErrorKind::ReadOnly);
+        let result = self.delete_if_exists(lump_id, true);
+        track!(self.poison_on_error(result))
     }
 
     /// LumpIdのrange [start..end) を用いて、これに含まれるLumpIdを全て削除する。
@@ -247,24 +566,36 @@ where
     ///
     /// `range`が大量の要素を含む場合には、
     /// このメソッドは巨大なLumpIdの配列を返しうることに注意されたい。
+    ///
+    /// また、ストレージが読み込み専用で開かれている場合には`ErrorKind::ReadOnly`エラーが返される.
     pub fn delete_range(&mut self, range: Range<LumpId>) -> Result<Vec<LumpId>> {
-        let targets = self.lump_index.list_range(range.clone());
+        track_assert!(!self.read_only, ErrorKind::ReadOnly);
+        let result = self.delete_range_impl(range);
+        track!(self.poison_on_error(result))
+    }
+
+    fn delete_range_impl(&mut self, range: Range<LumpId>) -> Result<Vec<LumpId>> {
+        let targets = self.lump_index.read().list_range(range.clone());
+        track!(self.seal_pack(false))?;
 
         // ジャーナル領域に範囲削除レコードを一つ書き込むため、一度のディスクアクセスが起こる。
         // 削除レコードを範囲分書き込むわけ *ではない* ため、複数回のディスクアクセスは発生しない。
         track!(self
             .journal_region
-            .records_delete_range(&mut self.lump_index, range))?;
+            .records_delete_range(&mut self.lump_index.write(), range))?;
 
         for lump_id in &targets {
-            if let Some(portion) = self.lump_index.remove(lump_id) {
+            let removed = self.lump_index.write().remove(lump_id);
+            if let Some(portion) = removed {
                 self.metrics.delete_lumps.increment();
 
-                if let Portion::Data(portion) = portion {
-                    // DataRegion::deleteはメモリアロケータに対する解放要求をするのみで
-                    // ディスクにアクセスすることはない。
-                    // （管理領域から外すだけで、例えばディスク上の値を0クリアするようなことはない）
-                    self.data_region.delete(portion);
+                // DataRegion::{delete, delete_packed}はメモリアロケータに対する解放要求をするのみで
+                // ディスクにアクセスすることはない。
+                // （管理領域から外すだけで、例えばディスク上の値を0クリアするようなことはない）
+                match portion {
+                    Portion::Data(portion) => self.data_region.delete(portion),
+                    Portion::Packed(portion) => self.data_region.delete_packed(portion),
+                    Portion::Journal(_) => {}
                 }
             }
         }
@@ -285,9 +616,13 @@ where
     ///
     /// # Errors
     ///
-    /// 指定されたサイズが`MAX_SIZE`を超えている場合は、`ErrorKind::InvalidInput`エラーが返される.
+    /// 指定されたサイズが`max_lump_size()`を超えている場合は、`ErrorKind::InvalidInput`エラーが返される.
     pub fn allocate_lump_data(&self, size: usize) -> Result<LumpData> {
-        track!(LumpData::aligned_allocate(size, self.header.block_size))
+        track!(LumpData::aligned_allocate(
+            size,
+            self.header.block_size,
+            self.max_lump_size()
+        ))
     }
 
     /// `allocate_lump_data`メソッドにデータの初期化を加えたメソッド.
@@ -305,20 +640,102 @@ where
         Ok(data)
     }
 
+    /// 保存先の選択をストレージに委ねる`LumpData`インスタンスを、データの初期化も含めて生成する.
+    ///
+    /// データのサイズがジャーナル領域への埋め込みの閾値以下の場合には`LumpData::new_auto`と同様となり、
+    /// それ以外の場合には`allocate_lump_data_with_bytes`と同様にアライメント済みのメモリ領域が使用される.
+    /// いずれの場合でも、実際の保存先の選択は`Storage::put`の呼び出し時に行われる.
+    ///
+    /// # Errors
+    ///
+    /// 指定されたサイズが`max_lump_size()`を超えている場合は、`ErrorKind::InvalidInput`エラーが返される.
+    pub fn allocate_lump_data_auto(&self, bytes: &[u8]) -> Result<LumpData> {
+        if bytes.len() <= self.embed_threshold {
+            track!(LumpData::new_auto(bytes.to_vec()))
+        } else {
+            let mut data = track!(LumpData::aligned_allocate_auto(
+                bytes.len(),
+                self.header.block_size,
+                self.max_lump_size()
+            ))?;
+            data.as_bytes_mut().copy_from_slice(bytes);
+            Ok(data)
+        }
+    }
+
     /// 補助的な処理を一単位実行する.
     ///
     /// このメソッドを呼ばなくても動作上は問題はないが、
     /// リソースが空いているタイミングで実行することによって、
     /// 全体的な性能を改善できる可能性がある.
+    ///
+    /// ストレージが読み込み専用で開かれている場合には何も行わない.
     pub fn run_side_job_once(&mut self) -> Result<()> {
-        track!(self.journal_region.run_side_job_once(&mut self.lump_index))?;
-        Ok(())
+        if self.read_only {
+            return Ok(());
+        }
+        self.data_region.release_deferred();
+        let result = self.seal_pack(false).and_then(|()| {
+            self.journal_region
+                .run_side_job_once(&mut self.lump_index.write())
+        });
+        track!(self.poison_on_error(result))
     }
 
     /// メモリにバッファされているジャーナルをディスクに書き出す。
     /// 副作用として、バッファはクリアされる。
+    ///
+    /// ストレージが読み込み専用で開かれている場合には何も行わない。
     pub fn journal_sync(&mut self) -> Result<()> {
-        self.journal_region.sync()
+        if self.read_only {
+            return Ok(());
+        }
+        let result = self
+            .seal_pack(false)
+            .and_then(|()| self.journal_region.sync());
+        track!(self.poison_on_error(result))
+    }
+
+    /// ジャーナルの同期期限(`StorageBuilder::journal_sync_max_delay`)を返す.
+    ///
+    /// 書き込み中のパックに、まだジャーナルに記録されていないlumpが存在する場合には、
+    /// それらの格納時点も同期期限の算出に使用される.
+    ///
+    /// 同期期限が設定されていない場合や、未同期のレコードが存在しない場合には`None`が返される.
+    pub fn journal_sync_deadline(&self) -> Option<Instant> {
+        if self.read_only {
+            return None;
+        }
+        self.journal_region
+            .sync_deadline()
+            .into_iter()
+            .chain(self.pending_packed_deadline())
+            .min()
+    }
+
+    /// 未同期のジャーナルが同期期限を過ぎている場合には、ディスクに書き出す.
+    ///
+    /// 書き出しを行った場合には`true`が返される.
+    ///
+    /// ストレージが読み込み専用で開かれている場合には何も行わない.
+    pub fn journal_sync_if_overdue(&mut self) -> Result<bool> {
+        if self.read_only {
+            return Ok(false);
+        }
+        let result = self.journal_sync_if_overdue_impl();
+        track!(self.poison_on_error(result))
+    }
+
+    fn journal_sync_if_overdue_impl(&mut self) -> Result<bool> {
+        if self
+            .pending_packed_deadline()
+            .is_some_and(|t| t <= Instant::now())
+        {
+            track!(self.seal_pack(false))?;
+            track!(self.journal_region.sync())?;
+            return Ok(true);
+        }
+        track!(self.journal_region.sync_if_overdue())
     }
 
     /// ジャーナル領域に対するGCを実行する。
@@ -329,8 +746,15 @@ where
     /// 通常のstorageの使用では、各エントリをジャーナルに追加する際に、
     /// 小規模のGCが走る（正確には `JournalRegion::gc_once`）ので、
     /// このGCを手動で呼び出す必要はない。
+    ///
+    /// ストレージが読み込み専用で開かれている場合には`ErrorKind::ReadOnly`エラーが返される。
     pub fn journal_gc(&mut self) -> Result<()> {
-        self.journal_region.gc_all_entries(&mut self.lump_index)
+        track_assert!(!self.read_only, ErrorKind::ReadOnly);
+        let result = self.seal_pack(false).and_then(|()| {
+            self.journal_region
+                .gc_all_entries(&mut self.lump_index.write())
+        });
+        track!(self.poison_on_error(result))
     }
 
     /// ジャーナル領域のスナップショットを取得する。
@@ -344,6 +768,173 @@ where
         })
     }
 
+    /// 物理デバイスへの同期が完了している(i.e., コミット済みの)ジャーナルの終端のLSNを返す.
+    ///
+    /// `mutations_since`で取得可能なのは、このLSNよりも前に記録された操作のみとなる.
+    pub fn committed_lsn(&self) -> u64 {
+        self.journal_region.committed_lsn()
+    }
+
+    /// `mutations_since`に指定可能な最小のLSNを返す.
+    ///
+    /// これよりも古いレコードは、ジャーナルのGCによって既に破棄されている可能性がある.
+    pub fn oldest_lsn(&self) -> u64 {
+        self.journal_region.oldest_lsn()
+    }
+
+    /// LSNが`since`以上の、コミット済みの更新操作群を、記録順に最大で`max_mutations`個取得する.
+    ///
+    /// PUT操作には、その時点でのlumpのデータが含まれる.
+    /// なお、既に上書きないし削除されたlumpに対するPUT操作は結果から除外されるが、
+    /// 取得した操作群を全て(順番に)適用した後の状態は、除外しなかった場合と変わらない.
+    ///
+    /// また、ジャーナルのGCによって再配置されたレコードは、新しいLSNで再度返されることがあるが、
+    /// 各操作は冪等なので、同じ操作を複数回適用しても問題はない.
+    ///
+    /// 返された最後の要素の`next_lsn`を、次回の呼び出し時の`since`に指定することで、
+    /// ジャーナルを追跡(tail)することができる.
+    ///
+    /// # Errors
+    ///
+    /// `since`が`oldest_lsn()`よりも小さい場合には`ErrorKind::InvalidInput`エラーが返される.
+    /// この場合には、必要な操作が既に失われているので、複製先は全体を同期し直す必要がある.
+    pub fn mutations_since(&mut self, since: u64, max_mutations: usize) -> Result<Vec<Mutation>> {
+        let entries = track!(self.journal_region.committed_entries_since(
+            &self.lump_index.read(),
+            since,
+            max_mutations
+        ))?;
+        let mut mutations = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let lsn = entry.lsn;
+            let next_lsn = entry.next_lsn();
+            let kind = match entry.record {
+                JournalRecord::Put(lump_id, portion) => {
+                    let data = track!(self.data_region.get(portion).map(LumpData::from))?;
+                    MutationKind::Put {
+                        lump_id,
+                        data: data.as_bytes().to_vec(),
+                        embedded: false,
+                    }
+                }
+                JournalRecord::PutPacked(lump_id, portion) => {
+                    let data = track!(self.data_region.get_packed(portion))?;
+                    MutationKind::Put {
+                        lump_id,
+                        data,
+                        embedded: false,
+                    }
+                }
+                JournalRecord::Embed(lump_id, data) => MutationKind::Put {
+                    lump_id,
+                    data,
+                    embedded: true,
+                },
+                JournalRecord::Delete(lump_id) => MutationKind::Delete(lump_id),
+                JournalRecord::DeleteRange(range) => MutationKind::DeleteRange(range),
+                JournalRecord::EndOfRecords | JournalRecord::GoToFront => unreachable!(),
+            };
+            mutations.push(Mutation {
+                lsn,
+                next_lsn,
+                kind,
+            });
+        }
+        Ok(mutations)
+    }
+
+    /// `mutations_since`で取得した更新操作を、このストレージに適用する.
+    ///
+    /// ストレージが読み込み専用で開かれている場合には`ErrorKind::ReadOnly`エラーが返される.
+    pub fn apply_mutation(&mut self, mutation: &Mutation) -> Result<()> {
+        track_assert!(!self.read_only, ErrorKind::ReadOnly);
+        match mutation.kind {
+            MutationKind::Put {
+                ref lump_id,
+                ref data,
+                embedded,
+            } => {
+                let data = if embedded {
+                    track!(LumpData::new_embedded(data.clone()))?
+                } else {
+                    track!(self.allocate_lump_data_with_bytes(data))?
+                };
+                track!(self.put(lump_id, &data))?;
+            }
+            MutationKind::Delete(ref lump_id) => {
+                track!(self.delete(lump_id))?;
+            }
+            MutationKind::DeleteRange(ref range) => {
+                track!(self.delete_range(range.clone()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 使用率の低いパックを詰め直して、データ領域の空き容量を回収する.
+    ///
+    /// 有効なデータが占める割合が`max_utilization`以下のパック(書き込み中のものを除く)が対象となり、
+    /// それらに含まれるlumpは、全て書き込み中のパック(ないし新たに割り当てられたパック)に再配置される.
+    /// 再配置先のパックがNVMに書き出され、再配置がジャーナルに記録された後に、元のパックは解放される.
+    ///
+    /// 対象のパックが一つだけで、かつ書き込み中のパックも存在しない場合には、
+    /// 詰め直しても空き容量は増えないので、何も行わない.
+    ///
+    /// パックは、全てのメンバーが削除された時点で自動的に解放されるが、
+    /// 一部のメンバーのみが削除された場合には、このメソッドを呼び出さない限り、その空き領域は再利用されない.
+    ///
+    /// 結果として、解放されたパックの数が返される.
+    ///
+    /// # Errors
+    ///
+    /// `max_utilization`が`0.0..=1.0`の範囲外の場合には`ErrorKind::InvalidInput`エラーが返される.
+    ///
+    /// ストレージが読み込み専用で開かれている場合には`ErrorKind::ReadOnly`エラーが返される.
+    pub fn compact_packs(&mut self, max_utilization: f64) -> Result<usize> {
+        track_assert!(!self.read_only, ErrorKind::ReadOnly);
+        track_assert!(
+            (0.0..=1.0).contains(&max_utilization),
+            ErrorKind::InvalidInput,
+            "Invalid max utilization: {}",
+            max_utilization
+        );
+        let result = self.compact_packs_impl(max_utilization);
+        track!(self.poison_on_error(result))
+    }
+
+    fn compact_packs_impl(&mut self, max_utilization: f64) -> Result<usize> {
+        let targets = self
+            .data_region
+            .sparse_packs(max_utilization)
+            .into_iter()
+            .collect::<HashSet<_>>();
+        if targets.is_empty() || (targets.len() == 1 && !self.data_region.has_open_pack()) {
+            return Ok(0);
+        }
+
+        let members = self
+            .lump_index
+            .read()
+            .packed_portions()
+            .filter(|(_, portion)| targets.contains(&portion.pack))
+            .collect::<Vec<_>>();
+        for &(lump_id, old_portion) in &members {
+            let data = track!(self.data_region.get_packed(old_portion))?;
+            if !track!(self.put_lump_to_pack(&lump_id, &data, false))? {
+                let mut aligned_data = DataRegionLumpData::new(data.len(), self.header.block_size);
+                aligned_data.as_bytes_mut().copy_from_slice(&data);
+                track!(self.put_lump_to_dedicated_portion(&lump_id, &aligned_data, false))?;
+            }
+        }
+
+        // 再配置先がジャーナルに記録されるまでは、元のパックを解放しない
+        track!(self.seal_pack(false))?;
+        for (_, old_portion) in members {
+            self.data_region.delete_packed(old_portion);
+        }
+        Ok(targets.len())
+    }
+
     /// ジャーナル領域に対する自動小規模GCの有無を切り替えることができる（ユニットテスト用メソッド）。
     ///
     /// デフォルトの設定では、ジャーナル領域への変更操作が行われた際に、
@@ -359,29 +950,167 @@ where
         &mut self,
         lump_id: &LumpId,
         data: &DataRegionLumpData,
+        sync_data: bool,
+    ) -> Result<()> {
+        if data.as_bytes().len() <= self.pack_threshold
+            && track!(self.put_lump_to_pack(lump_id, data.as_bytes(), sync_data))?
+        {
+            return Ok(());
+        }
+        track!(self.put_lump_to_dedicated_portion(lump_id, data, sync_data))
+    }
+
+    /// `sync_data`が`true`の場合には、ジャーナルにレコードを追記する前に、データ領域を同期する.
+    fn put_lump_to_dedicated_portion(
+        &mut self,
+        lump_id: &LumpId,
+        data: &DataRegionLumpData,
+        sync_data: bool,
     ) -> Result<()> {
         let portion = track!(self.data_region.put(data))?;
+        if sync_data {
+            track!(self
+                .data_region
+                .sync_with(SyncLevel::DataSync)
+                .inspect_err(|_| self.data_region.delete(portion)))?;
+        }
         track!(self
-            .journal_region
-            .records_put(&mut self.lump_index, lump_id, portion)
-            .map_err(|e| {
-                self.data_region.delete(portion);
-                e
-            }))?;
-        self.lump_index.insert(*lump_id, Portion::Data(portion));
+            .seal_pack(false)
+            .inspect_err(|_| self.data_region.delete(portion)))?;
+        let result =
+            self.journal_region
+                .records_put(&mut self.lump_index.write(), lump_id, portion);
+        track!(result.map_err(|e| {
+            self.data_region.delete(portion);
+            e
+        }))?;
+        self.lump_index
+            .write()
+            .insert(*lump_id, Portion::Data(portion));
         Ok(())
     }
 
+    fn put_unaligned_lump_to_data_region(
+        &mut self,
+        lump_id: &LumpId,
+        data: &[u8],
+        sync_data: bool,
+    ) -> Result<()> {
+        if data.len() <= self.pack_threshold
+            && track!(self.put_lump_to_pack(lump_id, data, sync_data))?
+        {
+            return Ok(());
+        }
+        let mut aligned_data = DataRegionLumpData::new(data.len(), self.header.block_size);
+        aligned_data.as_bytes_mut().copy_from_slice(data);
+        track!(self.put_lump_to_dedicated_portion(lump_id, &aligned_data, sync_data))
+    }
+
+    fn put_lump_to_journal_region(&mut self, lump_id: &LumpId, data: &[u8]) -> Result<()> {
+        track!(self.seal_pack(false))?;
+        track!(self
+            .journal_region
+            .records_embed(&mut self.lump_index.write(), lump_id, data))
+    }
+
+    /// パック内にlumpを保存する.
+    ///
+    /// 保存先は書き込み中のパックで、ジャーナルへの記録は、そのパックがNVMに書き出される時点(`seal_pack`)まで延期される.
+    /// `sync_data`が`true`の場合には、その場で書き出し・同期・記録が行われる.
+    ///
+    /// パックを割り当てられなかった場合には`Ok(false)`が返される.
+    fn put_lump_to_pack(&mut self, lump_id: &LumpId, data: &[u8], sync_data: bool) -> Result<bool> {
+        if self.data_region.needs_seal(data.len()) {
+            track!(self.seal_pack(false))?;
+        }
+        let portion = match track!(self.data_region.put_packed(data))? {
+            None => return Ok(false),
+            Some(portion) => portion,
+        };
+        self.lump_index
+            .write()
+            .insert(*lump_id, Portion::Packed(portion));
+        self.pending_packed.push((*lump_id, portion));
+        if self.pending_packed_since.is_none() {
+            self.pending_packed_since = Some(Instant::now());
+        }
+        if sync_data {
+            track!(self.seal_pack(true))?;
+        }
+        Ok(true)
+    }
+
+    /// 書き込み中のパックをNVMに書き出し、そのメンバーのPUT操作をジャーナルに記録する.
+    ///
+    /// パックの書き出しは、そのメンバーがジャーナルに記録されるよりも前に行われるので、
+    /// ジャーナルが参照するパックが未書き込みの状態になることはない.
+    /// 書き出し後のパックに追記されることはない.
+    ///
+    /// ジャーナルの記録順序を保つために、ジャーナル領域を操作する前には必ずこのメソッドを呼び出す必要がある.
+    ///
+    /// `sync_data`が`true`の場合には、ジャーナルに記録する前に、データ領域を同期する.
+    fn seal_pack(&mut self, sync_data: bool) -> Result<()> {
+        track!(self.data_region.seal_pack())?;
+        if sync_data {
+            track!(self.data_region.sync_with(SyncLevel::DataSync))?;
+        }
+        if self.pending_packed.is_empty() {
+            return Ok(());
+        }
+        self.pending_packed_since = None;
+
+        // 既に削除ないし上書きされたものは記録しない
+        let members = {
+            let index = self.lump_index.read();
+            mem::take(&mut self.pending_packed)
+                .into_iter()
+                .filter(|(lump_id, portion)| index.get(lump_id) == Some(Portion::Packed(*portion)))
+                .collect::<Vec<_>>()
+        };
+        let result = self
+            .journal_region
+            .records_put_packed(&mut self.lump_index.write(), &members);
+        track!(result.inspect_err(|_| {
+            for (lump_id, portion) in &members {
+                self.lump_index.write().remove(lump_id);
+                self.data_region.delete_packed(*portion);
+            }
+        }))
+    }
+
+    /// 操作の結果が致命的なエラーであれば、このインスタンスに印を付ける.
+    ///
+    /// `ErrorKind::{StorageFull, InvalidInput}`は回復可能なエラーとして扱い、印は付けない.
+    fn poison_on_error<T>(&mut self, result: Result<T>) -> Result<T> {
+        if let Err(ref e) = result {
+            match *e.kind() {
+                ErrorKind::StorageFull | ErrorKind::InvalidInput => {}
+                _ => self.poisoned = true,
+            }
+        }
+        result
+    }
+
+    /// 書き込み中のパック内の、未記録のlumpをジャーナルに記録しなければならない期限を返す.
+    fn pending_packed_deadline(&self) -> Option<Instant> {
+        let delay = self.journal_region.sync_max_delay()?;
+        self.pending_packed_since.map(|t| t + delay)
+    }
+
     fn delete_if_exists(&mut self, lump_id: &LumpId, do_record: bool) -> Result<bool> {
-        if let Some(portion) = self.lump_index.remove(lump_id) {
+        let removed = self.lump_index.write().remove(lump_id);
+        if let Some(portion) = removed {
             self.metrics.delete_lumps.increment();
             if do_record {
+                track!(self.seal_pack(false))?;
                 track!(self
                     .journal_region
-                    .records_delete(&mut self.lump_index, lump_id,))?;
+                    .records_delete(&mut self.lump_index.write(), lump_id,))?;
             }
-            if let Portion::Data(portion) = portion {
-                self.data_region.delete(portion);
+            match portion {
+                Portion::Data(portion) => self.data_region.delete(portion),
+                Portion::Packed(portion) => self.data_region.delete_packed(portion),
+                Portion::Journal(_) => {}
             }
             Ok(true)
         } else {
@@ -389,6 +1118,21 @@ where
         }
     }
 }
+impl<N> Drop for Storage<N>
+where
+    N: NonVolatileMemory,
+{
+    fn drop(&mut self) {
+        // `close`を呼ばずに破棄された場合でも、書き込み中のパック内のlumpが失われないように、
+        // パックおよびそのレコードを書き出しておく(エラーは無視する)
+        //
+        // 未記録のlumpが存在しない場合や、既に致命的なエラーが発生している場合には、何も書き込まない
+        if !self.read_only && !self.poisoned && !self.pending_packed.is_empty() {
+            let _ = self.seal_pack(false);
+            let _ = self.journal_region.sync();
+        }
+    }
+}
 
 /// ストレージ使用量。
 #[derive(Debug, Clone)]
@@ -426,9 +1170,12 @@ impl Default for StorageUsage {
 #[cfg(test)]
 mod tests {
     use std::fs::OpenOptions;
+    use std::io::{Read, Seek, SeekFrom, Write};
     use std::mem;
+    use std::sync::{Arc, Mutex};
     use tempdir::TempDir;
     use trackable::result::TestResult;
+    use uuid::Uuid;
 
     use super::*;
     use crate::block::BlockSize;
@@ -526,6 +1273,1038 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn open_read_only_works() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut storage = track!(Storage::create(nvm.clone()))?;
+        assert!(!storage.is_read_only());
+        assert!(storage.put(&id("000"), &data("hello"))?);
+        assert!(storage.put(&id("111"), &zeroed_data(512))?);
+        mem::drop(storage);
+
+        // マイナーバージョンを減らしておく
+        let mut header = track!(Storage::open(nvm.clone()))?.header().clone();
+        header.minor_version -= 1;
+        {
+            let mut nvm = nvm.clone();
+            let mut buf = Vec::new();
+            track!(header.write_header_region_to(&mut buf))?;
+            track_io!(nvm.seek(SeekFrom::Start(0)))?;
+            track_io!(nvm.write_all(&buf))?;
+        }
+        let before = nvm.to_bytes();
+
+        let mut storage = track!(Storage::open_read_only(nvm.clone()))?;
+        assert!(storage.is_read_only());
+        assert_eq!(storage.header().minor_version, MINOR_VERSION - 1);
+        assert_eq!(storage.list(), vec![id("000"), id("111")]);
+        assert_eq!(storage.get(&id("000"))?, Some(data("hello")));
+        assert_eq!(storage.get(&id("111"))?, Some(zeroed_data(512)));
+
+        // 更新系の操作は全て拒否される
+        assert_eq!(
+            storage
+                .put(&id("222"), &data("world"))
+                .err()
+                .map(|e| *e.kind()),
+            Some(ErrorKind::ReadOnly)
+        );
+        assert_eq!(
+            storage.delete(&id("000")).err().map(|e| *e.kind()),
+            Some(ErrorKind::ReadOnly)
+        );
+        assert_eq!(
+            storage
+                .delete_range(id("000")..id("999"))
+                .err()
+                .map(|e| *e.kind()),
+            Some(ErrorKind::ReadOnly)
+        );
+        assert_eq!(
+            storage.journal_gc().err().map(|e| *e.kind()),
+            Some(ErrorKind::ReadOnly)
+        );
+        for _ in 0..10 {
+            track!(storage.run_side_job_once())?;
+        }
+        track!(storage.journal_sync())?;
+        assert_eq!(storage.list(), vec![id("000"), id("111")]);
+        mem::drop(storage);
+
+        // NVMには一切書き込みが行われていない
+        assert!(before == nvm.to_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn journal_recovery_mode_works() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut storage = track!(Storage::create(nvm.clone()))?;
+        assert!(storage.put(&id("000"), &data("foo"))?);
+        assert!(storage.put(&id("111"), &data("bar"))?);
+        assert!(storage.put(&id("222"), &data("baz"))?);
+        track!(storage.journal_sync())?;
+        let last_entry_start = track!(storage.journal_snapshot())?.entries[2]
+            .start
+            .as_u64();
+        mem::drop(storage);
+
+        // 最後のレコードを破損させる(torn write を模擬)
+        {
+            let journal_start = StorageHeader::calc_region_size(BlockSize::min())
+                + journal::JournalHeader::region_size(BlockSize::min()) as u64;
+            let offset = journal_start + last_entry_start + 8;
+            let block_start = BlockSize::min().floor_align(offset);
+            let mut nvm = nvm.clone();
+            let mut block = vec![0; BlockSize::min().as_u16() as usize];
+            track_io!(nvm.seek(SeekFrom::Start(block_start)))?;
+            track_io!(nvm.read_exact(&mut block))?;
+            block[(offset - block_start) as usize] ^= 0xFF;
+            track_io!(nvm.seek(SeekFrom::Start(block_start)))?;
+            track_io!(nvm.write_all(&block))?;
+        }
+
+        // デフォルト(strict)では開けない
+        assert_eq!(
+            Storage::open(nvm.clone()).err().map(|e| *e.kind()),
+            Some(ErrorKind::StorageCorrupted)
+        );
+
+        // 読み込み専用の場合には、切り詰めは行われるがNVMへの書き込みは行われない
+        let before = nvm.to_bytes();
+        let storage = track!(StorageBuilder::new()
+            .journal_recovery_mode(JournalRecoveryMode::TruncateTornTail)
+            .open_read_only(nvm.clone()))?;
+        assert_eq!(storage.list(), vec![id("000"), id("111")]);
+        assert_eq!(storage.metrics().journal_region().recovery_truncations(), 1);
+        mem::drop(storage);
+        assert!(before == nvm.to_bytes());
+
+        // 破損したレコード以降を切り詰めて開く
+        let mut storage = track!(StorageBuilder::new()
+            .journal_recovery_mode(JournalRecoveryMode::TruncateTornTail)
+            .open(nvm.clone()))?;
+        assert_eq!(storage.list(), vec![id("000"), id("111")]);
+        assert_eq!(storage.metrics().journal_region().recovery_truncations(), 1);
+
+        // 破棄されるのは、破損した埋め込みレコード("222" => "baz")の範囲
+        assert_eq!(
+            storage
+                .metrics()
+                .journal_region()
+                .recovery_discarded_bytes(),
+            4 + 1 + 16 + 2 + 3
+        );
+        assert_eq!(storage.get(&id("111"))?, Some(data("bar")));
+        mem::drop(storage);
+
+        // 切り詰め後は strict でも開ける
+        let mut storage = track!(Storage::open(nvm.clone()))?;
+        assert_eq!(storage.list(), vec![id("000"), id("111")]);
+        assert_eq!(storage.metrics().journal_region().recovery_truncations(), 0);
+        assert!(storage.put(&id("333"), &data("qux"))?);
+        track!(storage.journal_sync())?;
+        mem::drop(storage);
+
+        let storage = track!(Storage::open(nvm))?;
+        assert_eq!(storage.list(), vec![id("000"), id("111"), id("333")]);
+        Ok(())
+    }
+
+    #[test]
+    fn redundant_headers_works() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut storage = track!(StorageBuilder::new()
+            .redundant_headers(true)
+            .create(nvm.clone()))?;
+        let header = storage.header().clone();
+        assert!(header.redundant_headers);
+        assert_eq!(header.backup_region_size(), header.region_size());
+        assert!(header.storage_size() <= 1024 * 1024);
+
+        assert!(storage.put(&id("000"), &data("foo"))?);
+        track!(storage.journal_gc())?;
+        assert!(storage.put(&id("111"), &data("bar"))?);
+        track!(storage.journal_sync())?;
+        mem::drop(storage);
+        let healthy = nvm.to_bytes();
+
+        // 先頭のヘッダが壊れていても、バックアップを使って開ける(かつ修復される)
+        track!(flip_byte(&nvm, 10))?;
+        assert!(Storage::open_read_only(nvm.clone()).is_ok());
+        assert!(healthy != nvm.to_bytes());
+        let storage = track!(Storage::open(nvm.clone()))?;
+        assert_eq!(storage.header().instance_uuid, header.instance_uuid);
+        assert_eq!(storage.list(), vec![id("000"), id("111")]);
+        mem::drop(storage);
+        assert!(healthy == nvm.to_bytes());
+
+        // バックアップが壊れている場合にも、修復される
+        track!(flip_byte(&nvm, header.storage_size() - 10))?;
+        let broken = nvm.to_bytes();
+
+        // ただし、オープン時の検証に失敗した場合には、修復は行われない
+        assert_eq!(
+            StorageBuilder::new()
+                .instance_uuid(Uuid::new_v4())
+                .open(nvm.clone())
+                .err()
+                .map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+        assert!(broken == nvm.to_bytes());
+        let mut large_block_nvm = nvm.clone();
+        large_block_nvm.set_block_size(track!(BlockSize::new(BlockSize::MIN * 2))?);
+        assert_eq!(
+            Storage::open(large_block_nvm).err().map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+        assert!(broken == nvm.to_bytes());
+
+        mem::drop(track!(Storage::open(nvm.clone()))?);
+        assert!(healthy == nvm.to_bytes());
+
+        // ジャーナルヘッダの一方のスロットが壊れていても、もう一方を使って開ける
+        //
+        // 壊れたスロットは修復されるので、続けてもう一方のスロットが壊れても問題ない
+        for slot in 0..2 {
+            let offset = header.region_size() + slot * u64::from(BlockSize::MIN) + 4;
+            track!(flip_byte(&nvm, offset))?;
+            let storage = track!(Storage::open(nvm.clone()))?;
+            assert_eq!(storage.list(), vec![id("000"), id("111")]);
+        }
+
+        // 両方のヘッダが壊れている場合には、開けない
+        track!(flip_byte(&nvm, 10))?;
+        track!(flip_byte(&nvm, header.storage_size() - 10))?;
+        assert!(Storage::open(nvm.clone()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_works() -> TestResult {
+        let dir = track_io!(TempDir::new("cannyls_test"))?;
+        let path = dir.path().join("test.lusf");
+        let nvm = track!(FileNvm::create(&path, 1024 * 1024))?;
+        let mut storage = track!(StorageBuilder::new()
+            .redundant_headers(true)
+            .label("disk-001")
+            .metadata_property("rack", "r1")
+            .create(nvm))?;
+        let metadata = storage.header().metadata.clone();
+        assert_eq!(metadata.label, "disk-001");
+        assert!(metadata.created_at.is_some());
+        assert_eq!(metadata.library_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            metadata.properties.get("rack").map(|v| v.as_str()),
+            Some("r1")
+        );
+        assert_eq!(storage.metrics().metadata(), &metadata);
+
+        // その場で更新できる
+        track!(storage.set_label("disk-002"))?;
+        track!(storage.update_metadata(|m| {
+            m.properties.insert("slot".to_owned(), "3".to_owned());
+        }))?;
+        assert_eq!(storage.header().metadata.label, "disk-002");
+        assert_eq!(storage.metrics().metadata().label, "disk-002");
+        let h = track!(StorageHeader::read_from_file(&path))?;
+        assert_eq!(h.metadata, storage.header().metadata);
+
+        // サイズを超える場合には、何も変更されない
+        let label = "a".repeat(StorageMetadata::MAX_SIZE);
+        assert_eq!(
+            storage.set_label(&label).err().map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+        assert_eq!(storage.header().metadata.label, "disk-002");
+        assert!(StorageBuilder::new()
+            .label(&label)
+            .create(track!(FileNvm::create(
+                dir.path().join("x.lusf"),
+                1024 * 1024
+            ))?)
+            .is_err());
+        let expected = storage.header().metadata.clone();
+        mem::drop(storage);
+
+        // 再オープン後も保持されている
+        let nvm = track!(FileNvm::open(&path))?;
+        let mut storage = track!(Storage::open_read_only(nvm))?;
+        assert_eq!(storage.header().metadata, expected);
+        assert_eq!(
+            storage.set_label("foo").err().map(|e| *e.kind()),
+            Some(ErrorKind::ReadOnly)
+        );
+        mem::drop(storage);
+
+        // 先頭のヘッダが壊れていても、バックアップから読み込める
+        {
+            let mut file = track_io!(OpenOptions::new().write(true).open(&path))?;
+            track_io!(file.seek(SeekFrom::Start(10)))?;
+            track_io!(file.write_all(&[0xFF; 4]))?;
+        }
+        let h = track!(StorageHeader::read_from_file(&path))?;
+        assert_eq!(h.metadata, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn close_works() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut storage = track!(Storage::create(nvm.clone()))?;
+        assert!(!storage.last_shutdown_clean());
+        assert!(storage.put(&id("000"), &data("foo"))?);
+        track!(storage.close())?;
+
+        // 正常に閉じられた
+        let storage = track!(Storage::open_read_only(nvm.clone()))?;
+        assert!(storage.last_shutdown_clean());
+        mem::drop(storage);
+
+        let mut storage = track!(Storage::open(nvm.clone()))?;
+        assert!(storage.last_shutdown_clean());
+        assert!(storage.metrics().last_shutdown_clean());
+        assert_eq!(storage.list(), vec![id("000")]);
+        assert!(storage.put(&id("111"), &data("bar"))?);
+        track!(storage.journal_sync())?;
+        mem::drop(storage); // `close`を呼ばずに破棄 (i.e., クラッシュ)
+
+        // 正常に閉じられなかった
+        let storage = track!(Storage::open(nvm.clone()))?;
+        assert!(!storage.last_shutdown_clean());
+        assert!(!storage.metrics().last_shutdown_clean());
+        assert_eq!(storage.list(), vec![id("000"), id("111")]);
+        track!(storage.close())?;
+
+        // 冗長化されたヘッダでも同様
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let storage = track!(StorageBuilder::new()
+            .redundant_headers(true)
+            .create(nvm.clone()))?;
+        track!(storage.close())?;
+        let storage = track!(Storage::open(nvm.clone()))?;
+        assert!(storage.last_shutdown_clean());
+        mem::drop(storage);
+        let storage = track!(Storage::open(nvm))?;
+        assert!(!storage.last_shutdown_clean());
+        Ok(())
+    }
+
+    #[test]
+    fn lump_index_backend_works() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut storage = track!(StorageBuilder::new()
+            .journal_region_ratio(0.5)
+            .lump_index_backend(LumpIndexBackend::Compact)
+            .create(nvm.clone()))?;
+        for i in 0..300 {
+            assert!(storage.put(&LumpId::new(i), &data("foo"))?);
+        }
+        assert!(storage.delete(&LumpId::new(10))?);
+        assert_eq!(
+            storage
+                .delete_range(LumpId::new(100)..LumpId::new(200))?
+                .len(),
+            100
+        );
+        assert!(storage.metrics().index_memory_bytes() > 0);
+        track!(storage.journal_sync())?;
+        mem::drop(storage);
+
+        // バックエンドを変えて開き直しても、同じ内容となる
+        for &backend in &[LumpIndexBackend::BTree, LumpIndexBackend::Compact] {
+            let mut storage = track!(StorageBuilder::new()
+                .lump_index_backend(backend)
+                .open(nvm.clone()))?;
+            assert_eq!(storage.list().len(), 199);
+            assert_eq!(storage.get(&LumpId::new(10))?, None);
+            assert_eq!(storage.get(&LumpId::new(299))?, Some(data("foo")));
+            assert_eq!(
+                storage.list_range(LumpId::new(0)..LumpId::new(110)).len(),
+                99
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn open_progress_works() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut storage = track!(Storage::create(nvm.clone()))?;
+        assert!(storage.put(&id("000"), &data("foo"))?);
+        assert!(storage.put(&id("111"), &zeroed_data(512))?);
+        assert!(storage.delete(&id("000"))?);
+        track!(storage.journal_sync())?;
+        let snapshot = track!(storage.journal_snapshot())?;
+        mem::drop(storage);
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let storage = track!(StorageBuilder::new()
+            .open_progress_callback({
+                let reports = reports.clone();
+                move |p| reports.lock().unwrap().push(p.clone())
+            })
+            .open(nvm))?;
+
+        let reports = reports.lock().unwrap();
+        let phases = reports.iter().map(|p| p.phase).collect::<Vec<_>>();
+        assert_eq!(
+            phases,
+            vec![
+                OpenPhase::ReplayJournal,
+                OpenPhase::RebuildAllocator,
+                OpenPhase::Done
+            ]
+        );
+        let last = reports.last().unwrap();
+        assert_eq!(last.records_replayed, 3);
+        assert_eq!(
+            last.journal_bytes_replayed,
+            snapshot.tail - snapshot.unreleased_head
+        );
+        assert!(last.journal_bytes_replayed < last.journal_capacity);
+
+        let metrics = storage.metrics();
+        assert_eq!(metrics.open_replayed_records(), 3);
+        assert_eq!(metrics.open_replayed_bytes(), last.journal_bytes_replayed);
+        assert!(metrics.open_replay_duration_seconds() >= 0.0);
+        assert_eq!(metrics.put_lumps(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn auto_placement_works() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut storage = track!(StorageBuilder::new()
+            .embed_threshold(10)
+            .create(nvm.clone()))?;
+
+        // 閾値以下なら埋め込まれる
+        let data = track!(LumpData::new_auto(vec![1; 10]))?;
+        assert!(storage.put(&id("000"), &data)?);
+        assert_eq!(storage.get(&id("000"))?, Some(data));
+        assert_eq!(storage.metrics().get_journal_lumps(), 1);
+
+        // 閾値を超えたらデータ領域に保存される
+        let data = track!(LumpData::new_auto(vec![2; 11]))?;
+        assert!(storage.put(&id("111"), &data)?);
+        assert_eq!(storage.get(&id("111"))?, Some(data));
+        assert_eq!(storage.metrics().get_data_lumps(), 1);
+
+        let data = track!(storage.allocate_lump_data_auto(&[3; 512]))?;
+        assert!(storage.put(&id("222"), &data)?);
+        assert_eq!(storage.get(&id("222"))?, Some(data));
+        assert_eq!(storage.metrics().get_data_lumps(), 2);
+
+        assert_eq!(storage.metrics().auto_placements_journal(), 1);
+        assert_eq!(storage.metrics().auto_placements_data(), 2);
+        assert_eq!(storage.embed_threshold(), 10);
+
+        // 閾値が大きすぎる
+        mem::drop(storage);
+        assert_eq!(
+            StorageBuilder::new()
+                .embed_threshold(LumpData::MAX_EMBEDDED_SIZE + 1)
+                .open(nvm)
+                .err()
+                .map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_auto_placement_keeps_existing_lump() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 4 * 1024 * 1024]);
+        let mut storage = track!(StorageBuilder::new().create(nvm.clone()))?;
+        let data = track!(LumpData::new_auto(vec![1; 1000]))?;
+        assert!(storage.put(&id("000"), &data)?);
+        track!(storage.journal_sync())?;
+
+        // ストレージの上限を超えるデータは、既存のlumpを削除せずに拒否される
+        let oversized = track!(LumpData::new_auto(vec![2; LumpData::MAX_SIZE + 10]))?;
+        assert_eq!(
+            storage.put(&id("000"), &oversized).err().map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+        assert_eq!(storage.list(), vec![id("000")]);
+        assert_eq!(storage.get(&id("000"))?, Some(data.clone()));
+
+        let other = track!(LumpData::new_auto(vec![3; 1000]))?;
+        assert!(storage.put(&id("111"), &other)?);
+        track!(storage.journal_sync())?;
+        mem::drop(storage);
+
+        let mut storage = track!(Storage::open(nvm))?;
+        assert_eq!(storage.get(&id("000"))?, Some(data));
+        assert_eq!(storage.get(&id("111"))?, Some(other));
+        Ok(())
+    }
+
+    #[test]
+    fn packing_works() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut storage = track!(StorageBuilder::new()
+            .pack_threshold(100)
+            .create(nvm.clone()))?;
+
+        // 50バイトのlumpは56バイトのスロットに格納されるので、一つのパック(512バイト)には9個まで入る
+        for i in 0..18 {
+            let data = track!(LumpData::new(vec![i as u8; 50]))?;
+            assert!(storage.put(&LumpId::new(i), &data)?);
+        }
+        assert_eq!(
+            storage.head(&LumpId::new(0)).unwrap().approximate_data_size,
+            56
+        );
+        assert_eq!(storage.metrics().data_region().packs(), 2);
+        assert_eq!(storage.metrics().data_region().usage_bytes(), 1024);
+
+        // 閾値を超えるlumpには、専用のブロックが割り当てられる
+        assert!(storage.put(&id("aaa"), &zeroed_data(101))?);
+        assert_eq!(storage.head(&id("aaa")).unwrap().approximate_data_size, 512);
+        assert_eq!(storage.metrics().data_region().packs(), 2);
+
+        // 再オープン後もパック内のlumpを読み込める
+        track!(storage.journal_sync())?;
+        mem::drop(storage);
+        let mut storage = track!(StorageBuilder::new().pack_threshold(100).open(nvm.clone()))?;
+        assert_eq!(storage.metrics().data_region().packs(), 2);
+        for i in 0..18 {
+            let data = storage
+                .get(&LumpId::new(i))?
+                .map(|d| d.as_bytes().to_owned());
+            assert_eq!(data, Some(vec![i as u8; 50]));
+        }
+
+        // 全メンバーを削除するとパックが解放される
+        for i in 0..9 {
+            assert!(storage.delete(&LumpId::new(i))?);
+        }
+        assert_eq!(storage.metrics().data_region().packs(), 1);
+        assert_eq!(storage.metrics().data_region().reclaimed_packs(), 1);
+
+        // 使用率の低いパックは詰め直される
+        for i in 9..15 {
+            assert!(storage.delete(&LumpId::new(i))?);
+        }
+        assert!(storage.put(&LumpId::new(100), &track!(LumpData::new(vec![9; 8]))?)?);
+        assert_eq!(storage.metrics().data_region().packs(), 2);
+        assert_eq!(
+            storage.compact_packs(1.5).err().map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+        assert_eq!(storage.compact_packs(0.5)?, 1);
+        assert_eq!(storage.compact_packs(0.5)?, 0);
+        assert_eq!(storage.metrics().data_region().packs(), 1);
+        for i in 15..18 {
+            let data = storage
+                .get(&LumpId::new(i))?
+                .map(|d| d.as_bytes().to_owned());
+            assert_eq!(data, Some(vec![i as u8; 50]));
+        }
+
+        track!(storage.journal_sync())?;
+        mem::drop(storage);
+        let mut storage = track!(Storage::open(nvm.clone()))?;
+        assert_eq!(storage.metrics().data_region().packs(), 1);
+        assert_eq!(storage.list().len(), 5);
+        for i in 15..18 {
+            let data = storage
+                .get(&LumpId::new(i))?
+                .map(|d| d.as_bytes().to_owned());
+            assert_eq!(data, Some(vec![i as u8; 50]));
+        }
+
+        // 閾値が大きすぎる
+        mem::drop(storage);
+        assert_eq!(
+            StorageBuilder::new()
+                .pack_threshold(512)
+                .open(nvm)
+                .err()
+                .map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn packs_are_written_only_once() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut storage = track!(StorageBuilder::new()
+            .pack_threshold(100)
+            .create(nvm.clone()))?;
+        assert!(storage.header().packed_lumps);
+
+        // 書き込み中のパックはメモリ上にのみ存在し、NVMには書き出されない
+        let before = nvm.to_bytes();
+        assert!(storage.put(&LumpId::new(0), &track!(LumpData::new(vec![0; 50]))?)?);
+        assert!(before == nvm.to_bytes());
+        assert_eq!(
+            storage
+                .get(&LumpId::new(0))?
+                .map(|d| d.as_bytes().to_owned()),
+            Some(vec![0; 50])
+        );
+
+        // 同期時に書き出されたパックには、以後追記されない
+        track!(storage.journal_sync())?;
+        let pack0 = storage.lump_position(&LumpId::new(0));
+        assert!(storage.put(&LumpId::new(1), &track!(LumpData::new(vec![1; 50]))?)?);
+        assert_ne!(storage.lump_position(&LumpId::new(1)), pack0);
+        assert_eq!(storage.metrics().data_region().packs(), 2);
+
+        // 書き出し前のパック内のlumpは、ジャーナルにも記録されていない
+        let mut crashed = track!(StorageBuilder::new()
+            .pack_threshold(100)
+            .open(SharedMemoryNvm::new(nvm.to_bytes())))?;
+        assert_eq!(crashed.list(), vec![LumpId::new(0)]);
+        assert_eq!(
+            crashed
+                .get(&LumpId::new(0))?
+                .map(|d| d.as_bytes().to_owned()),
+            Some(vec![0; 50])
+        );
+
+        // 上書きされたlumpは、古い方の位置では記録されない
+        assert!(!storage.put(&LumpId::new(1), &track!(LumpData::new(vec![2; 50]))?)?);
+        track!(storage.journal_sync())?;
+        mem::drop(storage);
+        let mut storage = track!(Storage::open(nvm.clone()))?;
+        assert_eq!(
+            storage
+                .get(&LumpId::new(1))?
+                .map(|d| d.as_bytes().to_owned()),
+            Some(vec![2; 50])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn packed_lumps_survive_drop_without_close() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut storage = track!(StorageBuilder::new()
+            .pack_threshold(100)
+            .create(nvm.clone()))?;
+        assert!(storage.put(&LumpId::new(0), &track!(LumpData::new(vec![0; 50]))?)?);
+        assert!(storage.put(
+            &LumpId::new(1),
+            &track!(LumpData::new_embedded(vec![1; 10]))?
+        )?);
+        assert!(storage.put(&LumpId::new(2), &track!(LumpData::new(vec![2; 50]))?)?);
+
+        // `close`を呼ばずに破棄しても、書き込み中のパック内のlumpは失われない
+        mem::drop(storage);
+        let mut storage = track!(Storage::open(nvm.clone()))?;
+        assert!(!storage.last_shutdown_clean());
+        assert_eq!(
+            storage.list(),
+            vec![LumpId::new(0), LumpId::new(1), LumpId::new(2)]
+        );
+        assert_eq!(
+            storage
+                .get(&LumpId::new(2))?
+                .map(|d| d.as_bytes().to_owned()),
+            Some(vec![2; 50])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn poisoned_storage_does_not_seal_pack_on_drop() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut storage = track!(StorageBuilder::new()
+            .pack_threshold(100)
+            .create(nvm.clone()))?;
+        assert!(storage.put(&LumpId::new(0), &track!(LumpData::new(vec![0; 50]))?)?);
+
+        // 致命的なエラーが発生した後は、書き込み中のパックは書き出されない
+        storage.poisoned = true;
+        mem::drop(storage);
+        let storage = track!(Storage::open(nvm))?;
+        assert!(storage.list().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn packed_lumps_flag_works() -> TestResult {
+        // パッキングを有効にしてオープンした時点で、フラグが記録される
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let storage = track!(Storage::create(nvm.clone()))?;
+        assert!(!storage.header().packed_lumps);
+        mem::drop(storage);
+
+        let mut storage = track!(StorageBuilder::new().pack_threshold(100).open(nvm.clone()))?;
+        assert!(storage.header().packed_lumps);
+        assert!(storage.put(&LumpId::new(0), &track!(LumpData::new(vec![0; 50]))?)?);
+        track!(storage.journal_sync())?;
+        let mut header = storage.header().clone();
+        mem::drop(storage);
+
+        // フラグが無いストレージにパック用のレコードが含まれている場合にはエラーとなる
+        header.packed_lumps = false;
+        let mut broken = nvm.clone();
+        track!(header.write_primary_region(&mut broken))?;
+        assert_eq!(
+            Storage::open(nvm).err().map(|e| *e.kind()),
+            Some(ErrorKind::StorageCorrupted)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejected_open_does_not_write_packed_lumps_flag() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let storage = track!(Storage::create(nvm.clone()))?;
+        mem::drop(storage);
+        let before = nvm.to_bytes();
+
+        // パックの閾値が大きすぎる
+        assert_eq!(
+            StorageBuilder::new()
+                .pack_threshold(1000)
+                .open(nvm.clone())
+                .err()
+                .map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+        assert!(before == nvm.to_bytes());
+
+        // UUIDが一致しない
+        assert_eq!(
+            StorageBuilder::new()
+                .pack_threshold(100)
+                .instance_uuid(Uuid::new_v4())
+                .open(nvm.clone())
+                .err()
+                .map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+        assert!(before == nvm.to_bytes());
+
+        let storage = track!(Storage::open(nvm))?;
+        assert!(!storage.header().packed_lumps);
+        Ok(())
+    }
+
+    #[test]
+    fn durability_works() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut storage = track!(Storage::create(nvm.clone()))?;
+
+        // `Buffered`の場合には、ジャーナルバッファに留まる
+        let before = nvm.to_bytes();
+        assert!(storage.put_with_durability(&id("000"), &data("foo"), Durability::Buffered)?);
+        assert!(before == nvm.to_bytes());
+
+        // `JournalFlushed`以上の場合には、NVMに書き出される
+        assert!(storage.put_with_durability(
+            &id("111"),
+            &data("bar"),
+            Durability::JournalFlushed
+        )?);
+        assert!(before != nvm.to_bytes());
+
+        let before = nvm.to_bytes();
+        let lump = track!(storage.allocate_lump_data_with_bytes(&[1; 1000]))?;
+        assert!(storage.put_with_durability(
+            &id("222"),
+            &lump,
+            Durability::DataAndJournalSynced
+        )?);
+        assert!(before != nvm.to_bytes());
+
+        let counter = storage.metrics().durability_syncs();
+        assert_eq!(counter.get(Durability::Buffered), 0);
+        assert_eq!(counter.get(Durability::JournalFlushed), 1);
+        assert_eq!(counter.get(Durability::JournalSynced), 0);
+        assert_eq!(counter.get(Durability::DataAndJournalSynced), 1);
+
+        // 同期済みのlumpは、(クローズせずに)再オープンした後でも読み込める
+        let nvm = SharedMemoryNvm::new(nvm.to_bytes());
+        let mut storage = track!(Storage::open(nvm))?;
+        assert_eq!(storage.list(), vec![id("000"), id("111"), id("222")]);
+        assert_eq!(
+            storage.get(&id("222"))?.map(|d| d.as_bytes().to_owned()),
+            Some(vec![1; 1000])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn wide_format_works() -> TestResult {
+        // v1の上限を超えるlumpはワイドフォーマットでのみ保存可能
+        let nvm = SharedMemoryNvm::new(vec![0; 48 * 1024 * 1024]);
+        let mut storage = track!(StorageBuilder::new().create(nvm.clone()))?;
+        assert!(!storage.header().is_wide_format());
+        assert_eq!(storage.max_lump_size(), LumpData::MAX_SIZE);
+        assert!(storage.allocate_lump_data(LumpData::MAX_SIZE + 1).is_err());
+        assert!(storage.put(&id("000"), &zeroed_data(1000))?);
+        track!(storage.journal_sync())?;
+        mem::drop(storage);
+
+        // v1からの移行
+        let header = track!(StorageBuilder::new().migrate_to_wide_format(nvm.clone()))?;
+        assert!(header.is_wide_format());
+        let mut storage = track!(Storage::open(nvm.clone()))?;
+        assert!(storage.header().is_wide_format());
+        assert_eq!(storage.max_lump_size(), LumpData::MAX_WIDE_SIZE);
+        assert_eq!(
+            storage.get(&id("000"))?.map(|d| d.as_bytes().len()),
+            Some(1000)
+        );
+
+        let size = LumpData::MAX_SIZE + 1000;
+        let mut data = track!(storage.allocate_lump_data(size))?;
+        data.as_bytes_mut()[size - 1] = 7;
+        assert!(storage.put(&id("111"), &data)?);
+        track!(storage.journal_sync())?;
+        mem::drop(storage);
+
+        let mut storage = track!(Storage::open(nvm.clone()))?;
+        let data = storage.get(&id("111"))?.expect("exists");
+        assert_eq!(data.as_bytes().len(), size);
+        assert_eq!(data.as_bytes()[size - 1], 7);
+
+        // 保存先の選択をストレージに委ねる場合も、同じ上限が適用される
+        assert!(storage.delete(&id("111"))?);
+        let data = track!(storage.allocate_lump_data_auto(&vec![8; size]))?;
+        assert!(storage.put(&id("222"), &data)?);
+        assert!(storage.delete(&id("222"))?);
+        let data = track!(LumpData::new_auto(vec![9; size]))?;
+        assert!(storage.put(&id("333"), &data)?);
+        assert!(storage
+            .allocate_lump_data_auto(&vec![0; LumpData::MAX_WIDE_SIZE + 1])
+            .is_err());
+        mem::drop(storage);
+
+        // 移行済みのストレージに対しては何もしない
+        let header = track!(StorageBuilder::new().migrate_to_wide_format(nvm))?;
+        assert!(header.is_wide_format());
+
+        // ワイドフォーマットでは、u16に収まらないブロックサイズも使用可能
+        let block_size = track!(BlockSize::from_u32(128 * 1024))?;
+        assert_eq!(
+            StorageBuilder::new()
+                .block_size(block_size)
+                .journal_region_ratio(0.2)
+                .create(SharedMemoryNvm::new(vec![0; 4 * 1024 * 1024]))
+                .err()
+                .map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+
+        let nvm = SharedMemoryNvm::new(vec![0; 4 * 1024 * 1024]);
+        let mut storage = track!(StorageBuilder::new()
+            .wide_format(true)
+            .block_size(block_size)
+            .journal_region_ratio(0.2)
+            .create(nvm.clone()))?;
+        assert!(storage.header().is_wide_format());
+        let data = track!(storage.allocate_lump_data_with_bytes(&[1; 1000]))?;
+        assert!(storage.put(&id("222"), &data)?);
+        track!(storage.journal_sync())?;
+        mem::drop(storage);
+
+        let mut storage = track!(Storage::open(nvm))?;
+        assert_eq!(storage.header().block_size, block_size);
+        assert_eq!(
+            storage.get(&id("222"))?.map(|d| d.as_bytes().to_owned()),
+            Some(vec![1; 1000])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn replication_works() -> TestResult {
+        let dir = track_io!(TempDir::new("cannyls_test"))?;
+        let capacity = BlockSize::min().ceil_align(4 * 1024 * 1024);
+        let nvm = track!(FileNvm::create(dir.path().join("primary.lusf"), capacity))?;
+        let mut primary = track!(Storage::create(nvm))?;
+        let nvm = track!(FileNvm::create(dir.path().join("replica.lusf"), capacity))?;
+        let mut replica = track!(Storage::create(nvm))?;
+
+        assert_eq!(primary.committed_lsn(), 0);
+        assert_eq!(primary.oldest_lsn(), 0);
+
+        let large = track!(primary.allocate_lump_data_with_bytes(&[7; 5000]))?;
+        assert!(primary.put(&id("000"), &data("foo"))?);
+        assert!(primary.put(&id("111"), &large)?);
+        assert!(primary.put(&id("222"), &data("bar"))?);
+        assert!(primary.put(&id("333"), &data("baz"))?);
+        assert!(primary.delete(&id("000"))?);
+
+        // 同期前の操作は取得できない
+        assert!(primary.mutations_since(0, 100)?.is_empty());
+        track!(primary.journal_sync())?;
+
+        let mutations = track!(primary.mutations_since(0, 100))?;
+        assert_eq!(mutations.len(), 4); // 削除済みの"000"のPUTは除外される
+        for w in mutations.windows(2) {
+            assert!(w[0].lsn < w[1].lsn);
+            assert_eq!(w[0].next_lsn, w[1].lsn);
+        }
+        assert_eq!(mutations[3].next_lsn, primary.committed_lsn());
+        assert_eq!(
+            mutations[0].kind,
+            MutationKind::Put {
+                lump_id: id("111"),
+                data: vec![7; 5000],
+                embedded: false
+            }
+        );
+        assert_eq!(mutations[3].kind, MutationKind::Delete(id("000")));
+
+        // 件数の上限と、途中からの取得
+        let first = track!(primary.mutations_since(0, 1))?;
+        assert_eq!(first.len(), 1);
+        let rest = track!(primary.mutations_since(first[0].next_lsn, 100))?;
+        assert_eq!(rest[..], mutations[1..]);
+
+        // 一件ずつ追跡した場合も、結果は変わらない
+        let mut tailed = Vec::new();
+        let mut since = 0;
+        loop {
+            let m = track!(primary.mutations_since(since, 1))?;
+            if m.is_empty() {
+                break;
+            }
+            since = m[0].next_lsn;
+            tailed.extend(m);
+        }
+        assert_eq!(tailed, mutations);
+
+        for m in &mutations {
+            track!(replica.apply_mutation(m))?;
+        }
+        let mut since = primary.committed_lsn();
+
+        assert!(primary.delete_range(range("200", "300"))?.len() == 1);
+        assert!(primary.put(&id("444"), &data("qux"))?);
+        track!(primary.journal_sync())?;
+        for m in track!(primary.mutations_since(since, 100))? {
+            since = m.next_lsn;
+            track!(replica.apply_mutation(&m))?;
+        }
+        assert_eq!(since, primary.committed_lsn());
+
+        assert_eq!(replica.list(), primary.list());
+        for lump_id in primary.list() {
+            assert_eq!(replica.get(&lump_id)?, primary.get(&lump_id)?);
+        }
+
+        // LSNは再オープン後も引き継がれる
+        let committed_lsn = primary.committed_lsn();
+        track!(primary.close())?;
+        let nvm = track!(FileNvm::open(dir.path().join("primary.lusf")))?;
+        let mut primary = track!(Storage::open(nvm))?;
+        assert!(primary.committed_lsn() >= committed_lsn);
+        assert!(primary.put(&id("555"), &data("quux"))?);
+        track!(primary.journal_sync())?;
+        let mutations = track!(primary.mutations_since(since, 100))?;
+        assert_eq!(mutations.len(), 1);
+        assert!(mutations[0].lsn >= committed_lsn);
+
+        // GCで破棄された範囲は指定できない
+        for _ in 0..100 {
+            track!(primary.journal_gc())?;
+        }
+        assert!(primary.oldest_lsn() > 0);
+        assert_eq!(
+            primary.mutations_since(0, 100).err().map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn resize_journal_region_works() -> TestResult {
+        let dir = track_io!(TempDir::new("cannyls_test"))?;
+        let path = dir.path().join("test.lusf");
+        let nvm = track!(FileNvm::create(
+            &path,
+            BlockSize::min().ceil_align(4 * 1024 * 1024)
+        ))?;
+        let mut storage = track!(StorageBuilder::new().pack_threshold(100).create(nvm))?;
+        let mut expected = Vec::new();
+        for i in 0..10 {
+            let packed = track!(LumpData::new(vec![i as u8; 50]))?;
+            let large = track!(storage.allocate_lump_data_with_bytes(&[i as u8; 1000]))?;
+            let embedded = track!(LumpData::new_embedded(vec![i as u8; 10]))?;
+            for (lump_id, data) in vec![(i, packed), (100 + i, large), (200 + i, embedded)] {
+                assert!(storage.put(&LumpId::new(lump_id), &data)?);
+                expected.push((LumpId::new(lump_id), data.as_bytes().to_owned()));
+            }
+        }
+        for i in &[0, 100, 200] {
+            assert!(storage.delete(&LumpId::new(*i))?);
+        }
+        expected.retain(|(id, _)| ![0, 100, 200].contains(&id.as_u128()));
+        expected.sort();
+        let committed_lsn = storage.committed_lsn();
+        let old_header = storage.header().clone();
+        track!(storage.close())?;
+
+        let verify = |journal_region_size: u64| -> Result<()> {
+            let nvm = track!(FileNvm::open(&path))?;
+            let mut storage = track!(Storage::open(nvm))?;
+            assert_eq!(storage.header().journal_region_size, journal_region_size);
+            assert_eq!(storage.header().storage_size(), old_header.storage_size());
+            assert!(storage.oldest_lsn() >= committed_lsn);
+            let mut actual = Vec::new();
+            for lump_id in storage.list() {
+                let data = track_assert_some!(storage.get(&lump_id)?, ErrorKind::Other);
+                actual.push((lump_id, data.as_bytes().to_owned()));
+            }
+            assert_eq!(actual, expected);
+            Ok(())
+        };
+
+        // 拡張: データ領域の先頭にあるlumpは移動される
+        let nvm = track!(FileNvm::open(&path))?;
+        let header = track!(StorageBuilder::new().resize_journal_region(nvm, 1024 * 1024))?;
+        assert_eq!(header.journal_region_size, 1024 * 1024);
+        track!(verify(1024 * 1024))?;
+
+        // 縮小
+        let nvm = track!(FileNvm::open(&path))?;
+        let header = track!(StorageBuilder::new().resize_journal_region(nvm, 16 * 1024))?;
+        assert_eq!(header.journal_region_size, 16 * 1024);
+        track!(verify(16 * 1024))?;
+
+        // 生存中のレコード群が収まらない場合や、領域全体を超える場合にはエラー
+        for size in &[1024, 4 * 1024 * 1024] {
+            let nvm = track!(FileNvm::open(&path))?;
+            assert_eq!(
+                StorageBuilder::new()
+                    .resize_journal_region(nvm, *size)
+                    .err()
+                    .map(|e| *e.kind()),
+                Some(ErrorKind::InvalidInput)
+            );
+        }
+        track!(verify(16 * 1024))?;
+        Ok(())
+    }
+
+    fn range(start: &str, end: &str) -> Range<LumpId> {
+        id(start)..id(end)
+    }
+
+    fn flip_byte(nvm: &SharedMemoryNvm, offset: u64) -> Result<()> {
+        let block_start = BlockSize::min().floor_align(offset);
+        let mut nvm = nvm.clone();
+        let mut block = vec![0; BlockSize::min().as_u16() as usize];
+        track_io!(nvm.seek(SeekFrom::Start(block_start)))?;
+        track_io!(nvm.read_exact(&mut block))?;
+        block[(offset - block_start) as usize] ^= 0xFF;
+        track_io!(nvm.seek(SeekFrom::Start(block_start)))?;
+        track_io!(nvm.write_all(&block))?;
+        Ok(())
+    }
+
     fn id(id: &str) -> LumpId {
         id.parse().unwrap()
     }
@@ -535,7 +2314,8 @@ mod tests {
     }
 
     fn zeroed_data(size: usize) -> LumpData {
-        let mut data = LumpData::aligned_allocate(size, BlockSize::min()).unwrap();
+        let mut data =
+            LumpData::aligned_allocate(size, BlockSize::min(), LumpData::MAX_SIZE).unwrap();
         for v in data.as_bytes_mut() {
             *v = 0;
         }
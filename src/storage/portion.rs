@@ -10,14 +10,23 @@ pub struct DataPortion {
     pub start: Address,
 
     /// 部分領域の長さ（ブロック単位）
-    pub len: u16,
+    ///
+    /// v1フォーマットのストレージでは`DataPortion::MAX_LEN_V1`が、
+    /// ワイドフォーマット(v2)のストレージでは`DataPortion::MAX_LEN`が上限となる.
+    pub len: u32,
 }
 impl DataPortion {
+    /// 部分領域の長さの最大値（ブロック単位）.
+    pub const MAX_LEN: u32 = 0x3_FFFF;
+
+    /// v1フォーマットのストレージにおける、部分領域の長さの最大値（ブロック単位）.
+    pub const MAX_LEN_V1: u32 = 0xFFFF;
+
     /// 部分領域の終端位置を返す.  
     /// **注意**: DataPortionは [start, end) の領域を用いるため、
     /// end部には書き込みは行われていない。
     pub fn end(&self) -> Address {
-        self.start + Address::from(u32::from(self.len))
+        self.start + Address::from(self.len)
     }
 }
 
@@ -31,6 +40,39 @@ pub struct JournalPortion {
     pub len: u16,
 }
 
+/// データ領域内のパック(複数の小さなlumpを詰め込んだブロック)の中の部分領域を示すための構造体.
+///
+/// 各lumpは、パック内で`PackedPortion::ALIGNMENT`バイト境界に揃えられた"スロット"に格納される.
+/// スロットの末尾一バイトには、スロット内のパディングの長さが格納されている.
+///
+/// パックの位置は、`Address::MAX_V1`以下である必要がある.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PackedPortion {
+    /// パックの位置（ブロック単位）
+    pub pack: Address,
+
+    /// パック内でのスロットの開始位置（バイト単位）
+    pub offset: u16,
+
+    /// スロットの長さ（バイト単位）
+    pub len: u16,
+}
+impl PackedPortion {
+    /// スロットの開始位置および長さのアライメント（バイト単位）.
+    pub const ALIGNMENT: u16 = 8;
+
+    /// スロットの長さの最大値（バイト単位）.
+    pub const MAX_LEN: u16 = 511 * Self::ALIGNMENT;
+
+    /// パックが占有する部分領域を返す.
+    pub fn pack_portion(&self) -> DataPortion {
+        DataPortion {
+            start: self.pack,
+            len: 1,
+        }
+    }
+}
+
 /// 部分領域.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Portion {
@@ -39,13 +81,17 @@ pub enum Portion {
 
     /// データ領域内の部分領域.
     Data(DataPortion),
+
+    /// データ領域のパック内の部分領域.
+    Packed(PackedPortion),
 }
 impl Portion {
     /// 部分領域の長さをバイト単位で返す.
     pub fn len(&self, block_size: BlockSize) -> u32 {
         match *self {
             Portion::Journal(ref p) => u32::from(p.len),
-            Portion::Data(ref p) => u32::from(p.len) * u32::from(block_size.as_u16()),
+            Portion::Data(ref p) => p.len * block_size.as_u32(),
+            Portion::Packed(ref p) => u32::from(p.len),
         }
     }
 }
@@ -55,26 +101,73 @@ impl Portion {
 /// `LumpIndex`のような、数百万～数千万オーダーの部分領域を保持する
 /// データ構造では、各要素のメモリ使用量を節約することが
 /// 重要となるので、そのような目的でこの構造体が提供されている.
+///
+/// 上位2bitが部分領域の種類を示し、残りの62bitの使い方は種類によって異なる:
+///
+/// - `0b00`(ジャーナル領域): 開始位置(44bit) + 長さ(16bit)
+/// - `0b10`(データ領域): 開始位置(44bit) + 長さ(18bit)
+/// - `0b11`(パック): パックの位置(40bit) + スロットの開始位置(13bit) + スロットの長さ(9bit)
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct PortionU64(u64);
+impl PortionU64 {
+    /// 内部表現の値をそのまま返す.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// `as_u64`で得られた内部表現の値から`PortionU64`を復元する.
+    pub fn from_u64(n: u64) -> Self {
+        PortionU64(n)
+    }
+}
 impl From<Portion> for PortionU64 {
     fn from(f: Portion) -> Self {
-        let (kind, offset, len) = match f {
-            Portion::Journal(p) => (0, p.start.as_u64(), u64::from(p.len)),
-            Portion::Data(p) => (1, p.start.as_u64(), u64::from(p.len)),
+        let (kind, value) = match f {
+            Portion::Journal(p) => (0b00, p.start.as_u64() | (u64::from(p.len) << 44)),
+            Portion::Data(p) => {
+                debug_assert!(p.len <= DataPortion::MAX_LEN);
+                (0b10, p.start.as_u64() | (u64::from(p.len) << 44))
+            }
+            Portion::Packed(p) => {
+                debug_assert!(p.pack.as_u64() <= Address::MAX_V1);
+                debug_assert_eq!(p.offset % PackedPortion::ALIGNMENT, 0);
+                debug_assert_eq!(p.len % PackedPortion::ALIGNMENT, 0);
+                debug_assert!(p.len <= PackedPortion::MAX_LEN);
+                let slot_offset = u64::from(p.offset / PackedPortion::ALIGNMENT);
+                let slot_len = u64::from(p.len / PackedPortion::ALIGNMENT);
+                (
+                    0b11,
+                    p.pack.as_u64() | (slot_offset << 40) | (slot_len << 53),
+                )
+            }
         };
-        PortionU64(offset | (len << 40) | (kind << 63))
+        PortionU64(value | (kind << 62))
     }
 }
 impl From<PortionU64> for Portion {
     fn from(f: PortionU64) -> Self {
-        let is_journal = (f.0 >> 63) == 0;
-        let len = (f.0 >> 40) as u16;
-        let start = Address::from_u64(f.0 & Address::MAX).unwrap();
-        if is_journal {
-            Portion::Journal(JournalPortion { start, len })
-        } else {
-            Portion::Data(DataPortion { start, len })
+        let kind = f.0 >> 62;
+        match kind {
+            0b11 => {
+                let pack = Address::from_u64(f.0 & Address::MAX_V1).unwrap();
+                let slot_offset = ((f.0 >> 40) & 0x1FFF) as u16;
+                let slot_len = ((f.0 >> 53) & 0x1FF) as u16;
+                Portion::Packed(PackedPortion {
+                    pack,
+                    offset: slot_offset * PackedPortion::ALIGNMENT,
+                    len: slot_len * PackedPortion::ALIGNMENT,
+                })
+            }
+            0b10 => {
+                let start = Address::from_u64(f.0 & Address::MAX).unwrap();
+                let len = ((f.0 >> 44) & u64::from(DataPortion::MAX_LEN)) as u32;
+                Portion::Data(DataPortion { start, len })
+            }
+            _ => {
+                let start = Address::from_u64(f.0 & Address::MAX).unwrap();
+                let len = (f.0 >> 44) as u16;
+                Portion::Journal(JournalPortion { start, len })
+            }
         }
     }
 }
@@ -99,6 +192,12 @@ mod tests {
         let p2 = Portion::from(p1);
         assert_eq!(p0, p2);
 
+        let p0 = Portion::Data(DataPortion {
+            start: Address::from_u64(Address::MAX).unwrap(),
+            len: DataPortion::MAX_LEN,
+        });
+        assert_eq!(Portion::from(PortionU64::from(p0)), p0);
+
         // JournalPortion
         let p0 = Portion::Journal(JournalPortion {
             start: Address::from(10),
@@ -109,5 +208,17 @@ mod tests {
 
         let p2 = Portion::from(p1);
         assert_eq!(p0, p2);
+
+        // PackedPortion
+        let p0 = Portion::Packed(PackedPortion {
+            pack: Address::from_u64(Address::MAX_V1).unwrap(),
+            offset: 65_016,
+            len: PackedPortion::MAX_LEN,
+        });
+        let p1 = PortionU64::from(p0);
+        assert_eq!(mem::size_of_val(&p1), 8);
+
+        let p2 = Portion::from(p1);
+        assert_eq!(p0, p2);
     }
 }
@@ -0,0 +1,177 @@
+//! ストレージのオープン処理の進捗報告.
+use slog::Logger;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::metrics::StorageMetrics;
+use crate::storage::JournalRecord;
+
+/// 進捗の報告間隔.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 経過時間の確認を行うレコード数の間隔.
+///
+/// レコード毎に時刻を取得するのは無駄が多いので、ある程度間引いている.
+const CHECK_INTERVAL_RECORDS: u64 = 1024;
+
+/// ストレージのオープン処理のフェーズ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenPhase {
+    /// ジャーナルを再生して、インデックスを再構築している.
+    ReplayJournal,
+
+    /// インデックスの情報を元に、データ領域のアロケータを再構築している.
+    RebuildAllocator,
+
+    /// オープン処理が完了した.
+    Done,
+}
+
+/// ストレージのオープン処理の進捗.
+///
+/// `StorageBuilder::open_progress_callback`で登録したコールバックに渡される.
+#[derive(Debug, Clone)]
+pub struct OpenProgress {
+    /// 現在のフェーズ.
+    pub phase: OpenPhase,
+
+    /// これまでに再生したジャーナルのバイト数.
+    pub journal_bytes_replayed: u64,
+
+    /// ジャーナル領域(リングバッファ)の容量.
+    ///
+    /// 再生すべきバイト数の上限であり、進捗率の目安に使える.
+    pub journal_capacity: u64,
+
+    /// これまでに再生したジャーナルのレコード数.
+    pub records_replayed: u64,
+
+    /// オープン処理の開始からの経過時間.
+    pub elapsed: Duration,
+}
+
+/// オープン処理の進捗を受け取るコールバック.
+#[derive(Clone)]
+pub(crate) struct OpenProgressCallback(pub Arc<dyn Fn(&OpenProgress) + Send + Sync>);
+impl fmt::Debug for OpenProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OpenProgressCallback(_)")
+    }
+}
+
+/// オープン処理の進捗を、ロガーおよびコールバックに報告するためのオブジェクト.
+#[derive(Debug)]
+pub(crate) struct OpenProgressReporter {
+    logger: Logger,
+    callback: Option<OpenProgressCallback>,
+    progress: OpenProgress,
+    started_at: Instant,
+    replay_started_at: Instant,
+    replay_duration: Duration,
+    last_reported_at: Instant,
+    puts: u64,
+    embeds: u64,
+    deletes: u64,
+    delete_ranges: u64,
+}
+impl OpenProgressReporter {
+    pub fn new(logger: Logger, callback: Option<OpenProgressCallback>) -> Self {
+        let now = Instant::now();
+        OpenProgressReporter {
+            logger,
+            callback,
+            progress: OpenProgress {
+                phase: OpenPhase::ReplayJournal,
+                journal_bytes_replayed: 0,
+                journal_capacity: 0,
+                records_replayed: 0,
+                elapsed: Duration::from_secs(0),
+            },
+            started_at: now,
+            replay_started_at: now,
+            replay_duration: Duration::from_secs(0),
+            last_reported_at: now,
+            puts: 0,
+            embeds: 0,
+            deletes: 0,
+            delete_ranges: 0,
+        }
+    }
+
+    /// ジャーナルの再生を開始する.
+    pub fn start_replay(&mut self, journal_capacity: u64) {
+        self.progress.journal_capacity = journal_capacity;
+        self.replay_started_at = Instant::now();
+        self.enter_phase(OpenPhase::ReplayJournal);
+    }
+
+    /// ジャーナルのレコードを一つ再生した.
+    ///
+    /// `journal_bytes_replayed`は、リングバッファの始端からレコードの終端までのバイト数.
+    pub fn record_replayed<B>(&mut self, record: &JournalRecord<B>, journal_bytes_replayed: u64) {
+        match *record {
+            JournalRecord::Put(..) | JournalRecord::PutPacked(..) => self.puts += 1,
+            JournalRecord::Embed(..) => self.embeds += 1,
+            JournalRecord::Delete(..) => self.deletes += 1,
+            JournalRecord::DeleteRange(..) => self.delete_ranges += 1,
+            JournalRecord::EndOfRecords | JournalRecord::GoToFront => {}
+        }
+        self.progress.records_replayed += 1;
+        self.progress.journal_bytes_replayed = journal_bytes_replayed;
+        if self
+            .progress
+            .records_replayed
+            .is_multiple_of(CHECK_INTERVAL_RECORDS)
+            && self.last_reported_at.elapsed() >= REPORT_INTERVAL
+        {
+            self.report();
+        }
+    }
+
+    /// アロケータの再構築を開始する.
+    pub fn start_rebuild_allocator(&mut self) {
+        self.replay_duration = self.replay_started_at.elapsed();
+        self.enter_phase(OpenPhase::RebuildAllocator);
+    }
+
+    /// オープン処理が完了した.
+    ///
+    /// ジャーナルの再生結果は`metrics`に反映される.
+    pub fn finish(&mut self, metrics: &StorageMetrics) {
+        metrics
+            .open_replay_duration_seconds
+            .set(self.replay_duration.as_secs_f64());
+        metrics
+            .open_replayed_bytes
+            .set(self.progress.journal_bytes_replayed as f64);
+        metrics.open_replayed_puts.add_u64(self.puts);
+        metrics.open_replayed_embeds.add_u64(self.embeds);
+        metrics.open_replayed_deletes.add_u64(self.deletes);
+        metrics
+            .open_replayed_delete_ranges
+            .add_u64(self.delete_ranges);
+        self.enter_phase(OpenPhase::Done);
+    }
+
+    fn enter_phase(&mut self, phase: OpenPhase) {
+        self.progress.phase = phase;
+        self.report();
+    }
+
+    fn report(&mut self) {
+        self.last_reported_at = Instant::now();
+        self.progress.elapsed = self.started_at.elapsed();
+        info!(
+            self.logger,
+            "Opening storage: phase={:?}", self.progress.phase;
+            "journal_bytes_replayed" => self.progress.journal_bytes_replayed,
+            "journal_capacity" => self.progress.journal_capacity,
+            "records_replayed" => self.progress.records_replayed,
+            "elapsed" => self.progress.elapsed.as_secs_f64()
+        );
+        if let Some(ref callback) = self.callback {
+            (callback.0)(&self.progress);
+        }
+    }
+}
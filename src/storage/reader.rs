@@ -0,0 +1,77 @@
+use super::data_region::DataRegionReader;
+use super::index::SharedLumpIndex;
+use super::portion::Portion;
+use crate::lump::{LumpData, LumpId};
+use crate::metrics::StorageMetrics;
+use crate::Result;
+
+/// ストレージを並列に読み込むためのハンドル.
+///
+/// `Storage::reader`によって生成され、`Storage`を保持するスレッドとは別のスレッドから、
+/// データ領域に格納されているlumpの取得を行うために使用される.
+///
+/// インデックスは`Storage`と共有されているので、常に最新の状態が参照される.
+/// また、読み込み中のlumpが削除(ないし上書き)された場合でも、
+/// その部分領域は読み込みが完了するまで解放されないので、別のデータに置き換わってしまうことはない.
+#[derive(Debug)]
+pub(crate) struct StorageReader {
+    lump_index: SharedLumpIndex,
+    data_region: DataRegionReader,
+    metrics: StorageMetrics,
+}
+impl StorageReader {
+    pub fn new(
+        lump_index: SharedLumpIndex,
+        data_region: DataRegionReader,
+        metrics: StorageMetrics,
+    ) -> Self {
+        StorageReader {
+            lump_index,
+            data_region,
+            metrics,
+        }
+    }
+
+    /// 指定されたIDのlumpを取得する.
+    ///
+    /// ジャーナル領域に埋め込まれているlumpや、書き込み中のパック内のlumpは扱えないので、
+    /// その場合には`ParallelGet::Delegated`が返される.
+    pub fn get(&self, lump_id: &LumpId) -> Result<ParallelGet> {
+        // インデックスのロックを保持したまま登録することで、
+        // 検索から登録までの間に部分領域が解放されることを防ぐ
+        let (portion, _pin) = {
+            let index = self.lump_index.read();
+            match index.get(lump_id) {
+                None => return Ok(ParallelGet::Done(None)),
+                Some(Portion::Journal(_)) => return Ok(ParallelGet::Delegated),
+                Some(portion @ Portion::Data(p)) => (portion, self.data_region.pins().pin(p.start)),
+                Some(Portion::Packed(p)) if self.data_region.pins().is_open_pack(p.pack) => {
+                    return Ok(ParallelGet::Delegated)
+                }
+                Some(portion @ Portion::Packed(p)) => {
+                    (portion, self.data_region.pins().pin(p.pack))
+                }
+            }
+        };
+        self.metrics.get_data_lumps.increment();
+        let data = match portion {
+            Portion::Data(portion) => track!(self.data_region.get(portion).map(LumpData::from))?,
+            Portion::Packed(portion) => {
+                let bytes = track!(self.data_region.get_packed(portion))?;
+                track!(LumpData::new(bytes))?
+            }
+            Portion::Journal(_) => unreachable!(),
+        };
+        Ok(ParallelGet::Done(Some(data)))
+    }
+}
+
+/// `StorageReader::get`の結果.
+#[derive(Debug)]
+pub(crate) enum ParallelGet {
+    /// 取得が完了した.
+    Done(Option<LumpData>),
+
+    /// ジャーナル領域に埋め込まれているか、書き込み中のパック内のlumpなので、`Storage`経由で取得する必要がある.
+    Delegated,
+}
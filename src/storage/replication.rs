@@ -0,0 +1,43 @@
+//! ジャーナルの追記内容を他のストレージに複製するための型群.
+use std::ops::Range;
+
+use crate::lump::LumpId;
+
+/// ストレージに対して行われた、コミット済みの更新操作.
+///
+/// `Storage::mutations_since`で取得し、`Storage::apply_mutation`で別のストレージに適用することができる.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mutation {
+    /// 操作を記録したジャーナルレコードのLSN.
+    pub lsn: u64,
+
+    /// 操作を記録したジャーナルレコードの次のLSN.
+    ///
+    /// 続きを取得する際には、この値を`Storage::mutations_since`に渡せば良い.
+    pub next_lsn: u64,
+
+    /// 操作の内容.
+    pub kind: MutationKind,
+}
+
+/// 更新操作の種類.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MutationKind {
+    /// Lumpの格納.
+    Put {
+        /// 対象のlumpのID.
+        lump_id: LumpId,
+
+        /// Lumpのデータ.
+        data: Vec<u8>,
+
+        /// 複製元でジャーナル領域に埋め込まれていたかどうか.
+        embedded: bool,
+    },
+
+    /// Lumpの削除.
+    Delete(LumpId),
+
+    /// 範囲指定でのlumpの削除.
+    DeleteRange(Range<LumpId>),
+}
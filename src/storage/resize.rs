@@ -0,0 +1,705 @@
+//! 既存のストレージのジャーナル領域のサイズ変更.
+use prometrics::metrics::MetricBuilder;
+use slog::Logger;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::block::BlockSize;
+use crate::lump::LumpId;
+use crate::metrics::DataAllocatorMetrics;
+use crate::nvm::{NonVolatileMemory, SyncLevel};
+use crate::storage::address::Address;
+use crate::storage::allocator::DataPortionAllocator;
+use crate::storage::data_region::{DataRegion, DataRegionLumpData};
+use crate::storage::index::LumpIndex;
+use crate::storage::journal::{adler32, JournalHeaderRegion, JournalRecord};
+use crate::storage::portion::{DataPortion, PackedPortion, Portion};
+use crate::storage::{
+    StorageBuilder, StorageHeader, MAX_DATA_REGION_SIZE, MAX_JOURNAL_REGION_SIZE,
+    MAX_WIDE_DATA_REGION_SIZE, MINOR_VERSION, WIDE_MINOR_VERSION,
+};
+use crate::{Error, ErrorKind, Result};
+
+/// 新しいジャーナル領域に書き込まれる、生存中のlumpのPUT操作.
+#[derive(Debug)]
+enum LiveRecord {
+    Embed(LumpId, Vec<u8>),
+    Put(LumpId, DataPortion),
+    PutPacked(LumpId, PackedPortion),
+}
+impl LiveRecord {
+    fn external_size(&self) -> u64 {
+        let size = match *self {
+            LiveRecord::Embed(lump_id, ref data) => {
+                JournalRecord::Embed(lump_id, data).external_size()
+            }
+            LiveRecord::Put(lump_id, portion) => {
+                JournalRecord::Put::<[_; 0]>(lump_id, portion).external_size()
+            }
+            LiveRecord::PutPacked(lump_id, portion) => {
+                JournalRecord::PutPacked::<[_; 0]>(lump_id, portion).external_size()
+            }
+        };
+        size as u64
+    }
+}
+
+/// データ領域内での位置が変わる(ないし新しいジャーナル領域と重なるために移動が必要な)lump.
+#[derive(Debug)]
+enum Relocation {
+    Data(LumpId, DataRegionLumpData),
+    Packed(LumpId, Vec<u8>),
+}
+
+/// ジャーナル領域のサイズ変更が途中であることを示すマーカーの、ヘッダのメタデータ内でのキー.
+///
+/// 値は`${ステージング領域の位置}:${ジャーナル領域のイメージのサイズ}:${イメージのチェックサム}`形式.
+///
+/// マーカーが記録されているストレージは、
+/// `StorageBuilder::resize_journal_region`によってサイズ変更を完了させるまでオープンできない.
+pub const RESIZE_MARKER_KEY: &str = "cannyls.resize";
+
+/// ストレージのジャーナル領域のサイズを変更する.
+///
+/// 詳細は`StorageBuilder::resize_journal_region`のドキュメントを参照のこと.
+pub(crate) fn resize_journal_region<N>(
+    builder: &StorageBuilder,
+    logger: &Logger,
+    nvm: N,
+    journal_region_size: u64,
+) -> Result<StorageHeader>
+where
+    N: NonVolatileMemory,
+{
+    let mut nvm = SharedNvm::new(nvm);
+
+    // 0. 前回のサイズ変更が中断されている場合には、先にそれを完了させる
+    let (header, _) = track!(builder.read_header(&mut nvm))?;
+    if header.metadata.properties.contains_key(RESIZE_MARKER_KEY) {
+        warn!(
+            logger,
+            "Resumes the interrupted resizing of the journal region";
+            "journal_region_size" => header.journal_region_size
+        );
+        track!(complete_resize(&mut nvm, header))?;
+    }
+
+    let header = track!(prepare_resize(
+        builder,
+        logger,
+        nvm.clone(),
+        journal_region_size
+    ))?;
+    track!(complete_resize(&mut nvm, header))
+}
+
+/// サイズ変更後のジャーナル領域のイメージをステージング領域に書き込み、マーカー付きのヘッダを保存する.
+///
+/// ここまでの書き込みは全て、新旧いずれのレイアウトにおいても空き領域に対して行われるので、
+/// 途中でクラッシュしても既存のストレージは壊れない.
+///
+/// 結果として、マーカー付きの新しいヘッダが返される(サイズが変わらない場合には、既存のヘッダがそのまま返される).
+fn prepare_resize<N>(
+    builder: &StorageBuilder,
+    logger: &Logger,
+    nvm: SharedNvm<N>,
+    journal_region_size: u64,
+) -> Result<StorageHeader>
+where
+    N: NonVolatileMemory,
+{
+    // 1. 既存のストレージから、生存中のlumpの情報を集める
+    let mut storage = track!(builder.open_read_only(nvm.clone()))?;
+    let old_header = storage.header().clone();
+    let block_size = old_header.block_size;
+
+    let journal_region_size = block_size.ceil_align(journal_region_size);
+    if journal_region_size == old_header.journal_region_size {
+        return Ok(old_header);
+    }
+    track_assert!(
+        journal_region_size <= MAX_JOURNAL_REGION_SIZE,
+        ErrorKind::InvalidInput,
+        "Too large journal region: {}",
+        journal_region_size
+    );
+    let total_size = old_header.journal_region_size + old_header.data_region_size;
+    let data_region_size = track_assert_some!(
+        total_size.checked_sub(journal_region_size),
+        ErrorKind::InvalidInput,
+        "Too large journal region: {} (available={})",
+        journal_region_size,
+        total_size
+    );
+    let (latest_minor_version, max_data_region_size) = if old_header.is_wide_format() {
+        (WIDE_MINOR_VERSION, MAX_WIDE_DATA_REGION_SIZE)
+    } else {
+        (MINOR_VERSION, MAX_DATA_REGION_SIZE)
+    };
+    track_assert!(
+        data_region_size <= max_data_region_size,
+        ErrorKind::InvalidInput,
+        "Too large data region: {}",
+        data_region_size
+    );
+    let new_header = StorageHeader {
+        minor_version: latest_minor_version,
+        journal_region_size,
+        data_region_size,
+        ..old_header.clone()
+    };
+
+    // データ領域の先頭位置の移動量(ブロック単位).
+    // 正の場合にはジャーナル領域が拡張され、データ領域の先頭部分が失われることになる.
+    let block_bytes = u64::from(block_size.as_u32());
+    let shift =
+        (journal_region_size as i64 - old_header.journal_region_size as i64) / block_bytes as i64;
+    let rebase = |address: Address| -> Option<Address> {
+        let start = address.as_u64() as i64 - shift;
+        if start < 0 {
+            None
+        } else {
+            Address::from_u64(start as u64)
+        }
+    };
+
+    // 新しいデータ領域内で、旧レイアウトにおいて使用中の(が、新しいインデックスには含まれない)部分領域群.
+    //
+    // 縮小時には、旧ジャーナル領域がデータ領域の先頭部分となるので、それも含まれる.
+    let mut reserved = Vec::new();
+    if shift < 0 {
+        reserved.push(DataPortion {
+            start: Address::from(0),
+            len: (-shift) as u32,
+        });
+    }
+
+    let mut records = Vec::new();
+    let mut relocations = Vec::new();
+    for lump_id in storage.list() {
+        let portion = track_assert_some!(storage.lump_index.read().get(&lump_id), ErrorKind::Other);
+        match portion {
+            Portion::Journal(portion) => {
+                let data = track!(storage.journal_region.get_embedded_data(portion))?;
+                records.push(LiveRecord::Embed(lump_id, data));
+            }
+            Portion::Data(portion) => {
+                if let Some(start) = rebase(portion.start) {
+                    let portion = DataPortion {
+                        start,
+                        len: portion.len,
+                    };
+                    records.push(LiveRecord::Put(lump_id, portion));
+                } else {
+                    // 新しい境界を跨ぐ場合には、その後半部分は新しいデータ領域内に残るので、確保済みとして扱う
+                    let end = portion.end().as_u64() as i64;
+                    if end > shift {
+                        reserved.push(DataPortion {
+                            start: Address::from(0),
+                            len: (end - shift) as u32,
+                        });
+                    }
+                    let data = track!(storage.data_region.get(portion))?;
+                    relocations.push(Relocation::Data(lump_id, data));
+                }
+            }
+            Portion::Packed(portion) => {
+                match rebase(portion.pack).filter(|a| a.as_u64() <= Address::MAX_V1) {
+                    Some(pack) => {
+                        let portion = PackedPortion { pack, ..portion };
+                        records.push(LiveRecord::PutPacked(lump_id, portion));
+                    }
+                    None => {
+                        if let Some(pack) = rebase(portion.pack) {
+                            reserved.push(PackedPortion { pack, ..portion }.pack_portion());
+                        }
+                        let data = track!(storage.data_region.get_packed(portion))?;
+                        relocations.push(Relocation::Packed(lump_id, data));
+                    }
+                }
+            }
+        }
+    }
+    reserved.sort();
+    reserved.dedup();
+    let head_lsn = storage.committed_lsn();
+    drop(storage);
+
+    // 2. 移動が必要なlumpを、新しいデータ領域内の空き領域に書き込む
+    //
+    // 書き込み先は、旧レイアウトにおいても空き領域なので、この時点で失敗しても既存のストレージは壊れない
+    let regions = track!(new_header.split_regions(nvm.clone()))?;
+    let mut index = LumpIndex::new();
+    for record in &records {
+        match *record {
+            LiveRecord::Put(lump_id, portion) => index.insert(lump_id, Portion::Data(portion)),
+            LiveRecord::PutPacked(lump_id, portion) => {
+                index.insert(lump_id, Portion::Packed(portion))
+            }
+            LiveRecord::Embed(..) => {}
+        }
+    }
+    let metrics = MetricBuilder::new();
+    let allocator = track!(DataPortionAllocator::build(
+        DataAllocatorMetrics::new(&metrics, data_region_size, block_size),
+        index.data_portions().chain(reserved.iter().cloned()),
+    ))?;
+    let mut data_region = DataRegion::new(&metrics, allocator, regions.data);
+    data_region.set_max_portion_len(new_header.max_portion_len());
+    data_region.restore_packs(index.packed_portions().map(|(_, p)| p));
+
+    // 新しい`DataRegion`は書き込み中のパックを持たないので、
+    // パック内に格納されていたlumpは、新規に確保されたパックにのみ書き込まれる(既存のパックへの追記は行われない)
+    debug_assert!(!data_region.has_open_pack());
+    let relocated = relocations.len();
+    for relocation in relocations {
+        match relocation {
+            Relocation::Data(lump_id, data) => {
+                let portion = track!(data_region.put(&data))?;
+                records.push(LiveRecord::Put(lump_id, portion));
+                index.insert(lump_id, Portion::Data(portion));
+            }
+            Relocation::Packed(lump_id, data) => {
+                if data_region.needs_seal(data.len()) {
+                    track!(data_region.seal_pack())?;
+                }
+                if let Some(portion) = track!(data_region.put_packed(&data))? {
+                    records.push(LiveRecord::PutPacked(lump_id, portion));
+                    index.insert(lump_id, Portion::Packed(portion));
+                } else {
+                    let mut lump_data = DataRegionLumpData::new(data.len(), block_size);
+                    lump_data.as_bytes_mut().copy_from_slice(&data);
+                    let portion = track!(data_region.put(&lump_data))?;
+                    records.push(LiveRecord::Put(lump_id, portion));
+                    index.insert(lump_id, Portion::Data(portion));
+                }
+            }
+        }
+    }
+    track!(data_region.seal_pack())?;
+    track!(data_region.sync_with(SyncLevel::DataSync))?;
+
+    // 新しいリングバッファには、GCが機能するだけの余裕が必要
+    let header_region_size =
+        JournalHeaderRegion::<N>::region_size(block_size, new_header.redundant_headers) as u64;
+    let ring_buffer_size = track_assert_some!(
+        journal_region_size.checked_sub(header_region_size),
+        ErrorKind::InvalidInput,
+        "Too small journal region: {}",
+        journal_region_size
+    );
+    let records_size = records.iter().map(LiveRecord::external_size).sum::<u64>();
+    track_assert!(
+        records_size <= ring_buffer_size / 2,
+        ErrorKind::InvalidInput,
+        "Too small journal region: live records need {} bytes, but the ring buffer has only {} bytes",
+        records_size,
+        ring_buffer_size
+    );
+
+    info!(
+        logger,
+        "Resizes the journal region";
+        "from" => old_header.journal_region_size,
+        "to" => journal_region_size,
+        "live_records" => records.len(),
+        "relocated_lumps" => relocated
+    );
+
+    // 3. 生存中のlumpのレコード群のみを含む、新しいジャーナル領域のイメージを作成して、
+    //    新旧いずれのレイアウトにおいても空き領域となっている位置(ステージング領域)に書き込む
+    let mut image = Vec::new();
+    track!(JournalHeaderRegion::<N>::initialize(
+        &mut image,
+        block_size,
+        new_header.redundant_headers,
+        head_lsn
+    ))?;
+    for record in &records {
+        match *record {
+            LiveRecord::Embed(lump_id, ref data) => {
+                track!(JournalRecord::Embed(lump_id, data).write_to(&mut image))?;
+            }
+            LiveRecord::Put(lump_id, portion) => {
+                track!(JournalRecord::Put::<[_; 0]>(lump_id, portion).write_to(&mut image))?;
+            }
+            LiveRecord::PutPacked(lump_id, portion) => {
+                track!(JournalRecord::PutPacked::<[_; 0]>(lump_id, portion).write_to(&mut image))?;
+            }
+        }
+    }
+    track!(JournalRecord::EndOfRecords::<[_; 0]>.write_to(&mut image))?;
+
+    let image_blocks = block_size.ceil_align(image.len() as u64) / block_bytes;
+    let staging = track_assert_some!(
+        find_free_area(
+            index.data_portions().chain(reserved.iter().cloned()),
+            data_region_size / block_bytes,
+            image_blocks
+        ),
+        ErrorKind::StorageFull,
+        "No space to stage the new journal region: {} bytes",
+        image.len()
+    );
+    let staging_offset = new_header.region_size() + journal_region_size + staging * block_bytes;
+    let mut staging_nvm = nvm.clone();
+    track_io!(staging_nvm.seek(SeekFrom::Start(staging_offset)))?;
+    track!(staging_nvm.aligned_write_all(|temp_buf| {
+        temp_buf.extend_from_slice(&image);
+        Ok(())
+    }))?;
+    track!(staging_nvm.sync())?;
+
+    // 4. マーカー付きの新しいヘッダを書き込む
+    //
+    // これ以降にクラッシュした場合には、`complete_resize`によって処理を再開できる
+    let mut header = new_header;
+    header.metadata.properties.insert(
+        RESIZE_MARKER_KEY.to_owned(),
+        format!("{}:{}:{}", staging_offset, image.len(), adler32(&image)),
+    );
+    let mut nvm = nvm;
+    track!(header.write_primary_region(&mut nvm))?;
+    track!(header.write_backup_region(&mut nvm))?;
+    track!(nvm.sync())?;
+    Ok(header)
+}
+
+/// ステージング領域からジャーナル領域にイメージを複写して、ヘッダからマーカーを取り除く.
+///
+/// 処理は冪等なので、途中でクラッシュした場合でも、再度呼び出せば完了させることができる.
+///
+/// `header`にマーカーが含まれていない場合には、何も行わない.
+fn complete_resize<N>(nvm: &mut SharedNvm<N>, mut header: StorageHeader) -> Result<StorageHeader>
+where
+    N: NonVolatileMemory,
+{
+    let marker = match header.metadata.properties.remove(RESIZE_MARKER_KEY) {
+        None => return Ok(header),
+        Some(marker) => marker,
+    };
+    let (staging_offset, image_size, checksum) = track!(parse_marker(&marker))?;
+    let data_region_offset = header.region_size() + header.journal_region_size;
+    track_assert!(
+        image_size <= header.journal_region_size
+            && data_region_offset <= staging_offset
+            && staging_offset + image_size <= data_region_offset + header.data_region_size,
+        ErrorKind::StorageCorrupted,
+        "Broken resize marker: {}",
+        marker
+    );
+
+    // 5. ステージング領域のイメージを、ジャーナル領域に複写する
+    track_io!(nvm.seek(SeekFrom::Start(staging_offset)))?;
+    let image = track!(nvm.aligned_read_bytes(image_size as usize))?;
+    track_assert_eq!(
+        adler32(&image),
+        checksum,
+        ErrorKind::StorageCorrupted,
+        "The staged journal region is broken"
+    );
+    track_io!(nvm.seek(SeekFrom::Start(header.region_size())))?;
+    track!(nvm.aligned_write_all(|temp_buf| {
+        temp_buf.extend_from_slice(&image);
+        Ok(())
+    }))?;
+    track!(nvm.sync())?;
+
+    // 6. マーカーを取り除いたヘッダを書き込む
+    track!(header.write_primary_region(nvm))?;
+    track!(header.write_backup_region(nvm))?;
+    track!(nvm.sync())?;
+    Ok(header)
+}
+
+/// マーカーの値を`(ステージング領域の位置, イメージのサイズ, チェックサム)`に分解する.
+fn parse_marker(marker: &str) -> Result<(u64, u64, u32)> {
+    let mut tokens = marker.splitn(3, ':');
+    let staging_offset = tokens.next().and_then(|t| t.parse().ok());
+    let image_size = tokens.next().and_then(|t| t.parse().ok());
+    let checksum = tokens.next().and_then(|t| t.parse().ok());
+    match (staging_offset, image_size, checksum) {
+        (Some(staging_offset), Some(image_size), Some(checksum)) => {
+            Ok((staging_offset, image_size, checksum))
+        }
+        _ => track_panic!(
+            ErrorKind::StorageCorrupted,
+            "Broken resize marker: {}",
+            marker
+        ),
+    }
+}
+
+/// 使用中の部分領域群`portions`と重ならない、`len`ブロック分の連続した空き領域を探す.
+///
+/// 空き領域は、データ領域(長さは`capacity`ブロック)の末尾側から優先的に選ばれる.
+fn find_free_area<I>(portions: I, capacity: u64, len: u64) -> Option<u64>
+where
+    I: Iterator<Item = DataPortion>,
+{
+    let mut portions = portions.collect::<Vec<_>>();
+    portions.sort_by_key(|p| std::cmp::Reverse(p.end()));
+
+    let mut tail = capacity;
+    for portion in portions {
+        let end = portion.end().as_u64();
+        if end < tail && tail - end >= len {
+            return Some(tail - len);
+        }
+        tail = std::cmp::min(tail, portion.start.as_u64());
+    }
+    if tail >= len {
+        Some(tail - len)
+    } else {
+        None
+    }
+}
+
+/// 一つのNVMを、複数の(重なり得る)領域から共有するためのラッパー.
+///
+/// `Storage`は内部でNVMを分割して所有してしまうため、
+/// 一度オープンしたストレージのNVMを、別のレイアウトで書き直す際に使用する.
+#[derive(Debug)]
+struct SharedNvm<N> {
+    inner: Arc<Mutex<N>>,
+    start: u64,
+    end: u64,
+    position: u64,
+    block_size: BlockSize,
+}
+impl<N: NonVolatileMemory> SharedNvm<N> {
+    fn new(inner: N) -> Self {
+        let end = inner.capacity();
+        let block_size = inner.block_size();
+        SharedNvm {
+            inner: Arc::new(Mutex::new(inner)),
+            start: 0,
+            end,
+            position: 0,
+            block_size,
+        }
+    }
+
+    fn with_inner<F, T>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut N, u64) -> Result<T>,
+    {
+        let mut inner = track!(self.inner.lock().map_err(Error::from))?;
+        let offset = self.start + self.position;
+        track_io!(inner.seek(SeekFrom::Start(offset)))?;
+        track!(f(&mut inner, self.end - offset))
+    }
+}
+impl<N> Clone for SharedNvm<N> {
+    fn clone(&self) -> Self {
+        SharedNvm {
+            inner: Arc::clone(&self.inner),
+            start: self.start,
+            end: self.end,
+            position: self.position,
+            block_size: self.block_size,
+        }
+    }
+}
+impl<N: NonVolatileMemory> NonVolatileMemory for SharedNvm<N> {
+    fn sync(&mut self) -> Result<()> {
+        track!(self.with_inner(|inner, _| track!(inner.sync())))
+    }
+    fn sync_with(&mut self, level: SyncLevel) -> Result<()> {
+        track!(self.with_inner(|inner, _| track!(inner.sync_with(level))))
+    }
+    fn position(&self) -> u64 {
+        self.position
+    }
+    fn capacity(&self) -> u64 {
+        self.end - self.start
+    }
+    fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+    fn split(self, position: u64) -> Result<(Self, Self)> {
+        track_assert_eq!(
+            position,
+            self.block_size.ceil_align(position),
+            ErrorKind::InvalidInput
+        );
+        track_assert!(position <= self.capacity(), ErrorKind::InvalidInput);
+        let mut left = self.clone();
+        let mut right = self;
+        left.end = left.start + position;
+        right.start = left.end;
+        left.position = 0;
+        right.position = 0;
+        Ok((left, right))
+    }
+}
+impl<N: NonVolatileMemory> Seek for SharedNvm<N> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = self.convert_to_offset(pos)?;
+        self.position = position;
+        Ok(position)
+    }
+}
+impl<N: NonVolatileMemory> Read for SharedNvm<N> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = track!(self.with_inner(|inner, remaining| {
+            let len = std::cmp::min(buf.len() as u64, remaining) as usize;
+            track_io!(inner.read(&mut buf[..len]))
+        }))?;
+        self.position += size as u64;
+        Ok(size)
+    }
+}
+impl<N: NonVolatileMemory> Write for SharedNvm<N> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = track!(self.with_inner(|inner, remaining| {
+            let len = std::cmp::min(buf.len() as u64, remaining) as usize;
+            track_io!(inner.write(&buf[..len]))
+        }))?;
+        self.position += size as u64;
+        Ok(size)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        track!(self.with_inner(|inner, _| track_io!(inner.flush())))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use slog::Discard;
+    use std::collections::{HashMap, HashSet};
+    use std::io::{Seek, SeekFrom, Write};
+    use trackable::result::TestResult;
+
+    use super::*;
+    use crate::lump::LumpData;
+    use crate::nvm::SharedMemoryNvm;
+    use crate::storage::Storage;
+
+    #[test]
+    fn interrupted_resize_can_be_resumed() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut storage = track!(StorageBuilder::new()
+            .pack_threshold(100)
+            .create(nvm.clone()))?;
+        let mut expected = Vec::new();
+        for i in 0..40 {
+            let data = track!(LumpData::new(vec![i as u8; 50]))?;
+            assert!(storage.put(&LumpId::new(i), &data)?);
+            expected.push((LumpId::new(i), data.as_bytes().to_owned()));
+        }
+        let old_header = storage.header().clone();
+        let old_packs = track!(pack_positions(&storage))?;
+        track!(storage.close())?;
+
+        // データ領域の先頭の二ブロック分だけ、ジャーナル領域を拡張する
+        let journal_region_size = old_header.journal_region_size + 1024;
+        let builder = StorageBuilder::new();
+        let logger = Logger::root(Discard, o!());
+        let header = track!(prepare_resize(
+            &builder,
+            &logger,
+            SharedNvm::new(nvm.clone()),
+            journal_region_size
+        ))?;
+        assert!(header.metadata.properties.contains_key(RESIZE_MARKER_KEY));
+
+        // ジャーナル領域への複写の途中でクラッシュした状況を模倣する
+        let mut torn = nvm.clone();
+        track_io!(torn.seek(SeekFrom::Start(header.region_size())))?;
+        track_io!(torn.write_all(&[0xFF; 512]))?;
+
+        // マーカーが残っている間は、オープンできない
+        assert_eq!(
+            Storage::open(nvm.clone()).err().map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+
+        // 再度呼び出すと、中断されたサイズ変更が完了する
+        let header = track!(builder.resize_journal_region(nvm.clone(), journal_region_size))?;
+        assert_eq!(header.journal_region_size, journal_region_size);
+        assert!(!header.metadata.properties.contains_key(RESIZE_MARKER_KEY));
+
+        let mut storage = track!(Storage::open(nvm.clone()))?;
+        let mut actual = Vec::new();
+        for lump_id in storage.list() {
+            let data = track_assert_some!(storage.get(&lump_id)?, ErrorKind::Other);
+            actual.push((lump_id, data.as_bytes().to_owned()));
+        }
+        assert_eq!(actual, expected);
+
+        // 移動されたlumpは、既存のパックではなく、新規に確保されたパックに格納されている
+        let new_packs = track!(pack_positions(&storage))?;
+        let old_positions = old_packs.values().cloned().collect::<HashSet<_>>();
+        let mut relocated = 0;
+        for (lump_id, position) in &new_packs {
+            if old_packs[lump_id] != *position {
+                assert!(!old_positions.contains(position));
+                relocated += 1;
+            }
+        }
+        assert!(0 < relocated && relocated < new_packs.len());
+        Ok(())
+    }
+
+    #[test]
+    fn interrupted_resize_keeps_lump_straddling_new_boundary() -> TestResult {
+        let nvm = SharedMemoryNvm::new(vec![0; 1024 * 1024]);
+        let mut storage = track!(Storage::create(nvm.clone()))?;
+
+        // データ領域の先頭に、一ブロックのlumpと、新しい境界を跨ぐ二十ブロックのlumpを配置し、
+        // 残りの大部分を別のlumpで埋めておく(移動される前者が、跨ぐlumpの後半部分に書き込まれ得る状況を作る)
+        let blocks = storage.header().data_region_size / 512;
+        let small = track!(LumpData::new(vec![0; 100]))?;
+        assert!(storage.put(&LumpId::new(0), &small)?);
+        let straddling = track!(LumpData::new(vec![1; 20 * 512 - 100]))?;
+        assert!(storage.put(&LumpId::new(1), &straddling)?);
+        let following = track!(LumpData::new(vec![2; (blocks as usize - 61) * 512 - 100]))?;
+        assert!(storage.put(&LumpId::new(2), &following)?);
+        let old_header = storage.header().clone();
+        track!(storage.close())?;
+
+        // データ領域の先頭の二ブロック分だけ、ジャーナル領域を拡張する
+        let journal_region_size = old_header.journal_region_size + 1024;
+        let builder = StorageBuilder::new();
+        let logger = Logger::root(Discard, o!());
+        let header = track!(prepare_resize(
+            &builder,
+            &logger,
+            SharedNvm::new(nvm.clone()),
+            journal_region_size
+        ))?;
+        assert!(header.metadata.properties.contains_key(RESIZE_MARKER_KEY));
+
+        // マーカー付きのヘッダが書き込まれる直前にクラッシュした状況を模倣する
+        let mut nvm_for_header = nvm.clone();
+        track!(old_header.write_primary_region(&mut nvm_for_header))?;
+        track!(old_header.write_backup_region(&mut nvm_for_header))?;
+
+        // 旧レイアウトのままでも、境界を跨いでいたlumpの内容は壊れていない
+        let mut storage = track!(Storage::open(nvm))?;
+        for (i, expected) in [small, straddling, following].iter().enumerate() {
+            let data = track_assert_some!(storage.get(&LumpId::new(i as u128))?, ErrorKind::Other);
+            assert_eq!(data.as_bytes(), expected.as_bytes());
+        }
+        Ok(())
+    }
+
+    /// パック内に格納されている各lumpについて、パックのNVM上での位置(バイト単位)を返す.
+    fn pack_positions(storage: &Storage<SharedMemoryNvm>) -> Result<HashMap<LumpId, u64>> {
+        let header = storage.header();
+        let data_region_offset = header.region_size() + header.journal_region_size;
+        let block_bytes = u64::from(header.block_size.as_u32());
+        let mut positions = HashMap::new();
+        for lump_id in storage.list() {
+            let portion =
+                track_assert_some!(storage.lump_index.read().get(&lump_id), ErrorKind::Other);
+            if let Portion::Packed(portion) = portion {
+                let position = data_region_offset + portion.pack.as_u64() * block_bytes;
+                positions.insert(lump_id, position);
+            }
+        }
+        Ok(positions)
+    }
+}